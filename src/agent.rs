@@ -0,0 +1,226 @@
+use probe_rs::{Permissions, Session};
+use serde::{Deserialize, Serialize};
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use crate::cancel;
+use crate::flow::{self, FlowOptions, Stage};
+use crate::hooks::Hooks;
+use crate::i18n::tr;
+use crate::image::ImageFile;
+use crate::output::say;
+use crate::registry;
+use crate::{open_probe, ProbeArgs, RecoveryError};
+
+/// Where the agent listens unless told otherwise; loopback only.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:7401";
+
+/// A request to the agent, sent as one line of JSON.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Request {
+    /// Flash images and reset the device, like `flash`. Paths are read by
+    /// the agent, so they must be absolute.
+    Flash { images: Vec<ImageFile> },
+    /// Reset the device.
+    Reset,
+    /// Stop the request in flight, from any client, at its next safe point.
+    Cancel,
+    /// Close the session and exit the agent.
+    Stop,
+}
+
+/// The agent's answer to a request, sent as one line of JSON.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    /// The request was cancelled rather than failed.
+    #[serde(default)]
+    pub cancelled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<Result<(), RecoveryError>> for Response {
+    fn from(result: Result<(), RecoveryError>) -> Self {
+        match result {
+            Ok(()) => Response {
+                ok: true,
+                cancelled: false,
+                error: None,
+            },
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                Response {
+                    ok: false,
+                    cancelled: e.is_cancelled(),
+                    error: Some(e.to_string()),
+                }
+            }
+        }
+    }
+}
+
+/// A request handed to the agent's worker, with where to send the answer.
+type Queued = (Request, Sender<Response>);
+
+/// Keeps the probe open and the session attached between flashes, so a
+/// development loop only pays for the flash itself.
+pub struct Agent<'a> {
+    probe: &'a ProbeArgs,
+    /// Chip and flash settings; the images come with each request.
+    options: FlowOptions,
+    session: Option<Session>,
+}
+
+impl<'a> Agent<'a> {
+    pub fn new(probe: &'a ProbeArgs, options: FlowOptions) -> Self {
+        Agent {
+            probe,
+            options,
+            session: None,
+        }
+    }
+
+    /// Attach right away, so the first flash is as fast as the rest, then
+    /// serve requests one at a time until a client asks the agent to stop.
+    /// Clients are read on their own threads, so a cancel reaches a request
+    /// in flight.
+    pub fn serve(&mut self, address: SocketAddr) -> Result<(), RecoveryError> {
+        let listener =
+            TcpListener::bind(address).map_err(|e| RecoveryError::AgentError(format!("{}: {}", address, e)))?;
+        if let Err(e) = self.session() {
+            // The board may not be plugged in yet; the first request
+            // attaches again.
+            log::warn!("unable to attach yet: {}", e);
+        }
+        say(tr!("agent_listening", address = address));
+
+        let (queue, requests) = mpsc::channel::<Queued>();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let queue = queue.clone();
+                        thread::spawn(move || client(stream, queue));
+                    }
+                    Err(e) => log::warn!("agent connection failed: {}", e),
+                }
+            }
+        });
+
+        for (request, reply) in requests {
+            if matches!(request, Request::Stop) {
+                break;
+            }
+            cancel::clear();
+            let _ = reply.send(Response::from(self.execute(request)));
+        }
+        Ok(())
+    }
+
+    /// The attached session, attaching on first use.
+    fn session(&mut self) -> Result<&mut Session, RecoveryError> {
+        if self.session.is_none() {
+            let probe = open_probe(self.probe, &self.options.ports)?;
+            let session = registry::attach(probe, &self.options.chip, Permissions::new())
+                .map_err(|e| RecoveryError::from(e).at(Stage::Attach))?;
+            self.session = Some(session);
+        }
+        Ok(self.session.as_mut().unwrap())
+    }
+
+
+    fn execute(&mut self, request: Request) -> Result<(), RecoveryError> {
+        let result = match request {
+            Request::Flash { images } => {
+                let options = FlowOptions {
+                    images,
+                    ..self.options.clone()
+                };
+                self.session().and_then(|session| {
+                    flow::flash_images(session, &options, &Hooks::default())?;
+                    session
+                        .core(0)
+                        .and_then(|mut core| core.reset())
+                        .map_err(|e| RecoveryError::from(e).at(Stage::Reset))
+                })
+            }
+            Request::Reset => self.session().and_then(|session| {
+                session
+                    .core(0)
+                    .and_then(|mut core| core.reset())
+                    .map_err(|e| RecoveryError::from(e).at(Stage::Reset))
+            }),
+            Request::Cancel | Request::Stop => Ok(()),
+        };
+        // The probe or the board may have gone away; attach from scratch
+        // for the next request.
+        if result.is_err() {
+            self.session = None;
+        }
+        result
+    }
+}
+
+/// Answer the requests of one client, passing them to the agent's worker.
+/// `cancel` is answered right away, and `stop` before the worker sees it,
+/// so the client hears back before the agent exits.
+fn client(stream: TcpStream, queue: Sender<Queued>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => return log::warn!("agent connection failed: {}", e),
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { return };
+        let (response, stop) = match serde_json::from_str::<Request>(&line) {
+            Err(e) => (Response::from(Err(RecoveryError::AgentError(e.to_string()))), false),
+            Ok(Request::Cancel) => {
+                cancel::request();
+                say(tr!("agent_cancelling"));
+                (Response::from(Ok(())), false)
+            }
+            Ok(Request::Stop) => (Response::from(Ok(())), true),
+            Ok(request) => {
+                let (reply, answer) = mpsc::channel();
+                if queue.send((request, reply)).is_err() {
+                    return;
+                }
+                let Ok(response) = answer.recv() else { return };
+                (response, false)
+            }
+        };
+        let reply = serde_json::to_string(&response).unwrap_or_default();
+        if writeln!(writer, "{}", reply).is_err() {
+            return;
+        }
+        if stop {
+            let (reply, _) = mpsc::channel();
+            let _ = queue.send((Request::Stop, reply));
+            return;
+        }
+    }
+}
+
+/// Send `request` to the agent at `address` and wait for it to be done.
+pub fn send(address: SocketAddr, request: &Request) -> Result<(), RecoveryError> {
+    let err = |e: std::io::Error| RecoveryError::AgentError(format!("{}: {}", address, e));
+    let mut stream = TcpStream::connect(address).map_err(|e| {
+        RecoveryError::AgentError(format!("no agent at {}: {}; start one with `recovery agent`", address, e))
+    })?;
+    let line = serde_json::to_string(request).map_err(|e| RecoveryError::AgentError(e.to_string()))?;
+    writeln!(stream, "{}", line).map_err(err)?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply).map_err(err)?;
+    let response: Response = serde_json::from_str(&reply)
+        .map_err(|e| RecoveryError::AgentError(format!("{}: unexpected reply: {}", address, e)))?;
+    match response {
+        Response { ok: true, .. } => Ok(()),
+        Response { cancelled: true, .. } => Err(RecoveryError::Cancelled),
+        Response { error, .. } => Err(RecoveryError::AgentError(error.unwrap_or_else(|| "request failed".into()))),
+    }
+}
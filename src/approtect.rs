@@ -0,0 +1,188 @@
+use clap::ValueEnum;
+use probe_rs::{probe::Probe, MemoryInterface, Session};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::dap::{self, DebugPorts};
+use crate::RecoveryError;
+
+pub const UICR_APPROTECT: u64 = 0x00FF8000;
+pub const UICR_SECUREAPPROTECT: u64 = 0x00FF802C;
+/// Keeps the access port open on parts with hardware access port protection.
+pub const HW_UNPROTECTED: u32 = 0x50FA50FA;
+/// Keeps the access port open on the nRF9160: PALL left set. Its protection
+/// closes on any other PALL value, HwUnprotected included.
+pub const NRF9160_UNPROTECTED: u32 = 0xFFFFFFFF;
+/// Closes the access port from the next reset on every nRF91 revision.
+pub const PROTECTED: u32 = 0x00000000;
+
+/// What to write to one of the protection words.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApprotectPolicy {
+    /// HwUnprotected: the access port stays open as long as the firmware
+    /// opens it during boot.
+    #[default]
+    HwDisabled,
+    /// Protected: the access port is closed from the next reset on.
+    Enabled,
+    /// Leave the word as it is.
+    Untouched,
+}
+
+/// The nRF9160 predates hardware access port protection.
+fn is_nrf9160(chip: &str) -> bool {
+    chip.to_ascii_lowercase().starts_with("nrf9160")
+}
+
+/// The word that keeps the access port of `chip` open: [`NRF9160_UNPROTECTED`]
+/// on nRF9160, [`HW_UNPROTECTED`] on nRF9161 and nRF9151.
+pub fn unprotected(chip: &str) -> u32 {
+    if is_nrf9160(chip) {
+        NRF9160_UNPROTECTED
+    } else {
+        HW_UNPROTECTED
+    }
+}
+
+impl ApprotectPolicy {
+    /// The register value for `chip`'s family. `None` for
+    /// [`ApprotectPolicy::Untouched`].
+    pub fn value(self, chip: &str) -> Option<u32> {
+        match self {
+            ApprotectPolicy::HwDisabled => Some(unprotected(chip)),
+            ApprotectPolicy::Enabled => Some(PROTECTED),
+            ApprotectPolicy::Untouched => None,
+        }
+    }
+}
+
+/// What the flow writes to APPROTECT and SECUREAPPROTECT.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Protection {
+    pub approtect: ApprotectPolicy,
+    pub secure_approtect: ApprotectPolicy,
+}
+
+impl Protection {
+    /// Both words protected, for units that leave the line locked.
+    pub const LOCKED: Protection = Protection {
+        approtect: ApprotectPolicy::Enabled,
+        secure_approtect: ApprotectPolicy::Enabled,
+    };
+
+    /// The words to write on `chip`, leaving out the untouched ones.
+    pub fn words(&self, chip: &str) -> Vec<(u64, u32)> {
+        [(UICR_APPROTECT, self.approtect), (UICR_SECUREAPPROTECT, self.secure_approtect)]
+            .into_iter()
+            .filter_map(|(address, policy)| Some((address, policy.value(chip)?)))
+            .collect()
+    }
+
+    /// Whether either word closes the access port, so nothing can be read
+    /// from the device after the flow's reset.
+    pub fn enables(&self) -> bool {
+        self.approtect == ApprotectPolicy::Enabled || self.secure_approtect == ApprotectPolicy::Enabled
+    }
+}
+
+fn hex_word<S: Serializer>(value: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("0x{:08x}", value))
+}
+
+/// The UICR words controlling access port protection.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct UicrProtect {
+    #[serde(serialize_with = "hex_word")]
+    pub approtect: u32,
+    #[serde(serialize_with = "hex_word")]
+    pub secureapprotect: u32,
+}
+
+impl UicrProtect {
+    /// Whether the device locks itself on its next reset.
+    ///
+    /// The nRF9160 has no hardware access port protection and is open while
+    /// PALL, the low byte, is 0xFF; later parts are protected unless both
+    /// words hold [`HW_UNPROTECTED`]. Agrees with [`ApprotectPolicy::value`]
+    /// for each family.
+    pub fn locks(&self, chip: &str) -> bool {
+        if is_nrf9160(chip) {
+            self.approtect & 0xff != 0xff || self.secureapprotect & 0xff != 0xff
+        } else {
+            self.approtect != HW_UNPROTECTED || self.secureapprotect != HW_UNPROTECTED
+        }
+    }
+}
+
+/// Protection state of an nRF91 before and after recovery, for reporting
+/// unlock anomalies. `before` is missing when the device was locked.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ApprotectReport {
+    pub before: Option<UicrProtect>,
+    pub after: Option<UicrProtect>,
+    /// Whether a device left locked was seen to refuse debug access after a
+    /// reset. Missing when the device was left unlocked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locked_verified: Option<bool>,
+}
+
+/// Read the UICR protection words through the AHB-AP before anything is
+/// changed. Returns `None` if the device is locked and they cannot be read.
+pub fn read_before(probe: Probe, ports: &DebugPorts) -> Result<(Probe, Option<UicrProtect>), RecoveryError> {
+    let mut iface = ports.connect(probe)?;
+    let words = if ports.debug_enabled(iface.as_mut())? {
+        Some(UicrProtect {
+            approtect: ports.read_word(iface.as_mut(), UICR_APPROTECT as u32)?,
+            secureapprotect: ports.read_word(iface.as_mut(), UICR_SECUREAPPROTECT as u32)?,
+        })
+    } else {
+        None
+    };
+    Ok((dap::disconnect(iface), words))
+}
+
+/// Read the UICR protection words through an attached session.
+pub fn read(session: &mut Session) -> Result<UicrProtect, RecoveryError> {
+    let mut core = session.core(0)?;
+    Ok(UicrProtect {
+        approtect: core.read_word_32(UICR_APPROTECT)?,
+        secureapprotect: core.read_word_32(UICR_SECUREAPPROTECT)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unprotected_value_per_family() {
+        assert_eq!(ApprotectPolicy::HwDisabled.value("nRF9160_xxAA"), Some(0xFFFF_FFFF));
+        assert_eq!(ApprotectPolicy::HwDisabled.value("nRF9161_xxAA"), Some(HW_UNPROTECTED));
+        assert_eq!(ApprotectPolicy::HwDisabled.value("nRF9151_xxAA"), Some(HW_UNPROTECTED));
+        assert_eq!(ApprotectPolicy::Enabled.value("nRF9160_xxAA"), Some(PROTECTED));
+        assert_eq!(ApprotectPolicy::Untouched.value("nRF9151_xxAA"), None);
+    }
+
+    /// The words a flow with `protection` leaves on an erased device.
+    fn written(protection: Protection, chip: &str) -> UicrProtect {
+        let word = |address| {
+            protection
+                .words(chip)
+                .into_iter()
+                .find(|(a, _)| *a == address)
+                .map_or(0xFFFF_FFFF, |(_, value)| value)
+        };
+        UicrProtect {
+            approtect: word(UICR_APPROTECT),
+            secureapprotect: word(UICR_SECUREAPPROTECT),
+        }
+    }
+
+    #[test]
+    fn default_protection_does_not_lock() {
+        for chip in ["nRF9160_xxAA", "nRF9161_xxAA", "nRF9151_xxAA"] {
+            assert!(!written(Protection::default(), chip).locks(chip), "{}", chip);
+            assert!(written(Protection::LOCKED, chip).locks(chip), "{}", chip);
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use sha2::{Digest, Sha256};
+
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::SystemTime;
+
+use crate::notify::shell;
+use crate::RecoveryError;
+
+/// A place images can be fetched from.
+///
+/// The CLI ships local files and shell-command backends; embedders can add
+/// their own stores by implementing this trait and registering the source
+/// with a [`Resolver`].
+pub trait ArtifactSource: Send + Sync {
+    /// Whether `reference` (a path or URL from a job file or the command
+    /// line) belongs to this source.
+    fn handles(&self, reference: &str) -> bool;
+
+    /// Make the artifact available as a local file and return its path.
+    /// Relative references are relative to `base`.
+    fn fetch(&self, reference: &str, base: &Path) -> Result<PathBuf, RecoveryError>;
+}
+
+/// Files on the local file system. Handles every reference without a URL
+/// scheme.
+pub struct LocalSource;
+
+impl ArtifactSource for LocalSource {
+    fn handles(&self, reference: &str) -> bool {
+        !reference.contains("://")
+    }
+
+    fn fetch(&self, reference: &str, base: &Path) -> Result<PathBuf, RecoveryError> {
+        let path = base.join(reference);
+        if !path.exists() {
+            return Err(RecoveryError::FileNotFound(path.display().to_string()));
+        }
+        Ok(path)
+    }
+}
+
+/// URLs with a given scheme, downloaded by a shell command into a cache
+/// directory, e.g. `curl -sfL -o {dest} {url}` for `https`.
+///
+/// Each download's SHA-256 is kept next to it and checked whenever the
+/// cached file is used; a file that no longer matches is fetched again.
+pub struct CommandSource {
+    pub scheme: String,
+    /// Command with `{url}` and `{dest}` placeholders.
+    pub command: String,
+    pub cache: PathBuf,
+    /// Size in bytes the cache is trimmed to after a download, dropping the
+    /// files used least recently.
+    pub limit: Option<u64>,
+}
+
+impl CommandSource {
+    /// Parse a `SCHEME=COMMAND` definition.
+    pub fn parse(s: &str, cache: &Path, limit: Option<u64>) -> Result<Self, String> {
+        let (scheme, command) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected SCHEME=COMMAND, got '{}'", s))?;
+        Ok(CommandSource {
+            scheme: scheme.trim().to_string(),
+            command: command.trim().to_string(),
+            cache: cache.to_path_buf(),
+            limit,
+        })
+    }
+
+    /// Cache file for `url`, keeping the file name so the format stays
+    /// recognizable.
+    fn cache_path(&self, url: &str) -> PathBuf {
+        let name = url.rsplit('/').next().unwrap_or_default();
+        let hash = hex::encode(&Sha256::digest(url.as_bytes())[..8]);
+        self.cache.join(format!("{}-{}", hash, name))
+    }
+}
+
+impl ArtifactSource for CommandSource {
+    fn handles(&self, reference: &str) -> bool {
+        reference
+            .split_once("://")
+            .is_some_and(|(scheme, _)| scheme == self.scheme)
+    }
+
+    fn fetch(&self, reference: &str, _base: &Path) -> Result<PathBuf, RecoveryError> {
+        let err = |e: String| RecoveryError::ArtifactError(format!("{}: {}", reference, e));
+
+        let dest = self.cache_path(reference);
+        if dest.exists() {
+            if intact(&dest) {
+                log::info!("Using cached {}", dest.display());
+                // The modification time records the last use, for eviction.
+                if let Err(e) = File::options().append(true).open(&dest).and_then(|f| f.set_modified(SystemTime::now())) {
+                    log::debug!("Unable to mark {} as used: {}", dest.display(), e);
+                }
+                return Ok(dest);
+            }
+            log::warn!("{} does not match the hash recorded when it was fetched, fetching it again", dest.display());
+            remove(&dest);
+        }
+        fs::create_dir_all(&self.cache).map_err(|e| err(e.to_string()))?;
+
+        // Download next to the destination so a failed fetch never leaves a
+        // partial file in the cache.
+        let partial = dest.with_extension("partial");
+        let command = self
+            .command
+            .replace("{url}", reference)
+            .replace("{dest}", &partial.display().to_string());
+        let status = shell(&command).status().map_err(|e| err(e.to_string()))?;
+        if !status.success() {
+            let _ = fs::remove_file(&partial);
+            return Err(err(format!("'{}' exited with {}", command, status)));
+        }
+        fs::rename(&partial, &dest).map_err(|e| err(e.to_string()))?;
+        let digest = sha256(&dest).map_err(|e| err(e.to_string()))?;
+        fs::write(digest_path(&dest), digest).map_err(|e| err(e.to_string()))?;
+
+        if let Some(limit) = self.limit {
+            evict(&self.cache, limit, &dest);
+        }
+        Ok(dest)
+    }
+}
+
+/// Where the SHA-256 of a cached file is kept.
+fn digest_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".sha256");
+    name.into()
+}
+
+fn sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Whether a cached file still has the hash recorded when it was fetched.
+fn intact(path: &Path) -> bool {
+    match (fs::read_to_string(digest_path(path)), sha256(path)) {
+        (Ok(recorded), Ok(actual)) => recorded.trim() == actual,
+        _ => false,
+    }
+}
+
+/// Remove a cached file and its hash.
+fn remove(path: &Path) {
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(digest_path(path));
+}
+
+/// Trim the cache in `dir` to `limit` bytes, removing the files used least
+/// recently first. `keep`, the file just fetched, stays even if it alone
+/// exceeds the limit.
+fn evict(dir: &Path, limit: u64, keep: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| !matches!(path.extension().and_then(|e| e.to_str()), Some("sha256" | "partial")))
+        .filter_map(|path| {
+            let meta = fs::metadata(&path).ok()?;
+            Some((meta.modified().ok()?, meta.len(), path))
+        })
+        .collect();
+    files.sort();
+
+    let mut total: u64 = files.iter().map(|(_, size, _)| size).sum();
+    for (_, size, path) in files {
+        if total <= limit {
+            break;
+        }
+        if path == keep {
+            continue;
+        }
+        log::info!("Evicting {} from the artifact cache", path.display());
+        remove(&path);
+        total -= size;
+    }
+}
+
+/// Picks the source for each reference, in registration order, with local
+/// files as the fallback.
+pub struct Resolver {
+    sources: Vec<Box<dyn ArtifactSource>>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver {
+            sources: vec![Box::new(LocalSource)],
+        }
+    }
+}
+
+impl Resolver {
+    /// Register a source, taking priority over the ones added before.
+    pub fn with_source(mut self, source: impl ArtifactSource + 'static) -> Self {
+        self.sources.insert(0, Box::new(source));
+        self
+    }
+
+    pub fn resolve(&self, reference: &Path, base: &Path) -> Result<PathBuf, RecoveryError> {
+        let text = reference.to_string_lossy();
+        let source = self.sources.iter().find(|s| s.handles(&text)).ok_or_else(|| {
+            RecoveryError::ArtifactError(format!("{}: no artifact source for this scheme", text))
+        })?;
+        source.fetch(&text, base)
+    }
+
+    /// Resolve every `(reference, base)` at the same time, each reference
+    /// once, so remote artifacts are in the cache before they are needed.
+    /// Returns the outcome per reference, sorted by reference.
+    pub fn prefetch(&self, references: &[(PathBuf, PathBuf)]) -> Vec<(PathBuf, Result<PathBuf, RecoveryError>)> {
+        let unique: BTreeSet<&(PathBuf, PathBuf)> = references.iter().collect();
+        thread::scope(|scope| {
+            let handles: Vec<_> = unique
+                .into_iter()
+                .map(|(reference, base)| (reference, scope.spawn(move || self.resolve(reference, base))))
+                .collect();
+            handles
+                .into_iter()
+                .map(|(reference, handle)| {
+                    let result = handle.join().unwrap_or_else(|_| {
+                        Err(RecoveryError::ArtifactError(format!("{}: fetch panicked", reference.display())))
+                    });
+                    (reference.clone(), result)
+                })
+                .collect()
+        })
+    }
+}
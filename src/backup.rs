@@ -0,0 +1,66 @@
+use chrono::Utc;
+use probe_rs::probe::Probe;
+
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::dap::{self, DebugPorts};
+use crate::device::FICR_DEVICEID;
+use crate::image::{Image, Segment};
+use crate::nvmc::{FLASH_END, FLASH_START};
+use crate::uicr::{UICR_END, UICR_START};
+use crate::RecoveryError;
+
+/// What is saved: all of flash and UICR.
+const AREAS: [Range<u64>; 2] = [FLASH_START..FLASH_END, UICR_START..UICR_END];
+
+/// Save the flash and UICR of an unlocked nRF91 to an Intel hex file in
+/// `dir`, named after the device ID and the time, e.g.
+/// `4E2F1A0B9C8D7E6F-20240301T101500Z.hex`.
+///
+/// The memory is read through the memory access port before anything is
+/// attached, so the device is not reset or halted first. Erased rows are
+/// left out, and the file can be flashed back as it is.
+pub fn backup(probe: Probe, ports: &DebugPorts, dir: &Path) -> Result<(Probe, PathBuf), RecoveryError> {
+    let mut iface = ports.connect(probe)?;
+    if !ports.debug_enabled(iface.as_mut())? {
+        return Err(RecoveryError::BackupError("the device is locked and cannot be read".into()));
+    }
+    let id = [
+        ports.read_word(iface.as_mut(), FICR_DEVICEID as u32)?,
+        ports.read_word(iface.as_mut(), FICR_DEVICEID as u32 + 4)?,
+    ];
+
+    let mut segments = Vec::new();
+    for area in AREAS {
+        let mut words = vec![0u32; ((area.end - area.start) / 4) as usize];
+        ports.read_block(iface.as_mut(), area.start as u32, &mut words)?;
+        segments.push(Segment {
+            address: area.start,
+            data: words.iter().flat_map(|word| word.to_le_bytes()).collect(),
+        });
+    }
+
+    let path = dir.join(format!("{:08X}{:08X}-{}.hex", id[1], id[0], Utc::now().format("%Y%m%dT%H%M%SZ")));
+    fs::create_dir_all(dir).map_err(|e| RecoveryError::BackupError(format!("{}: {}", dir.display(), e)))?;
+    Image { segments }.save_hex(&path)?;
+    Ok((dap::disconnect(iface), path))
+}
+
+/// The most recent backup of the device with ID `device_id` in `dir`.
+pub fn latest(dir: &Path, device_id: &str) -> Result<PathBuf, RecoveryError> {
+    let prefix = format!("{}-", device_id);
+    let entries = fs::read_dir(dir).map_err(|e| RecoveryError::BackupError(format!("{}: {}", dir.display(), e)))?;
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            name.starts_with(&prefix) && name.ends_with(".hex")
+        })
+        // The time in the name sorts the same as the time itself.
+        .max()
+        .ok_or_else(|| {
+            RecoveryError::BackupError(format!("{}: no backup of device {}", dir.display(), device_id))
+        })
+}
@@ -0,0 +1,483 @@
+use chrono::Utc;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::cycle::CycleBudget;
+use crate::hooks::Hooks;
+use crate::i18n::tr;
+use crate::job::{self, Job, Unit};
+use crate::output::say;
+use crate::paths::{self, PathVars};
+use crate::plc::Plc;
+use crate::prompt::ask;
+use crate::results::ResultsWriter;
+use crate::station::Station;
+use crate::RecoveryError;
+
+/// Column recording when a row was allocated to a device.
+const USED_AT: &str = "used_at";
+/// Column recording the programming result of the row's device.
+const RESULT: &str = "result";
+
+/// Per-unit data loaded from a CSV file, one row per device.
+pub struct UnitTable {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl UnitTable {
+    pub fn load(path: &Path) -> Result<Self, RecoveryError> {
+        let err = |e: csv::Error| RecoveryError::BatchError(format!("{}: {}", path.display(), e));
+
+        let mut reader = csv::Reader::from_path(path).map_err(err)?;
+        let mut headers: Vec<String> = reader.headers().map_err(err)?.iter().map(String::from).collect();
+        for column in [USED_AT, RESULT] {
+            if !headers.iter().any(|h| h == column) {
+                headers.push(column.to_string());
+            }
+        }
+
+        let mut rows = Vec::new();
+        for record in reader.records() {
+            let mut row: Vec<String> = record.map_err(err)?.iter().map(String::from).collect();
+            row.resize(headers.len(), String::new());
+            rows.push(row);
+        }
+
+        Ok(UnitTable { headers, rows })
+    }
+
+    /// Write the table back atomically so a crash never leaves a truncated file.
+    pub fn save(&self, path: &Path) -> Result<(), RecoveryError> {
+        let err = |e: String| RecoveryError::BatchError(format!("{}: {}", path.display(), e));
+
+        let tmp = path.with_extension("csv.tmp");
+        {
+            let mut writer = csv::Writer::from_path(&tmp).map_err(|e| err(e.to_string()))?;
+            writer.write_record(&self.headers).map_err(|e| err(e.to_string()))?;
+            for row in &self.rows {
+                writer.write_record(row).map_err(|e| err(e.to_string()))?;
+            }
+            writer.flush().map_err(|e| err(e.to_string()))?;
+        }
+        fs::rename(&tmp, path).map_err(|e| err(e.to_string()))
+    }
+
+    fn column(&self, name: &str) -> usize {
+        self.headers.iter().position(|h| h == name).unwrap()
+    }
+
+    /// Index of the next row that has not been allocated yet.
+    pub fn next_unused(&self) -> Option<usize> {
+        let used_at = self.column(USED_AT);
+        self.rows.iter().position(|row| row[used_at].is_empty())
+    }
+
+    pub fn remaining(&self) -> usize {
+        let used_at = self.column(USED_AT);
+        self.rows.iter().filter(|row| row[used_at].is_empty()).count()
+    }
+
+    pub fn unit(&self, index: usize) -> Unit {
+        self.headers
+            .iter()
+            .cloned()
+            .zip(self.rows[index].iter().cloned())
+            .collect()
+    }
+
+    fn set(&mut self, index: usize, column: &str, value: String) {
+        let column = self.column(column);
+        self.rows[index][column] = value;
+    }
+
+    /// Mark row `index` as allocated now, unless it already is. Returns
+    /// whether the row was marked.
+    fn mark_used(&mut self, index: usize) -> bool {
+        let used_at = self.column(USED_AT);
+        if !self.rows[index][used_at].is_empty() {
+            return false;
+        }
+        self.rows[index][used_at] = Utc::now().to_rfc3339();
+        true
+    }
+}
+
+/// Progress of the unit currently being programmed, persisted next to the
+/// CSV file so an interrupted run can pick up the same row again.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct BatchState {
+    /// Row allocated to the unit in progress.
+    pub row: usize,
+    /// Targets of the job already programmed successfully for that row.
+    pub completed: Vec<String>,
+}
+
+impl BatchState {
+    /// Default state file for a CSV file.
+    pub fn path_for(csv_path: &Path) -> PathBuf {
+        let mut path = csv_path.as_os_str().to_owned();
+        path.push(".state.json");
+        PathBuf::from(path)
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Self>, RecoveryError> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let text = fs::read_to_string(path)
+            .map_err(|e| RecoveryError::BatchError(format!("{}: {}", path.display(), e)))?;
+        serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|e| RecoveryError::BatchError(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), RecoveryError> {
+        let err = |e: String| RecoveryError::BatchError(format!("{}: {}", path.display(), e));
+        let tmp = path.with_extension("tmp");
+        let json = serde_json::to_string_pretty(self).map_err(|e| err(e.to_string()))?;
+        fs::write(&tmp, json).map_err(|e| err(e.to_string()))?;
+        fs::rename(&tmp, path).map_err(|e| err(e.to_string()))
+    }
+
+    /// Refuse a state saved at `path` for a row `table`, loaded from
+    /// `csv_path`, does not have.
+    fn check_row(&self, table: &UnitTable, path: &Path, csv_path: &Path) -> Result<(), RecoveryError> {
+        if self.row >= table.rows.len() {
+            return Err(RecoveryError::BatchError(format!(
+                "{}: row {} does not exist in {}",
+                path.display(),
+                self.row + 1,
+                csv_path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn clear(path: &Path) -> Result<(), RecoveryError> {
+        match fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(RecoveryError::BatchError(format!("{}: {}", path.display(), e))),
+        }
+    }
+}
+
+/// Label used to identify a unit to the operator.
+fn label(row: usize, unit: &Unit) -> String {
+    unit.get("serial")
+        .cloned()
+        .unwrap_or_else(|| tr!("batch_row", row = row + 1))
+}
+
+/// What to do when a device that already passed in this run comes back as
+/// a new unit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnDuplicate {
+    /// Tell the operator and program it anyway.
+    Warn,
+    /// Fail the unit before anything is written to the device.
+    #[default]
+    Fail,
+}
+
+/// Where a batch run reports besides the CSV file.
+pub struct Reporting<'a> {
+    /// Receives every finished target.
+    pub results: Option<&'a ResultsWriter>,
+    /// Flow hooks for every target.
+    pub hooks: Hooks,
+    pub on_duplicate: OnDuplicate,
+    /// Per-unit JSON report path, with placeholders.
+    pub report: Option<&'a Path>,
+    pub station: &'a Station,
+    /// Handshake with the line PLC, which then takes the place of the
+    /// operator prompts.
+    pub plc: Option<&'a Plc>,
+}
+
+/// FICR device IDs that passed in this run, with the unit they passed as.
+#[derive(Default)]
+struct Seen {
+    /// Unit being programmed.
+    current: String,
+    devices: HashMap<String, String>,
+    /// A duplicate of the current unit was failed.
+    rejected: bool,
+}
+
+impl Seen {
+    /// Check a device just identified against the units already done.
+    fn check(&mut self, device_id: &str, on_duplicate: OnDuplicate) -> Result<(), RecoveryError> {
+        match self.devices.get(device_id) {
+            Some(unit) if *unit != self.current => match on_duplicate {
+                OnDuplicate::Warn => {
+                    eprintln!("{}", tr!("duplicate_device", device = device_id, unit = unit));
+                    Ok(())
+                }
+                OnDuplicate::Fail => {
+                    self.rejected = true;
+                    Err(RecoveryError::BatchError(format!(
+                        "device {} was already programmed as {} in this run",
+                        device_id, unit
+                    )))
+                }
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Program one device per unused CSV row until the rows run out or the
+/// operator quits.
+///
+/// A row is marked as used before its device is programmed, so a row can
+/// never be handed out twice, even if programming fails part way. Progress of
+/// the unit in flight is kept in `state_path`; if a previous run was
+/// interrupted the operator is offered to resume that same row first, skipping
+/// the targets that were already completed. Each finished target is also
+/// recorded in the results file given in `reporting`.
+///
+/// A device whose FICR device ID already passed as another unit of this run
+/// is reported, or failed before anything is written to it, and its row is
+/// left for the next unit, so a board socketed twice does not use up a
+/// second row. A locked device can only be identified once the unlock has
+/// erased it; a locked duplicate still fails before it is programmed, but
+/// uses up its row.
+pub fn run(
+    csv_path: &Path,
+    state_path: &Path,
+    job_path: &Path,
+    job: &Job,
+    defaults: job::Defaults<'_>,
+    budget: Option<&CycleBudget>,
+    reporting: Reporting<'_>,
+) -> Result<(), RecoveryError> {
+    let mut table = UnitTable::load(csv_path)?;
+    println!(
+        "{}",
+        tr!("batch_unused_rows", count = table.remaining(), file = csv_path.display())
+    );
+
+    let mut resume = BatchState::load(state_path)?;
+    if let Some(state) = &resume {
+        state.check_row(&table, state_path, csv_path)?;
+    }
+
+    let seen = Arc::new(Mutex::new(Seen::default()));
+    let hooks = {
+        let seen = seen.clone();
+        let on_duplicate = reporting.on_duplicate;
+        reporting
+            .hooks
+            .check_identified(move |device_id| seen.lock().unwrap().check(device_id, on_duplicate))
+    };
+
+    let mut passed = 0;
+    let mut failed = 0;
+    loop {
+        let state = match resume.take() {
+            Some(state) => {
+                let unit = table.unit(state.row);
+                let answer = match reporting.plc {
+                    // The interrupted board is still in the fixture.
+                    Some(plc) => plc.begin().map(|_| Some(String::new()))?,
+                    None => ask(&tr!(
+                        "batch_resume_prompt",
+                        unit = label(state.row, &unit),
+                        done = state.completed.len(),
+                        total = job.targets.len()
+                    )),
+                };
+                if answer.is_none() || answer.as_deref() == Some("q") {
+                    break;
+                }
+
+                // The run may have stopped before the row was marked.
+                if table.mark_used(state.row) {
+                    table.save(csv_path)?;
+                }
+
+                if answer.as_deref() == Some("s") {
+                    table.set(state.row, RESULT, "interrupted".to_string());
+                    table.save(csv_path)?;
+                    BatchState::clear(state_path)?;
+                    continue;
+                }
+
+                state
+            }
+            None => {
+                let Some(row) = table.next_unused() else { break };
+                let unit = table.unit(row);
+                match reporting.plc {
+                    Some(plc) => {
+                        say(tr!("batch_waiting_for_plc", unit = label(row, &unit)));
+                        plc.begin()?;
+                    }
+                    None => {
+                        let answer = ask(&tr!("batch_insert_prompt", unit = label(row, &unit)));
+                        if matches!(answer.as_deref(), None | Some("q")) {
+                            break;
+                        }
+                    }
+                }
+
+                let state = BatchState {
+                    row,
+                    completed: Vec::new(),
+                };
+                state.save(state_path)?;
+                table.mark_used(row);
+                table.save(csv_path)?;
+                state
+            }
+        };
+
+        let unit = table.unit(state.row);
+        let row = state.row;
+        let skip = state.completed.clone();
+        let progress = Mutex::new(state);
+        let unit_label = label(row, &unit);
+        {
+            let mut seen = seen.lock().unwrap();
+            seen.current = unit_label.clone();
+            seen.rejected = false;
+        }
+        let record = |target: &crate::job::TargetReport| {
+            if let Some(results) = reporting.results {
+                results.record(job_path, Some(&unit_label), target);
+            }
+            if let (true, Some(device_id)) = (target.success, &target.device_id) {
+                seen.lock().unwrap().devices.insert(device_id.clone(), unit_label.clone());
+            }
+            if target.success {
+                let mut state = progress.lock().unwrap();
+                state.completed.push(target.name.clone());
+                if let Err(e) = state.save(state_path) {
+                    log::warn!("Unable to save batch state: {}", e);
+                }
+            }
+        };
+
+        let mut report = job::run(job_path, job, Some(&unit), &skip, defaults, &hooks, &record);
+        if let Some(budget) = budget {
+            job::apply_budget(&mut report, budget);
+        }
+        job::print_summary(&report);
+        if let Some(template) = reporting.report {
+            report.station = reporting.station.clone();
+            let vars = PathVars {
+                device_id: report.targets.iter().find_map(|t| t.device_id.clone()),
+                imei: report.targets.iter().find_map(|t| t.imei.clone()),
+                unit: Some(unit_label.clone()),
+                ..PathVars::for_station(reporting.station)
+            };
+            job::write_report(&paths::expand(template, &vars)?, &report)?;
+        }
+
+        // A duplicate turned away before anything was written to it, on a
+        // fresh row, leaves that row to the next unit.
+        let rejected = seen.lock().unwrap().rejected;
+        if rejected && skip.is_empty() && !report.targets.iter().any(|t| t.written) {
+            table.set(row, USED_AT, String::new());
+            say(tr!("batch_row_released", unit = unit_label));
+        } else {
+            table.set(row, RESULT, report.result().to_string());
+        }
+        table.save(csv_path)?;
+        BatchState::clear(state_path)?;
+
+        if let Some(plc) = reporting.plc {
+            plc.finish(report.success)?;
+        }
+        if report.success {
+            passed += 1;
+        } else {
+            failed += 1;
+        }
+    }
+
+    println!(
+        "{}",
+        tr!(
+            "batch_finished",
+            passed = passed,
+            failed = failed,
+            remaining = table.remaining()
+        )
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A CSV file in the temporary directory holding `text`.
+    fn csv_file(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("recovery-batch-{}-{}.csv", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn used_rows_are_skipped() {
+        let path = csv_file("used", "serial,used_at,result\nA1,2024-05-01T10:00:00+00:00,pass\nA2,,\nA3,,\n");
+        let mut table = UnitTable::load(&path).unwrap();
+        assert_eq!(table.next_unused(), Some(1));
+        assert_eq!(table.remaining(), 2);
+
+        assert!(table.mark_used(1));
+        table.save(&path).unwrap();
+        let table = UnitTable::load(&path).unwrap();
+        assert_eq!(table.next_unused(), Some(2));
+        assert_eq!(table.unit(0)[RESULT], "pass");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resumed_row_is_marked_once() {
+        // Columns the file lacks are added on load.
+        let path = csv_file("resume", "serial\nB1\nB2\n");
+        let state_path = BatchState::path_for(&path);
+        BatchState {
+            row: 1,
+            completed: vec!["app".into()],
+        }
+        .save(&state_path)
+        .unwrap();
+
+        let mut table = UnitTable::load(&path).unwrap();
+        let state = BatchState::load(&state_path).unwrap().unwrap();
+        state.check_row(&table, &state_path, &path).unwrap();
+        assert_eq!(state.completed, ["app"]);
+        assert!(table.unit(1)[USED_AT].is_empty());
+        assert!(table.mark_used(state.row));
+        assert!(!table.unit(1)[USED_AT].is_empty());
+        assert!(!table.mark_used(state.row));
+
+        BatchState::clear(&state_path).unwrap();
+        assert!(BatchState::load(&state_path).unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn state_row_outside_the_table_is_refused() {
+        let path = csv_file("range", "serial\nC1\nC2\n");
+        let table = UnitTable::load(&path).unwrap();
+        let state = BatchState {
+            row: 2,
+            completed: Vec::new(),
+        };
+        assert!(state.check_row(&table, &BatchState::path_for(&path), &path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
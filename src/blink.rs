@@ -0,0 +1,51 @@
+use probe_rs::{MemoryInterface, Session};
+
+use std::thread;
+use std::time::Duration;
+
+use crate::board::Led;
+use crate::RecoveryError;
+
+const GPIO_P0: u64 = 0x50842500; // Secure GPIO P0
+const GPIO_OUT: u64 = GPIO_P0 + 0x004;
+const GPIO_OUTSET: u64 = GPIO_P0 + 0x008;
+const GPIO_OUTCLR: u64 = GPIO_P0 + 0x00C;
+const GPIO_DIR: u64 = GPIO_P0 + 0x014;
+const GPIO_DIRSET: u64 = GPIO_P0 + 0x018;
+
+const BLINKS: usize = 3;
+const BLINK_PERIOD: Duration = Duration::from_millis(250);
+
+/// Blink the board's LED from the debugger so the operator can see which
+/// board was just programmed.
+///
+/// The core is halted while blinking and the pin's direction and level are
+/// restored before it is resumed.
+pub fn blink(session: &mut Session, led: &Led) -> Result<(), RecoveryError> {
+    let mut core = session.core(0)?;
+    core.halt(Duration::from_millis(100))?;
+
+    let mask = 1u32 << led.pin;
+    let dir = core.read_word_32(GPIO_DIR)?;
+    let out = core.read_word_32(GPIO_OUT)?;
+
+    let (on, off) = if led.active_high {
+        (GPIO_OUTSET, GPIO_OUTCLR)
+    } else {
+        (GPIO_OUTCLR, GPIO_OUTSET)
+    };
+
+    core.write_word_32(GPIO_DIRSET, mask)?;
+    for _ in 0..BLINKS {
+        core.write_word_32(on, mask)?;
+        thread::sleep(BLINK_PERIOD);
+        core.write_word_32(off, mask)?;
+        thread::sleep(BLINK_PERIOD);
+    }
+
+    core.write_word_32(GPIO_OUT, out)?;
+    core.write_word_32(GPIO_DIR, dir)?;
+    core.run()?;
+
+    Ok(())
+}
@@ -0,0 +1,185 @@
+use clap::ValueEnum;
+
+use std::fmt;
+
+use crate::console::ConsoleSpec;
+
+/// Chip assumed when no board is given.
+pub const DEFAULT_CHIP: &str = "nRF9151_xxAA";
+
+const SEGGER_VID: u16 = 0x1366;
+/// USB-UART bridge on the nRF9160 Feather.
+const SILABS_VID: u16 = 0x10c4;
+/// Nordic connectivity bridge firmware on the Thingy:91 interface MCU.
+const NORDIC_VID: u16 = 0x1915;
+
+/// Circuit Dojo programmer (Raspberry Pi debugprobe firmware), the default probe.
+pub const CIRCUIT_DOJO_VID: u16 = 0x2e8a;
+pub const CIRCUIT_DOJO_PID: u16 = 0x000c;
+
+/// Boards with known settings, selected with `--board`.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Board {
+    /// Nordic nRF9161 DK with on-board J-Link.
+    #[value(name = "nrf9161dk")]
+    Nrf9161Dk,
+    /// Circuit Dojo nRF9160 Feather.
+    #[value(name = "feather-nrf9160")]
+    FeatherNrf9160,
+    /// Circuit Dojo nRF9151 Feather.
+    #[value(name = "feather-nrf9151")]
+    FeatherNrf9151,
+    /// Nordic Thingy:91 (nRF9160 + nRF52840 bridge), external probe.
+    #[value(name = "thingy91")]
+    Thingy91,
+    /// Nordic Thingy:91 X (nRF9151 + nRF5340 bridge), external probe.
+    #[value(name = "thingy91x")]
+    Thingy91X,
+}
+
+/// External SPI flash fitted to a board.
+#[derive(Clone, Copy, Debug)]
+pub struct ExternalFlash {
+    /// Part number, which picks the flash driver.
+    pub part: &'static str,
+    pub size: u32,
+}
+
+/// A user LED on a P0 pin, used by `--blink`.
+#[derive(Clone, Copy, Debug)]
+pub struct Led {
+    pub pin: u8,
+    pub active_high: bool,
+}
+
+/// Settings a board implies, applied unless given on the command line.
+#[derive(Clone, Debug)]
+pub struct BoardPreset {
+    /// probe-rs target name.
+    pub chip: &'static str,
+    /// VID/PID of the on-board debug probe.
+    pub probe: Option<(u16, u16)>,
+    /// Console the firmware's AT/shell interface is reachable on.
+    pub console: Option<ConsoleSpec>,
+    pub external_flash: Option<ExternalFlash>,
+    pub led: Option<Led>,
+}
+
+impl Board {
+    pub fn preset(self) -> BoardPreset {
+        match self {
+            // UART0 is routed to the first J-Link virtual COM port.
+            Board::Nrf9161Dk => BoardPreset {
+                chip: "nRF9161_xxAA",
+                probe: Some((SEGGER_VID, 0x1051)),
+                console: Some(ConsoleSpec::UsbUart {
+                    vid: SEGGER_VID,
+                    index: 0,
+                    baud: 115200,
+                }),
+                external_flash: Some(ExternalFlash {
+                    part: "GD25WB256E3IR",
+                    size: 32 << 20,
+                }),
+                led: Some(Led {
+                    pin: 0,
+                    active_high: true,
+                }),
+            },
+            // UART0 goes through the on-board CP2102N.
+            Board::FeatherNrf9160 => BoardPreset {
+                chip: "nRF9160_xxAA",
+                probe: Some((CIRCUIT_DOJO_VID, CIRCUIT_DOJO_PID)),
+                console: Some(ConsoleSpec::UsbUart {
+                    vid: SILABS_VID,
+                    index: 0,
+                    baud: 115200,
+                }),
+                external_flash: Some(ExternalFlash {
+                    part: "W25Q32JV",
+                    size: 4 << 20,
+                }),
+                led: Some(Led {
+                    pin: 3,
+                    active_high: true,
+                }),
+            },
+            // UART0 is bridged by the on-board programmer.
+            Board::FeatherNrf9151 => BoardPreset {
+                chip: "nRF9151_xxAA",
+                probe: Some((CIRCUIT_DOJO_VID, CIRCUIT_DOJO_PID)),
+                console: Some(ConsoleSpec::UsbUart {
+                    vid: CIRCUIT_DOJO_VID,
+                    index: 0,
+                    baud: 115200,
+                }),
+                external_flash: Some(ExternalFlash {
+                    part: "W25Q32JV",
+                    size: 4 << 20,
+                }),
+                led: Some(Led {
+                    pin: 3,
+                    active_high: true,
+                }),
+            },
+            // The bridge's first CDC port is the nRF91's UART0.
+            Board::Thingy91 => BoardPreset {
+                chip: "nRF9160_xxAA",
+                probe: None,
+                console: Some(ConsoleSpec::UsbUart {
+                    vid: NORDIC_VID,
+                    index: 0,
+                    baud: 115200,
+                }),
+                external_flash: None,
+                led: Some(Led {
+                    pin: 29,
+                    active_high: true,
+                }),
+            },
+            Board::Thingy91X => BoardPreset {
+                chip: "nRF9151_xxAA",
+                probe: None,
+                console: Some(ConsoleSpec::UsbUart {
+                    vid: NORDIC_VID,
+                    index: 0,
+                    baud: 115200,
+                }),
+                external_flash: Some(ExternalFlash {
+                    part: "GD25LE255E",
+                    size: 32 << 20,
+                }),
+                led: None,
+            },
+        }
+    }
+
+    /// Job file programming every chip on the board, for boards with more
+    /// than one.
+    pub fn job_template(self) -> Option<&'static str> {
+        match self {
+            Board::Thingy91 => Some(include_str!("../templates/thingy91.toml")),
+            Board::Thingy91X => Some(include_str!("../templates/thingy91x.toml")),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BoardPreset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  chip:           {}", self.chip)?;
+        if let Some((vid, pid)) = self.probe {
+            writeln!(f, "  probe:          {:04x}:{:04x}", vid, pid)?;
+        }
+        if let Some(console) = &self.console {
+            writeln!(f, "  console:        {}", console)?;
+        }
+        if let Some(flash) = &self.external_flash {
+            writeln!(f, "  external flash: {} ({} MiB)", flash.part, flash.size >> 20)?;
+        }
+        if let Some(led) = &self.led {
+            writeln!(f, "  led:            P0.{:02}", led.pin)?;
+        }
+        Ok(())
+    }
+}
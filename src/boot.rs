@@ -0,0 +1,49 @@
+use probe_rs::{CoreStatus, Session};
+
+use std::thread;
+use std::time::Duration;
+
+use crate::debug::find_register;
+use crate::RecoveryError;
+
+/// Names of the Cortex-M fault exceptions, indexed by exception number.
+const FAULT_EXCEPTIONS: [(u32, &str); 5] = [
+    (3, "HardFault"),
+    (4, "MemManage"),
+    (5, "BusFault"),
+    (6, "UsageFault"),
+    (7, "SecureFault"),
+];
+
+/// Let the freshly reset firmware run for `wait`, then halt the core and
+/// check that it is not locked up or parked in a fault handler.
+///
+/// The core is resumed again when the check passes and left halted when it
+/// fails, so the faulting state can still be captured.
+pub fn check_boot(session: &mut Session, wait: Duration) -> Result<(), RecoveryError> {
+    thread::sleep(wait);
+
+    let mut core = session.core(0)?;
+
+    if core.status()? == CoreStatus::LockedUp {
+        return Err(RecoveryError::BootCheckFailed("Core is locked up".into()));
+    }
+
+    core.halt(Duration::from_millis(100))?;
+
+    let xpsr: u32 = core.read_core_reg(find_register(&core, "XPSR")?.id())?;
+    let pc: u32 = core.read_core_reg(find_register(&core, "PC")?.id())?;
+    let exception = xpsr & 0x1FF;
+    log::info!("Boot check PC: 0x{:08x}, IPSR: {}", pc, exception);
+
+    if let Some((_, name)) = FAULT_EXCEPTIONS.iter().find(|(n, _)| *n == exception) {
+        return Err(RecoveryError::BootCheckFailed(format!(
+            "Core is in {} handler (PC 0x{:08x})",
+            name, pc
+        )));
+    }
+
+    core.run()?;
+
+    Ok(())
+}
@@ -0,0 +1,34 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::flow::Stage;
+use crate::RecoveryError;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Ask the work in flight to stop at its next safe point.
+///
+/// Safe points lie between the steps of the flow: never inside an NVMC
+/// write, and never between starting ERASEALL and seeing it complete, so a
+/// cancelled device is in a state the next run can pick up from.
+pub fn request() {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Forget an earlier request, before starting new work.
+pub fn clear() {
+    REQUESTED.store(false, Ordering::Relaxed);
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+/// A safe point before `stage`: fails with [`RecoveryError::Cancelled`] if
+/// cancellation was requested.
+pub fn checkpoint(stage: Stage) -> Result<(), RecoveryError> {
+    if requested() {
+        log::info!("Cancelled before {}", stage);
+        return Err(RecoveryError::Cancelled.at(stage));
+    }
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use chrono::{DateTime, Utc};
+use clap::Args as ClapArgs;
+use serde::Serialize;
+
+use std::net::UdpSocket;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::i18n::tr;
+use crate::parse_duration;
+
+pub const DEFAULT_TIME_SERVER: &str = "pool.ntp.org:123";
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch.
+const NTP_EPOCH_OFFSET: f64 = 2_208_988_800.0;
+
+/// How far the wall clock may drift from the monotonic clock during a run
+/// before the run's timestamps are considered unreliable.
+const MAX_JUMP: Duration = Duration::from_secs(2);
+
+#[derive(ClapArgs, Clone, Debug)]
+pub struct ClockArgs {
+    #[arg(long, global = true, value_name = "HOST:PORT", default_value = DEFAULT_TIME_SERVER, help = "SNTP server used to check the station clock before job and batch runs")]
+    pub time_server: String,
+
+    #[arg(long, global = true, value_name = "DURATION", value_parser = parse_duration, default_value = "5s", help = "Flag reports when the station clock is off by more than this")]
+    pub max_clock_offset: Duration,
+
+    #[arg(long, global = true, help = "Do not check the station clock before job and batch runs")]
+    pub skip_time_check: bool,
+}
+
+impl ClockArgs {
+    /// Check the station clock, unless disabled.
+    pub fn status(&self) -> ClockStatus {
+        if self.skip_time_check {
+            return ClockStatus::default();
+        }
+        check(&self.time_server, self.max_clock_offset)
+    }
+}
+
+/// What is known about the station clock, recorded in reports.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ClockStatus {
+    /// Station clock minus time server clock, if the server answered.
+    pub offset_ms: Option<i64>,
+    /// Timestamps from this run should not be trusted.
+    pub suspect: bool,
+}
+
+impl ClockStatus {
+    /// Add the result of a check made during the run.
+    pub fn merge(&mut self, other: &ClockStatus) {
+        self.offset_ms = self.offset_ms.or(other.offset_ms);
+        self.suspect |= other.suspect;
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+/// Ask an SNTP server for the offset of the local clock, in seconds.
+fn query(server: &str) -> std::io::Result<f64> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    socket.connect(server)?;
+
+    // LI = 0, version 4, mode 3 (client).
+    let mut packet = [0u8; 48];
+    packet[0] = 0x23;
+
+    let sent = SystemTime::now();
+    let started = Instant::now();
+    socket.send(&packet)?;
+    let len = socket.recv(&mut packet)?;
+    let round_trip = started.elapsed().as_secs_f64();
+    if len < 48 {
+        return Err(std::io::Error::other("short reply"));
+    }
+
+    // Transmit timestamp, 32.32 fixed point seconds since 1900.
+    let seconds = u32::from_be_bytes(packet[40..44].try_into().unwrap()) as f64;
+    let fraction = u32::from_be_bytes(packet[44..48].try_into().unwrap()) as f64 / 4_294_967_296.0;
+    let server = seconds + fraction - NTP_EPOCH_OFFSET;
+
+    Ok(unix_seconds(sent) + round_trip / 2.0 - server)
+}
+
+/// Compare the station clock with `server` before a production run, warning
+/// when it is off by more than `max_offset` or cannot be checked.
+pub fn check(server: &str, max_offset: Duration) -> ClockStatus {
+    match query(server) {
+        Ok(offset) => {
+            let suspect = offset.abs() > max_offset.as_secs_f64();
+            if suspect {
+                eprintln!(
+                    "{}",
+                    tr!("clock_offset", offset = format!("{:+.1}", offset), server = server)
+                );
+            }
+            ClockStatus {
+                offset_ms: Some((offset * 1000.0).round() as i64),
+                suspect,
+            }
+        }
+        Err(e) => {
+            log::info!("Time server {}: {}", server, e);
+            eprintln!("{}", tr!("clock_unverified", server = server));
+            ClockStatus::default()
+        }
+    }
+}
+
+/// Whether the wall clock was changed while a run started at `started` took
+/// `elapsed` by the monotonic clock.
+pub fn jumped(started: DateTime<Utc>, elapsed: Duration) -> bool {
+    let wall = (Utc::now() - started).to_std().unwrap_or_default();
+    wall.abs_diff(elapsed) > MAX_JUMP
+}
@@ -0,0 +1,69 @@
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Number, Value};
+use toml_edit::{Document, Item};
+
+use std::fs;
+use std::path::Path;
+
+use crate::RecoveryError;
+
+/// Load a TOML file and deserialize it into `T`.
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<T, RecoveryError> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| RecoveryError::ConfigError(format!("{}: {}", path.display(), e)))?;
+    parse(&text).map_err(|e| RecoveryError::ConfigError(format!("{}: {}", path.display(), e)))
+}
+
+/// Parse TOML text and deserialize it into `T`.
+pub fn parse<T: DeserializeOwned>(text: &str) -> Result<T, String> {
+    serde_json::from_value(to_json(text)?).map_err(|e| e.to_string())
+}
+
+/// Parse TOML text into the JSON value it deserializes from.
+pub fn to_json(text: &str) -> Result<Value, String> {
+    let document = text.parse::<Document>().map_err(|e| e.to_string())?;
+    Ok(table_to_json(document.as_table()))
+}
+
+fn table_to_json<'a>(entries: impl IntoIterator<Item = (&'a str, &'a Item)>) -> Value {
+    let mut map = Map::new();
+    for (key, item) in entries {
+        if let Some(value) = item_to_json(item) {
+            map.insert(key.to_string(), value);
+        }
+    }
+    Value::Object(map)
+}
+
+fn item_to_json(item: &Item) -> Option<Value> {
+    match item {
+        Item::None => None,
+        Item::Value(value) => Some(value_to_json(value)),
+        Item::Table(table) => Some(table_to_json(table.iter())),
+        Item::ArrayOfTables(tables) => Some(Value::Array(
+            tables.iter().map(|t| table_to_json(t.iter())).collect(),
+        )),
+    }
+}
+
+fn value_to_json(value: &toml_edit::Value) -> Value {
+    use toml_edit::Value as V;
+
+    match value {
+        V::String(s) => Value::String(s.value().clone()),
+        V::Integer(i) => Value::Number(Number::from(*i.value())),
+        V::Float(f) => Number::from_f64(*f.value())
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+        V::Boolean(b) => Value::Bool(*b.value()),
+        V::Datetime(d) => Value::String(d.value().to_string()),
+        V::Array(array) => Value::Array(array.iter().map(value_to_json).collect()),
+        V::InlineTable(table) => {
+            let mut map = Map::new();
+            for (key, value) in table.iter() {
+                map.insert(key.to_string(), value_to_json(value));
+            }
+            Value::Object(map)
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use probe_rs::rtt::Rtt;
+use probe_rs::{Core, Session};
+use regex::Regex;
+use serialport::SerialPort;
+
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::rtt;
+use crate::RecoveryError;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const DEFAULT_BAUD: u32 = 115200;
+
+/// Where to find the firmware's console, as given on the command line:
+/// `rtt`, `rtt:N`, `uart:PORT`, `uart:PORT:BAUD` or `usb:VID:INDEX[:BAUD]`.
+#[derive(Clone, Debug)]
+pub enum ConsoleSpec {
+    Rtt(usize),
+    Uart { port: String, baud: u32 },
+    /// The `index`th serial port of a USB device with vendor ID `vid`, such
+    /// as a debug probe's virtual COM port.
+    UsbUart { vid: u16, index: usize, baud: u32 },
+}
+
+impl Default for ConsoleSpec {
+    fn default() -> Self {
+        ConsoleSpec::Rtt(0)
+    }
+}
+
+impl FromStr for ConsoleSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = s.split_once(':').unwrap_or((s, ""));
+        match kind {
+            "rtt" if rest.is_empty() => Ok(ConsoleSpec::Rtt(0)),
+            "rtt" => rest
+                .parse()
+                .map(ConsoleSpec::Rtt)
+                .map_err(|_| format!("Invalid RTT channel '{}'", rest)),
+            "uart" if rest.is_empty() => Err("UART console requires a port".into()),
+            "uart" => {
+                // A trailing numeric component is the baud rate.
+                match rest.rsplit_once(':') {
+                    Some((port, baud)) if baud.parse::<u32>().is_ok() => Ok(ConsoleSpec::Uart {
+                        port: port.to_string(),
+                        baud: baud.parse().unwrap(),
+                    }),
+                    _ => Ok(ConsoleSpec::Uart {
+                        port: rest.to_string(),
+                        baud: DEFAULT_BAUD,
+                    }),
+                }
+            }
+            "usb" => {
+                let mut parts = rest.split(':');
+                let vid = parts
+                    .next()
+                    .and_then(|v| u16::from_str_radix(v.trim_start_matches("0x"), 16).ok())
+                    .ok_or_else(|| format!("Invalid USB vendor ID in '{}'", s))?;
+                let index = match parts.next() {
+                    Some(i) => i.parse().map_err(|_| format!("Invalid port index in '{}'", s))?,
+                    None => 0,
+                };
+                let baud = match parts.next() {
+                    Some(b) => b.parse().map_err(|_| format!("Invalid baud rate in '{}'", s))?,
+                    None => DEFAULT_BAUD,
+                };
+                Ok(ConsoleSpec::UsbUart { vid, index, baud })
+            }
+            _ => Err(format!("Unknown console '{}', expected rtt, uart or usb", s)),
+        }
+    }
+}
+
+impl fmt::Display for ConsoleSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsoleSpec::Rtt(channel) => write!(f, "rtt:{}", channel),
+            ConsoleSpec::Uart { port, baud } => write!(f, "uart:{}:{}", port, baud),
+            ConsoleSpec::UsbUart { vid, index, baud } => write!(f, "usb:{:04x}:{}:{}", vid, index, baud),
+        }
+    }
+}
+
+/// Name of the `index`th serial port belonging to a USB device with vendor
+/// ID `vid`, in port name order.
+fn find_usb_port(vid: u16, index: usize) -> Result<String, RecoveryError> {
+    let ports = serialport::available_ports().map_err(|e| RecoveryError::ConsoleError(e.to_string()))?;
+    let mut names: Vec<String> = ports
+        .into_iter()
+        .filter(|p| matches!(&p.port_type, serialport::SerialPortType::UsbPort(usb) if usb.vid == vid))
+        .map(|p| p.port_name)
+        .collect();
+    names.sort();
+    names.into_iter().nth(index).ok_or_else(|| {
+        RecoveryError::ConsoleError(format!("No serial port {} found for USB vendor {:04x}", index, vid))
+    })
+}
+
+/// Open the console described by `spec`.
+pub fn open<'a>(
+    session: &'a mut Session,
+    spec: &ConsoleSpec,
+) -> Result<Box<dyn Console + 'a>, RecoveryError> {
+    Ok(match spec {
+        ConsoleSpec::Rtt(channel) => Box::new(RttConsole::open(session, *channel)?),
+        ConsoleSpec::Uart { port, baud } => Box::new(UartConsole::open(port, *baud)?),
+        ConsoleSpec::UsbUart { vid, index, baud } => {
+            Box::new(UartConsole::open(&find_usb_port(*vid, *index)?, *baud)?)
+        }
+    })
+}
+
+/// A byte stream to the firmware's console, either over RTT or a UART.
+pub trait Console {
+    /// Write all of `data` to the device.
+    fn write_all(&mut self, data: &[u8]) -> Result<(), RecoveryError>;
+
+    /// Read whatever is available without blocking for long.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, RecoveryError>;
+}
+
+/// Console on an RTT up/down channel pair.
+pub struct RttConsole<'a> {
+    core: Core<'a>,
+    rtt: Rtt,
+    channel: usize,
+}
+
+impl<'a> RttConsole<'a> {
+    pub fn open(session: &'a mut Session, channel: usize) -> Result<Self, RecoveryError> {
+        let mut core = session.core(0)?;
+        let mut rtt = rtt::attach(&mut core)?;
+
+        if rtt.up_channel(channel).is_none() || rtt.down_channel(channel).is_none() {
+            return Err(RecoveryError::RttChannelError(format!(
+                "RTT channel {} not found",
+                channel
+            )));
+        }
+
+        Ok(Self { core, rtt, channel })
+    }
+}
+
+impl Console for RttConsole<'_> {
+    fn write_all(&mut self, mut data: &[u8]) -> Result<(), RecoveryError> {
+        let down = self.rtt.down_channel(self.channel).unwrap();
+        while !data.is_empty() {
+            let written = down.write(&mut self.core, data)?;
+            data = &data[written..];
+            if written == 0 {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, RecoveryError> {
+        let up = self.rtt.up_channel(self.channel).unwrap();
+        Ok(up.read(&mut self.core, buf)?)
+    }
+}
+
+/// Console on a host serial port.
+pub struct UartConsole {
+    port: Box<dyn SerialPort>,
+}
+
+impl UartConsole {
+    pub fn open(path: &str, baud: u32) -> Result<Self, RecoveryError> {
+        let port = serialport::new(path, baud)
+            .timeout(POLL_INTERVAL)
+            .open()
+            .map_err(|e| RecoveryError::ConsoleError(format!("{}: {}", path, e)))?;
+        Ok(Self { port })
+    }
+}
+
+impl Console for UartConsole {
+    fn write_all(&mut self, data: &[u8]) -> Result<(), RecoveryError> {
+        self.port
+            .write_all(data)
+            .map_err(|e| RecoveryError::ConsoleError(e.to_string()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, RecoveryError> {
+        match self.port.read(buf) {
+            Ok(count) => Ok(count),
+            Err(e) if e.kind() == ErrorKind::TimedOut => Ok(0),
+            Err(e) => Err(RecoveryError::ConsoleError(e.to_string())),
+        }
+    }
+}
+
+/// Remove VT100 escape sequences (colors, cursor movement) from console output.
+fn strip_escapes(text: &str) -> String {
+    let escapes = Regex::new(r"\x1b\[[0-9;?]*[A-Za-z]").unwrap();
+    escapes.replace_all(text, "").into_owned()
+}
+
+/// Discard anything already buffered so responses are not matched against stale output.
+pub fn drain(console: &mut dyn Console) -> Result<(), RecoveryError> {
+    let mut buf = [0u8; 256];
+    while console.read(&mut buf)? > 0 {}
+    Ok(())
+}
+
+/// Output following the echo of `command`, if the device echoed it.
+fn response<'t>(text: &'t str, command: &str) -> &'t str {
+    match text.find(command) {
+        Some(i) => &text[i + command.len()..],
+        None => text,
+    }
+}
+
+/// Send `command` and collect output until the response matches `expect`.
+///
+/// Returns the response with the command echo and escape sequences removed.
+pub fn exchange(
+    console: &mut dyn Console,
+    command: &str,
+    expect: &Regex,
+    timeout: Duration,
+) -> Result<String, RecoveryError> {
+    drain(console)?;
+    console.write_all(format!("{}\r\n", command).as_bytes())?;
+
+    let start = Instant::now();
+    let mut output = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let count = console.read(&mut buf)?;
+        output.extend_from_slice(&buf[..count]);
+        let text = strip_escapes(&String::from_utf8_lossy(&output));
+        let response = response(&text, command);
+
+        if count > 0 && expect.is_match(response) {
+            return Ok(response.to_string());
+        }
+
+        if start.elapsed() >= timeout {
+            return Err(RecoveryError::ConsoleError(format!(
+                "'{}' did not match /{}/ within {:?}, got: {:?}",
+                command,
+                expect.as_str(),
+                timeout,
+                response.trim()
+            )));
+        }
+
+        if count == 0 {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
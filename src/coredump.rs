@@ -0,0 +1,81 @@
+use probe_rs::{MemoryInterface, Session};
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::debug::find_register;
+use crate::output::say;
+use crate::RecoveryError;
+
+// Zephyr coredump format (see zephyr/include/zephyr/debug/coredump.h), which
+// can be loaded by Zephyr's coredump_gdbserver.py.
+const HDR_VERSION: u16 = 1;
+const TGT_ARM_CORTEX_M: u16 = 3;
+const PTR_SIZE_BITS_32: u8 = 5; // log2(32)
+const REASON_CPU_EXCEPTION: u32 = 0;
+
+const ARCH_HDR_VERSION: u16 = 2;
+const MEM_HDR_VERSION: u16 = 1;
+
+/// Registers in the order of the Cortex-M arch block (version 2).
+const ARCH_REGISTERS: [&str; 17] = [
+    "R0", "R1", "R2", "R3", "R12", "R14", "R15", "XPSR", "R13", "R4", "R5", "R6", "R7", "R8",
+    "R9", "R10", "R11",
+];
+
+/// Capture core registers and all RAM regions of the halted core into a
+/// Zephyr coredump file at `path`.
+pub fn capture(session: &mut Session, path: &Path) -> Result<(), RecoveryError> {
+    let ram: Vec<_> = session
+        .target()
+        .memory_map
+        .iter()
+        .filter_map(|region| region.as_ram_region())
+        .map(|region| region.range.clone())
+        .collect();
+
+    let mut core = session.core(0)?;
+    if !core.core_halted()? {
+        core.halt(Duration::from_millis(100))?;
+    }
+
+    let mut dump = Vec::new();
+
+    // File header.
+    dump.extend_from_slice(b"ZE");
+    dump.extend_from_slice(&HDR_VERSION.to_le_bytes());
+    dump.extend_from_slice(&TGT_ARM_CORTEX_M.to_le_bytes());
+    dump.push(PTR_SIZE_BITS_32);
+    dump.push(0);
+    dump.extend_from_slice(&REASON_CPU_EXCEPTION.to_le_bytes());
+
+    // Architecture block.
+    dump.push(b'A');
+    dump.extend_from_slice(&ARCH_HDR_VERSION.to_le_bytes());
+    dump.extend_from_slice(&((ARCH_REGISTERS.len() * 4) as u16).to_le_bytes());
+    for name in ARCH_REGISTERS {
+        let value: u32 = core.read_core_reg(find_register(&core, name)?.id())?;
+        dump.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Memory blocks.
+    for range in ram {
+        log::info!("Dumping RAM 0x{:08x}..0x{:08x}", range.start, range.end);
+        let mut data = vec![0u8; (range.end - range.start) as usize];
+        core.read(range.start, &mut data)?;
+
+        dump.push(b'M');
+        dump.extend_from_slice(&MEM_HDR_VERSION.to_le_bytes());
+        dump.extend_from_slice(&(range.start as u32).to_le_bytes());
+        dump.extend_from_slice(&(range.end as u32).to_le_bytes());
+        dump.extend_from_slice(&data);
+    }
+
+    fs::write(path, &dump)
+        .map_err(|e| RecoveryError::CoredumpError(format!("{}: {}", path.display(), e)))?;
+
+    say(format!("Wrote coredump to {}", path.display()));
+
+    Ok(())
+}
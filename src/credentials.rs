@@ -0,0 +1,107 @@
+use probe_rs::Session;
+use regex::Regex;
+use serde::Serialize;
+
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::console::{self, ConsoleSpec};
+use crate::RecoveryError;
+
+/// How long the modem gets to start and answer.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time one AT%CMNG=1 may take to list everything.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A credential stored in the modem, as `AT%CMNG=1` lists it. The modem
+/// only ever reports a hash of the content, so nothing secret ends up in a
+/// report.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Credential {
+    pub sec_tag: u32,
+    #[serde(rename = "type")]
+    pub kind: u8,
+    /// SHA-256 of the content, missing for types the modem does not hash.
+    pub sha256: Option<String>,
+}
+
+impl Credential {
+    /// What the `AT%CMNG` type number stands for.
+    pub fn kind_name(&self) -> &'static str {
+        match self.kind {
+            0 => "root CA certificate",
+            1 => "client certificate",
+            2 => "client private key",
+            3 => "PSK",
+            4 => "PSK identity",
+            5 => "public key",
+            6 => "device identity public key",
+            8 => "endorsement private key",
+            9 => "ownership key",
+            10 => "Nordic identity root CA",
+            11 => "Nordic base public key",
+            _ => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>10}  {:>2} {:<26}  {}",
+            self.sec_tag,
+            self.kind,
+            self.kind_name(),
+            self.sha256.as_deref().unwrap_or("-")
+        )
+    }
+}
+
+/// The `%CMNG:` lines of a listing.
+fn parse(response: &str) -> Vec<Credential> {
+    let line = Regex::new(r#"%CMNG:\s*(\d+)\s*,\s*(\d+)\s*(?:,\s*"([0-9A-Fa-f]*)")?"#).unwrap();
+    line.captures_iter(response)
+        .filter_map(|c| {
+            Some(Credential {
+                sec_tag: c[1].parse().ok()?,
+                kind: c[2].parse().ok()?,
+                sha256: c.get(3).map(|sha| sha.as_str().to_ascii_lowercase()).filter(|sha| !sha.is_empty()),
+            })
+        })
+        .collect()
+}
+
+/// List the credentials stored in the modem with `AT%CMNG=1` over the
+/// firmware's console.
+///
+/// Like [`crate::imei::read_imei`] this needs firmware that hands AT
+/// commands to the modem, and repeats the query until `timeout` while the
+/// modem starts. A modem answering `ERROR` fails at once.
+pub fn list(session: &mut Session, spec: &ConsoleSpec, timeout: Duration) -> Result<Vec<Credential>, RecoveryError> {
+    let pattern = Regex::new(r"(?m)^\s*(OK|ERROR|\+CME ERROR.*)\s*$").unwrap();
+    let mut console = console::open(session, spec)?;
+
+    let start = Instant::now();
+    loop {
+        match console::exchange(console.as_mut(), "AT%CMNG=1", &pattern, QUERY_TIMEOUT) {
+            Ok(response) if response.contains("ERROR") => {
+                return Err(RecoveryError::ModemError(format!(
+                    "AT%CMNG=1 failed: {}",
+                    pattern.captures(&response).unwrap()[1].trim()
+                )))
+            }
+            Ok(response) => return Ok(parse(&response)),
+            Err(e) => log::info!("Credential query failed: {}", e),
+        }
+        if start.elapsed() >= timeout {
+            return Err(RecoveryError::ModemError(format!(
+                "the modem did not answer AT%CMNG=1 within {:?}",
+                timeout
+            )));
+        }
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
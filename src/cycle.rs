@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+use crate::RecoveryError;
+
+/// Per-unit programming time budget.
+#[derive(Clone, Copy, Debug)]
+pub struct CycleBudget {
+    pub max: Duration,
+    /// Fail units over budget instead of flagging them as slow passes.
+    pub strict: bool,
+}
+
+impl CycleBudget {
+    /// Check a unit's total programming time against the budget.
+    ///
+    /// Returns whether the unit was slow, or an error if it was slow and the
+    /// budget is strict.
+    pub fn check(&self, elapsed: Duration) -> Result<bool, RecoveryError> {
+        if elapsed <= self.max {
+            return Ok(false);
+        }
+        if self.strict {
+            return Err(RecoveryError::CycleTimeExceeded {
+                elapsed,
+                budget: self.max,
+            });
+        }
+        Ok(true)
+    }
+}
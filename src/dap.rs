@@ -0,0 +1,271 @@
+use clap::{Args as ClapArgs, ValueEnum};
+use probe_rs::architecture::arm::{
+    ap::{ApRegister, CSW, IDR},
+    dp::DpAddress,
+    ArmError, ArmProbeInterface, FullyQualifiedApAddress,
+};
+use probe_rs::probe::Probe;
+
+use std::thread;
+use std::time::Duration;
+
+use crate::{parse_number, strict, RecoveryError};
+
+const TAR: u64 = 0x04;
+const DRW: u64 = 0x0C;
+const CSW_SIZE_WORD: u32 = 0b010;
+const CSW_SIZE_MASK: u32 = 0b111;
+const CSW_ADDRINC_MASK: u32 = 0b11 << 4;
+const CSW_ADDRINC_SINGLE: u32 = 0b01 << 4;
+const CSW_DBGSTATUS: u32 = 1 << 6;
+const CSW_HNONSEC: u32 = 1 << 30;
+
+/// TAR is only guaranteed to auto-increment within a 1 KiB block.
+const TAR_BLOCK: u32 = 0x400;
+
+/// CTRL-AP register in bank 0, read to move SELECT off the IDR's bank.
+const CTRL_AP_RESET: u64 = 0x000;
+/// CTRL-AP erase protection registers of parts that have it.
+const ERASEPROTECT_STATUS: u64 = 0x018;
+const ERASEPROTECT_DISABLE: u64 = 0x01C;
+/// JEP106 code of Nordic Semiconductor, as IDR[27:17] holds it: the
+/// continuation code 0x2 above the identity code 0x44.
+const IDR_DESIGNER_NORDIC: u32 = 0x144;
+/// Attempts at reading the CTRL-AP IDR, and the wait before the second;
+/// each further wait doubles.
+const IDR_ATTEMPTS: u32 = 4;
+const IDR_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+/// Security of the bus accesses made through the memory access port.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ApView {
+    /// Secure accesses, which see all of the device. Needs SPIDEN.
+    #[default]
+    Secure,
+    /// Non-secure accesses, as seen by the non-secure domain.
+    NonSecure,
+}
+
+fn parse_targetsel(s: &str) -> Result<u32, String> {
+    let value = parse_number(s)?;
+    u32::try_from(value).map_err(|_| format!("TARGETSEL '{}' does not fit 32 bits", s))
+}
+
+fn parse_key(s: &str) -> Result<u32, String> {
+    match parse_number(s)? {
+        0 => Err("the ERASEALL key must not be 0, which keeps erase protection on".into()),
+        value => u32::try_from(value).map_err(|_| format!("key '{}' does not fit 32 bits", s)),
+    }
+}
+
+/// The debug port and access ports the raw DAP operations go through: the
+/// lock check, the wake-up, the CTRL-AP unlock and the FICR and UICR reads
+/// made before a target is attached.
+///
+/// The defaults are the nRF91's single DP with the memory access port at
+/// AP 0 and CTRL-AP at AP 4. Sessions attached with a probe-rs target use
+/// the ports of the target description. Parts with erase protection also
+/// need the key that lets CTRL-AP's ERASEALL through.
+#[derive(ClapArgs, Clone, Copy, Debug)]
+pub struct DebugPorts {
+    #[arg(long = "dp", global = true, value_name = "TARGETSEL", value_parser = parse_targetsel, help = "Select the debug port with this TARGETSEL on an SWD multidrop bus instead of the only one")]
+    pub targetsel: Option<u32>,
+
+    #[arg(long, global = true, value_name = "AP", default_value_t = 0, help = "Access port used for memory accesses and the lock check")]
+    pub mem_ap: u8,
+
+    #[arg(long, global = true, value_name = "AP", default_value_t = 4, help = "Access port of the CTRL-AP used to unlock and reset the device")]
+    pub ctrl_ap: u8,
+
+    #[arg(long, global = true, value_enum, default_value_t = ApView::Secure, help = "Security of memory accesses through --mem-ap")]
+    pub ap_view: ApView,
+
+    #[arg(long, global = true, value_name = "KEY", value_parser = parse_key, help = "Key to write to CTRL-AP ERASEPROTECT.DISABLE before ERASEALL, for parts whose firmware keeps erase protection on")]
+    pub eraseall_key: Option<u32>,
+}
+
+impl Default for DebugPorts {
+    fn default() -> Self {
+        DebugPorts {
+            targetsel: None,
+            mem_ap: 0,
+            ctrl_ap: 4,
+            ap_view: ApView::Secure,
+            eraseall_key: None,
+        }
+    }
+}
+
+impl DebugPorts {
+    pub fn dp(&self) -> DpAddress {
+        match self.targetsel {
+            Some(targetsel) => DpAddress::Multidrop(targetsel),
+            None => DpAddress::Default,
+        }
+    }
+
+    pub fn mem_ap(&self) -> FullyQualifiedApAddress {
+        FullyQualifiedApAddress::v1_with_dp(self.dp(), self.mem_ap)
+    }
+
+    pub fn ctrl_ap(&self) -> FullyQualifiedApAddress {
+        FullyQualifiedApAddress::v1_with_dp(self.dp(), self.ctrl_ap)
+    }
+
+    /// Attach without a target and power up the debug port.
+    pub fn connect(&self, mut probe: Probe) -> Result<Box<dyn ArmProbeInterface>, RecoveryError> {
+        probe.attach_to_unspecified()?;
+        probe
+            .try_into_arm_interface()
+            .map_err(|(_p, e)| RecoveryError::DebugProbeError(e))?
+            .initialize_unspecified(self.dp())
+            .map_err(|(_p, e)| RecoveryError::ProbeError(e))
+    }
+
+    /// Whether the memory access port grants debug access (CSW.DbgStatus).
+    pub fn debug_enabled(&self, iface: &mut dyn ArmProbeInterface) -> Result<bool, RecoveryError> {
+        let csw = iface.read_raw_ap_register(&self.mem_ap(), CSW::ADDRESS)?;
+        log::info!("CSW: 0x{:x}", csw);
+        Ok(csw & CSW_DBGSTATUS != 0)
+    }
+
+    /// Read the CTRL-AP's IDR, which sits in bank 0xF of the AP.
+    ///
+    /// probe-rs caches the DP SELECT register and only writes it when the
+    /// bank changes, so a SELECT lost to a reset or a fault would send the
+    /// read to another register. Reading a bank 0 register first makes the
+    /// IDR read write SELECT. Transient failures, such as WAIT responses
+    /// while the device comes out of reset, are retried with a growing delay;
+    /// the last error is returned if every attempt fails. An IDR of zero
+    /// means there is no access port at `--ctrl-ap`.
+    pub fn ctrl_ap_idr(&self, iface: &mut dyn ArmProbeInterface) -> Result<u32, RecoveryError> {
+        let ap = self.ctrl_ap();
+        let mut read = || -> Result<u32, ArmError> {
+            iface.read_raw_ap_register(&ap, CTRL_AP_RESET)?;
+            iface.read_raw_ap_register(&ap, IDR::ADDRESS)
+        };
+
+        let mut delay = IDR_RETRY_DELAY;
+        let mut attempt = 1;
+        let idr = loop {
+            match read() {
+                Ok(idr) => break idr,
+                Err(e) if attempt < IDR_ATTEMPTS => {
+                    log::info!("CTRL-AP IDR read failed (attempt {}): {}", attempt, e);
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(RecoveryError::UnlockError(format!(
+                        "unable to read the CTRL-AP IDR at AP {} after {} attempts: {}",
+                        self.ctrl_ap, attempt, e
+                    )))
+                }
+            }
+        };
+        log::info!("CTRL-AP IDR: 0x{:x}", idr);
+
+        if idr == 0 {
+            return Err(RecoveryError::UnlockError(format!(
+                "no access port at AP {} (IDR reads 0); check --ctrl-ap",
+                self.ctrl_ap
+            )));
+        }
+        if !designed_by_nordic(idr) {
+            strict::warn(RecoveryError::UnlockError(format!(
+                "AP {} is not a Nordic CTRL-AP (IDR 0x{:08x}); check --ctrl-ap",
+                self.ctrl_ap, idr
+            )))?;
+        }
+        Ok(idr)
+    }
+
+    /// Lift erase protection with `--eraseall-key`, if given, so that ERASEALL
+    /// is honoured.
+    ///
+    /// A part with erase protection ignores ERASEALL until the debugger writes
+    /// a key to CTRL-AP's ERASEPROTECT.DISABLE that matches the one its
+    /// firmware wrote to CTRLAPPERI.ERASEPROTECT.DISABLE. A wrong key leaves
+    /// ERASEPROTECT.STATUS enabled, and that is reported instead of an erase
+    /// that silently does nothing.
+    pub fn unlock_erase(&self, iface: &mut dyn ArmProbeInterface) -> Result<(), RecoveryError> {
+        let Some(key) = self.eraseall_key else {
+            return Ok(());
+        };
+        let ap = self.ctrl_ap();
+        iface.write_raw_ap_register(&ap, ERASEPROTECT_DISABLE, key)?;
+        let status = iface.read_raw_ap_register(&ap, ERASEPROTECT_STATUS)?;
+        log::info!("ERASEPROTECT.STATUS: 0x{:x}", status);
+        if status & 1 == 0 {
+            return Err(RecoveryError::UnlockError(
+                "erase protection is still on after writing --eraseall-key; the key does not match the firmware's".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Read a word through the memory access port with the selected view.
+    pub fn read_word(&self, iface: &mut dyn ArmProbeInterface, address: u32) -> Result<u32, RecoveryError> {
+        let ap = self.mem_ap();
+        self.set_csw(iface, 0)?;
+        iface.write_raw_ap_register(&ap, TAR, address)?;
+        Ok(iface.read_raw_ap_register(&ap, DRW)?)
+    }
+
+    /// Read consecutive words through the memory access port with the
+    /// selected view, letting TAR auto-increment within each 1 KiB block.
+    pub fn read_block(&self, iface: &mut dyn ArmProbeInterface, address: u32, words: &mut [u32]) -> Result<(), RecoveryError> {
+        let ap = self.mem_ap();
+        self.set_csw(iface, CSW_ADDRINC_SINGLE)?;
+        let mut address = address;
+        let mut words = words;
+        while !words.is_empty() {
+            let count = (((TAR_BLOCK - address % TAR_BLOCK) / 4) as usize).min(words.len());
+            let (block, rest) = words.split_at_mut(count);
+            iface.write_raw_ap_register(&ap, TAR, address)?;
+            iface.read_raw_ap_register_repeated(&ap, DRW, block)?;
+            address += count as u32 * 4;
+            words = rest;
+        }
+        Ok(())
+    }
+
+    /// Set up word accesses with the selected view and the given address
+    /// increment mode.
+    fn set_csw(&self, iface: &mut dyn ArmProbeInterface, addrinc: u32) -> Result<(), RecoveryError> {
+        let ap = self.mem_ap();
+        let csw = iface.read_raw_ap_register(&ap, CSW::ADDRESS)?;
+        let mut csw = (csw & !(CSW_SIZE_MASK | CSW_ADDRINC_MASK | CSW_HNONSEC)) | CSW_SIZE_WORD | addrinc;
+        if self.ap_view == ApView::NonSecure {
+            csw |= CSW_HNONSEC;
+        }
+        iface.write_raw_ap_register(&ap, CSW::ADDRESS, csw)?;
+        Ok(())
+    }
+}
+
+/// Hand back a probe that can be attached from scratch.
+pub fn disconnect(iface: Box<dyn ArmProbeInterface>) -> Probe {
+    let mut probe = iface.close();
+    let _ = probe.detach();
+    probe
+}
+
+/// Whether an access port IDR names Nordic as the designer.
+fn designed_by_nordic(idr: u32) -> bool {
+    (idr >> 17) & 0x7FF == IDR_DESIGNER_NORDIC
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_nordic_ctrl_ap() {
+        // CTRL-AP IDR of an nRF91.
+        assert!(designed_by_nordic(0x1288_0000));
+        // A MEM-AP designed by Arm.
+        assert!(!designed_by_nordic(0x2477_0011));
+    }
+}
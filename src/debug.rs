@@ -0,0 +1,191 @@
+use probe_rs::{Core, CoreRegister, MemoryInterface, RegisterValue, Session};
+
+use std::time::Duration;
+
+use crate::RecoveryError;
+
+const HALT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Look up a core register by name, accepting the usual aliases.
+pub fn find_register(core: &Core, name: &str) -> Result<&'static CoreRegister, RecoveryError> {
+    let name = match name.to_ascii_uppercase().as_str() {
+        "SP" => "R13".to_string(),
+        "LR" => "R14".to_string(),
+        "PC" => "R15".to_string(),
+        "PSR" => "XPSR".to_string(),
+        other => other.to_string(),
+    };
+
+    core.registers()
+        .all_registers()
+        .find(|r| r.name().eq_ignore_ascii_case(&name))
+        .ok_or_else(|| RecoveryError::DebugError(format!("Unknown register '{}'", name)))
+}
+
+/// Halt the core if requested. Memory can be accessed while it is running.
+fn prepare_core(core: &mut Core, halt: bool) -> Result<(), RecoveryError> {
+    if halt && !core.core_halted()? {
+        core.halt(HALT_TIMEOUT)?;
+    }
+    Ok(())
+}
+
+/// Read `count` words starting at `addr` and print them.
+pub fn mem_read(
+    session: &mut Session,
+    addr: u64,
+    count: usize,
+    halt: bool,
+) -> Result<Vec<u32>, RecoveryError> {
+    let mut core = session.core(0)?;
+    prepare_core(&mut core, halt)?;
+
+    let mut words = vec![0u32; count];
+    core.read_32(addr, &mut words)?;
+
+    for (i, chunk) in words.chunks(4).enumerate() {
+        let line: Vec<String> = chunk.iter().map(|w| format!("{:08x}", w)).collect();
+        println!("0x{:08x}: {}", addr + (i * 16) as u64, line.join(" "));
+    }
+
+    Ok(words)
+}
+
+/// Write consecutive words starting at `addr`.
+pub fn mem_write(
+    session: &mut Session,
+    addr: u64,
+    values: &[u32],
+    halt: bool,
+) -> Result<(), RecoveryError> {
+    let mut core = session.core(0)?;
+    prepare_core(&mut core, halt)?;
+
+    core.write_32(addr, values)?;
+    core.flush()?;
+
+    println!("Wrote {} word(s) at 0x{:08x}", values.len(), addr);
+
+    Ok(())
+}
+
+/// Check that the word at `addr`, masked with `mask`, equals `expected`.
+pub fn mem_expect(
+    session: &mut Session,
+    addr: u64,
+    expected: u32,
+    mask: u32,
+    halt: bool,
+) -> Result<(), RecoveryError> {
+    let mut core = session.core(0)?;
+    prepare_core(&mut core, halt)?;
+
+    let value = core.read_word_32(addr)?;
+    if value & mask != expected & mask {
+        return Err(RecoveryError::DebugError(format!(
+            "0x{:08x} holds 0x{:08x}, expected 0x{:08x} (mask 0x{:08x})",
+            addr, value, expected, mask
+        )));
+    }
+
+    println!("0x{:08x}: {:08x} as expected", addr, value);
+
+    Ok(())
+}
+
+/// Read one or all core registers and print them.
+///
+/// Core registers are only accessible while halted; if the core was running
+/// and `halt` was not requested it is resumed afterwards.
+pub fn reg_read(
+    session: &mut Session,
+    name: Option<&str>,
+    halt: bool,
+) -> Result<(), RecoveryError> {
+    let mut core = session.core(0)?;
+    let was_halted = core.core_halted()?;
+    if !was_halted {
+        core.halt(HALT_TIMEOUT)?;
+    }
+
+    let registers: Vec<&'static CoreRegister> = match name {
+        Some(name) => vec![find_register(&core, name)?],
+        None => core.registers().core_registers().collect(),
+    };
+
+    for register in registers {
+        let value: RegisterValue = core.read_core_reg(register.id())?;
+        println!("{:>6}: {}", register.name(), value);
+    }
+
+    if !was_halted && !halt {
+        core.run()?;
+    }
+
+    Ok(())
+}
+
+/// Write a single core register.
+pub fn reg_write(
+    session: &mut Session,
+    name: &str,
+    value: u32,
+    halt: bool,
+) -> Result<(), RecoveryError> {
+    let mut core = session.core(0)?;
+    let was_halted = core.core_halted()?;
+    if !was_halted {
+        core.halt(HALT_TIMEOUT)?;
+    }
+
+    let register = find_register(&core, name)?;
+    core.write_core_reg(register.id(), value)?;
+    println!("{} = 0x{:08x}", register.name(), value);
+
+    if !was_halted && !halt {
+        core.run()?;
+    }
+
+    Ok(())
+}
+
+/// Manual core run control actions.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum CoreAction {
+    Halt,
+    Resume,
+    Step,
+    Reset,
+    ResetHalt,
+}
+
+/// Apply a run control action to the core and report where it stopped.
+pub fn core_control(session: &mut Session, action: CoreAction) -> Result<(), RecoveryError> {
+    let mut core = session.core(0)?;
+
+    let info = match action {
+        CoreAction::Halt => Some(core.halt(HALT_TIMEOUT)?),
+        CoreAction::Resume => {
+            core.run()?;
+            None
+        }
+        CoreAction::Step => {
+            if !core.core_halted()? {
+                core.halt(HALT_TIMEOUT)?;
+            }
+            Some(core.step()?)
+        }
+        CoreAction::Reset => {
+            core.reset()?;
+            None
+        }
+        CoreAction::ResetHalt => Some(core.reset_and_halt(HALT_TIMEOUT)?),
+    };
+
+    match info {
+        Some(info) => println!("Core halted at PC 0x{:08x}", info.pc),
+        None => println!("Core running"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use clap::ValueEnum;
+use probe_rs::{MemoryInterface, Session};
+use serde::Deserialize;
+
+use std::thread;
+use std::time::Duration;
+
+use crate::approtect::{self, UICR_APPROTECT, UICR_SECUREAPPROTECT};
+use crate::dap::DebugPorts;
+use crate::i18n::tr;
+use crate::output::say;
+use crate::{check_locked, open_probe, ProbeArgs, RecoveryError};
+
+const APPROTECTSTATUS: u64 = 0x00C;
+
+/// Time the firmware gets after reset to open the access port again.
+const BOOT_TIME: Duration = Duration::from_millis(200);
+
+/// How to leave the device when the tool is done with it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum DetachMode {
+    /// Just close the session.
+    #[default]
+    Normal,
+    /// Make sure the device stays debuggable for the next tool in the line.
+    KeepUnlocked,
+}
+
+/// Check that the device will stay unlocked after the debugger detaches.
+///
+/// nRF91 revisions with hardware access port protection lock again on every
+/// reset unless UICR.APPROTECT and UICR.SECUREAPPROTECT hold the
+/// HwUnprotected value *and* the firmware writes APPROTECT.DISABLE early in
+/// boot; the nRF9160 only needs both words left unprotected for its family.
+/// This verifies both UICR words, gives the firmware time to boot, reads
+/// CTRL-AP.APPROTECTSTATUS and leaves the core running without another reset.
+pub fn keep_unlocked(session: &mut Session, chip: &str, ports: &DebugPorts) -> Result<(), RecoveryError> {
+    let expected = approtect::unprotected(chip);
+    {
+        let mut core = session.core(0)?;
+        for (name, addr) in [("APPROTECT", UICR_APPROTECT), ("SECUREAPPROTECT", UICR_SECUREAPPROTECT)] {
+            let value = core.read_word_32(addr)?;
+            if value != expected {
+                return Err(RecoveryError::DetachError(format!(
+                    "UICR.{} is 0x{:08x}, expected 0x{:08x}",
+                    name, value, expected
+                )));
+            }
+        }
+        if core.core_halted()? {
+            core.run()?;
+        }
+    }
+
+    thread::sleep(BOOT_TIME);
+
+    let status = session
+        .get_arm_interface()?
+        .read_raw_ap_register(&ports.ctrl_ap(), APPROTECTSTATUS)?;
+    log::info!("APPROTECTSTATUS: 0x{:x}", status);
+    if status & 0b11 != 0b11 {
+        return Err(RecoveryError::DetachError(
+            "access port protection is enabled again after boot; build the firmware with CONFIG_NRF_APPROTECT_USE_UICR=y and CONFIG_NRF_SECURE_APPROTECT_USE_UICR=y".into(),
+        ));
+    }
+
+    say(tr!("stays_unlocked"));
+    Ok(())
+}
+
+/// Check that a device whose UICR now enables protection really refuses
+/// debug access.
+///
+/// The device is reset so the UICR words take effect, the session is closed
+/// and the probe is opened again without a target, as any debugger would,
+/// to read CSW.DbgStatus of the memory access port. Returns whether access
+/// was refused.
+pub fn verify_locked(
+    mut session: Session,
+    probe_args: &ProbeArgs,
+    ports: &DebugPorts,
+) -> Result<bool, RecoveryError> {
+    session.core(0)?.reset()?;
+    drop(session);
+    thread::sleep(BOOT_TIME);
+
+    let (_probe, locked) = check_locked(open_probe(probe_args, ports)?, ports)?;
+    log::info!("Debug access after re-locking: {}", if locked { "refused" } else { "granted" });
+    if locked {
+        say(tr!("locked_verified"));
+    }
+    Ok(locked)
+}
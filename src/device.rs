@@ -0,0 +1,163 @@
+use probe_rs::probe::Probe;
+use probe_rs::{MemoryInterface, Session};
+
+use std::fmt;
+
+use crate::dap::{self, DebugPorts};
+use crate::RecoveryError;
+
+pub(crate) const FICR_DEVICEID: u64 = 0x00FF0204; // FICR.INFO.DEVICEID[0..2]
+const FICR_INFO_PART: u64 = 0x00FF020C;
+const FICR_INFO_VARIANT: u64 = 0x00FF0210;
+const FICR_INFO_RAM: u64 = 0x00FF0218;
+const FICR_INFO_FLASH: u64 = 0x00FF021C;
+
+/// Read the nRF91's 64-bit factory device ID, as 16 hex digits.
+pub fn device_id(session: &mut Session) -> Result<String, RecoveryError> {
+    let mut core = session.core(0)?;
+    let mut words = [0u32; 2];
+    core.read_32(FICR_DEVICEID, &mut words)?;
+    Ok(format_id(words))
+}
+
+fn format_id(words: [u32; 2]) -> String {
+    format!("{:08X}{:08X}", words[1], words[0])
+}
+
+/// Part number and build code from FICR.INFO.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Part {
+    pub part: u32,
+    pub variant: u32,
+}
+
+impl Part {
+    /// probe-rs target for the part. All variants of a part share a target.
+    pub fn target(&self) -> Option<&'static str> {
+        match self.part {
+            0x9160 => Some("nRF9160_xxAA"),
+            0x9161 => Some("nRF9161_xxAA"),
+            0x9151 => Some("nRF9151_xxAA"),
+            _ => None,
+        }
+    }
+
+    /// Whether `chip` is the probe-rs target for this part.
+    pub fn matches(&self, chip: &str) -> bool {
+        self.target().is_some_and(|t| t.eq_ignore_ascii_case(chip))
+    }
+}
+
+impl fmt::Display for Part {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let variant = self.variant.to_be_bytes();
+        write!(f, "nRF{:x} {}", self.part, String::from_utf8_lossy(&variant))
+    }
+}
+
+/// Read FICR.INFO.PART and VARIANT through the memory access port, before
+/// anything is attached with a target.
+///
+/// FICR can only be read while the access port is open, so a locked device
+/// gives `None`; it can be detected again once unlocked, since FICR survives
+/// the erase.
+pub fn detect(probe: Probe, ports: &DebugPorts) -> Result<(Probe, Option<Part>), RecoveryError> {
+    let mut iface = ports.connect(probe)?;
+    let part = if ports.debug_enabled(iface.as_mut())? {
+        let part = Part {
+            part: ports.read_word(iface.as_mut(), FICR_INFO_PART as u32)?,
+            variant: ports.read_word(iface.as_mut(), FICR_INFO_VARIANT as u32)?,
+        };
+        log::info!("FICR.INFO.PART 0x{:x}, VARIANT 0x{:08x}", part.part, part.variant);
+        Some(part)
+    } else {
+        None
+    };
+    Ok((dap::disconnect(iface), part))
+}
+
+/// Read the device ID through the memory access port like [`detect`], so a
+/// device can be turned away before anything is written to it. A locked
+/// device gives `None` until the unlock has erased it.
+pub fn detect_id(probe: Probe, ports: &DebugPorts) -> Result<(Probe, Option<String>), RecoveryError> {
+    let mut iface = ports.connect(probe)?;
+    let device_id = if ports.debug_enabled(iface.as_mut())? {
+        let words = [
+            ports.read_word(iface.as_mut(), FICR_DEVICEID as u32)?,
+            ports.read_word(iface.as_mut(), FICR_DEVICEID as u32 + 4)?,
+        ];
+        Some(format_id(words))
+    } else {
+        None
+    };
+    Ok((dap::disconnect(iface), device_id))
+}
+
+/// Factory information of an nRF91.
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub device_id: String,
+    /// Part number, e.g. 0x9151.
+    pub part: u32,
+    /// Build code, e.g. `AAA0`.
+    pub variant: String,
+    pub ram_kib: u32,
+    pub flash_kib: u32,
+}
+
+/// Read the nRF91's FICR information block.
+pub fn info(session: &mut Session) -> Result<DeviceInfo, RecoveryError> {
+    let device_id = device_id(session)?;
+    let mut core = session.core(0)?;
+    let variant = core.read_word_32(FICR_INFO_VARIANT)?.to_be_bytes();
+    Ok(DeviceInfo {
+        device_id,
+        part: core.read_word_32(FICR_INFO_PART)?,
+        variant: String::from_utf8_lossy(&variant).into_owned(),
+        ram_kib: core.read_word_32(FICR_INFO_RAM)?,
+        flash_kib: core.read_word_32(FICR_INFO_FLASH)?,
+    })
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Device ID:      {}", self.device_id)?;
+        writeln!(f, "Part:           nRF{:x} {}", self.part, self.variant)?;
+        writeln!(f, "RAM:            {} KiB", self.ram_kib)?;
+        writeln!(f, "Flash:          {} KiB", self.flash_kib)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn info_words_follow_device_id() {
+        // DEVICEID[0..2] at 0x204, then INFO.PART, VARIANT, PACKAGE, RAM, FLASH.
+        assert_eq!(FICR_INFO_PART, FICR_DEVICEID + 0x08);
+        assert_eq!(FICR_INFO_VARIANT, 0x00FF0210);
+        assert_eq!(FICR_INFO_RAM, 0x00FF0218);
+        assert_eq!(FICR_INFO_FLASH, 0x00FF021C);
+    }
+
+    #[test]
+    fn decodes_ficr_part_and_variant() {
+        // As read from an nRF9151 and an nRF9160.
+        let nrf9151 = Part {
+            part: 0x0000_9151,
+            variant: 0x4141_4130,
+        };
+        assert_eq!(nrf9151.target(), Some("nRF9151_xxAA"));
+        assert!(nrf9151.matches("nrf9151_xxaa"));
+        assert_eq!(nrf9151.to_string(), "nRF9151 AAA0");
+
+        let nrf9160 = Part {
+            part: 0x0000_9160,
+            variant: 0x4141_4331,
+        };
+        assert_eq!(nrf9160.target(), Some("nRF9160_xxAA"));
+        assert!(!nrf9160.matches("nRF9151_xxAA"));
+        assert_eq!(nrf9160.to_string(), "nRF9160 AAC1");
+    }
+}
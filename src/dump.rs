@@ -0,0 +1,30 @@
+use probe_rs::{MemoryInterface, Session};
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use crate::RecoveryError;
+
+const CHUNK_SIZE: usize = 0x1000;
+
+/// Read `length` bytes starting at `start` into a raw binary file.
+pub fn dump(session: &mut Session, start: u64, length: u64, path: &Path) -> Result<(), RecoveryError> {
+    let err = |e: std::io::Error| RecoveryError::DumpError(format!("{}: {}", path.display(), e));
+
+    let mut core = session.core(0)?;
+    let mut file = File::create(path).map_err(err)?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+
+    let end = start + length;
+    let mut addr = start;
+    while addr < end {
+        let len = ((end - addr) as usize).min(CHUNK_SIZE);
+        core.read(addr, &mut buf[..len])?;
+        file.write_all(&buf[..len]).map_err(err)?;
+        addr += len as u64;
+    }
+
+    println!("Wrote {} bytes from 0x{:08x} to {}", length, start, path.display());
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Completion times kept per part, newest last.
+const KEPT: usize = 20;
+
+/// Erases seen before the status is left alone for a while.
+const MIN_SAMPLES: usize = 3;
+
+/// Recent ERASEALL times in milliseconds by part, e.g. `nRF9151 AAA0`, or
+/// by chip name where the part was not known, loaded from [`path`] on first
+/// use.
+static TIMES: Mutex<Option<BTreeMap<String, Vec<u64>>>> = Mutex::new(None);
+
+/// Kept in the temporary directory, so a station starts from scratch after
+/// a reboot and learns again within a few devices.
+fn path() -> PathBuf {
+    std::env::temp_dir().join("recovery-erase-times.json")
+}
+
+fn with_times<T>(f: impl FnOnce(&mut BTreeMap<String, Vec<u64>>) -> T) -> T {
+    let mut times = TIMES.lock().unwrap();
+    let times = times.get_or_insert_with(|| {
+        fs::read(path())
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default()
+    });
+    f(times)
+}
+
+/// How long an ERASEALL of `part` can be left alone before its status is
+/// polled: four fifths of the fastest recent erase of that part, or nothing
+/// until enough erases were seen.
+pub fn quiet_period(part: &str) -> Duration {
+    with_times(|times| match times.get(part) {
+        Some(seen) if seen.len() >= MIN_SAMPLES => {
+            Duration::from_millis(seen.iter().min().copied().unwrap_or_default() * 4 / 5)
+        }
+        _ => Duration::ZERO,
+    })
+}
+
+/// Record an ERASEALL of `part` that completed after `elapsed`. Failing to
+/// save the statistics only costs the next runs some polling.
+pub fn record(part: &str, elapsed: Duration) {
+    with_times(|times| {
+        let seen = times.entry(part.to_string()).or_default();
+        seen.push(elapsed.as_millis() as u64);
+        if seen.len() > KEPT {
+            seen.remove(0);
+        }
+        let saved = serde_json::to_vec(times).map_err(|e| e.to_string()).and_then(|json| {
+            fs::write(path(), json).map_err(|e| e.to_string())
+        });
+        if let Err(e) = saved {
+            log::warn!("Unable to save ERASEALL times to {}: {}", path().display(), e);
+        }
+    })
+}
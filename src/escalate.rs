@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+
+use std::thread;
+use std::time::Duration;
+
+use crate::notify::shell;
+use crate::RecoveryError;
+
+/// Time given to USB to settle before a probe is opened again.
+const REOPEN_DELAY: Duration = Duration::from_secs(1);
+
+/// Time given to the target to power up after a power cycle.
+const POWER_UP_DELAY: Duration = Duration::from_secs(2);
+
+/// Wait before the first retry of a whole flow; doubled for each further one.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest wait between retries of a whole flow.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How long to wait before retry number `retry`, counting from 1.
+pub fn backoff(retry: u32) -> Duration {
+    FIRST_BACKOFF.saturating_mul(1 << retry.saturating_sub(1).min(16)).min(MAX_BACKOFF)
+}
+
+/// One rung of the escalation ladder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Step {
+    /// Run the attempt again as it was.
+    Retry,
+    /// Halve the SWD clock for this and all further attempts.
+    SlowDown,
+    /// Let the probe re-enumerate before opening it again.
+    Reopen,
+    /// Run the configured power cycle command.
+    PowerCycle,
+}
+
+/// What to try, in order, when programming a target fails.
+///
+/// ```toml
+/// [escalation]
+/// steps = ["retry", "slow-down", "reopen", "power-cycle"]
+/// power_cycle = "uhubctl -l 1-1 -p 2 -a cycle"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Escalation {
+    #[serde(default)]
+    pub steps: Vec<Step>,
+    /// Shell command that power cycles the target, required for
+    /// `power-cycle`.
+    pub power_cycle: Option<String>,
+}
+
+/// Settings for one attempt, changed as the ladder is climbed.
+#[derive(Clone, Copy, Debug)]
+pub struct Attempt {
+    /// Divide the probe's SWD clock by this.
+    pub speed_divisor: u32,
+}
+
+impl Escalation {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.steps.contains(&Step::PowerCycle) && self.power_cycle.is_none() {
+            return Err("escalation step 'power-cycle' needs a power_cycle command".into());
+        }
+        Ok(())
+    }
+
+    /// Run `attempt`, climbing one step of the ladder after every failure
+    /// until it succeeds or the ladder is exhausted. Steps taken are appended
+    /// to `taken`.
+    ///
+    /// Cancelled attempts are never retried.
+    pub fn run<T>(
+        &self,
+        taken: &mut Vec<Step>,
+        mut attempt: impl FnMut(Attempt) -> Result<T, RecoveryError>,
+    ) -> Result<T, RecoveryError> {
+        let mut settings = Attempt { speed_divisor: 1 };
+        let mut steps = self.steps.iter();
+        loop {
+            let error = match attempt(settings) {
+                Ok(value) => return Ok(value),
+                Err(e) if e.is_cancelled() => return Err(e),
+                Err(e) => e,
+            };
+            let Some(step) = steps.next() else { return Err(error) };
+
+            log::warn!("Attempt failed ({}), escalating: {:?}", error, step);
+            taken.push(*step);
+            match step {
+                Step::Retry => {}
+                Step::SlowDown => settings.speed_divisor *= 2,
+                Step::Reopen => thread::sleep(REOPEN_DELAY),
+                Step::PowerCycle => {
+                    // Checked by `validate`.
+                    let command = self.power_cycle.as_deref().unwrap_or_default();
+                    match shell(command).status() {
+                        Ok(status) if status.success() => {}
+                        Ok(status) => log::warn!("Power cycle command exited with {}", status),
+                        Err(e) => log::warn!("Unable to run power cycle command: {}", e),
+                    }
+                    thread::sleep(POWER_UP_DELAY);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,741 @@
+use probe_rs::{
+    flashing::{self, FlashProgress},
+    probe::Probe,
+    MemoryInterface, Permissions, Session,
+};
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::approtect::{self, ApprotectReport, Protection, UICR_APPROTECT, UICR_SECUREAPPROTECT};
+use crate::backup;
+use crate::cancel;
+use crate::dap::DebugPorts;
+use crate::detach::{self, DetachMode};
+use crate::device::{self, Part};
+use crate::hooks::Hooks;
+use crate::image::{ImageFile, Segment};
+use crate::i18n::tr;
+use crate::output::say;
+use crate::prompt::confirm;
+use crate::modem::{self, ModemFirmware};
+use crate::plan::{DeviceState, Plan};
+use crate::progress;
+use crate::provision;
+use crate::registry;
+use crate::rollback::{self, CounterKind};
+use crate::slot::{self, SlotOptions};
+use crate::strict;
+use crate::uicr;
+use crate::verify::{self, VerifyLevel};
+use crate::wake;
+use crate::{check_locked, try_unlock_device, write_uicr, RecoveryError, UnlockReset};
+
+/// Steps of the recovery flow, used to tell where a failure happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Backup,
+    Unlock,
+    Attach,
+    Identify,
+    Erase,
+    Flash,
+    Verify,
+    Modem,
+    Counter,
+    Uicr,
+    Reset,
+    Detach,
+    Fields,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Backup => "backing up device",
+            Stage::Unlock => "unlocking device",
+            Stage::Attach => "attaching to device",
+            Stage::Identify => "identifying device",
+            Stage::Erase => "erasing device",
+            Stage::Flash => "flashing file",
+            Stage::Verify => "verifying flash",
+            Stage::Modem => "updating modem firmware",
+            Stage::Counter => "provisioning rollback counter",
+            Stage::Uicr => "writing UICR",
+            Stage::Reset => "resetting device",
+            Stage::Detach => "preparing to detach",
+            Stage::Fields => "writing unit data",
+        })
+    }
+}
+
+/// What to do to a single target.
+///
+/// The force flags go from most to least destructive: an unlock always erases
+/// the whole device, so `force_erase` only adds an erase when no unlock
+/// happened, and `force_reflash` only matters when neither erased the device.
+#[derive(Clone, Debug)]
+pub struct FlowOptions {
+    /// probe-rs target name.
+    pub chip: String,
+    /// Images to flash, in order.
+    pub images: Vec<ImageFile>,
+    /// Run the unlock sequence even if the device appears unlocked.
+    pub force_unlock: bool,
+    /// Mass erase the device even if it did not need unlocking.
+    pub force_erase: bool,
+    /// Program every page instead of skipping the ones that already match.
+    pub force_reflash: bool,
+    /// How to check what was programmed.
+    pub verify: VerifyLevel,
+    /// Downgrade protection counter to raise to the images' version.
+    pub rollback: Option<CounterKind>,
+    /// A/B application slots to flash the images into.
+    pub slots: Option<SlotOptions>,
+    /// UICR words to write on nRF91 besides the protection words, which an
+    /// entry for their address overrides.
+    pub uicr: Vec<(u64, u32)>,
+    /// What to write to APPROTECT and SECUREAPPROTECT on nRF91.
+    pub protection: Protection,
+    /// Directory to save flash and UICR to before an erase.
+    pub backup: Option<PathBuf>,
+    /// Modem firmware package to install after the images.
+    pub modem: Option<PathBuf>,
+    /// How to leave the device at the end.
+    pub detach: DetachMode,
+    /// Program images one window at a time instead of loading them whole.
+    pub stream: bool,
+    /// Debug and access ports for the unlock and the checks before attaching.
+    pub ports: DebugPorts,
+    /// Pick the probe-rs target from FICR instead of trusting `chip`.
+    pub detect_chip: bool,
+    /// Ask the operator before anything erases the device.
+    pub confirm_erase: bool,
+    /// Draw progress bars while erasing and flashing, on a terminal.
+    pub progress: bool,
+}
+
+/// The CTRL-AP unlock sequence and the UICR writes only apply to nRF91 parts.
+pub fn is_nrf91(chip: &str) -> bool {
+    chip.to_ascii_lowercase().starts_with("nrf91")
+}
+
+/// The options for a device identified as `part`.
+///
+/// A device that is not the chip asked for is refused, unless the chip is to
+/// be detected; then the detected part's target is used, after checking the
+/// images against its memory map.
+fn for_part(options: &FlowOptions, part: Option<Part>) -> Result<FlowOptions, RecoveryError> {
+    let Some(part) = part else {
+        return Ok(options.clone());
+    };
+    let target = match part.target() {
+        _ if part.matches(&options.chip) => return Ok(options.clone()),
+        Some(target) if options.detect_chip => target,
+        Some(target) => {
+            return Err(RecoveryError::TargetError(format!(
+                "the device is an {}, not {}; use --chip {}",
+                part, options.chip, target
+            )))
+        }
+        None if options.detect_chip => {
+            return Err(RecoveryError::TargetError(format!("{} is not a known part; use --chip", part)))
+        }
+        None => {
+            strict::warn(RecoveryError::TargetError(format!(
+                "unknown part {}, continuing as {}",
+                part, options.chip
+            )))?;
+            return Ok(options.clone());
+        }
+    };
+
+    let detected = FlowOptions {
+        chip: target.to_string(),
+        ..options.clone()
+    };
+    Plan::build(&detected).map_err(|e| {
+        RecoveryError::TargetError(format!("the images do not match the detected {}: {}", part, e))
+    })?;
+    say(tr!("detected_part", part = part, chip = target));
+    Ok(detected)
+}
+
+/// Ask before a mass erase. Anything but yes, or no terminal, cancels.
+fn confirm_erase() -> Result<(), RecoveryError> {
+    if confirm(&tr!("erase_confirm"), false) {
+        Ok(())
+    } else {
+        Err(RecoveryError::Cancelled)
+    }
+}
+
+/// Bytes of image data held in memory at once when streaming. A multiple of
+/// every sector size in use, so consecutive windows never share a sector.
+const STREAM_WINDOW: u64 = 64 * 1024;
+
+/// Program `segments` through one run of the target's RAM flash algorithm.
+/// With a `pipeline`, probe-rs does not verify; the data is read back right
+/// away and compared on the pipeline's thread while the caller goes on.
+fn program(
+    session: &mut Session,
+    segments: &[Segment],
+    options: &FlowOptions,
+    pipeline: Option<&mut verify::Pipeline>,
+) -> Result<(), RecoveryError> {
+    let mut loader = session.target().flash_loader();
+    for segment in segments {
+        loader.add_data(segment.address, &segment.data)?;
+    }
+    let mut download = download_options(options);
+    download.verify &= pipeline.is_none();
+    loader.commit(session, download)?;
+
+    if let Some(pipeline) = pipeline {
+        let mut core = session.core(0)?;
+        let mut window = Vec::new();
+        for segment in segments {
+            let mut actual = vec![0u8; segment.data.len()];
+            core.read(segment.address, &mut actual)?;
+            window.push(verify::Readback {
+                address: segment.address,
+                expected: segment.data.clone(),
+                actual,
+            });
+        }
+        pipeline.check(window).map_err(|e| e.at(Stage::Verify))?;
+    }
+    Ok(())
+}
+
+/// The pipeline for full verification of images programmed in several
+/// runs of the flash algorithm.
+fn verify_pipeline(options: &FlowOptions) -> Option<verify::Pipeline> {
+    (options.verify == VerifyLevel::Full).then(verify::Pipeline::start)
+}
+
+/// Program an image one window at a time, each through its own run of the
+/// target's RAM flash algorithm, to keep memory use flat for images of many
+/// megabytes. Full verification of a window overlaps with programming the
+/// next.
+fn stream_image(session: &mut Session, file: &ImageFile, options: &FlowOptions) -> Result<(), RecoveryError> {
+    let mut pipeline = verify_pipeline(options);
+    file.stream(STREAM_WINDOW, |segments| program(session, segments, options, pipeline.as_mut()))?;
+    pipeline.map_or(Ok(()), verify::Pipeline::finish).map_err(|e| e.at(Stage::Verify))
+}
+
+/// Program an image through the target's RAM flash algorithm, leaving out
+/// its provisioning data, which is written word by word afterwards.
+fn flash_outside_provision(session: &mut Session, file: &ImageFile, options: &FlowOptions) -> Result<(), RecoveryError> {
+    let mut pipeline = verify_pipeline(options);
+    let mut program_outside = |segments: &[Segment]| {
+        let (outside, _) = provision::split(segments);
+        if outside.is_empty() {
+            return Ok(());
+        }
+        program(session, &outside, options, pipeline.as_mut())
+    };
+    if options.stream {
+        file.stream(STREAM_WINDOW, &mut program_outside)?;
+    } else {
+        program_outside(&file.load()?.segments)?;
+    }
+    pipeline.map_or(Ok(()), verify::Pipeline::finish).map_err(|e| e.at(Stage::Verify))
+}
+
+/// probe-rs download options for the verify level. `force_reflash` turns
+/// off the pre-verify skip whatever the level.
+fn download_options(options: &FlowOptions) -> flashing::DownloadOptions<'static> {
+    let mut download = flashing::DownloadOptions::new();
+    download.preverify = options.verify >= VerifyLevel::Preverify && !options.force_reflash;
+    download.verify = options.verify >= VerifyLevel::Full;
+    // Streamed windows are too small for a bar to say much.
+    if options.progress && !options.stream {
+        download.progress = progress::flash_progress();
+    }
+    download
+}
+
+/// Check the CRC of every segment of `file` on the target. Streamed images
+/// are checked window by window, like they were written. Provisioning data
+/// is read back as it is written instead.
+fn crc_image(session: &mut Session, file: &ImageFile, stream: bool) -> Result<(), RecoveryError> {
+    if stream {
+        file.stream(STREAM_WINDOW, |segments| verify::crc_check(session, &provision::split(segments).0))
+    } else {
+        verify::crc_check(session, &provision::split(&file.load()?.segments).0)
+    }
+}
+
+/// Flash the images of `options` to an attached, unlocked device and check
+/// them at the options' verify level. Nothing is erased beyond the sectors
+/// written, and the device is not reset.
+pub fn flash_images(session: &mut Session, options: &FlowOptions, hooks: &Hooks) -> Result<(), RecoveryError> {
+    let nrf91 = is_nrf91(&options.chip);
+    for image in &options.images {
+        // Between images; an image itself is written to the end.
+        cancel::checkpoint(Stage::Flash)?;
+        // NSIB provisioning data goes to UICR.OTP, which must not be erased.
+        let otp = if nrf91 { provision::find(image).map_err(|e| e.at(Stage::Flash))? } else { Vec::new() };
+
+        // Flash file to device
+        if !otp.is_empty() {
+            flash_outside_provision(session, image, options).map_err(|e| e.at(Stage::Flash))?;
+        } else if options.stream {
+            stream_image(session, image, options).map_err(|e| e.at(Stage::Flash))?;
+        } else {
+            flashing::download_file_with_options(session, &image.path, image.probe_rs_format(), download_options(options))
+                .map_err(|e| RecoveryError::from(e).at(Stage::Flash))?;
+        }
+        if options.verify == VerifyLevel::Crc {
+            crc_image(session, image, options.stream).map_err(|e| e.at(Stage::Verify))?;
+        }
+        if !otp.is_empty() {
+            let words = provision::write(session, &otp).map_err(|e| e.at(Stage::Flash))?;
+            say(tr!("provisioned", count = words, image = image));
+        }
+        hooks.flashed(&image.path);
+    }
+
+    say(tr!("flashed"));
+    Ok(())
+}
+
+/// Look at a device the way the flow would before deciding what to do, for a
+/// dry run: wake it, read its lock state, part and UICR protection words,
+/// and on an unlocked device compare the images with what it holds.
+/// Nothing is erased or written.
+///
+/// Fails like the flow would if the device is not the chip asked for.
+pub fn inspect(probe: Probe, options: &FlowOptions) -> Result<DeviceState, RecoveryError> {
+    let nrf91 = is_nrf91(&options.chip);
+    let (probe, part, locked, protection) = if nrf91 {
+        let probe = wake::wake(probe, &options.ports)?.0;
+        let (probe, protection) = approtect::read_before(probe, &options.ports)?;
+        let (probe, locked) = check_locked(probe, &options.ports)?;
+        let (probe, part) = device::detect(probe, &options.ports)?;
+        (probe, part, locked, protection)
+    } else {
+        (probe, None, false, None)
+    };
+    let options = for_part(options, part)?;
+
+    let mut unchanged = Vec::new();
+    if !locked {
+        let mut session = registry::attach(probe, options.chip.as_str(), Permissions::new())?;
+        for file in &options.images {
+            let mut same = true;
+            file.stream(STREAM_WINDOW, |segments| {
+                let mut core = session.core(0)?;
+                for segment in &provision::split(segments).0 {
+                    let mut data = vec![0u8; segment.data.len()];
+                    core.read(segment.address, &mut data)?;
+                    same &= data == segment.data;
+                }
+                Ok(())
+            })?;
+            if same {
+                unchanged.push(file.path.clone());
+            }
+        }
+    }
+
+    Ok(DeviceState {
+        part,
+        locked,
+        protection,
+        unchanged,
+    })
+}
+
+/// Save flash and UICR to `dir` before an nRF91 is erased. A locked device
+/// cannot be read and is erased without a backup; an unlocked one is only
+/// saved if it is going to be erased (`erasing`), since the unlock leaves
+/// it alone otherwise.
+pub fn backup_before_erase(probe: Probe, dir: &Path, erasing: bool, ports: &DebugPorts) -> Result<Probe, RecoveryError> {
+    let (probe, locked) = check_locked(probe, ports)?;
+    if locked {
+        say(tr!("backup_locked"));
+        return Ok(probe);
+    }
+    if !erasing {
+        return Ok(probe);
+    }
+    let (probe, path) = backup::backup(probe, ports, dir)?;
+    say(tr!("backup_written", path = path.display()));
+    Ok(probe)
+}
+
+/// Erase UICR on an unlocked nRF91 and write the protection words of
+/// `protection` again, so an erased APPROTECT does not lock the device on
+/// the reset that follows. Flash is left alone.
+///
+/// A locked device is refused, since only the unlock's full erase can
+/// reach it.
+pub fn erase_uicr(probe: Probe, chip: &str, protection: &Protection, ports: &DebugPorts) -> Result<(), RecoveryError> {
+    if !is_nrf91(chip) {
+        return Err(RecoveryError::UicrError(format!("{} has no UICR to erase on its own", chip)));
+    }
+
+    let (probe, locked) = check_locked(probe, ports).map_err(|e| e.at(Stage::Unlock))?;
+    if locked {
+        return Err(RecoveryError::UnlockError(tr!("erase_uicr_locked")).at(Stage::Unlock));
+    }
+
+    let mut session = registry::attach(probe, chip, Permissions::new())
+        .map_err(|e| RecoveryError::from(e).at(Stage::Attach))?;
+    uicr::erase(&mut session).map_err(|e| e.at(Stage::Erase))?;
+    say(tr!("uicr_erased"));
+
+    for (addr, value) in protection.words(chip) {
+        write_uicr(&mut session, addr, value)
+            .map_err(|e| RecoveryError::UicrError(format!("0x{:08x}: {}", addr, e)).at(Stage::Uicr))?;
+    }
+
+    session
+        .core(0)
+        .and_then(|mut core| core.reset())
+        .map_err(|e| RecoveryError::from(e).at(Stage::Reset))
+}
+
+/// Erase all flash and UICR: through CTRL-AP on nRF91, which also unlocks
+/// the device, otherwise with the target's erase-all sequence.
+pub fn erase(probe: Probe, chip: &str, ports: &DebugPorts) -> Result<(), RecoveryError> {
+    if is_nrf91(chip) {
+        try_unlock_device(probe, true, UnlockReset::for_chip(chip), ports, chip).map_err(|e| e.at(Stage::Erase))?;
+    } else {
+        let mut session = registry::attach(probe, chip, Permissions::new().allow_erase_all())
+            .map_err(|e| RecoveryError::from(e).at(Stage::Attach))?;
+        flashing::erase_all(&mut session, FlashProgress::empty())
+            .map_err(|e| RecoveryError::from(e).at(Stage::Erase))?;
+    }
+    say(tr!("erased"));
+    Ok(())
+}
+
+/// Whether the flow writes UICR.OTP itself: the images carry NSIB
+/// provisioning data, or a rollback counter is raised.
+fn provisions_otp(options: &FlowOptions) -> Result<bool, RecoveryError> {
+    if options.rollback.is_some() {
+        return Ok(true);
+    }
+    for file in &options.images {
+        if !provision::find(file)?.is_empty() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Run the recovery flow: unlock, attach, flash, write UICR and reset.
+///
+/// Returns the session so callers can run post-boot checks on it. The UICR
+/// protection words of nRF91 devices are recorded in `approtect` as far as the
+/// flow gets; a `before` state already recorded by an earlier attempt is kept.
+/// `written` is set once the flow goes on to unlock, erase or program the
+/// device, so a failure before that left it as it was. `hooks` are told
+/// about each milestone and about a failure.
+pub fn run(
+    probe: Probe,
+    options: &FlowOptions,
+    approtect: &mut ApprotectReport,
+    written: &mut bool,
+    hooks: &Hooks,
+) -> Result<Session, RecoveryError> {
+    run_steps(probe, options, approtect, written, hooks).inspect_err(|e| hooks.failed(e))
+}
+
+fn run_steps(
+    probe: Probe,
+    options: &FlowOptions,
+    approtect: &mut ApprotectReport,
+    written: &mut bool,
+    hooks: &Hooks,
+) -> Result<Session, RecoveryError> {
+    let nrf91 = is_nrf91(&options.chip);
+
+    // Find the version before touching the device, so a wrong image fails
+    // early.
+    let counter = match options.rollback {
+        Some(_) if !nrf91 => {
+            return Err(RecoveryError::RollbackError(format!("{} has no UICR.OTP", options.chip)).at(Stage::Counter))
+        }
+        Some(kind) => Some((kind, kind.image_version(&options.images).map_err(|e| e.at(Stage::Counter))?)),
+        None => None,
+    };
+    let modem = match &options.modem {
+        Some(_) if !nrf91 => {
+            return Err(RecoveryError::ModemError(format!("{} has no modem", options.chip)).at(Stage::Modem))
+        }
+        Some(path) => Some(ModemFirmware::load(path).map_err(|e| e.at(Stage::Modem))?),
+        None => None,
+    };
+    // A UICR.OTP word would be written over the provisioning data.
+    if let Some((address, _)) = options.uicr.iter().find(|(a, _)| provision::PROVISION.contains(a)) {
+        if provisions_otp(options).map_err(|e| e.at(Stage::Uicr))? {
+            return Err(RecoveryError::UicrError(format!(
+                "{} is in UICR.OTP, which the images' provisioning data or the rollback counter write too",
+                uicr::name(*address).unwrap_or_else(|| format!("0x{:08x}", address))
+            ))
+            .at(Stage::Uicr));
+        }
+    }
+
+    cancel::checkpoint(Stage::Unlock)?;
+
+    // A sleeping device looks dead, or locked; wake it before judging.
+    let probe = if nrf91 {
+        wake::wake(probe, &options.ports).map_err(|e| e.at(Stage::Unlock))?.0
+    } else {
+        probe
+    };
+
+    let probe = if nrf91 {
+        let (probe, before) = approtect::read_before(probe, &options.ports).map_err(|e| e.at(Stage::Unlock))?;
+        log::info!("UICR protection before recovery: {:?}", before);
+        if approtect.before.is_none() {
+            approtect.before = before;
+        }
+        probe
+    } else {
+        probe
+    };
+
+    // An unlocked device can be told apart before it is touched, which also
+    // picks the right reset for the unlock.
+    let (probe, part) = if nrf91 {
+        device::detect(probe, &options.ports).map_err(|e| e.at(Stage::Identify))?
+    } else {
+        (probe, None)
+    };
+    let requested = options;
+    let mut detected = for_part(requested, part).map_err(|e| e.at(Stage::Identify))?;
+    let options = &mut detected;
+
+    // Let the hooks turn the device away before it is erased or written.
+    let (probe, identified) = if nrf91 {
+        let (probe, device_id) = device::detect_id(probe, &options.ports).map_err(|e| e.at(Stage::Identify))?;
+        if let Some(device_id) = &device_id {
+            hooks.identified(device_id).map_err(|e| e.at(Stage::Identify))?;
+        }
+        (probe, device_id.is_some())
+    } else {
+        (probe, false)
+    };
+
+    let probe = if options.confirm_erase {
+        let (probe, locked) = if nrf91 {
+            check_locked(probe, &options.ports).map_err(|e| e.at(Stage::Unlock))?
+        } else {
+            (probe, false)
+        };
+        if locked || options.force_unlock || options.force_erase {
+            confirm_erase().map_err(|e| e.at(Stage::Unlock))?;
+        }
+        probe
+    } else {
+        probe
+    };
+
+    cancel::checkpoint(Stage::Backup)?;
+    let probe = match &options.backup {
+        Some(dir) if nrf91 => {
+            backup_before_erase(probe, dir, options.force_unlock || options.force_erase, &options.ports)
+                .map_err(|e| e.at(Stage::Backup))?
+        }
+        _ => probe,
+    };
+
+    cancel::checkpoint(Stage::Unlock)?;
+    *written = true;
+    // The unlock waits for ERASEALL to complete, so it is not cancelled
+    // part way.
+    let (probe, erased, permissions) = if nrf91 {
+        let key = part.map_or_else(|| options.chip.clone(), |p| p.to_string());
+        let (probe, erased) =
+            try_unlock_device(probe, options.force_unlock, UnlockReset::for_chip(&options.chip), &options.ports, &key)
+                .map_err(|e| e.at(Stage::Unlock))?;
+        // FICR survives the erase, so a locked device can be told apart now.
+        let probe = match part {
+            Some(_) => probe,
+            None => {
+                let (probe, part) = device::detect(probe, &requested.ports).map_err(|e| e.at(Stage::Identify))?;
+                *options = for_part(requested, part).map_err(|e| e.at(Stage::Identify))?;
+                probe
+            }
+        };
+        (probe, erased, Permissions::new())
+    } else if options.force_unlock {
+        // Let probe-rs run the target's own erase-all sequence if it is locked.
+        (probe, false, Permissions::new().allow_erase_all())
+    } else {
+        (probe, false, Permissions::new())
+    };
+
+    let mut session = registry::attach(probe, options.chip.as_str(), permissions)
+        .map_err(|e| RecoveryError::from(e).at(Stage::Attach))?;
+
+    say(tr!("session_created"));
+    hooks.unlocked(erased);
+
+    // FICR survives the unlock's erase, so a locked device can be told apart
+    // here, before anything is programmed.
+    if nrf91 && !identified {
+        match device::device_id(&mut session) {
+            Ok(device_id) => hooks.identified(&device_id).map_err(|e| e.at(Stage::Identify))?,
+            Err(e) => strict::warn(RecoveryError::DebugError(format!("unable to read device ID: {}", e)))
+                .map_err(|e| e.at(Stage::Identify))?,
+        }
+    }
+
+    // UICR can only be erased as a whole; find out now if the words cannot
+    // be written, rather than after flashing.
+    if nrf91 && (erased || !options.force_erase) {
+        uicr::check_writable(&mut session, &uicr::flow_words(&options.uicr, &options.protection, &options.chip))
+            .map_err(|e| e.at(Stage::Uicr))?;
+    }
+
+    // Unlocking already erased everything, so only clean unlocked devices.
+    if options.force_erase && !erased {
+        cancel::checkpoint(Stage::Erase)?;
+        let bars = options.progress.then(progress::flash_progress).flatten();
+        flashing::erase_all(&mut session, bars.unwrap_or_else(FlashProgress::empty))
+            .map_err(|e| RecoveryError::from(e).at(Stage::Erase))?;
+        say(tr!("erased"));
+    }
+
+    if let Some(slots) = &options.slots {
+        let slot = slot::choose(&mut session, slots, erased).map_err(|e| e.at(Stage::Flash))?;
+        options.images = slot::place(&options.images, &slots.layout, slot).map_err(|e| e.at(Stage::Flash))?;
+    }
+
+    flash_images(&mut session, options, hooks)?;
+
+    if let Some(firmware) = &modem {
+        cancel::checkpoint(Stage::Modem)?;
+        modem::update(&mut session, firmware).map_err(|e| e.at(Stage::Modem))?;
+    }
+
+    if let Some((kind, version)) = counter {
+        cancel::checkpoint(Stage::Counter)?;
+        let previous = rollback::provision(&mut session, kind, version).map_err(|e| e.at(Stage::Counter))?;
+        say(tr!("rollback_counter", version = version, previous = previous));
+    }
+
+    if nrf91 {
+        cancel::checkpoint(Stage::Uicr)?;
+        let words = uicr::flow_words(&options.uicr, &options.protection, &options.chip);
+        for (addr, value) in &words {
+            write_uicr(&mut session, *addr, *value)
+                .map_err(|e| RecoveryError::UicrError(e.to_string()).at(Stage::Uicr))?;
+        }
+        if !options.uicr.is_empty() {
+            say(tr!("uicr_words_written", count = options.uicr.len()));
+        }
+        if options.protection.enables() {
+            say(tr!("uicr_locking"));
+        }
+        let after = approtect::read(&mut session).map_err(|e| e.at(Stage::Uicr))?;
+        log::info!("UICR protection after recovery: {:?}", after);
+        for (name, addr, value) in [
+            ("APPROTECT", UICR_APPROTECT, after.approtect),
+            ("SECUREAPPROTECT", UICR_SECUREAPPROTECT, after.secureapprotect),
+        ] {
+            if let Some((_, written)) = words.iter().find(|(a, v)| *a == addr && *v != value) {
+                strict::warn(RecoveryError::UicrError(format!(
+                    "UICR.{} reads 0x{:08x} after writing 0x{:08x}",
+                    name, value, written
+                )))
+                .map_err(|e| e.at(Stage::Uicr))?;
+            }
+        }
+        hooks.uicr_written(&after);
+        approtect.after = Some(after);
+    }
+
+    // Reset with probe_rs
+    session
+        .core(0)
+        .and_then(|mut core| core.reset())
+        .map_err(|e| RecoveryError::from(e).at(Stage::Reset))?;
+
+    if nrf91 && options.detach == DetachMode::KeepUnlocked {
+        detach::keep_unlocked(&mut session, &options.chip, &options.ports).map_err(|e| e.at(Stage::Detach))?;
+    }
+
+    // With no verify level nothing was checked against the images.
+    if options.verify != VerifyLevel::None {
+        hooks.verified();
+    }
+    Ok(session)
+}
+
+/// Write and verify the protection words of a device programmed by another
+/// tool, then reset it, leaving its firmware alone.
+///
+/// A locked device is refused, since unlocking it would erase the firmware
+/// this mode exists to keep.
+pub fn uicr_only(
+    probe: Probe,
+    chip: &str,
+    protection: &Protection,
+    detach: DetachMode,
+    ports: &DebugPorts,
+) -> Result<Session, RecoveryError> {
+    if !is_nrf91(chip) {
+        return Err(RecoveryError::UicrError(format!("{} has no UICR protection words", chip)));
+    }
+
+    let (probe, locked) = check_locked(probe, ports).map_err(|e| e.at(Stage::Unlock))?;
+    if locked {
+        return Err(RecoveryError::UnlockError(tr!("uicr_only_locked")).at(Stage::Unlock));
+    }
+
+    let mut session = registry::attach(probe, chip, Permissions::new())
+        .map_err(|e| RecoveryError::from(e).at(Stage::Attach))?;
+
+    let words = protection.words(chip);
+    for (addr, value) in &words {
+        write_uicr(&mut session, *addr, *value)
+            .map_err(|e| RecoveryError::UicrError(format!("0x{:08x}: {}", addr, e)).at(Stage::Uicr))?;
+    }
+
+    let after = approtect::read(&mut session).map_err(|e| e.at(Stage::Uicr))?;
+    let read = |addr: u64| if addr == UICR_APPROTECT { after.approtect } else { after.secureapprotect };
+    if words.iter().any(|(addr, value)| read(*addr) != *value) {
+        return Err(RecoveryError::UicrError(format!(
+            "verify failed: APPROTECT 0x{:08x}, SECUREAPPROTECT 0x{:08x}",
+            after.approtect, after.secureapprotect
+        ))
+        .at(Stage::Uicr));
+    }
+    say(tr!("uicr_verified"));
+
+    session
+        .core(0)
+        .and_then(|mut core| core.reset())
+        .map_err(|e| RecoveryError::from(e).at(Stage::Reset))?;
+
+    if detach == DetachMode::KeepUnlocked {
+        detach::keep_unlocked(&mut session, chip, ports).map_err(|e| e.at(Stage::Detach))?;
+    }
+
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecoverySession;
+
+    #[test]
+    fn detects_chip_from_ficr_words() {
+        let options = RecoverySession::builder().chip("nRF9160_xxAA").detect_chip(true).options();
+        let part = Part {
+            part: 0x0000_9151,
+            variant: 0x4141_4130,
+        };
+        let detected = for_part(&options, Some(part)).unwrap();
+        assert_eq!(detected.chip, "nRF9151_xxAA");
+    }
+}
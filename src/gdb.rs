@@ -0,0 +1,315 @@
+use probe_rs::{Core, MemoryInterface, Session};
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use crate::debug::find_register;
+use crate::RecoveryError;
+
+const HALT_TIMEOUT: Duration = Duration::from_millis(100);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Registers in `g` packet order, matching `TARGET_XML`.
+const REGISTERS: [&str; 17] = [
+    "R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7", "R8", "R9", "R10", "R11", "R12", "SP", "LR",
+    "PC", "XPSR",
+];
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>arm</architecture>
+  <feature name="org.gnu.gdb.arm.m-profile">
+    <reg name="r0" bitsize="32"/>
+    <reg name="r1" bitsize="32"/>
+    <reg name="r2" bitsize="32"/>
+    <reg name="r3" bitsize="32"/>
+    <reg name="r4" bitsize="32"/>
+    <reg name="r5" bitsize="32"/>
+    <reg name="r6" bitsize="32"/>
+    <reg name="r7" bitsize="32"/>
+    <reg name="r8" bitsize="32"/>
+    <reg name="r9" bitsize="32"/>
+    <reg name="r10" bitsize="32"/>
+    <reg name="r11" bitsize="32"/>
+    <reg name="r12" bitsize="32"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="lr" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="xpsr" bitsize="32"/>
+  </feature>
+</target>
+"#;
+
+/// What to do after handling a packet.
+enum Reply {
+    Packet(String),
+    /// The reply was already sent.
+    Sent,
+    /// The client detached or killed the session.
+    Close,
+}
+
+/// A GDB remote serial protocol connection.
+struct Connection {
+    stream: TcpStream,
+    ack: bool,
+}
+
+impl Connection {
+    /// Read the next packet, answering acks. Returns `None` on disconnect.
+    fn receive(&mut self) -> Result<Option<String>, RecoveryError> {
+        let mut byte = [0u8; 1];
+        loop {
+            if self.stream.read(&mut byte).map_err(io_error)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+            // Stray acks and interrupts while halted are ignored.
+        }
+
+        let mut data = Vec::new();
+        loop {
+            if self.stream.read(&mut byte).map_err(io_error)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut checksum = [0u8; 2];
+        self.stream.read_exact(&mut checksum).map_err(io_error)?;
+
+        if self.ack {
+            self.stream.write_all(b"+").map_err(io_error)?;
+        }
+        Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+    }
+
+    fn send(&mut self, data: &str) -> Result<(), RecoveryError> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, checksum);
+        self.stream.write_all(packet.as_bytes()).map_err(io_error)?;
+        if self.ack {
+            // Wait for the ack, but there is nothing useful to do on a nack.
+            let mut byte = [0u8; 1];
+            self.stream.read(&mut byte).map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    /// Whether GDB sent an interrupt (Ctrl-C) while the target runs.
+    fn interrupted(&mut self) -> Result<bool, RecoveryError> {
+        self.stream.set_nonblocking(true).map_err(io_error)?;
+        let mut byte = [0u8; 1];
+        let result = match self.stream.read(&mut byte) {
+            Ok(0) => Ok(true),
+            Ok(_) => Ok(byte[0] == 0x03),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(io_error(e)),
+        };
+        self.stream.set_nonblocking(false).map_err(io_error)?;
+        result
+    }
+}
+
+fn io_error(e: std::io::Error) -> RecoveryError {
+    RecoveryError::GdbError(e.to_string())
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s, 16).ok()
+}
+
+fn hex_word(value: u32) -> String {
+    hex::encode(value.to_le_bytes())
+}
+
+fn read_registers(core: &mut Core) -> Result<String, RecoveryError> {
+    let mut out = String::new();
+    for name in REGISTERS {
+        let register = find_register(core, name)?;
+        let value: u32 = core.read_core_reg(register.id())?;
+        out.push_str(&hex_word(value));
+    }
+    Ok(out)
+}
+
+/// `addr,length` as used by memory and breakpoint packets.
+fn parse_range(s: &str) -> Option<(u64, usize)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)? as usize))
+}
+
+/// Resume the core until it halts again or GDB interrupts it.
+fn resume(conn: &mut Connection, core: &mut Core) -> Result<Reply, RecoveryError> {
+    core.run()?;
+    loop {
+        if core.core_halted()? {
+            return Ok(Reply::Packet("S05".into()));
+        }
+        if conn.interrupted()? {
+            core.halt(HALT_TIMEOUT)?;
+            return Ok(Reply::Packet("S02".into()));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Answer one packet.
+fn handle(conn: &mut Connection, core: &mut Core, packet: &str) -> Result<Reply, RecoveryError> {
+    let reply = match packet.as_bytes().first() {
+        Some(b'?') => "S05".to_string(),
+        Some(b'g') => read_registers(core)?,
+        Some(b'p') => {
+            let index = parse_hex(&packet[1..]).unwrap_or(u64::MAX) as usize;
+            match REGISTERS.get(index) {
+                Some(name) => {
+                    let register = find_register(core, name)?;
+                    hex_word(core.read_core_reg(register.id())?)
+                }
+                None => "E01".into(),
+            }
+        }
+        Some(b'P') => {
+            let parsed = packet[1..].split_once('=').and_then(|(index, value)| {
+                let bytes = hex::decode(value).ok()?;
+                Some((parse_hex(index)? as usize, u32::from_le_bytes(bytes.try_into().ok()?)))
+            });
+            match parsed.and_then(|(index, value)| Some((REGISTERS.get(index)?, value))) {
+                Some((name, value)) => {
+                    let register = find_register(core, name)?;
+                    core.write_core_reg(register.id(), value)?;
+                    "OK".into()
+                }
+                None => "E01".into(),
+            }
+        }
+        Some(b'm') => match parse_range(&packet[1..]) {
+            Some((addr, len)) => {
+                let mut data = vec![0u8; len];
+                match core.read(addr, &mut data) {
+                    Ok(()) => hex::encode(data),
+                    Err(_) => "E01".into(),
+                }
+            }
+            None => "E01".into(),
+        },
+        Some(b'M') => {
+            let parsed = packet[1..].split_once(':').and_then(|(range, data)| {
+                Some((parse_range(range)?.0, hex::decode(data).ok()?))
+            });
+            match parsed {
+                Some((addr, data)) => match core.write(addr, &data) {
+                    Ok(()) => "OK".into(),
+                    Err(_) => "E01".into(),
+                },
+                None => "E01".into(),
+            }
+        }
+        Some(b'c') => return resume(conn, core),
+        Some(b's') => {
+            core.step()?;
+            "S05".into()
+        }
+        Some(b'Z') | Some(b'z') if matches!(packet.as_bytes().get(1), Some(b'0' | b'1')) => {
+            let addr = packet.get(3..).and_then(|args| args.split_once(',')).and_then(|(addr, _)| parse_hex(addr));
+            match addr {
+                Some(addr) if packet.starts_with('Z') => {
+                    core.set_hw_breakpoint(addr)?;
+                    "OK".into()
+                }
+                Some(addr) => {
+                    core.clear_hw_breakpoint(addr)?;
+                    "OK".into()
+                }
+                None => "E01".into(),
+            }
+        }
+        Some(b'D') => {
+            conn.send("OK")?;
+            core.clear_all_hw_breakpoints()?;
+            core.run()?;
+            return Ok(Reply::Close);
+        }
+        Some(b'k') => return Ok(Reply::Close),
+        Some(b'H') => "OK".into(),
+        _ if packet.starts_with("qSupported") => {
+            "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".into()
+        }
+        _ if packet == "QStartNoAckMode" => {
+            conn.send("OK")?;
+            conn.ack = false;
+            return Ok(Reply::Sent);
+        }
+        _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+            let range = &packet["qXfer:features:read:target.xml:".len()..];
+            let (offset, len) = parse_range(range).unwrap_or((0, TARGET_XML.len()));
+            let offset = (offset as usize).min(TARGET_XML.len());
+            let end = (offset + len).min(TARGET_XML.len());
+            let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+            format!("{}{}", marker, &TARGET_XML[offset..end])
+        }
+        _ if packet == "qAttached" => "1".into(),
+        _ if packet == "qfThreadInfo" => "m1".into(),
+        _ if packet == "qsThreadInfo" => "l".into(),
+        _ if packet == "qC" => "QC1".into(),
+        _ if packet.starts_with("qRcmd,") => {
+            let command = hex::decode(&packet["qRcmd,".len()..]).unwrap_or_default();
+            match String::from_utf8_lossy(&command).trim() {
+                "reset" => {
+                    core.reset_and_halt(HALT_TIMEOUT)?;
+                    "OK".into()
+                }
+                "halt" => {
+                    core.halt(HALT_TIMEOUT)?;
+                    "OK".into()
+                }
+                _ => hex::encode("Supported monitor commands: reset, halt\n"),
+            }
+        }
+        // Unsupported packets get an empty reply.
+        _ => String::new(),
+    };
+    Ok(Reply::Packet(reply))
+}
+
+/// Serve one GDB client until it detaches or disconnects.
+fn serve_client(stream: TcpStream, session: &mut Session) -> Result<(), RecoveryError> {
+    stream.set_nodelay(true).map_err(io_error)?;
+    let mut conn = Connection { stream, ack: true };
+    let mut core = session.core(0)?;
+    core.halt(HALT_TIMEOUT)?;
+
+    while let Some(packet) = conn.receive()? {
+        log::debug!("gdb <- {}", packet);
+        match handle(&mut conn, &mut core, &packet)? {
+            Reply::Packet(reply) => conn.send(&reply)?,
+            Reply::Sent => {}
+            Reply::Close => break,
+        }
+    }
+    Ok(())
+}
+
+/// Keep the session open and expose it to GDB on `addr`, one client at a
+/// time, until the process is stopped.
+pub fn serve(session: &mut Session, addr: &str) -> Result<(), RecoveryError> {
+    let listener = TcpListener::bind(addr).map_err(|e| RecoveryError::GdbError(format!("{}: {}", addr, e)))?;
+    println!("GDB server listening on {}", addr);
+
+    for stream in listener.incoming() {
+        let stream = stream.map_err(io_error)?;
+        let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+        println!("GDB client connected from {}", peer);
+        if let Err(e) = serve_client(stream, session) {
+            log::warn!("GDB client error: {}", e);
+        }
+        println!("GDB client disconnected");
+    }
+    Ok(())
+}
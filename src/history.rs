@@ -0,0 +1,98 @@
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::i18n::tr;
+use crate::output::say;
+use crate::RecoveryError;
+
+/// The parts of a results line the history needs.
+#[derive(Deserialize)]
+struct Line {
+    time: String,
+    device_id: Option<String>,
+    #[serde(default)]
+    images: Vec<LineImage>,
+    success: bool,
+}
+
+#[derive(Deserialize)]
+struct LineImage {
+    sha256: String,
+}
+
+/// One earlier programming attempt of a device.
+#[derive(Clone, Debug)]
+pub struct Visit {
+    pub time: String,
+    /// SHA-256 of each image flashed.
+    pub images: Vec<String>,
+    pub success: bool,
+}
+
+/// Earlier programming attempts per FICR device ID, read from a results
+/// file.
+#[derive(Debug, Default)]
+pub struct History {
+    devices: HashMap<String, Vec<Visit>>,
+}
+
+impl History {
+    /// Read the results file at `path`. A missing file is an empty history;
+    /// lines that cannot be parsed, such as a line cut short by a power loss,
+    /// are skipped.
+    pub fn load(path: &Path) -> Result<Self, RecoveryError> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(History::default()),
+            Err(e) => return Err(RecoveryError::ReportError(format!("{}: {}", path.display(), e))),
+        };
+
+        let mut history = History::default();
+        for (number, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+            let line: Line = match serde_json::from_str(line) {
+                Ok(line) => line,
+                Err(e) => {
+                    log::debug!("{}:{}: {}", path.display(), number + 1, e);
+                    continue;
+                }
+            };
+            let Some(device_id) = line.device_id else { continue };
+            history.devices.entry(device_id).or_default().push(Visit {
+                time: line.time,
+                images: line.images.into_iter().map(|i| i.sha256).collect(),
+                success: line.success,
+            });
+        }
+        Ok(history)
+    }
+
+    /// Earlier attempts of `device_id`, oldest first.
+    pub fn visits(&self, device_id: &str) -> &[Visit] {
+        self.devices.get(device_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Tell the operator about a device that has been through the station
+    /// before.
+    pub fn print(&self, device_id: &str) {
+        let visits = self.visits(device_id);
+        let Some(last) = visits.last() else { return };
+
+        let failures = visits.iter().filter(|v| !v.success).count();
+        let images = match last.images.as_slice() {
+            [] => "-".to_string(),
+            images => images.iter().map(|sha| &sha[..sha.len().min(12)]).collect::<Vec<_>>().join(", "),
+        };
+        say(tr!(
+            "device_history",
+            device = device_id,
+            count = visits.len(),
+            failures = failures,
+            last = last.time,
+            images = images
+        ));
+    }
+}
@@ -0,0 +1,166 @@
+use std::path::Path;
+
+use crate::approtect::UicrProtect;
+use crate::flow::Stage;
+use crate::notify::shell;
+use crate::RecoveryError;
+
+type Callbacks<F> = Vec<Box<F>>;
+type FailedFn = dyn Fn(Stage, &RecoveryError) + Send + Sync;
+type RetryingFn = dyn Fn(u32, &RecoveryError) + Send + Sync;
+type CheckFn = dyn Fn(&str) -> Result<(), RecoveryError> + Send + Sync;
+
+/// Callbacks fired as the recovery flow progresses, so an embedding
+/// application can drive its own UI or database.
+///
+/// ```ignore
+/// let hooks = Hooks::default()
+///     .on_flashed(|image| println!("flashed {}", image.display()))
+///     .on_failed(|stage, e| eprintln!("failed {}: {}", stage, e));
+/// ```
+#[derive(Default)]
+pub struct Hooks {
+    unlocked: Callbacks<dyn Fn(bool) + Send + Sync>,
+    identified: Callbacks<dyn Fn(&str) + Send + Sync>,
+    identified_checks: Callbacks<CheckFn>,
+    flashed: Callbacks<dyn Fn(&Path) + Send + Sync>,
+    uicr_written: Callbacks<dyn Fn(&UicrProtect) + Send + Sync>,
+    verified: Callbacks<dyn Fn() + Send + Sync>,
+    failed: Callbacks<FailedFn>,
+    retrying: Callbacks<RetryingFn>,
+}
+
+impl Hooks {
+    /// The debugger has full access to the device. The argument tells
+    /// whether the device was mass-erased to get there.
+    pub fn on_unlocked(mut self, f: impl Fn(bool) + Send + Sync + 'static) -> Self {
+        self.unlocked.push(Box::new(f));
+        self
+    }
+
+    /// The FICR device ID of an nRF91 was read, before anything is written:
+    /// before the unlock on an unlocked device, right after the unlock's
+    /// erase on a locked one.
+    pub fn on_identified(mut self, f: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.identified.push(Box::new(f));
+        self
+    }
+
+    /// Like `on_identified`, but an error stops the flow before anything is
+    /// written to the device.
+    pub fn check_identified(
+        mut self,
+        f: impl Fn(&str) -> Result<(), RecoveryError> + Send + Sync + 'static,
+    ) -> Self {
+        self.identified_checks.push(Box::new(f));
+        self
+    }
+
+    /// An image was written.
+    pub fn on_flashed(mut self, f: impl Fn(&Path) + Send + Sync + 'static) -> Self {
+        self.flashed.push(Box::new(f));
+        self
+    }
+
+    /// The UICR protection words were written and read back.
+    pub fn on_uicr_written(mut self, f: impl Fn(&UicrProtect) + Send + Sync + 'static) -> Self {
+        self.uicr_written.push(Box::new(f));
+        self
+    }
+
+    /// The device was reset and passed every check of the flow. Not called
+    /// with verify level `none`, as the images were not checked.
+    pub fn on_verified(mut self, f: impl Fn() + Send + Sync + 'static) -> Self {
+        self.verified.push(Box::new(f));
+        self
+    }
+
+    /// The flow stopped with an error in the given stage.
+    pub fn on_failed(mut self, f: impl Fn(Stage, &RecoveryError) + Send + Sync + 'static) -> Self {
+        self.failed.push(Box::new(f));
+        self
+    }
+
+    /// An attempt failed with the given error and the whole flow is about to
+    /// run again. The number counts the retries, from 1.
+    pub fn on_retrying(mut self, f: impl Fn(u32, &RecoveryError) + Send + Sync + 'static) -> Self {
+        self.retrying.push(Box::new(f));
+        self
+    }
+
+    /// Run `command` for every event, with the event in `RECOVERY_EVENT`.
+    pub fn command(command: &str) -> Self {
+        let run = |command: String, event: &'static str| {
+            move |vars: &[(&str, String)]| {
+                let mut cmd = shell(&command);
+                cmd.env("RECOVERY_EVENT", event).envs(vars.iter().map(|(k, v)| (*k, v)));
+                if let Err(e) = cmd.status() {
+                    log::warn!("Unable to run event command: {}", e);
+                }
+            }
+        };
+        let unlocked = run(command.to_string(), "unlocked");
+        let identified = run(command.to_string(), "identified");
+        let flashed = run(command.to_string(), "flashed");
+        let uicr_written = run(command.to_string(), "uicr-written");
+        let verified = run(command.to_string(), "verified");
+        let failed = run(command.to_string(), "failed");
+        let retrying = run(command.to_string(), "retrying");
+
+        Hooks::default()
+            .on_unlocked(move |erased| unlocked(&[("RECOVERY_ERASED", erased.to_string())]))
+            .on_identified(move |device_id| identified(&[("RECOVERY_DEVICE_ID", device_id.to_string())]))
+            .on_flashed(move |image| flashed(&[("RECOVERY_IMAGE", image.display().to_string())]))
+            .on_uicr_written(move |words| {
+                uicr_written(&[
+                    ("RECOVERY_APPROTECT", format!("0x{:08x}", words.approtect)),
+                    ("RECOVERY_SECUREAPPROTECT", format!("0x{:08x}", words.secureapprotect)),
+                ])
+            })
+            .on_verified(move || verified(&[]))
+            .on_failed(move |stage, e| {
+                failed(&[
+                    ("RECOVERY_STAGE", stage.to_string()),
+                    ("RECOVERY_ERROR", e.to_string()),
+                ])
+            })
+            .on_retrying(move |retry, e| {
+                retrying(&[
+                    ("RECOVERY_RETRY", retry.to_string()),
+                    ("RECOVERY_ERROR", e.to_string()),
+                ])
+            })
+    }
+
+    pub(crate) fn unlocked(&self, erased: bool) {
+        self.unlocked.iter().for_each(|f| f(erased));
+    }
+
+    pub(crate) fn identified(&self, device_id: &str) -> Result<(), RecoveryError> {
+        self.identified.iter().for_each(|f| f(device_id));
+        self.identified_checks.iter().try_for_each(|f| f(device_id))
+    }
+
+    pub(crate) fn flashed(&self, image: &Path) {
+        self.flashed.iter().for_each(|f| f(image));
+    }
+
+    pub(crate) fn uicr_written(&self, words: &UicrProtect) {
+        self.uicr_written.iter().for_each(|f| f(words));
+    }
+
+    pub(crate) fn verified(&self) {
+        self.verified.iter().for_each(|f| f());
+    }
+
+    pub(crate) fn retrying(&self, retry: u32, error: &RecoveryError) {
+        self.retrying.iter().for_each(|f| f(retry, error));
+    }
+
+    /// Report a flow error, if it is tagged with a stage.
+    pub(crate) fn failed(&self, error: &RecoveryError) {
+        if let RecoveryError::Stage { stage, source } = error {
+            self.failed.iter().for_each(|f| f(*stage, source));
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use clap::ValueEnum;
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::config;
+
+/// Language of the operator-facing messages.
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum Lang {
+    #[default]
+    En,
+    Es,
+    Vi,
+}
+
+impl Lang {
+    fn catalog(self) -> &'static str {
+        match self {
+            Lang::En => include_str!("../locales/en.toml"),
+            Lang::Es => include_str!("../locales/es.toml"),
+            Lang::Vi => include_str!("../locales/vi.toml"),
+        }
+    }
+}
+
+type Catalog = HashMap<String, String>;
+
+static SELECTED: OnceLock<Catalog> = OnceLock::new();
+static FALLBACK: OnceLock<Catalog> = OnceLock::new();
+
+fn load(lang: Lang) -> Catalog {
+    config::parse(lang.catalog()).expect("built-in message catalog is valid")
+}
+
+/// Select the message catalog. Messages missing from it fall back to English.
+pub fn init(lang: Lang) {
+    let _ = SELECTED.set(load(lang));
+}
+
+/// Look up `key` and fill in its `{name}` placeholders.
+pub fn text(key: &str, args: &[(&str, String)]) -> String {
+    let fallback = FALLBACK.get_or_init(|| load(Lang::En));
+    let template = SELECTED
+        .get()
+        .and_then(|catalog| catalog.get(key))
+        .or_else(|| fallback.get(key))
+        .map(String::as_str)
+        .unwrap_or(key);
+
+    let mut message = template.to_string();
+    for (name, value) in args {
+        message = message.replace(&format!("{{{}}}", name), value);
+    }
+    message
+}
+
+/// Translated operator message: `tr!("batch_row", row = 3)`.
+#[macro_export]
+macro_rules! tr {
+    ($key:literal $(, $name:ident = $value:expr)* $(,)?) => {
+        $crate::i18n::text($key, &[$((stringify!($name), $value.to_string())),*])
+    };
+}
+
+pub use crate::tr;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_locale_has_the_english_keys() {
+        let english = load(Lang::En);
+        for lang in [Lang::Es, Lang::Vi] {
+            let catalog = load(lang);
+            let mut missing: Vec<_> = english.keys().filter(|key| !catalog.contains_key(*key)).collect();
+            let mut extra: Vec<_> = catalog.keys().filter(|key| !english.contains_key(*key)).collect();
+            missing.sort();
+            extra.sort();
+            assert!(missing.is_empty() && extra.is_empty(), "{:?}: missing {:?}, unknown {:?}", lang, missing, extra);
+        }
+    }
+}
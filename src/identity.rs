@@ -0,0 +1,189 @@
+use clap::ValueEnum;
+use probe_rs::Session;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::console::{self, ConsoleSpec};
+use crate::imei;
+use crate::RecoveryError;
+
+/// Time `AT%ATTESTTOKEN` may take; the modem signs the token first.
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a cloud device registry needs to know about a unit.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    pub imei: String,
+    /// Device UUID from the attestation token, as nRF Cloud names devices.
+    pub uuid: String,
+    /// `AT%ATTESTTOKEN` output, which nRF Cloud takes to claim the device.
+    pub attestation_token: String,
+}
+
+/// Decode unpadded base64url, as used in attestation tokens.
+fn base64url(text: &str) -> Option<Vec<u8>> {
+    let mut bits = 0u32;
+    let mut count = 0;
+    let mut out = Vec::new();
+    for c in text.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// The device UUID in an attestation token.
+///
+/// The token's first part is a CBOR array of the message type, the 16 byte
+/// device UUID, the device type and the firmware UUID.
+fn token_uuid(token: &str) -> Result<String, RecoveryError> {
+    let invalid = || RecoveryError::ModemError(format!("'{}' is not an attestation token", token));
+    let claims = token.split('.').next().and_then(base64url).ok_or_else(invalid)?;
+    let mut bytes = claims.iter().copied();
+
+    // An array, then a small unsigned message type.
+    if bytes.next().ok_or_else(invalid)? >> 5 != 4 {
+        return Err(invalid());
+    }
+    match bytes.next().ok_or_else(invalid)? {
+        n if n < 0x18 => {}
+        0x18 => {
+            bytes.next();
+        }
+        _ => return Err(invalid()),
+    }
+    // A 16 byte string.
+    if bytes.next() != Some(0x50) {
+        return Err(invalid());
+    }
+    let uuid: Vec<u8> = bytes.take(16).collect();
+    if uuid.len() != 16 {
+        return Err(invalid());
+    }
+    let hex = hex::encode(uuid);
+    Ok(format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]))
+}
+
+/// Read the IMEI and the attestation token over the firmware's console.
+///
+/// The IMEI is read first, which also waits for the modem to start, the way
+/// [`imei::read_imei`] does.
+pub fn read(session: &mut Session, spec: &ConsoleSpec, timeout: Duration) -> Result<Identity, RecoveryError> {
+    let imei = imei::read_imei(session, spec, timeout)?;
+
+    let pattern = Regex::new(r#"%ATTESTTOKEN:\s*"([A-Za-z0-9_\-]+\.[A-Za-z0-9_\-]+)""#).unwrap();
+    let mut console = console::open(session, spec)?;
+    let response = console::exchange(console.as_mut(), "AT%ATTESTTOKEN", &pattern, TOKEN_TIMEOUT)?;
+    let attestation_token = pattern.captures(&response).unwrap()[1].to_string();
+
+    Ok(Identity {
+        imei,
+        uuid: token_uuid(&attestation_token)?,
+        attestation_token,
+    })
+}
+
+/// File layout for [`export`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// nRF Cloud's bulk onboarding CSV, one device ID per row.
+    NrfCloud,
+    /// A JSON array with every identity field, for other registries.
+    Json,
+}
+
+/// The parts of a results file line the export needs.
+#[derive(Deserialize)]
+struct ResultLine {
+    time: String,
+    unit: Option<String>,
+    serial: Option<String>,
+    device_id: Option<String>,
+    success: bool,
+    identity: Option<Identity>,
+}
+
+/// One device in a JSON export.
+#[derive(Serialize)]
+struct Registration {
+    uuid: String,
+    imei: String,
+    attestation_token: String,
+    device_id: Option<String>,
+    unit: Option<String>,
+    probe_serial: Option<String>,
+    programmed: String,
+}
+
+/// Write the identities of every unit a results file records as
+/// successfully programmed to `out`, ready to import in one go. A unit
+/// programmed more than once appears once, with its last run. Returns the
+/// number of devices written.
+pub fn export(results: &Path, format: ExportFormat, out: &Path) -> Result<usize, RecoveryError> {
+    let err = |e: String| RecoveryError::ReportError(format!("{}: {}", results.display(), e));
+    let file = File::open(results).map_err(|e| err(e.to_string()))?;
+
+    let mut devices: BTreeMap<String, Registration> = BTreeMap::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| err(e.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: ResultLine = serde_json::from_str(&line).map_err(|e| err(format!("line {}: {}", i + 1, e)))?;
+        let Some(identity) = entry.identity.filter(|_| entry.success) else {
+            continue;
+        };
+        devices.insert(
+            identity.uuid.clone(),
+            Registration {
+                uuid: identity.uuid,
+                imei: identity.imei,
+                attestation_token: identity.attestation_token,
+                device_id: entry.device_id,
+                unit: entry.unit,
+                probe_serial: entry.serial,
+                programmed: entry.time,
+            },
+        );
+    }
+
+    let write_err = |e: String| RecoveryError::ReportError(format!("{}: {}", out.display(), e));
+    match format {
+        ExportFormat::NrfCloud => {
+            let mut writer = csv::Writer::from_path(out).map_err(|e| write_err(e.to_string()))?;
+            writer
+                .write_record(["deviceId", "subType", "tags", "fwTypes", "certPem"])
+                .map_err(|e| write_err(e.to_string()))?;
+            for device in devices.values() {
+                writer
+                    .write_record([device.uuid.as_str(), "", "", "APP|MODEM", ""])
+                    .map_err(|e| write_err(e.to_string()))?;
+            }
+            writer.flush().map_err(|e| write_err(e.to_string()))?;
+        }
+        ExportFormat::Json => {
+            let devices: Vec<&Registration> = devices.values().collect();
+            let json = serde_json::to_string_pretty(&devices).map_err(|e| write_err(e.to_string()))?;
+            fs::write(out, json + "\n").map_err(|e| write_err(e.to_string()))?;
+        }
+    }
+    Ok(devices.len())
+}
@@ -0,0 +1,592 @@
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use ihex::{Reader, Record};
+use object::elf::{FileHeader32, PT_LOAD};
+use object::read::elf::{FileHeader, ProgramHeader};
+use object::Endianness;
+use probe_rs::flashing::{BinOptions, ElfOptions, Format};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::artifact::Resolver;
+use crate::i18n::tr;
+use crate::strict;
+use crate::version::MCUBOOT_MAGIC;
+use crate::{parse_number, RecoveryError};
+
+/// imgtool custom TLV carrying the build time, added with
+/// `imgtool sign --custom-tlv 0xa0 "$(date +%s)"`.
+pub const BUILD_TIME_TLV: u16 = 0xa0;
+
+/// MCUboot TLV holding the SHA-256 of the signed image.
+const IMAGE_TLV_SHA256: u16 = 0x10;
+
+/// Bytes per data record of the Intel hex files written.
+const HEX_RECORD_SIZE: usize = 16;
+
+const TLV_INFO_MAGIC: u16 = 0x6907;
+const TLV_PROT_INFO_MAGIC: u16 = 0x6908;
+
+/// A contiguous run of bytes from an image file.
+pub struct Segment {
+    pub address: u64,
+    pub data: Vec<u8>,
+}
+
+/// Contents of an image file.
+pub struct Image {
+    pub segments: Vec<Segment>,
+}
+
+impl Image {
+    pub fn load(path: &Path) -> Result<Self, RecoveryError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| RecoveryError::ImageError(format!("{}: {}", path.display(), e)))?;
+        Self::parse(&text, &path.display().to_string())
+    }
+
+    /// Write the segments to an Intel hex file. Rows that are all 0xFF are
+    /// left out, since flashing them back changes nothing.
+    pub fn save_hex(&self, path: &Path) -> Result<(), RecoveryError> {
+        let err = |e: String| RecoveryError::ImageError(format!("{}: {}", path.display(), e));
+
+        let mut records = Vec::new();
+        let mut upper = None;
+        for segment in &self.segments {
+            for (i, row) in segment.data.chunks(HEX_RECORD_SIZE).enumerate() {
+                if row.iter().all(|byte| *byte == 0xFF) {
+                    continue;
+                }
+                let address = segment.address + (i * HEX_RECORD_SIZE) as u64;
+                if upper != Some(address >> 16) {
+                    upper = Some(address >> 16);
+                    records.push(Record::ExtendedLinearAddress((address >> 16) as u16));
+                }
+                records.push(Record::Data {
+                    offset: address as u16,
+                    value: row.to_vec(),
+                });
+            }
+        }
+        records.push(Record::EndOfFile);
+        let text = ihex::create_object_file_representation(&records).map_err(|e| err(e.to_string()))?;
+        fs::write(path, text).map_err(|e| err(e.to_string()))
+    }
+
+    /// Parse Intel hex text. `name` identifies it in errors.
+    pub fn parse(text: &str, name: &str) -> Result<Self, RecoveryError> {
+        let err = |e: String| RecoveryError::ImageError(format!("{}: {}", name, e));
+
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut base = 0u64;
+        for record in Reader::new(text) {
+            match record.map_err(|e| err(e.to_string()))? {
+                Record::Data { offset, value } => {
+                    let address = base + offset as u64;
+                    match segments.last_mut() {
+                        Some(last) if last.address + last.data.len() as u64 == address => {
+                            last.data.extend_from_slice(&value)
+                        }
+                        _ => segments.push(Segment { address, data: value }),
+                    }
+                }
+                Record::ExtendedLinearAddress(upper) => base = (upper as u64) << 16,
+                Record::ExtendedSegmentAddress(segment) => base = (segment as u64) << 4,
+                Record::EndOfFile => break,
+                _ => {}
+            }
+        }
+
+        Ok(Image { segments })
+    }
+
+    /// The loadable segments of a 32-bit ELF file, at their physical (load)
+    /// addresses, which is where initialised data lives in flash.
+    pub fn load_elf(path: &Path) -> Result<Self, RecoveryError> {
+        let err = |e: &str| RecoveryError::ImageError(format!("{}: {}", path.display(), e));
+        let data = fs::read(path).map_err(|e| err(&e.to_string()))?;
+        let header = FileHeader32::<Endianness>::parse(&*data).map_err(|_| err("not a 32-bit ELF file"))?;
+        let endian = header.endian().map_err(|e| err(&e.to_string()))?;
+
+        let mut segments = Vec::new();
+        for program in header.program_headers(endian, &*data).map_err(|e| err(&e.to_string()))? {
+            if program.p_type(endian) != PT_LOAD || program.p_filesz(endian) == 0 {
+                continue;
+            }
+            let bytes = program.data(endian, &*data).map_err(|_| err("truncated segment"))?;
+            segments.push(Segment {
+                address: program.p_paddr(endian) as u64,
+                data: bytes.to_vec(),
+            });
+        }
+        segments.sort_by_key(|s| s.address);
+        Ok(Image { segments })
+    }
+
+    /// Read `len` bytes at `address` if the image covers all of them.
+    pub fn read(&self, address: u64, len: usize) -> Option<&[u8]> {
+        self.segments.iter().find_map(|s| {
+            let start = address.checked_sub(s.address)? as usize;
+            s.data.get(start..start + len)
+        })
+    }
+
+    /// Address of the first MCUboot image header in the image.
+    pub fn mcuboot_header(&self) -> Option<u64> {
+        let magic = MCUBOOT_MAGIC.to_le_bytes();
+        self.segments.iter().find_map(|s| {
+            (0..s.data.len().saturating_sub(3))
+                .step_by(4)
+                .find(|i| s.data[*i..*i + 4] == magic)
+                .map(|i| s.address + i as u64)
+        })
+    }
+
+    /// Find a TLV of the MCUboot image at `header` by type, searching both
+    /// the protected and the unprotected TLV areas.
+    pub fn mcuboot_tlv(&self, header: u64, kind: u16) -> Option<&[u8]> {
+        let fields = self.read(header, 16)?;
+        let hdr_size = u16::from_le_bytes([fields[8], fields[9]]) as u64;
+        let img_size = u32::from_le_bytes(fields[12..16].try_into().unwrap()) as u64;
+
+        let mut info = header + hdr_size + img_size;
+        loop {
+            let area = self.read(info, 4)?;
+            let magic = u16::from_le_bytes([area[0], area[1]]);
+            let total = u16::from_le_bytes([area[2], area[3]]) as u64;
+            // A length short of its own header would never reach the next
+            // area.
+            if (magic != TLV_INFO_MAGIC && magic != TLV_PROT_INFO_MAGIC) || total < 4 {
+                return None;
+            }
+
+            let end = info + total;
+            let mut tlv = info + 4;
+            while tlv + 4 <= end {
+                let entry = self.read(tlv, 4)?;
+                let tlv_kind = u16::from_le_bytes([entry[0], entry[1]]);
+                let len = u16::from_le_bytes([entry[2], entry[3]]) as usize;
+                if tlv_kind == kind {
+                    return self.read(tlv + 4, len);
+                }
+                tlv += 4 + len as u64;
+            }
+
+            // The unprotected area follows the protected one.
+            if magic == TLV_INFO_MAGIC {
+                return None;
+            }
+            info = end;
+        }
+    }
+
+    /// Build time embedded in the image's MCUboot TLVs, if any.
+    ///
+    /// The value is either Unix seconds or an RFC 3339 timestamp, as text.
+    pub fn build_time(&self) -> Option<DateTime<Utc>> {
+        let value = self.mcuboot_tlv(self.mcuboot_header()?, BUILD_TIME_TLV)?;
+        let text = std::str::from_utf8(value).ok()?.trim_end_matches('\0').trim();
+        match text.parse::<i64>() {
+            Ok(seconds) => DateTime::from_timestamp(seconds, 0),
+            Err(_) => DateTime::parse_from_rfc3339(text)
+                .ok()
+                .map(|t| t.with_timezone(&Utc)),
+        }
+    }
+}
+
+/// How an image file is laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    /// Intel hex, which carries its own addresses.
+    Hex,
+    /// ELF, flashed from its loadable segments, e.g. Zephyr's `zephyr.elf`.
+    Elf,
+    /// Raw bytes, placed at a base address.
+    Bin { base: u64 },
+}
+
+/// Image file format as chosen with `--format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum FileFormat {
+    Elf,
+    Hex,
+    Bin,
+}
+
+impl FileFormat {
+    /// Tell the format from the extension, then from the contents: ELF
+    /// files start with their magic, anything else is taken as Intel hex.
+    pub fn detect(path: &Path) -> Self {
+        let ext = path.extension().map(|ext| ext.to_ascii_lowercase());
+        match ext.as_ref().and_then(|ext| ext.to_str()) {
+            Some("elf" | "axf" | "out") => return FileFormat::Elf,
+            Some("bin") => return FileFormat::Bin,
+            Some("hex" | "ihex") => return FileFormat::Hex,
+            _ => {}
+        }
+        let mut magic = [0u8; 4];
+        match File::open(path).and_then(|mut file| file.read_exact(&mut magic)) {
+            Ok(()) if magic == *b"\x7fELF" => FileFormat::Elf,
+            _ => FileFormat::Hex,
+        }
+    }
+}
+
+/// An image as given on the command line or in a job file: `PATH[@ADDR]`,
+/// where ADDR is the base address of a raw `.bin` image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageSpec {
+    /// Path or URL of the file.
+    pub reference: PathBuf,
+    pub base: Option<u64>,
+    /// Format given with `--format`, detected from the file if not.
+    pub format: Option<FileFormat>,
+}
+
+impl FromStr for ImageSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // '@' only separates an address, so paths and URLs may contain it.
+        match s.rsplit_once('@') {
+            Some((reference, address)) if parse_number(address).is_ok() => Ok(ImageSpec {
+                reference: reference.into(),
+                base: parse_number(address).ok(),
+                format: None,
+            }),
+            _ => Ok(ImageSpec {
+                reference: s.into(),
+                base: None,
+                format: None,
+            }),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ImageSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl From<&ImageFile> for ImageSpec {
+    fn from(file: &ImageFile) -> Self {
+        ImageSpec {
+            reference: file.path.clone(),
+            base: match file.format {
+                ImageFormat::Bin { base } => Some(base),
+                _ => None,
+            },
+            format: Some(match file.format {
+                ImageFormat::Hex => FileFormat::Hex,
+                ImageFormat::Elf => FileFormat::Elf,
+                ImageFormat::Bin { .. } => FileFormat::Bin,
+            }),
+        }
+    }
+}
+
+impl ImageSpec {
+    /// Fetch the file through `resolver`, relative to `base_dir`.
+    pub fn resolve(&self, resolver: &Resolver, base_dir: &Path) -> Result<ImageFile, RecoveryError> {
+        ImageFile::new(resolver.resolve(&self.reference, base_dir)?, self.base, self.format)
+    }
+}
+
+/// A local image file and its format.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageFile {
+    pub path: PathBuf,
+    pub format: ImageFormat,
+}
+
+impl ImageFile {
+    /// The format is `format` if given, or else detected from the file, see
+    /// [`FileFormat::detect`]. Raw images need a base address.
+    pub fn new(path: PathBuf, base: Option<u64>, format: Option<FileFormat>) -> Result<Self, RecoveryError> {
+        let err = |e: &str| RecoveryError::ImageError(format!("{}: {}", path.display(), e));
+        let format = match (format.unwrap_or_else(|| FileFormat::detect(&path)), base) {
+            (FileFormat::Bin, Some(base)) => ImageFormat::Bin { base },
+            (FileFormat::Bin, None) => return Err(err("raw images need a base address, as app.bin@0x10000 or with --base-address")),
+            (FileFormat::Hex, None) => ImageFormat::Hex,
+            (FileFormat::Elf, None) => ImageFormat::Elf,
+            (_, Some(_)) => return Err(err("only raw images take a base address")),
+        };
+        Ok(ImageFile { path, format })
+    }
+
+    /// An Intel hex file.
+    pub fn hex(path: impl Into<PathBuf>) -> Self {
+        ImageFile {
+            path: path.into(),
+            format: ImageFormat::Hex,
+        }
+    }
+
+    pub fn load(&self) -> Result<Image, RecoveryError> {
+        match self.format {
+            ImageFormat::Hex => Image::load(&self.path),
+            ImageFormat::Elf => Image::load_elf(&self.path),
+            ImageFormat::Bin { base } => {
+                let data = fs::read(&self.path)
+                    .map_err(|e| RecoveryError::ImageError(format!("{}: {}", self.path.display(), e)))?;
+                Ok(Image {
+                    segments: vec![Segment { address: base, data }],
+                })
+            }
+        }
+    }
+
+    /// probe-rs format to download the file with.
+    pub fn probe_rs_format(&self) -> Format {
+        match self.format {
+            ImageFormat::Hex => Format::Hex,
+            ImageFormat::Elf => Format::Elf(ElfOptions::default()),
+            ImageFormat::Bin { base } => Format::Bin(BinOptions {
+                base_address: Some(base),
+                skip: 0,
+            }),
+        }
+    }
+
+    /// Read the image one address window at a time, see [`stream`].
+    pub fn stream(&self, window: u64, f: impl FnMut(&[Segment]) -> Result<(), RecoveryError>) -> Result<(), RecoveryError> {
+        match self.format {
+            ImageFormat::Hex => stream(&self.path, window, f),
+            // ELF files hold little more than the flash contents, so they
+            // are loaded whole and cut into windows.
+            ImageFormat::Elf => stream_segments(self.load()?.segments, window, f),
+            ImageFormat::Bin { base } => stream_bin(&self.path, base, window, f),
+        }
+    }
+}
+
+impl From<PathBuf> for ImageFile {
+    fn from(path: PathBuf) -> Self {
+        ImageFile::hex(path)
+    }
+}
+
+impl fmt::Display for ImageFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.format {
+            ImageFormat::Hex | ImageFormat::Elf => write!(f, "{}", self.path.display()),
+            ImageFormat::Bin { base } => write!(f, "{}@0x{:08x}", self.path.display(), base),
+        }
+    }
+}
+
+/// [`stream`] for segments already in memory, in ascending order.
+fn stream_segments(
+    segments: Vec<Segment>,
+    window: u64,
+    mut f: impl FnMut(&[Segment]) -> Result<(), RecoveryError>,
+) -> Result<(), RecoveryError> {
+    let mut batch: Vec<Segment> = Vec::new();
+    let mut current: Option<u64> = None;
+    for segment in segments {
+        let mut address = segment.address;
+        let mut data = &segment.data[..];
+        while !data.is_empty() {
+            let start = address - address % window;
+            if current.is_some_and(|c| c != start) && !batch.is_empty() {
+                f(&batch)?;
+                batch.clear();
+            }
+            current = Some(start);
+            let (head, tail) = data.split_at(((start + window - address) as usize).min(data.len()));
+            batch.push(Segment { address, data: head.to_vec() });
+            address += head.len() as u64;
+            data = tail;
+        }
+    }
+    if !batch.is_empty() {
+        f(&batch)?;
+    }
+    Ok(())
+}
+
+/// [`stream`] for a raw image at `base`.
+fn stream_bin(
+    path: &Path,
+    base: u64,
+    window: u64,
+    mut f: impl FnMut(&[Segment]) -> Result<(), RecoveryError>,
+) -> Result<(), RecoveryError> {
+    let err = |e: io::Error| RecoveryError::ImageError(format!("{}: {}", path.display(), e));
+    let mut file = File::open(path).map_err(err)?;
+
+    let mut address = base;
+    loop {
+        let len = (window - address % window) as usize;
+        let mut data = Vec::with_capacity(len);
+        (&mut file).take(len as u64).read_to_end(&mut data).map_err(err)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        let read = data.len() as u64;
+        f(&[Segment { address, data }])?;
+        address += read;
+    }
+}
+
+/// Read a hex file one address window at a time, calling `f` with the
+/// segments inside each window, so at most `window` bytes of image data are
+/// in memory at once.
+///
+/// Windows start at multiples of `window`. Records must come in ascending
+/// window order, which is how linkers and `mergehex` write them.
+pub fn stream(
+    path: &Path,
+    window: u64,
+    mut f: impl FnMut(&[Segment]) -> Result<(), RecoveryError>,
+) -> Result<(), RecoveryError> {
+    let err = |e: String| RecoveryError::ImageError(format!("{}: {}", path.display(), e));
+    let file = File::open(path).map_err(|e| err(e.to_string()))?;
+
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut current: Option<u64> = None;
+    let mut base = 0u64;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| err(e.to_string()))?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match Record::from_record_string(line).map_err(|e| err(e.to_string()))? {
+            Record::Data { offset, value } => {
+                let address = base + offset as u64;
+                let start = address - address % window;
+                match current {
+                    Some(c) if start < c => {
+                        return Err(err(format!(
+                            "data at 0x{:08x} comes after 0x{:08x}; streaming needs ascending addresses",
+                            address, c
+                        )));
+                    }
+                    Some(c) if start > c => {
+                        f(&segments)?;
+                        segments.clear();
+                    }
+                    _ => {}
+                }
+                current = Some(start);
+
+                // Split records that straddle the window boundary.
+                let split = ((start + window - address) as usize).min(value.len());
+                let (head, tail) = value.split_at(split);
+                match segments.last_mut() {
+                    Some(last) if last.address + last.data.len() as u64 == address => {
+                        last.data.extend_from_slice(head)
+                    }
+                    _ => segments.push(Segment { address, data: head.to_vec() }),
+                }
+                if !tail.is_empty() {
+                    f(&segments)?;
+                    segments.clear();
+                    current = Some(start + window);
+                    segments.push(Segment { address: start + window, data: tail.to_vec() });
+                }
+            }
+            Record::ExtendedLinearAddress(upper) => base = (upper as u64) << 16,
+            Record::ExtendedSegmentAddress(segment) => base = (segment as u64) << 4,
+            Record::EndOfFile => break,
+            _ => {}
+        }
+    }
+
+    if !segments.is_empty() {
+        f(&segments)?;
+    }
+    Ok(())
+}
+
+/// Identifies the exact artifact a device was programmed with.
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageInfo {
+    pub path: String,
+    /// SHA-256 of the file.
+    pub sha256: String,
+    /// Image hash from the MCUboot TLVs, the same value MCUboot reports on
+    /// the device.
+    pub mcuboot_hash: Option<String>,
+}
+
+impl ImageInfo {
+    pub fn load(file: &ImageFile) -> Result<Self, RecoveryError> {
+        let path = &file.path;
+        let bytes = fs::read(path).map_err(|e| RecoveryError::ImageError(format!("{}: {}", path.display(), e)))?;
+        let image = file.load()?;
+        let mcuboot_hash = image
+            .mcuboot_header()
+            .and_then(|header| image.mcuboot_tlv(header, IMAGE_TLV_SHA256))
+            .map(hex::encode);
+
+        Ok(ImageInfo {
+            path: path.display().to_string(),
+            sha256: hex::encode(Sha256::digest(&bytes)),
+            mcuboot_hash,
+        })
+    }
+}
+
+/// Warn if the image in `file` was built longer than `max` ago.
+pub fn check_age(file: &ImageFile, max: Duration) -> Result<(), RecoveryError> {
+    let path = &file.path;
+    let image = file.load()?;
+    let Some(built) = image.build_time() else {
+        eprintln!("{}", tr!("no_build_time", image = path.display()));
+        if strict::enabled() {
+            return Err(RecoveryError::ArtifactError(format!("{} has no build time", path.display())));
+        }
+        return Ok(());
+    };
+
+    let age = (Utc::now() - built).to_std().unwrap_or_default();
+    if age > max {
+        eprintln!(
+            "{}",
+            tr!(
+                "stale_artifact",
+                image = path.display(),
+                built = built.format("%Y-%m-%d %H:%M UTC"),
+                days = age.as_secs() / 86400
+            )
+        );
+        if strict::enabled() {
+            return Err(RecoveryError::ArtifactError(format!(
+                "{} was built {} days ago",
+                path.display(),
+                age.as_secs() / 86400
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_tlv_area_ends_the_search() {
+        // A 32-byte header with no image, then a protected TLV area whose
+        // length does not even cover its own info header.
+        let mut data = vec![0u8; 36];
+        data[8..10].copy_from_slice(&32u16.to_le_bytes());
+        data[32..34].copy_from_slice(&TLV_PROT_INFO_MAGIC.to_le_bytes());
+        let image = Image {
+            segments: vec![Segment { address: 0, data }],
+        };
+        assert_eq!(image.mcuboot_tlv(0, BUILD_TIME_TLV), None);
+    }
+}
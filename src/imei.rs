@@ -0,0 +1,54 @@
+use probe_rs::Session;
+use regex::Regex;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::console::{self, ConsoleSpec};
+use crate::RecoveryError;
+
+/// How long the modem gets to start and answer.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time one AT+CGSN may take to answer.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Whether the last digit of a 15 digit IMEI is its Luhn check digit.
+fn luhn_valid(imei: &str) -> bool {
+    let sum: u32 = imei
+        .chars()
+        .rev()
+        .filter_map(|c| c.to_digit(10))
+        .enumerate()
+        .map(|(i, d)| if i % 2 == 1 { (d * 2) / 10 + (d * 2) % 10 } else { d })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// Ask the modem for its IMEI with `AT+CGSN` over the firmware's console.
+///
+/// The firmware has to hand AT commands to the modem, as Nordic's at_client
+/// and serial_lte_modem samples do. The modem takes a moment to start after
+/// reset, so the query is repeated until `timeout`.
+pub fn read_imei(session: &mut Session, spec: &ConsoleSpec, timeout: Duration) -> Result<String, RecoveryError> {
+    let pattern = Regex::new(r"\b(\d{15})\b").unwrap();
+    let mut console = console::open(session, spec)?;
+
+    let start = Instant::now();
+    loop {
+        match console::exchange(console.as_mut(), "AT+CGSN", &pattern, QUERY_TIMEOUT) {
+            Ok(response) => {
+                let imei = pattern.captures(&response).unwrap()[1].to_string();
+                if luhn_valid(&imei) {
+                    return Ok(imei);
+                }
+                log::info!("Ignoring {}: bad IMEI check digit", imei);
+            }
+            Err(e) => log::info!("IMEI query failed: {}", e),
+        }
+        if start.elapsed() >= timeout {
+            return Err(RecoveryError::ImeiError);
+        }
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
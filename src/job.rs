@@ -0,0 +1,1005 @@
+use chrono::Utc;
+use probe_rs::{MemoryInterface, Permissions, Session};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::approtect::{self, ApprotectPolicy, ApprotectReport, Protection};
+use crate::artifact::Resolver;
+use crate::backup;
+use crate::cancel;
+use crate::clock::{self, ClockStatus};
+use crate::console::ConsoleSpec;
+use crate::credentials::{self, Credential};
+use crate::cycle::CycleBudget;
+use crate::dap::DebugPorts;
+use crate::detach::{self, DetachMode};
+use crate::escalate::{Escalation, Step};
+use crate::flow::{self, FlowOptions, Stage};
+use crate::hooks::Hooks;
+use crate::i18n::tr;
+use crate::identity::{self, Identity};
+use crate::image::{ImageFile, ImageInfo, ImageSpec};
+use crate::imei;
+use crate::patch::PatchSpec;
+use crate::prompt::ask;
+use crate::recipe::UicrWord;
+use crate::rollback::CounterKind;
+use crate::slot::{SlotChoice, SlotLayout, SlotOptions};
+use crate::station::Station;
+use crate::symbols::{Location, Symbols};
+use crate::uicr::{self, UicrTemplate};
+use crate::verify::VerifyLevel;
+use crate::{config, device, nvmc, open_probe, registry, ProbeArgs, RecoveryError};
+
+/// Per-unit data, keyed by CSV column name.
+pub type Unit = BTreeMap<String, String>;
+
+/// A job programming one or more chips on the same board, loaded from TOML.
+///
+/// ```toml
+/// parallel = false
+///
+/// [[target]]
+/// name = "modem"
+/// chip = "nRF9151_xxAA"
+/// serial = "E6614C311B4A5A2F"
+/// images = ["merged.hex"]
+///
+/// [[target]]
+/// name = "coprocessor"
+/// chip = "nRF52833_xxAA"
+/// serial = "E6614C311B4A8B11"
+/// images = ["coprocessor.hex"]
+/// # Shown before programming, e.g. to flip an SWD select switch.
+/// prompt = "Set SW2 to nRF52."
+///
+/// # What to try when a target fails, in order.
+/// [escalation]
+/// steps = ["retry", "slow-down", "reopen", "power-cycle"]
+/// power_cycle = "uhubctl -l 1-1 -p 2 -a cycle"
+///
+/// # Per-unit data written in batch mode.
+/// [[field]]
+/// column = "serial"
+/// address = 0x00FF8080
+/// format = "string"
+///
+/// # Or at a symbol of the target's `elf = "zephyr.elf"`.
+/// [[field]]
+/// column = "calibration"
+/// address = "symbol:factory_data+4"
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Job {
+    /// Program all targets at the same time instead of one after another.
+    #[serde(default)]
+    pub parallel: bool,
+    #[serde(rename = "target")]
+    pub targets: Vec<JobTarget>,
+    #[serde(default, rename = "field")]
+    pub fields: Vec<Field>,
+    #[serde(default)]
+    pub escalation: Escalation,
+}
+
+/// How a per-unit value is encoded into device memory.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldFormat {
+    /// A single 32-bit word, decimal or 0x-prefixed hex.
+    #[default]
+    U32,
+    /// NUL-terminated UTF-8 text.
+    String,
+    /// Raw bytes given as a hex string.
+    Hex,
+}
+
+/// Maps a per-unit column onto an nRF91 flash or UICR address.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Field {
+    pub column: String,
+    /// An address, or `symbol:name` in the target's `elf`.
+    #[serde(rename = "address")]
+    pub location: Location,
+    /// `location`, resolved when the job is loaded.
+    #[serde(skip)]
+    pub address: u64,
+    #[serde(default)]
+    pub format: FieldFormat,
+    /// Target to write to. Defaults to the first target of the job.
+    pub target: Option<String>,
+    /// Maximum encoded size in bytes.
+    pub length: Option<usize>,
+}
+
+impl Field {
+    /// Encode `value` into words, padding the last word with 0xFF.
+    pub fn encode(&self, value: &str) -> Result<Vec<u32>, RecoveryError> {
+        let err = |msg: String| RecoveryError::FieldError(format!("{}: {}", self.column, msg));
+
+        let mut bytes = match self.format {
+            FieldFormat::U32 => {
+                let word = crate::parse_word(value).map_err(err)?;
+                word.to_le_bytes().to_vec()
+            }
+            FieldFormat::String => {
+                let mut bytes = value.as_bytes().to_vec();
+                bytes.push(0);
+                bytes
+            }
+            FieldFormat::Hex => hex::decode(value.trim()).map_err(|e| err(e.to_string()))?,
+        };
+
+        if let Some(length) = self.length {
+            if bytes.len() > length {
+                return Err(err(format!(
+                    "{} bytes do not fit in {} bytes",
+                    bytes.len(),
+                    length
+                )));
+            }
+        }
+        if !self.address.is_multiple_of(4) {
+            return Err(err(format!("address 0x{:08x} is not word aligned", self.address)));
+        }
+
+        while !bytes.len().is_multiple_of(4) {
+            bytes.push(0xFF);
+        }
+
+        Ok(bytes
+            .chunks(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect())
+    }
+}
+
+/// Write the unit's field values through the NVMC and reset the device.
+///
+/// The destination words must still be erased (or already hold the value),
+/// since flash bits can only be cleared.
+pub fn write_fields(session: &mut Session, fields: &[&Field], unit: &Unit) -> Result<(), RecoveryError> {
+    if fields.is_empty() {
+        return Ok(());
+    }
+
+    let mut core = session.core(0)?;
+    core.halt(Duration::from_millis(100))?;
+
+    for field in fields {
+        let value = unit.get(&field.column).ok_or_else(|| {
+            RecoveryError::FieldError(format!("column '{}' missing for unit", field.column))
+        })?;
+        let words = field.encode(value)?;
+
+        let mut current = vec![0u32; words.len()];
+        core.read_32(field.address, &mut current)?;
+        if current == words {
+            continue;
+        }
+        if current.iter().any(|w| *w != 0xFFFFFFFF) {
+            return Err(RecoveryError::FieldError(format!(
+                "{}: memory at 0x{:08x} is not erased",
+                field.column, field.address
+            )));
+        }
+
+        log::info!("Writing {} = {:?} at 0x{:08x}", field.column, value, field.address);
+        nvmc::write_words(&mut core, field.address, &words)?;
+    }
+
+    core.reset()?;
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct JobTarget {
+    pub name: String,
+    pub chip: String,
+    /// Images to flash, relative to the job file, as `PATH[@ADDR]`.
+    #[serde(default)]
+    pub images: Vec<ImageSpec>,
+    /// `images`, fetched when the job is loaded.
+    #[serde(skip)]
+    pub image_files: Vec<ImageFile>,
+    /// ELF of the flashed firmware, for `symbol:` field addresses.
+    pub elf: Option<PathBuf>,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub serial: Option<String>,
+    /// Run the unlock sequence even if the target appears unlocked.
+    #[serde(default, alias = "force")]
+    pub force_unlock: bool,
+    /// Mass erase the target even if it did not need unlocking.
+    #[serde(default, alias = "clean")]
+    pub force_erase: bool,
+    /// Program every page even if it already holds the image contents.
+    #[serde(default)]
+    pub force_reflash: bool,
+    /// How to check what was programmed. Production runs default to an
+    /// on-target CRC check.
+    #[serde(default = "default_verify_level")]
+    pub verify_level: VerifyLevel,
+    /// Downgrade protection counter to raise to the images' version.
+    pub rollback_counter: Option<CounterKind>,
+    /// A/B application slots, as `A_ADDR,B_ADDR`, to flash the images into.
+    pub slots: Option<SlotLayout>,
+    /// Which of `slots` to flash.
+    #[serde(default)]
+    pub slot: SlotChoice,
+    /// How to leave the target at the end.
+    #[serde(default)]
+    pub detach: DetachMode,
+    /// Program images in windows instead of loading them whole.
+    #[serde(default)]
+    pub stream: bool,
+    /// UICR words to write along with the protection words.
+    #[serde(default)]
+    pub uicr: Vec<UicrWord>,
+    /// What to write to APPROTECT.
+    #[serde(default)]
+    pub approtect: ApprotectPolicy,
+    /// What to write to SECUREAPPROTECT.
+    #[serde(default)]
+    pub secure_approtect: ApprotectPolicy,
+    /// Enable both protection words after flashing, leaving the target
+    /// locked, whatever `approtect` and `secure_approtect` say.
+    #[serde(default)]
+    pub lock: bool,
+    /// Directory to save flash and UICR to before the device is erased.
+    pub backup: Option<PathBuf>,
+    /// What to do to this target when the job fails, so a half-provisioned
+    /// unit cannot pass for a good one.
+    #[serde(default)]
+    pub on_failure: OnFailure,
+    /// Instruction for the operator, confirmed before this target is
+    /// programmed. Only allowed for sequential jobs.
+    pub prompt: Option<String>,
+    /// Console to read the modem's IMEI from after programming, e.g. `rtt`
+    /// or `usb:10c4:0`.
+    pub read_imei: Option<String>,
+    /// `read_imei`, parsed with the job.
+    #[serde(skip)]
+    pub imei_console: Option<ConsoleSpec>,
+    /// Console to list the modem's credentials over after programming.
+    pub read_credentials: Option<String>,
+    /// `read_credentials`, parsed with the job.
+    #[serde(skip)]
+    pub credentials_console: Option<ConsoleSpec>,
+    /// Console to read the IMEI and attestation token over after
+    /// programming, for registering the device with a cloud.
+    pub read_identity: Option<String>,
+    /// `read_identity`, parsed with the job.
+    #[serde(skip)]
+    pub identity_console: Option<ConsoleSpec>,
+    /// Image patcher writing the unit's values into the images before they
+    /// are flashed, for identity formats fields cannot express.
+    pub patch: Option<PatchSpec>,
+    /// Golden UICR contents to compare with after the unit data is written.
+    pub uicr_template: Option<PathBuf>,
+    /// `uicr_template`, loaded with the job.
+    #[serde(skip)]
+    pub uicr_golden: Option<UicrTemplate>,
+    /// Identification of `images`, filled in when the job is loaded.
+    #[serde(skip)]
+    pub image_info: Vec<ImageInfo>,
+}
+
+impl JobTarget {
+    /// A target flashing `images` into `chip` with the defaults of a job
+    /// file entry, for jobs put together on the command line.
+    pub fn new(name: &str, chip: &str, images: Vec<ImageFile>) -> Result<Self, RecoveryError> {
+        let image_info = images.iter().map(ImageInfo::load).collect::<Result<_, _>>()?;
+        Ok(JobTarget {
+            name: name.to_string(),
+            chip: chip.to_string(),
+            images: images.iter().map(ImageSpec::from).collect(),
+            image_files: images,
+            elf: None,
+            vendor_id: None,
+            product_id: None,
+            serial: None,
+            force_unlock: false,
+            force_erase: false,
+            force_reflash: false,
+            verify_level: default_verify_level(),
+            rollback_counter: None,
+            slots: None,
+            slot: SlotChoice::default(),
+            detach: DetachMode::default(),
+            stream: false,
+            uicr: Vec::new(),
+            approtect: ApprotectPolicy::default(),
+            secure_approtect: ApprotectPolicy::default(),
+            lock: false,
+            backup: None,
+            on_failure: OnFailure::default(),
+            prompt: None,
+            patch: None,
+            read_imei: None,
+            imei_console: None,
+            read_credentials: None,
+            credentials_console: None,
+            read_identity: None,
+            identity_console: None,
+            uicr_template: None,
+            uicr_golden: None,
+            image_info,
+        })
+    }
+
+    /// What the flow writes to the protection words.
+    pub fn protection(&self) -> Protection {
+        if self.lock {
+            return Protection::LOCKED;
+        }
+        Protection {
+            approtect: self.approtect,
+            secure_approtect: self.secure_approtect,
+        }
+    }
+}
+
+fn default_verify_level() -> VerifyLevel {
+    VerifyLevel::Crc
+}
+
+/// What to do to a target that was programmed, in full or in part, when its
+/// job fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnFailure {
+    /// Leave the target as the job left it.
+    #[default]
+    Leave,
+    /// Mass erase the target.
+    Erase,
+    /// Flash back the target's latest backup from `backup`, which only
+    /// exists if the job erased the target.
+    Restore,
+}
+
+impl fmt::Display for OnFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OnFailure::Leave => "leave",
+            OnFailure::Erase => "erase",
+            OnFailure::Restore => "restore",
+        })
+    }
+}
+
+/// What was done to a target after its job failed.
+#[derive(Debug, Serialize)]
+pub struct Undo {
+    pub action: OnFailure,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TargetReport {
+    pub name: String,
+    pub chip: String,
+    pub serial: Option<String>,
+    /// FICR device ID of nRF91 targets.
+    pub device_id: Option<String>,
+    /// Modem IMEI, for targets with `read_imei`.
+    pub imei: Option<String>,
+    /// Credentials stored in the modem, for targets with
+    /// `read_credentials`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<Vec<Credential>>,
+    /// IMEI, device UUID and attestation token, for targets with
+    /// `read_identity`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<Identity>,
+    /// The target's `on_failure` action, if the job failed and it has one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub undone: Option<Undo>,
+    pub images: Vec<ImageInfo>,
+    /// Escalation steps taken before the final attempt.
+    pub escalations: Vec<Step>,
+    /// UICR protection words of nRF91 targets before and after recovery.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub approtect: Option<ApprotectReport>,
+    /// The flow went on to unlock, erase or program the device.
+    #[serde(skip)]
+    pub written: bool,
+    pub success: bool,
+    /// Stopped at a safe point on request, rather than failed.
+    pub cancelled: bool,
+    pub error: Option<String>,
+    pub duration_ms: u128,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobReport {
+    pub job: PathBuf,
+    #[serde(flatten)]
+    pub station: Station,
+    pub started: String,
+    /// Whether the timestamps can be trusted.
+    pub clock: ClockStatus,
+    pub success: bool,
+    /// Passed, but took longer than the cycle time budget.
+    pub slow: bool,
+    pub duration_ms: u128,
+    pub targets: Vec<TargetReport>,
+}
+
+impl JobReport {
+    /// Result label for the unit: `pass`, `slow-pass`, `cancelled` or
+    /// `fail`.
+    pub fn result(&self) -> &'static str {
+        match (self.success, self.slow) {
+            (false, _) if self.targets.iter().any(|t| t.cancelled) => "cancelled",
+            (false, _) => "fail",
+            (true, true) => "slow-pass",
+            (true, false) => "pass",
+        }
+    }
+}
+
+/// Flag or fail the unit if it took longer than `budget`.
+pub fn apply_budget(report: &mut JobReport, budget: &CycleBudget) {
+    if !report.success {
+        return;
+    }
+    let elapsed = Duration::from_millis(report.duration_ms as u64);
+    match budget.check(elapsed) {
+        Ok(slow) => report.slow = slow,
+        Err(e) => {
+            println!("{}", e);
+            report.success = false;
+        }
+    }
+}
+
+/// Load a job file, fetching its images through `resolver`. Local paths are
+/// relative to the job file.
+pub fn load(path: &Path, resolver: &Resolver) -> Result<Job, RecoveryError> {
+    let mut job: Job = config::load(path)?;
+    let base = path.parent().unwrap_or(Path::new("."));
+
+    if job.targets.is_empty() {
+        return Err(RecoveryError::ConfigError(format!(
+            "{}: no targets defined",
+            path.display()
+        )));
+    }
+
+    job.escalation
+        .validate()
+        .map_err(|e| RecoveryError::ConfigError(format!("{}: {}", path.display(), e)))?;
+
+    if job.parallel && job.targets.iter().any(|t| t.prompt.is_some()) {
+        return Err(RecoveryError::ConfigError(format!(
+            "{}: target prompts need parallel = false",
+            path.display()
+        )));
+    }
+
+    for target in &mut job.targets {
+        target.image_files = target
+            .images
+            .iter()
+            .map(|image| image.resolve(resolver, base))
+            .collect::<Result<_, _>>()?;
+        target.image_info = target
+            .image_files
+            .iter()
+            .map(ImageInfo::load)
+            .collect::<Result<_, _>>()?;
+        if let Some(spec) = &target.read_imei {
+            if !flow::is_nrf91(&target.chip) {
+                return Err(RecoveryError::ConfigError(format!(
+                    "{}: target '{}' has no modem to read an IMEI from",
+                    path.display(),
+                    target.name
+                )));
+            }
+            target.imei_console = Some(
+                spec.parse()
+                    .map_err(|e| RecoveryError::ConfigError(format!("{}: {}", path.display(), e)))?,
+            );
+        }
+        if let Some(spec) = &target.read_credentials {
+            if !flow::is_nrf91(&target.chip) {
+                return Err(RecoveryError::ConfigError(format!(
+                    "{}: target '{}' has no modem to list credentials from",
+                    path.display(),
+                    target.name
+                )));
+            }
+            target.credentials_console = Some(
+                spec.parse()
+                    .map_err(|e| RecoveryError::ConfigError(format!("{}: {}", path.display(), e)))?,
+            );
+        }
+        if let Some(spec) = &target.read_identity {
+            if !flow::is_nrf91(&target.chip) {
+                return Err(RecoveryError::ConfigError(format!(
+                    "{}: target '{}' has no modem to read an identity from",
+                    path.display(),
+                    target.name
+                )));
+            }
+            target.identity_console = Some(
+                spec.parse()
+                    .map_err(|e| RecoveryError::ConfigError(format!("{}: {}", path.display(), e)))?,
+            );
+        }
+        if !target.uicr.is_empty() && !flow::is_nrf91(&target.chip) {
+            return Err(RecoveryError::ConfigError(format!(
+                "{}: target '{}' has no UICR to write",
+                path.display(),
+                target.name
+            )));
+        }
+        if target.protection().enables() {
+            let after = if !flow::is_nrf91(&target.chip) {
+                Some("has no UICR protection words")
+            } else if target.detach == DetachMode::KeepUnlocked {
+                Some("cannot be locked and kept unlocked")
+            } else if target.read_imei.is_some()
+                || target.read_credentials.is_some()
+                || target.read_identity.is_some()
+                || target.uicr_template.is_some()
+            {
+                Some("cannot be read after it is locked; leave out read_imei, read_credentials, read_identity and uicr_template")
+            } else {
+                None
+            };
+            if let Some(problem) = after {
+                return Err(RecoveryError::ConfigError(format!(
+                    "{}: target '{}' {}",
+                    path.display(),
+                    target.name,
+                    problem
+                )));
+            }
+        }
+        for word in &target.uicr {
+            uicr::check_word(word.address).map_err(|e| RecoveryError::ConfigError(format!("{}: {}", path.display(), e)))?;
+        }
+        if target.on_failure == OnFailure::Restore && target.backup.is_none() {
+            return Err(RecoveryError::ConfigError(format!(
+                "{}: target '{}' restores a backup on failure, but sets no backup directory",
+                path.display(),
+                target.name
+            )));
+        }
+        if let Some(patch) = &target.patch {
+            patch.check().map_err(|e| {
+                RecoveryError::ConfigError(format!("{}: target '{}': {}", path.display(), target.name, e))
+            })?;
+        }
+        if let Some(template) = &target.uicr_template {
+            if !flow::is_nrf91(&target.chip) {
+                return Err(RecoveryError::ConfigError(format!(
+                    "{}: target '{}' has no UICR to compare with a template",
+                    path.display(),
+                    target.name
+                )));
+            }
+            target.uicr_golden = Some(UicrTemplate::load(&base.join(template))?);
+        }
+        if let Some(elf) = &mut target.elf {
+            *elf = resolver.resolve(elf, base)?;
+        }
+    }
+
+    for field in &mut job.fields {
+        let name = field.target.as_ref().unwrap_or(&job.targets[0].name);
+        let target = job.targets.iter().find(|t| &t.name == name).ok_or_else(|| {
+            RecoveryError::ConfigError(format!(
+                "{}: field '{}' refers to unknown target '{}'",
+                path.display(),
+                field.column,
+                name
+            ))
+        })?;
+        if !flow::is_nrf91(&target.chip) {
+            return Err(RecoveryError::ConfigError(format!(
+                "{}: field '{}' can only be written to nRF91 targets",
+                path.display(),
+                field.column
+            )));
+        }
+        if target.protection().enables() {
+            return Err(RecoveryError::ConfigError(format!(
+                "{}: field '{}' cannot be written to target '{}', which is locked after flashing",
+                path.display(),
+                field.column,
+                name
+            )));
+        }
+        let symbols = target.elf.as_deref().map(Symbols::load).transpose()?;
+        field.address = field
+            .location
+            .resolve(symbols.as_ref())
+            .map_err(|e| RecoveryError::ConfigError(format!("{}: field '{}': {}", path.display(), field.column, e)))?;
+    }
+
+    Ok(job)
+}
+
+/// The probe of `target`, with `defaults` for what it leaves out.
+fn probe_args(target: &JobTarget, defaults: &ProbeArgs) -> ProbeArgs {
+    ProbeArgs {
+        timeout: defaults.timeout,
+        vendor_id: target.vendor_id.or(defaults.vendor_id),
+        product_id: target.product_id.or(defaults.product_id),
+        probes: defaults.probes.clone(),
+        speed: defaults.speed,
+        serial: target.serial.clone().or_else(|| defaults.serial.clone()),
+        poll_interval: defaults.poll_interval,
+        wait_for_target: defaults.wait_for_target,
+        usb_profile: defaults.usb_profile,
+    }
+}
+
+/// The recovery flow options for `target`.
+fn flow_options(target: &JobTarget, ports: &DebugPorts) -> FlowOptions {
+    FlowOptions {
+        chip: target.chip.clone(),
+        images: target.image_files.clone(),
+        force_unlock: target.force_unlock,
+        force_erase: target.force_erase,
+        force_reflash: target.force_reflash,
+        verify: target.verify_level,
+        rollback: target.rollback_counter,
+        slots: target.slots.map(|layout| SlotOptions {
+            layout,
+            choice: target.slot,
+        }),
+        uicr: target.uicr.iter().map(|word| (word.address, word.value)).collect(),
+        protection: target.protection(),
+        backup: target.backup.clone(),
+        modem: None,
+        detach: target.detach,
+        stream: target.stream,
+        ports: *ports,
+        // Job targets name their chip; a different part is still refused.
+        detect_chip: false,
+        // Job files are written for a fixture; erasing is what they are for.
+        confirm_erase: false,
+        // Parallel targets would draw over each other's bars.
+        progress: false,
+    }
+}
+
+/// Program a single target of the job, writing the unit's fields if given.
+fn run_target(
+    target: &JobTarget,
+    fields: &[&Field],
+    unit: Option<&Unit>,
+    defaults: &ProbeArgs,
+    ports: &DebugPorts,
+    escalation: &Escalation,
+    hooks: &Hooks,
+) -> TargetReport {
+    let start = Instant::now();
+    let probe_args = probe_args(target, defaults);
+    let mut options = flow_options(target, ports);
+
+    let confirmed = match &target.prompt {
+        Some(prompt) => match ask(&tr!("target_prompt", prompt = prompt)) {
+            Some(answer) if answer != "q" => Ok(()),
+            _ => Err(RecoveryError::Cancelled),
+        },
+        None => Ok(()),
+    };
+    let confirmed = confirmed.and_then(|_| {
+        if let Some(patch) = &target.patch {
+            let unit = unit.ok_or_else(|| {
+                RecoveryError::FieldError(format!("target '{}' patches its images with unit values, but there is no unit", target.name))
+            })?;
+            let dir = std::env::temp_dir().join("recovery-patched");
+            options.images = patch.apply(&options.images, unit, &dir, &target.name).map_err(|e| e.at(Stage::Fields))?;
+        }
+        Ok(())
+    });
+
+    let mut device_id = None;
+    let mut imei = None;
+    let mut credentials = None;
+    let mut identity = None;
+    let mut escalations = Vec::new();
+    let mut approtect = ApprotectReport::default();
+    let mut written = false;
+    let result = confirmed.and_then(|_| {
+        escalation.run(&mut escalations, |attempt| {
+            let mut probe = open_probe(&probe_args, ports)?;
+            if attempt.speed_divisor > 1 {
+                let speed = (probe.speed_khz() / attempt.speed_divisor).max(1);
+                probe.set_speed(speed)?;
+                log::info!("Probe speed lowered to {} kHz", speed);
+            }
+            let mut session = flow::run(probe, &options, &mut approtect, &mut written, hooks)?;
+            if flow::is_nrf91(&target.chip) {
+                device_id = device::device_id(&mut session)
+                    .inspect_err(|e| log::warn!("Unable to read device ID: {}", e))
+                    .ok();
+            }
+            if let Some(unit) = unit {
+                write_fields(&mut session, fields, unit).map_err(|e| e.at(Stage::Fields))?;
+            }
+
+            if let Some(spec) = &target.imei_console {
+                let value = imei::read_imei(&mut session, spec, imei::DEFAULT_TIMEOUT)?;
+                println!("{}", tr!("imei", imei = value));
+                imei = Some(value);
+            }
+
+            if let Some(spec) = &target.credentials_console {
+                let listed = credentials::list(&mut session, spec, credentials::DEFAULT_TIMEOUT)?;
+                println!("{}", tr!("credentials_listed", count = listed.len()));
+                credentials = Some(listed);
+            }
+
+            if let Some(spec) = &target.identity_console {
+                let read = identity::read(&mut session, spec, imei::DEFAULT_TIMEOUT)?;
+                println!("{}", tr!("identity", uuid = read.uuid, imei = read.imei));
+                imei.get_or_insert_with(|| read.imei.clone());
+                identity = Some(read);
+            }
+
+            if let Some(template) = &target.uicr_golden {
+                uicr::check(&mut session, template).map_err(|e| e.at(Stage::Uicr))?;
+            }
+
+            // Fields may have re-locked the device; prove it took.
+            if flow::is_nrf91(&target.chip) {
+                let words = approtect::read(&mut session).map_err(|e| e.at(Stage::Detach))?;
+                approtect.after = Some(words);
+                if words.locks(&target.chip) {
+                    let locked = detach::verify_locked(session, &probe_args, &options.ports).map_err(|e| e.at(Stage::Detach))?;
+                    approtect.locked_verified = Some(locked);
+                    if !locked {
+                        return Err(RecoveryError::DetachError(
+                            "UICR enables access port protection, but the device still accepts debug access after a reset".into(),
+                        )
+                        .at(Stage::Detach));
+                    }
+                }
+            }
+            Ok(())
+        })
+    });
+
+    TargetReport {
+        name: target.name.clone(),
+        chip: target.chip.clone(),
+        serial: probe_args.serial,
+        device_id,
+        imei,
+        credentials,
+        identity,
+        undone: None,
+        images: target.image_info.clone(),
+        escalations,
+        approtect: flow::is_nrf91(&target.chip).then_some(approtect),
+        written,
+        success: result.is_ok(),
+        cancelled: result.as_ref().is_err_and(RecoveryError::is_cancelled),
+        error: result.err().map(|e| e.to_string()),
+        duration_ms: start.elapsed().as_millis(),
+    }
+}
+
+/// Fields of the job that are written to `target`.
+fn fields_for<'a>(job: &'a Job, target: &JobTarget) -> Vec<&'a Field> {
+    let first = &job.targets[0].name;
+    job.fields
+        .iter()
+        .filter(|f| f.target.as_ref().unwrap_or(first) == &target.name)
+        .collect()
+}
+
+/// The connection settings a job's targets start from: probe settings for
+/// what a target leaves out, and the debug and access ports.
+#[derive(Clone, Copy)]
+pub struct Defaults<'a> {
+    pub probe: &'a ProbeArgs,
+    pub ports: &'a DebugPorts,
+}
+
+/// Run every target of `job` and collect the results into one report.
+///
+/// When `unit` is given its values are written according to the job's fields.
+/// Targets named in `skip` are left out, and `on_target` is called as soon as
+/// each target finishes.
+pub fn run(
+    path: &Path,
+    job: &Job,
+    unit: Option<&Unit>,
+    skip: &[String],
+    defaults: Defaults<'_>,
+    hooks: &Hooks,
+    on_target: &(dyn Fn(&TargetReport) + Sync),
+) -> JobReport {
+    let Defaults { probe: defaults, ports } = defaults;
+    let started = Utc::now();
+    let start = Instant::now();
+
+    let pending = job.targets.iter().filter(|t| !skip.contains(&t.name));
+
+    let targets: Vec<TargetReport> = if job.parallel {
+        thread::scope(|scope| {
+            let handles: Vec<_> = pending
+                .map(|target| {
+                    let fields = fields_for(job, target);
+                    scope.spawn(move || {
+                        let report = run_target(target, &fields, unit, defaults, ports, &job.escalation, hooks);
+                        on_target(&report);
+                        report
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        })
+    } else {
+        pending
+            .map(|target| {
+                println!("{}", tr!("programming_target", name = target.name, chip = target.chip));
+                let report = run_target(target, &fields_for(job, target), unit, defaults, ports, &job.escalation, hooks);
+                on_target(&report);
+                report
+            })
+            .collect()
+    };
+
+    let mut targets = targets;
+    if targets.iter().any(|t| !t.success) {
+        // A cancelled job still gets cleaned up.
+        cancel::clear();
+        // Last programmed, first undone, as a station would take a board
+        // apart. A target that stopped before anything was written, e.g. at
+        // its prompt, is left as it was.
+        for report in targets.iter_mut().rev().filter(|r| r.written) {
+            let Some(target) = job.targets.iter().find(|t| t.name == report.name) else {
+                continue;
+            };
+            if target.on_failure == OnFailure::Leave {
+                continue;
+            }
+            println!("{}", tr!("undoing_target", name = target.name, action = target.on_failure));
+            let result = undo(target, report.device_id.as_deref(), &probe_args(target, defaults), ports);
+            report.undone = Some(Undo {
+                action: target.on_failure,
+                success: result.is_ok(),
+                error: result.err().map(|e| e.to_string()),
+            });
+        }
+    }
+
+    let clock = ClockStatus {
+        offset_ms: None,
+        suspect: clock::jumped(started, start.elapsed()),
+    };
+    if clock.suspect {
+        eprintln!("{}", tr!("clock_jumped"));
+    }
+
+    JobReport {
+        job: path.to_path_buf(),
+        station: Station::default(),
+        started: started.to_rfc3339(),
+        clock,
+        success: targets.iter().all(|t| t.success),
+        slow: false,
+        duration_ms: start.elapsed().as_millis(),
+        targets,
+    }
+}
+
+/// Apply the `on_failure` action of `target`. `device_id` is the one read
+/// while programming, if it got that far.
+fn undo(target: &JobTarget, device_id: Option<&str>, probe: &ProbeArgs, ports: &DebugPorts) -> Result<(), RecoveryError> {
+    match target.on_failure {
+        OnFailure::Leave => Ok(()),
+        OnFailure::Erase => flow::erase(open_probe(probe, ports)?, &target.chip, ports),
+        OnFailure::Restore => {
+            let device_id = match device_id {
+                Some(id) => id.to_string(),
+                None => {
+                    let mut session = registry::attach(open_probe(probe, ports)?, &target.chip, Permissions::new())?;
+                    device::device_id(&mut session)?
+                }
+            };
+            // Load validation makes sure there is a backup directory.
+            let dir = target.backup.as_deref().unwrap_or(Path::new("."));
+            let backup = backup::latest(dir, &device_id)?;
+            println!("{}", tr!("restoring_backup", path = backup.display()));
+            let options = FlowOptions {
+                images: vec![ImageFile::new(backup, None, None)?],
+                force_unlock: true,
+                rollback: None,
+                slots: None,
+                // The backup holds all of UICR, protection words included.
+                uicr: Vec::new(),
+                protection: Protection {
+                    approtect: ApprotectPolicy::Untouched,
+                    secure_approtect: ApprotectPolicy::Untouched,
+                },
+                backup: None,
+                detach: DetachMode::Normal,
+                ..flow_options(target, ports)
+            };
+            flow::run(open_probe(probe, ports)?, &options, &mut ApprotectReport::default(), &mut false, &Hooks::default()).map(|_| ())
+        }
+    }
+}
+
+/// Print a per-target summary of the report.
+pub fn print_summary(report: &JobReport) {
+    if report.slow {
+        println!("{}", tr!("job_slow_pass", ms = report.duration_ms));
+    }
+    for target in &report.targets {
+        match &target.error {
+            None => println!(
+                "{:<16} {:<16} {} ({} ms)",
+                target.name,
+                target.chip,
+                tr!("target_pass"),
+                target.duration_ms
+            ),
+            Some(e) => println!(
+                "{:<16} {:<16} {} ({} ms): {}",
+                target.name,
+                target.chip,
+                if target.cancelled { tr!("target_cancelled") } else { tr!("target_fail") },
+                target.duration_ms,
+                e
+            ),
+        }
+        match &target.undone {
+            Some(Undo { action, error: None, .. }) => println!("{:<16} {}", "", tr!("target_undone", action = action)),
+            Some(Undo {
+                action,
+                error: Some(e),
+                ..
+            }) => println!("{:<16} {}: {}", "", tr!("target_undo_failed", action = action), e),
+            None => {}
+        }
+    }
+}
+
+/// Write the report as JSON.
+pub fn write_report(path: &Path, report: &JobReport) -> Result<(), RecoveryError> {
+    let json = serde_json::to_string_pretty(report)
+        .map_err(|e| RecoveryError::ReportError(e.to_string()))?;
+    fs::write(path, json)
+        .map_err(|e| RecoveryError::ReportError(format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_run_on_the_cli_ports() {
+        let target = JobTarget::new("board", "nRF9160_xxAA", Vec::new()).unwrap();
+        let ports = DebugPorts {
+            targetsel: Some(0x0100_2A00),
+            mem_ap: 1,
+            eraseall_key: Some(0x1234_5678),
+            ..DebugPorts::default()
+        };
+        let options = flow_options(&target, &ports);
+        assert_eq!(options.ports.eraseall_key, Some(0x1234_5678));
+        assert_eq!(options.ports.targetsel, Some(0x0100_2A00));
+        assert_eq!(options.ports.mem_ap, 1);
+    }
+}
@@ -0,0 +1,784 @@
+//! Recovery of nRF91 devices through a CMSIS-DAP probe: unlock, flash,
+//! write UICR and reset, as done by the `recovery` binary.
+//!
+//! Tools that want to embed the flow build a [`RecoverySession`]:
+//!
+//! ```ignore
+//! let probe = recovery::open_probe(&probe_args, &DebugPorts::default())?;
+//! let recovered = RecoverySession::builder()
+//!     .chip("nRF9151_xxAA")
+//!     .image("merged.hex")
+//!     .probe(probe)
+//!     .build()
+//!     .run()?;
+//! ```
+
+use chrono::Utc;
+use clap::Args as ClapArgs;
+use probe_rs::{
+    architecture::arm::ap::{ApRegister, CSW},
+    probe::{list::Lister, DebugProbeError, DebugProbeSelector, Probe, ProbeCreationError},
+    Session,
+};
+
+use std::thread;
+use std::{path::PathBuf, time::Duration};
+use thiserror::Error;
+
+use approtect::{ApprotectReport, Protection};
+use dap::DebugPorts;
+use detach::DetachMode;
+use flow::FlowOptions;
+use hooks::Hooks;
+use image::ImageFile;
+use output::say;
+use rollback::CounterKind;
+use slot::SlotOptions;
+use verify::VerifyLevel;
+
+pub mod agent;
+pub mod approtect;
+pub mod artifact;
+pub mod backup;
+pub mod batch;
+pub mod blink;
+pub mod board;
+pub mod boot;
+pub mod cancel;
+pub mod clock;
+pub mod config;
+pub mod console;
+pub mod coredump;
+pub mod credentials;
+pub mod cycle;
+pub mod dap;
+pub mod debug;
+pub mod detach;
+pub mod device;
+pub mod dump;
+pub mod erasetimes;
+pub mod escalate;
+pub mod flow;
+pub mod gdb;
+pub mod history;
+pub mod hooks;
+pub mod i18n;
+pub mod identity;
+pub mod image;
+pub mod imei;
+pub mod job;
+pub mod lint;
+pub mod manifest;
+pub mod modem;
+pub mod notify;
+pub mod nvmc;
+pub mod output;
+pub mod patch;
+pub mod paths;
+pub mod plan;
+pub mod plc;
+pub mod poke;
+pub mod power;
+pub mod probe;
+pub mod progress;
+pub mod prompt;
+pub mod provision;
+pub mod recipe;
+pub mod registry;
+pub mod repl;
+pub mod results;
+pub mod rollback;
+pub mod rtt;
+pub mod selfcheck;
+pub mod shell;
+pub mod slot;
+pub mod station;
+pub mod strict;
+pub mod swo;
+pub mod symbols;
+pub mod traffic;
+pub mod uicr;
+pub mod upload;
+pub mod verify;
+pub mod version;
+pub mod wake;
+
+#[derive(ClapArgs, Clone)]
+pub struct ProbeArgs {
+    #[arg(short, long, global = true, default_value_t = 2000, help = "Timeout in milliseconds for probe connection")]
+    pub timeout: u64,
+
+    #[arg(long, global = true, help = "Vendor ID for debug probe; any supported probe if not given")]
+    pub vendor_id: Option<u16>,
+
+    #[arg(long, global = true, help = "Product ID for debug probe; any supported probe if not given")]
+    pub product_id: Option<u16>,
+
+    #[arg(long = "probe", global = true, value_name = "VID:PID", value_delimiter = ',', conflicts_with_all = ["vendor_id", "product_id"], help = "Probe types to look for, in order of preference, e.g. 2e8a:000c,1366:1015; may be repeated")]
+    pub probes: Vec<probe::UsbId>,
+
+    #[arg(short, long, global = true, help = "Serial number of debug probe")]
+    pub serial: Option<String>,
+
+    #[arg(long, global = true, value_name = "MS", default_value_t = 100, help = "Interval in milliseconds between attempts to open the probe")]
+    pub poll_interval: u64,
+
+    #[arg(long, global = true, value_name = "DURATION", value_parser = parse_duration, help = "After opening the probe, keep retrying until a target responds (e.g. 60s)")]
+    pub wait_for_target: Option<Duration>,
+
+    #[arg(long, global = true, value_name = "KHZ", help = "SWD clock in kHz [default: the --usb-profile's]; lowered to 4000 and then 1000 kHz while the target answers WAIT or FAULT")]
+    pub speed: Option<u32>,
+
+    #[arg(long, global = true, value_enum, env = "RECOVERY_USB_PROFILE", default_value_t = probe::UsbProfile::Default, help = "Probe settings for the USB host; slow-host lowers the SWD clock and lengthens timeouts (e.g. Raspberry Pi 3)")]
+    pub usb_profile: probe::UsbProfile,
+}
+
+/// Parse a decimal or `0x` prefixed hexadecimal number.
+pub fn parse_number(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let result = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.replace('_', "").parse::<u64>(),
+    };
+    result.map_err(|e| format!("Invalid number '{}': {}", s, e))
+}
+
+/// Parse a duration such as `500ms`, `45s`, `2m`, `1h` or `30d`. A bare
+/// number is taken as seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("Invalid duration '{}'", s))?;
+    let seconds = match unit {
+        "ms" => return Ok(Duration::from_millis(value)),
+        "" | "s" => value,
+        "m" => value * 60,
+        "h" => value * 60 * 60,
+        "d" => value * 60 * 60 * 24,
+        _ => return Err(format!("Invalid duration unit '{}' in '{}'", unit, s)),
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Parse a number that must fit in a 32-bit word.
+pub fn parse_word(s: &str) -> Result<u32, String> {
+    let value = parse_number(s)?;
+    u32::try_from(value).map_err(|_| format!("Value 0x{:x} does not fit in 32 bits", value))
+}
+
+#[derive(Error, Debug)]
+pub enum RecoveryError {
+    #[error("Programming error {0}")]
+    ProbeError(#[from] probe_rs::Error),
+    #[error("File download error {0}")]
+    FlashingError(#[from] probe_rs::flashing::FileDownloadError),
+    #[error("Flash error {0}")]
+    FlashError(#[from] probe_rs::flashing::FlashError),
+    #[error("No IMEI from the modem; the firmware must answer AT+CGSN on the console")]
+    ImeiError,
+    #[error("Timeout error")]
+    TimeoutError,
+    #[error("No target responded within {0:?}")]
+    TargetTimeout(Duration),
+    #[error("Arm interface error {0}")]
+    ArmError(#[from] probe_rs::architecture::arm::ArmError),
+    #[error("Debug probe error {0}")]
+    DebugProbeError(#[from] probe_rs::probe::DebugProbeError),
+    #[error("Unable to open probe: {source} ({hint})")]
+    ProbeOpenError {
+        source: probe_rs::probe::DebugProbeError,
+        hint: &'static str,
+    },
+    #[error("{0}")]
+    UnlockError(String),
+    #[error("Probe speed error: {0}")]
+    ProbeSpeedError(String),
+    #[error("UICR write needs mass erase")]
+    UicrWriteNeedsMassErase,
+    #[error("File not found: {0}")]
+    FileNotFound(String),
+    #[error("Image error: {0}")]
+    ImageError(String),
+    #[error("Artifact error: {0}")]
+    ArtifactError(String),
+    #[error("Target error: {0}")]
+    TargetError(String),
+    #[error("Verify error: {0}")]
+    VerifyError(String),
+    #[error("Rollback counter error: {0}")]
+    RollbackError(String),
+    #[error("Modem error: {0}")]
+    ModemError(String),
+    #[error("{0}")]
+    PokeError(String),
+    #[error("Symbol error: {0}")]
+    SymbolError(String),
+    #[error("{0}")]
+    DebugError(String),
+    #[error("Boot check failed: {0}")]
+    BootCheckFailed(String),
+    #[error("Coredump error: {0}")]
+    CoredumpError(String),
+    #[error("RTT error {0}")]
+    RttError(#[from] probe_rs::rtt::Error),
+    #[error("{0}")]
+    RttChannelError(String),
+    #[error("Config error: {0}")]
+    ConfigError(String),
+    #[error("Console error: {0}")]
+    ConsoleError(String),
+    #[error("Version error: {0}")]
+    VersionError(String),
+    #[error("Firmware version {actual} does not match expected {expected}")]
+    VersionMismatch {
+        expected: version::ImageVersion,
+        actual: version::ImageVersion,
+    },
+    #[error("{0}")]
+    UicrError(String),
+    #[error("Report error: {0}")]
+    ReportError(String),
+    #[error("Field error: {0}")]
+    FieldError(String),
+    #[error("Batch error: {0}")]
+    BatchError(String),
+    #[error("PLC error: {0}")]
+    PlcError(String),
+    #[error("Slot error: {0}")]
+    SlotError(String),
+    #[error("Agent error: {0}")]
+    AgentError(String),
+    #[error("Backup error: {0}")]
+    BackupError(String),
+    #[error("Dump error: {0}")]
+    DumpError(String),
+    #[error("Detach error: {0}")]
+    DetachError(String),
+    #[error("GDB server error: {0}")]
+    GdbError(String),
+    #[error("Cancelled by operator")]
+    Cancelled,
+    #[error("Cycle time {elapsed:?} exceeded budget of {budget:?}")]
+    CycleTimeExceeded { elapsed: Duration, budget: Duration },
+    #[error("Error {stage}: {source}")]
+    Stage {
+        stage: flow::Stage,
+        source: Box<RecoveryError>,
+    },
+}
+
+impl RecoveryError {
+    /// Tag the error with the recovery stage it happened in. An error
+    /// already tagged keeps its more specific stage.
+    pub fn at(self, stage: flow::Stage) -> Self {
+        if let RecoveryError::Stage { .. } = self {
+            return self;
+        }
+        RecoveryError::Stage {
+            stage,
+            source: Box::new(self),
+        }
+    }
+
+    /// Whether the work was cancelled rather than failed.
+    pub fn is_cancelled(&self) -> bool {
+        match self {
+            RecoveryError::Cancelled => true,
+            RecoveryError::Stage { source, .. } => source.is_cancelled(),
+            _ => false,
+        }
+    }
+}
+
+/// Check whether access port protection blocks the debugger, without
+/// touching the device.
+pub fn check_locked(probe: Probe, ports: &DebugPorts) -> Result<(Probe, bool), RecoveryError> {
+    let mut iface = ports.connect(probe)?;
+    let locked = !ports.debug_enabled(iface.as_mut())?;
+    Ok((dap::disconnect(iface), locked))
+}
+
+/// How the device is reset after ERASEALL to apply the unlock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnlockReset {
+    /// CTRL-AP soft reset, which is enough on nRF91x1.
+    Soft,
+    /// Pin reset through the probe's nRESET line. The nRF9160 only leaves
+    /// the erased state unlocked after a pin reset or power cycle.
+    Pin,
+}
+
+impl UnlockReset {
+    pub fn for_chip(chip: &str) -> Self {
+        if chip.to_ascii_lowercase().starts_with("nrf9160") {
+            UnlockReset::Pin
+        } else {
+            UnlockReset::Soft
+        }
+    }
+}
+
+/// Unlock the device through CTRL-AP if needed. `part` picks the ERASEALL
+/// times to wait by: the detected part, or the chip name when the part is
+/// not known.
+///
+/// Returns the probe and whether the device was mass-erased in the process.
+pub fn try_unlock_device(
+    probe: Probe,
+    force: bool,
+    reset: UnlockReset,
+    ports: &DebugPorts,
+    part: &str,
+) -> Result<(Probe, bool), RecoveryError> {
+    // Attach to unspecified target for raw AP access.
+    let mut iface = ports.connect(probe)?;
+
+    // AP addresses (based on nRF91 docs, CTRL-AP typically at AP4).
+    let app_mem = ports.mem_ap(); // For CSW check.
+    let ctrl_ap = ports.ctrl_ap(); // CTRL-AP for nRF91.
+
+    const ERASEALL: u64 = 0x004;
+    const ERASEALLSTATUS: u64 = 0x008;
+    const RESET: u64 = 0x000;
+
+    // Check if locked
+    let csw = iface.read_raw_ap_register(&app_mem, CSW::ADDRESS)?;
+    let dbg_status = (csw >> 6) & 1;
+    log::info!("CSW: 0x{:x}, DbgStatus: {}", csw, dbg_status);
+    if dbg_status == 1 && !force {
+        say(tr!("already_unlocked"));
+        return Ok((iface.close(), false));
+    }
+
+    // Make sure CTRL-AP is where the ports say before erasing through it.
+    ports.ctrl_ap_idr(iface.as_mut())?;
+    ports.unlock_erase(iface.as_mut())?;
+
+    // Step 1: Erase all through CTRL-AP.
+    iface.write_raw_ap_register(&ctrl_ap, ERASEALL, 1)?;
+    log::info!("Started ERASEALL");
+
+    // Leave the probe quiet for as long as this part's erases have taken,
+    // then poll closely; without statistics, poll at a relaxed pace.
+    let start = std::time::Instant::now();
+    let quiet = erasetimes::quiet_period(part);
+    if !quiet.is_zero() {
+        log::info!("Waiting {:?} before polling ERASEALLSTATUS", quiet);
+        thread::sleep(quiet);
+    }
+    let poll = if quiet.is_zero() { Duration::from_millis(500) } else { Duration::from_millis(100) };
+
+    // Wait for ERASEALLSTATUS = 0 or 15 seconds.
+    loop {
+        let status = iface.read_raw_ap_register(&ctrl_ap, ERASEALLSTATUS)?;
+        if status == 0 {
+            log::info!("Erase completed");
+            erasetimes::record(part, start.elapsed());
+            break;
+        }
+        if start.elapsed() >= Duration::from_secs(15) {
+            log::info!("Erase timeout after 15s");
+            strict::warn(RecoveryError::UnlockError(
+                "ERASEALL did not report completion within 15 s".into(),
+            ))?;
+            break;
+        }
+        thread::sleep(poll);
+    }
+
+    log::info!("Time used to erase: {:?}", start.elapsed());
+
+    // Step 2: Reset (nRF9160: pin reset, nRF91x1: soft reset).
+    thread::sleep(Duration::from_millis(10));
+    let mut iface = match reset {
+        UnlockReset::Soft => {
+            iface.write_raw_ap_register(&ctrl_ap, RESET, 1)?;
+            iface.write_raw_ap_register(&ctrl_ap, RESET, 0)?;
+            thread::sleep(Duration::from_millis(20));
+            log::info!("Issued soft reset for nRF91x1");
+            iface
+        }
+        UnlockReset::Pin => {
+            let mut probe = iface.close();
+            probe.target_reset_assert()?;
+            thread::sleep(Duration::from_millis(10));
+            probe.target_reset_deassert()?;
+            thread::sleep(Duration::from_millis(20));
+            log::info!("Issued pin reset for nRF9160");
+
+            // The debug port loses its state with the reset.
+            let _ = probe.detach();
+            ports.connect(probe)?
+        }
+    };
+
+    let start = std::time::Instant::now();
+
+    loop {
+        // Step 3: Check CSW DbgStatus (bit 6) on AP0.
+        let csw = iface.read_raw_ap_register(&app_mem, CSW::ADDRESS)?;
+        let dbg_status = (csw >> 6) & 1;
+        log::info!("CSW: 0x{:x}, DbgStatus: {}", csw, dbg_status);
+        if dbg_status == 0 && start.elapsed() > Duration::from_secs(1) {
+            return Err(RecoveryError::UnlockError(
+                "Debug status = 0, access port not enabled".into(),
+            ));
+        } else if dbg_status == 1 {
+            break;
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    say(tr!("unlocked"));
+
+    Ok((iface.close(), true))
+}
+
+pub fn write_uicr(
+    session: &mut Session,
+    addr: u64,
+    value: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    uicr::write_bytes(session, addr, &value.to_le_bytes())?;
+    Ok(())
+}
+
+/// Open the debug probe, retrying until the connection timeout expires.
+/// The SWD speed is negotiated, and `--wait-for-target` waits, on the debug
+/// and memory access ports of `ports`.
+pub fn open_probe(args: &ProbeArgs, ports: &DebugPorts) -> Result<Probe, RecoveryError> {
+    let lister = Lister::new();
+    let start = Utc::now().timestamp_millis();
+    let timeout = args.timeout.max(args.usb_profile.min_timeout().as_millis() as u64);
+    let poll_interval = Duration::from_millis(args.poll_interval).max(args.usb_profile.min_poll_interval());
+
+    let probe;
+
+    loop {
+        let opened = match (args.vendor_id, args.product_id) {
+            (Some(vendor_id), Some(product_id)) => lister.open(DebugProbeSelector {
+                vendor_id,
+                product_id,
+                serial_number: args.serial.clone(),
+            }),
+            _ => match probe::candidates(&lister, args).into_iter().next() {
+                Some(info) => {
+                    log::info!("Using {}", info);
+                    lister.open(&info)
+                }
+                None => Err(DebugProbeError::ProbeCouldNotBeCreated(ProbeCreationError::NotFound)),
+            },
+        };
+        probe = match opened {
+            Ok(p) => p,
+            Err(e) => {
+                if let probe::OpenFailure::Permanent(hint) = probe::classify(&e) {
+                    return Err(RecoveryError::ProbeOpenError { source: e, hint });
+                }
+
+                let now = Utc::now().timestamp_millis();
+                if now >= start + timeout as i64 {
+                    log::debug!("Last probe open error: {}", e);
+                    return Err(RecoveryError::TimeoutError);
+                } else {
+                    thread::sleep(poll_interval);
+                    continue;
+                }
+            }
+        };
+
+        break;
+    }
+
+    say(tr!("got_probe"));
+
+    let probe = probe::negotiate_speed(probe, args.speed.unwrap_or(args.usb_profile.swd_khz()), ports)?;
+
+    match args.wait_for_target {
+        Some(timeout) => probe::wait_for_target(probe, ports, timeout, poll_interval),
+        None => Ok(probe),
+    }
+}
+
+/// Marker for a builder setting that has not been given yet.
+pub struct Unset;
+
+/// Builds a [`RecoverySession`]. The probe and chip must be given before
+/// [`build`](RecoverySessionBuilder::build) is available.
+pub struct RecoverySessionBuilder<P, C> {
+    probe: P,
+    chip: C,
+    /// Everything but the chip.
+    options: FlowOptions,
+    hooks: Hooks,
+}
+
+impl<P, C> RecoverySessionBuilder<P, C> {
+    /// Add an image to flash; a plain path is taken as Intel hex. Images
+    /// are flashed in the order added.
+    pub fn image(mut self, image: impl Into<ImageFile>) -> Self {
+        self.options.images.push(image.into());
+        self
+    }
+
+    /// Add several images to flash, in order.
+    pub fn images(mut self, images: impl IntoIterator<Item = ImageFile>) -> Self {
+        self.options.images.extend(images);
+        self
+    }
+
+    /// Run the unlock sequence even if the device appears unlocked.
+    pub fn force_unlock(mut self, force: bool) -> Self {
+        self.options.force_unlock = force;
+        self
+    }
+
+    /// Mass erase the device even if it did not need unlocking.
+    pub fn force_erase(mut self, force: bool) -> Self {
+        self.options.force_erase = force;
+        self
+    }
+
+    /// Program every page instead of skipping the ones that already match.
+    pub fn force_reflash(mut self, force: bool) -> Self {
+        self.options.force_reflash = force;
+        self
+    }
+
+    /// How to check what was programmed. Defaults to pre-verify.
+    pub fn verify(mut self, level: VerifyLevel) -> Self {
+        self.options.verify = level;
+        self
+    }
+
+    /// Raise a downgrade protection counter to the images' version.
+    pub fn rollback_counter(mut self, counter: Option<CounterKind>) -> Self {
+        self.options.rollback = counter;
+        self
+    }
+
+    /// Flash into one of two application slots, picking it from the
+    /// device's state unless told which.
+    pub fn slots(mut self, slots: Option<SlotOptions>) -> Self {
+        self.options.slots = slots;
+        self
+    }
+
+    /// Write these UICR words on nRF91 along with the protection words. A
+    /// word for APPROTECT or SECUREAPPROTECT replaces the value the flow
+    /// would write.
+    pub fn uicr_words(mut self, words: Vec<(u64, u32)>) -> Self {
+        self.options.uicr = words;
+        self
+    }
+
+    /// What to write to APPROTECT and SECUREAPPROTECT on nRF91.
+    /// [`Protection::LOCKED`] leaves the device locked from the reset at
+    /// the end of the flow on.
+    pub fn protection(mut self, protection: Protection) -> Self {
+        self.options.protection = protection;
+        self
+    }
+
+    /// Save flash and UICR to a file in this directory before the device is
+    /// erased, if it can be read.
+    pub fn backup(mut self, dir: Option<PathBuf>) -> Self {
+        self.options.backup = dir;
+        self
+    }
+
+    /// Install a modem firmware package (`mfw_nrf91*.zip`) after the images.
+    pub fn modem(mut self, package: Option<PathBuf>) -> Self {
+        self.options.modem = package;
+        self
+    }
+
+    /// How to leave the device at the end.
+    pub fn detach(mut self, mode: DetachMode) -> Self {
+        self.options.detach = mode;
+        self
+    }
+
+    /// Program images one window at a time instead of loading them whole.
+    pub fn stream(mut self, stream: bool) -> Self {
+        self.options.stream = stream;
+        self
+    }
+
+    /// Debug port and access ports to unlock the device through.
+    pub fn debug_ports(mut self, ports: DebugPorts) -> Self {
+        self.options.ports = ports;
+        self
+    }
+
+    /// Read the part from FICR and use its probe-rs target instead of the
+    /// chip given, which then only applies to devices that cannot be read.
+    pub fn detect_chip(mut self, detect: bool) -> Self {
+        self.options.detect_chip = detect;
+        self
+    }
+
+    /// Ask on the terminal before anything erases the device. Off by
+    /// default, since embedding tools usually have no terminal.
+    pub fn confirm_erase(mut self, confirm: bool) -> Self {
+        self.options.confirm_erase = confirm;
+        self
+    }
+
+    /// Draw erase and flash progress bars on stderr when it is a terminal.
+    /// Off by default, like `confirm_erase`.
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.options.progress = progress;
+        self
+    }
+
+    /// Callbacks for the milestones of the flow.
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+}
+
+impl<C> RecoverySessionBuilder<Unset, C> {
+    /// Probe to recover the device through, e.g. from [`open_probe`].
+    pub fn probe(self, probe: Probe) -> RecoverySessionBuilder<Probe, C> {
+        RecoverySessionBuilder {
+            probe,
+            chip: self.chip,
+            options: self.options,
+            hooks: self.hooks,
+        }
+    }
+}
+
+impl<P> RecoverySessionBuilder<P, Unset> {
+    /// probe-rs target name, e.g. `nRF9151_xxAA`.
+    pub fn chip(self, chip: impl Into<String>) -> RecoverySessionBuilder<P, String> {
+        RecoverySessionBuilder {
+            probe: self.probe,
+            chip: chip.into(),
+            options: self.options,
+            hooks: self.hooks,
+        }
+    }
+}
+
+impl<P> RecoverySessionBuilder<P, String> {
+    /// The options the flow will run with.
+    pub fn options(&self) -> FlowOptions {
+        FlowOptions {
+            chip: self.chip.clone(),
+            ..self.options.clone()
+        }
+    }
+
+    /// What the flow will do to the device, checked against the chip's
+    /// memory map. Available before a probe is opened.
+    pub fn plan(&self) -> Result<plan::Plan, RecoveryError> {
+        plan::Plan::build(&self.options())
+    }
+}
+
+impl RecoverySessionBuilder<Unset, String> {
+    /// Open the probe with `args` and run the flow, running it whole again,
+    /// up to `retries` times, after a failure in any stage. Each retry waits
+    /// [`escalate::backoff`], drops the failed session and opens the probe
+    /// afresh. Cancelled runs are not retried.
+    pub fn run_with_retries(self, args: &ProbeArgs, retries: u32) -> Result<Recovered, RecoveryError> {
+        let options = self.options();
+        let mut retry = 0;
+        loop {
+            let result = open_probe(args, &options.ports).and_then(|probe| {
+                let mut approtect = ApprotectReport::default();
+                let session = flow::run(probe, &options, &mut approtect, &mut false, &self.hooks)?;
+                Ok(Recovered { session, approtect })
+            });
+            let error = match result {
+                Err(e) if retry < retries && !e.is_cancelled() => e,
+                result => return result,
+            };
+            retry += 1;
+            let delay = escalate::backoff(retry);
+            say(tr!(
+                "retrying_flow",
+                retry = retry,
+                retries = retries,
+                delay = format!("{:?}", delay),
+                error = error
+            ));
+            self.hooks.retrying(retry, &error);
+            thread::sleep(delay);
+        }
+    }
+}
+
+impl RecoverySessionBuilder<Probe, String> {
+    pub fn build(self) -> RecoverySession {
+        let options = self.options();
+        RecoverySession {
+            probe: self.probe,
+            options,
+            hooks: self.hooks,
+        }
+    }
+}
+
+/// A device after a successful recovery.
+pub struct Recovered {
+    /// Session attached to the reset device, for post-boot checks.
+    pub session: Session,
+    /// UICR protection words before and after, on nRF91.
+    pub approtect: ApprotectReport,
+}
+
+/// One recovery of one device: unlock, flash, write UICR and reset.
+pub struct RecoverySession {
+    probe: Probe,
+    options: FlowOptions,
+    hooks: Hooks,
+}
+
+impl RecoverySession {
+    pub fn builder() -> RecoverySessionBuilder<Unset, Unset> {
+        RecoverySessionBuilder {
+            probe: Unset,
+            chip: Unset,
+            options: FlowOptions {
+                chip: String::new(),
+                images: Vec::new(),
+                force_unlock: false,
+                force_erase: false,
+                force_reflash: false,
+                verify: VerifyLevel::Preverify,
+                rollback: None,
+                slots: None,
+                uicr: Vec::new(),
+                protection: Protection::default(),
+                backup: None,
+                modem: None,
+                detach: DetachMode::Normal,
+                stream: false,
+                ports: DebugPorts::default(),
+                detect_chip: false,
+                confirm_erase: false,
+                progress: false,
+            },
+            hooks: Hooks::default(),
+        }
+    }
+
+    pub fn options(&self) -> &FlowOptions {
+        &self.options
+    }
+
+    /// Run the flow. Errors are tagged with the stage they happened in.
+    pub fn run(self) -> Result<Recovered, RecoveryError> {
+        let mut approtect = ApprotectReport::default();
+        let session = flow::run(self.probe, &self.options, &mut approtect, &mut false, &self.hooks)?;
+        Ok(Recovered { session, approtect })
+    }
+}
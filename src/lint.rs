@@ -0,0 +1,246 @@
+use serde_json::{Map, Value};
+
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::artifact::Resolver;
+use crate::console::ConsoleSpec;
+use crate::job::{FieldFormat, Job};
+use crate::nvmc::{FLASH_END, FLASH_START};
+use crate::symbols::Symbols;
+use crate::uicr::{UicrTemplate, UICR_END, UICR_START};
+use crate::{config, flow};
+
+/// JSON Schema of job files, published with the source for editors and CI.
+pub const JOB_SCHEMA: &str = include_str!("../schemas/job.schema.json");
+
+/// Check a job file and the files it refers to without touching hardware.
+///
+/// Returns every problem found rather than stopping at the first, each as a
+/// message naming the offending key, target or field.
+pub fn job(path: &Path, resolver: &Resolver) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let value = match fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| config::to_json(&text))
+    {
+        Ok(value) => value,
+        Err(e) => return vec![e],
+    };
+
+    let schema: Value = serde_json::from_str(JOB_SCHEMA).expect("job schema is valid JSON");
+    unknown_keys(&schema, &schema, &value, "", &mut problems);
+
+    let job: Job = match serde_json::from_value(value) {
+        Ok(job) => job,
+        Err(e) => {
+            problems.push(e.to_string());
+            return problems;
+        }
+    };
+    check_job(path, &job, resolver, &mut problems);
+    problems
+}
+
+/// Report keys that `schema` does not allow, recursing into tables and arrays.
+fn unknown_keys(root: &Value, schema: &Value, value: &Value, at: &str, problems: &mut Vec<String>) {
+    let schema = resolve_ref(root, schema);
+    match value {
+        Value::Object(table) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            let closed = schema.get("additionalProperties") == Some(&Value::Bool(false));
+            for (key, item) in table {
+                let path = if at.is_empty() { key.clone() } else { format!("{}.{}", at, key) };
+                match properties.and_then(|p| p.get(key)) {
+                    Some(property) => unknown_keys(root, property, item, &path, problems),
+                    None if closed => problems.push(format!("unknown key '{}'{}", path, suggestion(key, properties))),
+                    None => {}
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    unknown_keys(root, schema, item, &format!("{}[{}]", at, i), problems);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn resolve_ref<'a>(root: &'a Value, schema: &'a Value) -> &'a Value {
+    match schema.get("$ref").and_then(Value::as_str) {
+        Some(reference) => reference
+            .strip_prefix("#/")
+            .map(|pointer| root.pointer(&format!("/{}", pointer)).unwrap_or(schema))
+            .unwrap_or(schema),
+        None => schema,
+    }
+}
+
+/// A known key that differs from `key` only in case, `-` for `_`, or one
+/// letter, as a hint for typos.
+fn suggestion(key: &str, properties: Option<&Map<String, Value>>) -> String {
+    let normal = |s: &str| s.to_lowercase().replace('-', "_");
+    let close = |known: &str| {
+        let (a, b) = (normal(key), normal(known));
+        if a == b {
+            return true;
+        }
+        let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+        let (short, long) = if a.len() <= b.len() { (&a, &b) } else { (&b, &a) };
+        match long.len() - short.len() {
+            0 => short.iter().zip(long.iter()).filter(|(x, y)| x != y).count() == 1,
+            1 => (0..long.len()).any(|i| long[..i] == short[..i] && long[i + 1..] == short[i..]),
+            _ => false,
+        }
+    };
+    properties
+        .into_iter()
+        .flat_map(|p| p.keys())
+        .find(|known| close(known))
+        .map(|known| format!(", did you mean '{}'?", known))
+        .unwrap_or_default()
+}
+
+/// The checks `job::load` makes, plus address ranges and overlaps, all
+/// collected instead of failing on the first.
+fn check_job(path: &Path, job: &Job, resolver: &Resolver, problems: &mut Vec<String>) {
+    let base = path.parent().unwrap_or(Path::new("."));
+
+    if job.targets.is_empty() {
+        problems.push("no targets defined".into());
+        return;
+    }
+    if let Err(e) = job.escalation.validate() {
+        problems.push(e);
+    }
+    if job.parallel && job.targets.iter().any(|t| t.prompt.is_some()) {
+        problems.push("target prompts need parallel = false".into());
+    }
+
+    // Flash ranges covered by each target's images, and its symbols.
+    let mut covered: HashMap<&str, Vec<(String, Range<u64>)>> = HashMap::new();
+    let mut symbols: HashMap<&str, Symbols> = HashMap::new();
+
+    for (i, target) in job.targets.iter().enumerate() {
+        let name = target.name.as_str();
+        if job.targets[..i].iter().any(|t| t.name == target.name) {
+            problems.push(format!("target name '{}' is used more than once", name));
+        }
+        let nrf91 = flow::is_nrf91(&target.chip);
+
+        let mut ranges: Vec<(String, Range<u64>)> = Vec::new();
+        for reference in &target.images {
+            let image = match reference.resolve(resolver, base).and_then(|file| file.load()) {
+                Ok(image) => image,
+                Err(e) => {
+                    problems.push(format!("target '{}': {}", name, e));
+                    continue;
+                }
+            };
+            let label = reference.reference.display().to_string();
+            for segment in &image.segments {
+                let range = segment.address..segment.address + segment.data.len() as u64;
+                if let Some((other, _)) = ranges.iter().find(|(other, r)| *other != label && overlaps(r, &range)) {
+                    problems.push(format!(
+                        "target '{}': images {} and {} overlap at 0x{:08x}",
+                        name, other, label, range.start
+                    ));
+                }
+                if nrf91 && !in_flash_or_uicr(&range) {
+                    problems.push(format!(
+                        "target '{}': {} writes 0x{:08x}..0x{:08x}, outside flash and UICR",
+                        name, label, range.start, range.end
+                    ));
+                }
+                ranges.push((label.clone(), range));
+            }
+        }
+        covered.insert(name, ranges);
+
+        if let Some(elf) = &target.elf {
+            match resolver.resolve(elf, base).and_then(|p| Symbols::load(&p)) {
+                Ok(table) => {
+                    symbols.insert(name, table);
+                }
+                Err(e) => problems.push(format!("target '{}': {}", name, e)),
+            }
+        }
+        if let Some(spec) = &target.read_imei {
+            if !nrf91 {
+                problems.push(format!("target '{}' has no modem to read an IMEI from", name));
+            } else if let Err(e) = spec.parse::<ConsoleSpec>() {
+                problems.push(format!("target '{}': read_imei: {}", name, e));
+            }
+        }
+        if let Some(template) = &target.uicr_template {
+            if !nrf91 {
+                problems.push(format!("target '{}' has no UICR to compare with a template", name));
+            } else if let Err(e) = UicrTemplate::load(&base.join(template)) {
+                problems.push(format!("target '{}': {}", name, e));
+            }
+        }
+    }
+
+    let mut written: Vec<(&str, &str, Range<u64>)> = Vec::new();
+    for field in &job.fields {
+        let column = field.column.as_str();
+        let name = field.target.as_deref().unwrap_or(&job.targets[0].name);
+        let Some(target) = job.targets.iter().find(|t| t.name == name) else {
+            problems.push(format!("field '{}' refers to unknown target '{}'", column, name));
+            continue;
+        };
+        if !flow::is_nrf91(&target.chip) {
+            problems.push(format!("field '{}' can only be written to nRF91 targets", column));
+            continue;
+        }
+        let address = match field.location.resolve(symbols.get(name)) {
+            Ok(address) => address,
+            Err(e) => {
+                problems.push(format!("field '{}': {}", column, e));
+                continue;
+            }
+        };
+        if !address.is_multiple_of(4) {
+            problems.push(format!("field '{}': address 0x{:08x} is not word aligned", column, address));
+        }
+
+        // Strings and hex without a length are only known per unit.
+        let size = match (field.format, field.length) {
+            (FieldFormat::U32, _) => 4,
+            (_, Some(length)) => length.next_multiple_of(4) as u64,
+            (_, None) => 4,
+        };
+        let range = address..address + size;
+        if !in_flash_or_uicr(&range) {
+            problems.push(format!(
+                "field '{}': 0x{:08x}..0x{:08x} is outside flash and UICR",
+                column, range.start, range.end
+            ));
+        }
+        if let Some((image, _)) = covered[name].iter().find(|(_, r)| overlaps(r, &range)) {
+            problems.push(format!(
+                "field '{}' at 0x{:08x} is inside {}, so it will not be erased when the unit data is written",
+                column, address, image
+            ));
+        }
+        if let Some((other, _, _)) = written.iter().find(|(_, t, r)| *t == name && overlaps(r, &range)) {
+            problems.push(format!("fields '{}' and '{}' overlap at 0x{:08x}", other, column, address));
+        }
+        written.push((column, name, range));
+    }
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+fn in_flash_or_uicr(range: &Range<u64>) -> bool {
+    let within = |start: u64, end: u64| range.start >= start && range.end <= end;
+    within(FLASH_START, FLASH_END) || within(UICR_START, UICR_END)
+}
@@ -1,301 +1,1872 @@
-use chrono::Utc;
-use clap::Parser;
-use probe_rs::{
-    architecture::arm::{
-        ap::{ApRegister, CSW, IDR},
-        dp::DpAddress,
-        FullyQualifiedApAddress,
-    },
-    flashing::{self, Format},
-    probe::{list::Lister, DebugProbeSelector, Probe},
-    MemoryInterface, Permissions, Session,
-};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, ValueEnum, CommandFactory, FromArgMatches, Parser, Subcommand};
+use probe_rs::{Permissions, Session};
 
-use log;
-use std::thread;
-use std::{path::PathBuf, time::Duration};
-use thiserror::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use std::{path::{Path, PathBuf}, time::Duration};
+
+use recovery::i18n::tr;
+use recovery::output::say;
+use recovery::{
+    agent, approtect, artifact, batch, blink, board, boot, clock, config, console, coredump, credentials, cycle, dap, debug, detach, device, dump, escalate, flow,
+    gdb, hooks, history, i18n, identity, image, imei, job, lint, manifest, notify, output, paths, plc, poke, power, prompt, recipe, registry, repl, results, rollback, rtt, selfcheck, shell, slot, station, strict, swo, symbols, traffic, uicr,
+    upload, verify, version,
+};
+use recovery::{
+    check_locked, open_probe, parse_duration, parse_number, parse_word, try_unlock_device, ProbeArgs,
+    RecoveryError, RecoverySession, UnlockReset,
+};
+use recovery::image::{FileFormat, ImageFile, ImageSpec};
+use recovery::symbols::{parse_location, Location};
 
 #[derive(Parser)]
 #[command(name = "recovery")]
 #[command(about = "nRF91xx recovery tool")]
 #[command(version)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(help = "Path to the hex file to flash")]
-    image: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    #[arg(short, long, default_value_t = 2000, help = "Timeout in milliseconds for probe connection")]
-    timeout: u64,
+    #[arg(required_unless_present_any = ["recipe", "images"], value_name = "IMAGE", help = "Path or URL of the image to flash: Intel hex, ELF (e.g. zephyr.elf), or a raw .bin as PATH@ADDR")]
+    image: Option<ImageSpec>,
 
-    #[arg(short, long, help = "Force unlock even if device appears unlocked")]
-    force: bool,
+    #[arg(long = "image", value_name = "PATH[@ADDR]", help = "Another image to flash in the same session, after IMAGE (repeatable), e.g. --image mcuboot.hex --image app.bin@0x10000")]
+    images: Vec<ImageSpec>,
 
-    #[arg(long, help = "Vendor ID for debug probe", default_value_t = 0x2e8a)]
-    vendor_id: u16,
+    #[arg(long, value_enum, global = true, help = "Format of the images: elf, hex or bin; detected from the extension or the file's contents if not given")]
+    format: Option<FileFormat>,
 
-    #[arg(long, help = "Product ID for debug probe", default_value_t = 0x000c)]
-    product_id: u16,
+    #[arg(long, value_name = "ADDR", global = true, value_parser = parse_number, help = "Base address of raw .bin images that do not give one as PATH@ADDR, e.g. 0x10000 for an imgtool-signed application")]
+    base_address: Option<u64>,
 
-    #[arg(short, long, help = "Serial number of debug probe")]
-    serial: Option<String>,
-}
+    #[arg(long, value_name = "FILE", help = "Take the image, chip, probe, UICR writes, timeouts and post-flash checks from this TOML recipe; the command line overrides it")]
+    recipe: Option<PathBuf>,
+
+    #[arg(long = "uicr", value_name = "ADDR=VALUE", value_parser = uicr::parse_write, help = "UICR word to write after flashing; ADDR may be a register name such as HFXOSRC or OTP[3] (repeatable)")]
+    uicr_words: Vec<(u64, u32)>,
+
+    #[arg(long, value_name = "FILE", help = "TOML file with [[uicr]] address/value tables to write after flashing, as in a recipe")]
+    uicr_file: Option<PathBuf>,
+
+    /// UICR words to write after flashing: the recipe's, then --uicr-file's,
+    /// then --uicr's.
+    #[arg(skip)]
+    uicr_writes: Vec<(u64, u32)>,
+
+    #[arg(long, value_name = "DIR", global = true, help = "Before erasing a readable device, save its flash and UICR to a timestamped Intel hex file in this directory")]
+    backup: Option<PathBuf>,
+
+    #[arg(long, alias = "force", help = "Run the CTRL-AP unlock (ERASEALL) even if the device appears unlocked; erases flash and UICR")]
+    force_unlock: bool,
+
+    #[arg(long, alias = "clean", help = "Mass erase flash and UICR through probe-rs if the unlock did not already erase the device")]
+    force_erase: bool,
+
+    #[arg(long, help = "Program every page even if it already holds the image contents")]
+    force_reflash: bool,
+
+    #[arg(long, value_enum, default_value_t = verify::VerifyLevel::Preverify, help = "How to check flashed data: none, preverify (skip matching pages), crc (also compare an on-target CRC32) or full (also read everything back)")]
+    verify_level: verify::VerifyLevel,
+
+    #[arg(long, value_enum, value_name = "COUNTER", help = "After flashing, raise this NSIB/MCUboot downgrade protection counter in UICR.OTP to the image's version")]
+    rollback_counter: Option<rollback::CounterKind>,
+
+    #[arg(long, value_name = "A_ADDR,B_ADDR", help = "Base addresses of two equally sized A/B application slots (e.g. NSIB S0/S1); images are flashed into the slot picked by --slot")]
+    slots: Option<slot::SlotLayout>,
+
+    #[arg(long, value_enum, default_value_t = slot::SlotChoice::Auto, requires = "slots", help = "Slot to flash: a, b, or auto for the one the bootloader boots (A if neither holds a valid image)")]
+    slot: slot::SlotChoice,
+
+    #[arg(long, help = "After the device has restarted, read the modem's IMEI with AT+CGSN over --console and print it")]
+    read_imei: bool,
+
+    #[arg(long, help = "After the device has restarted, list the modem's credentials with AT%CMNG=1 over --console and add their sec tags, types and hashes to the report")]
+    read_credentials: bool,
+
+    #[arg(long, help = "After the device has restarted, read the IMEI and the attestation token with its device UUID over --console and add them to the report, for cloud registration")]
+    read_identity: bool,
+
+    #[arg(long, value_name = "FILE", help = "After programming, compare all of UICR with this golden JSON template and fail on any deviation")]
+    uicr_template: Option<PathBuf>,
+
+    #[arg(long, value_name = "ZIP", help = "After flashing, update the modem firmware from this mfw_nrf91*.zip package over SWD")]
+    modem: Option<PathBuf>,
+
+    #[arg(long, help = "Connect, report the device's lock state and print what would be erased and written, region by region, without erasing or writing anything")]
+    dry_run: bool,
+
+    #[arg(long, global = true, help = "Do not draw erase and flash progress bars")]
+    no_progress: bool,
+
+    #[arg(long = "output", value_name = "FORMAT", value_enum, default_value_t = output::OutputFormat::Text, conflicts_with_all = ["dry_run", "keep_alive", "gdb", "rtt", "swo_capture"], help = "Print the result as one JSON object on stdout, with progress messages on stderr")]
+    output_format: output::OutputFormat,
+
+    #[arg(long, value_name = "PATH", num_args = 0..=1, default_missing_value = "run-manifest.json", conflicts_with_all = ["dry_run", "all", "serials"], help = "After the run, write every effective option, artifact hash, the tool version and the device's details to this JSON file; may contain placeholders such as {deviceid}")]
+    manifest: Option<PathBuf>,
+
+    #[arg(long, help = "Program the image in 64 KiB windows instead of loading it whole, for large images on low-memory hosts")]
+    stream: bool,
+
+    #[arg(short, long, help = "Erase without asking for confirmation")]
+    yes: bool,
+
+    #[arg(long, value_name = "N", default_value_t = 0, conflicts_with = "dry_run", help = "After a failure in any stage, drop the session, open the probe again and rerun the whole flow, up to N times, waiting 1 s, 2 s, 4 s... up to 30 s in between")]
+    retries: u32,
+
+    #[arg(long, conflicts_with_all = ["serials", "serial", "dry_run", "keep_alive", "gdb", "rtt", "swo_capture", "blink", "boot_check", "shell_script", "expect_version", "modem", "modem_guard"], help = "Program every connected probe, or those with --vendor-id/--product-id, at once, one thread per probe")]
+    all: bool,
+
+    #[arg(long, value_name = "SERIAL,...", value_delimiter = ',', conflicts_with_all = ["serial", "dry_run", "keep_alive", "gdb", "rtt", "swo_capture", "blink", "boot_check", "shell_script", "expect_version", "modem", "modem_guard"], help = "Program the probes with these serial numbers at once, one thread per probe")]
+    serials: Vec<String>,
+
+    #[arg(long, value_enum, default_value_t = detach::DetachMode::Normal, help = "How to leave nRF91 devices: keep-unlocked checks they stay debuggable for the next tool")]
+    detach: detach::DetachMode,
+
+    #[arg(long, value_enum, default_value_t = approtect::ApprotectPolicy::HwDisabled, help = "What to write to UICR.APPROTECT on nRF91")]
+    approtect: approtect::ApprotectPolicy,
+
+    #[arg(long, value_enum, default_value_t = approtect::ApprotectPolicy::HwDisabled, help = "What to write to UICR.SECUREAPPROTECT on nRF91")]
+    secure_approtect: approtect::ApprotectPolicy,
+
+    #[arg(long, help = "After flashing and verifying, enable APPROTECT and SECUREAPPROTECT so the nRF91 refuses debug access from its next reset; overrides --approtect and --secure-approtect")]
+    lock: bool,
+
+    #[arg(long, value_name = "MS", help = "After reset, let the firmware run for MS milliseconds and fail if the core is faulted or locked up")]
+    boot_check: Option<u64>,
+
+    #[arg(long, value_name = "PATH", requires = "boot_check", help = "Write a Zephyr coredump to PATH when the boot check fails")]
+    coredump: Option<PathBuf>,
+
+    #[arg(long, value_name = "PATH", help = "Run the shell commands in this TOML script after boot and check their responses")]
+    shell_script: Option<PathBuf>,
+
+    #[arg(long, value_name = "VERSION", requires = "version_source", help = "Fail unless the firmware reports this version after reset (e.g. 2.4.1 or 2.4.1+7)")]
+    expect_version: Option<version::ImageVersion>,
+
+    #[arg(long, value_name = "SOURCE", help = "Where to read the firmware version: mcuboot:ADDR or shell:COMMAND")]
+    version_source: Option<version::VersionSource>,
+
+    #[arg(long, global = true, value_enum, help = "Use the chip, probe and console settings of a known board")]
+    board: Option<board::Board>,
+
+    #[arg(long, global = true, value_name = "NAME", help = "probe-rs target name, overriding the board preset (e.g. nRF9160_xxAA, which unlocks with a pin reset) [default: detected from FICR, or nRF9151_xxAA]")]
+    chip: Option<String>,
+
+    #[arg(long, global = true, value_name = "YAML", help = "probe-rs target description (e.g. from target-gen) whose flash algorithm and memory map are used when probe-rs does not know the chip")]
+    flash_algo: Option<PathBuf>,
+
+    #[arg(long, requires = "board", help = "Blink the board's LED after programming to identify it")]
+    blink: bool,
+
+    #[arg(long, global = true, default_value = "rtt", help = "Firmware console for queries: rtt[:N], uart:PORT[:BAUD] or usb:VID:INDEX[:BAUD]")]
+    console: console::ConsoleSpec,
+
+    #[arg(long, global = true, help = "Before erasing or reprogramming a readable nRF91, ask its modem over --console whether it is on the network and make the operator confirm if so")]
+    modem_guard: bool,
+
+    #[arg(long, global = true, value_name = "DURATION", value_parser = parse_duration, help = "Per-unit programming time budget (e.g. 45s); slower units are flagged as slow-pass")]
+    max_cycle_time: Option<Duration>,
+
+    #[arg(long, global = true, value_name = "DURATION", value_parser = parse_duration, help = "Warn when an image's embedded build time is older than this (e.g. 30d)")]
+    max_artifact_age: Option<Duration>,
+
+    #[arg(long, global = true, value_name = "FILE", help = "Append the result of every job target to this JSON lines file")]
+    results: Option<PathBuf>,
+
+    #[arg(long, global = true, value_name = "SCHEME=COMMAND", help = "Fetch images with this URL scheme using a shell command with {url} and {dest} placeholders (repeatable)")]
+    fetch: Vec<String>,
 
-#[derive(Error, Debug)]
-pub enum RecoveryError {
-    #[error("Programming error {0}")]
-    ProbeError(#[from] probe_rs::Error),
-    #[error("File download error {0}")]
-    FlashingError(#[from] probe_rs::flashing::FileDownloadError),
-    #[error("Imei error")]
-    ImeiError,
-    #[error("Timeout error")]
-    TimeoutError,
-    #[error("Arm interface error {0}")]
-    ArmError(#[from] probe_rs::architecture::arm::ArmError),
-    #[error("Debug probe error {0}")]
-    DebugProbeError(#[from] probe_rs::probe::DebugProbeError),
-    #[error("{0}")]
-    UnlockError(String),
-    #[error("UICR write needs mass erase")]
-    UicrWriteNeedsMassErase,
-    #[error("File not found: {0}")]
-    FileNotFound(String),
+    #[arg(long, global = true, value_name = "DIR", help = "Where fetched images are cached [default: <temp>/recovery-artifacts]")]
+    artifact_cache: Option<PathBuf>,
+
+    #[arg(long, global = true, value_name = "MB", help = "Trim the artifact cache to this many megabytes after each download, dropping the least recently used files")]
+    artifact_cache_size: Option<u64>,
+
+    #[arg(long, global = true, value_name = "FILE", help = "ELF of the flashed firmware, to look up symbol:NAME addresses in")]
+    elf: Option<PathBuf>,
+
+    #[arg(long, value_name = "COMMAND", help = "Shell command run on every recovery event (unlocked, flashed, uicr-written, verified, failed), given in RECOVERY_EVENT")]
+    event_command: Option<String>,
+
+    #[arg(long, global = true, value_name = "COMMAND", requires = "results", help = "Shell command run for every results line, given the line on stdin; failed uploads are queued and retried")]
+    upload: Option<String>,
+
+    #[arg(long, global = true, help = "Work without network access: queue uploads and skip the clock check")]
+    offline: bool,
+
+    #[arg(long, global = true, value_enum, default_value_t = batch::OnDuplicate::Fail, help = "In batch runs, what to do when a device that already passed as another unit is programmed again")]
+    on_duplicate_device: batch::OnDuplicate,
+
+    #[arg(long, global = true, requires = "max_cycle_time", help = "Fail units that exceed --max-cycle-time instead of flagging them")]
+    strict_cycle_time: bool,
+
+    #[arg(long, global = true, help = "Fail on any warning, such as an erase that timed out, UICR that reads back wrong or a slower SWD clock")]
+    strict: bool,
+
+    #[arg(long, global = true, value_enum, default_value_t = i18n::Lang::En, help = "Language of operator messages and prompts")]
+    lang: i18n::Lang,
+
+    #[arg(long, global = true, value_name = "KIND", help = "Announce when the run finishes: bell, desktop or command:<cmd>")]
+    notify: Option<notify::Notify>,
+
+    #[arg(long, value_name = "ADDR", conflicts_with = "rtt", help = "Keep the session open after flashing and serve it to GDB on ADDR (e.g. 127.0.0.1:3333)")]
+    keep_alive: Option<String>,
+
+    #[arg(long, value_name = "PORT", conflicts_with_all = ["rtt", "keep_alive"], help = "After a successful flash, serve the session to GDB on localhost:PORT")]
+    gdb: Option<u16>,
+
+    #[arg(long, help = "Stream RTT output after the final reset")]
+    rtt: bool,
+
+    #[arg(long, conflicts_with_all = ["rtt", "keep_alive", "gdb"], help = "Record ITM output over SWO after the final reset, for probes that support it")]
+    swo_capture: bool,
+
+    #[arg(long, value_name = "BAUD", default_value_t = 1_000_000, requires = "swo_capture", help = "SWO baud rate")]
+    swo_baud: u32,
+
+    #[arg(long, value_name = "N", requires = "swo_capture", help = "Only print this ITM stimulus port [default: all]")]
+    swo_port: Option<u8>,
+
+    #[arg(long, value_name = "HZ", requires = "swo_capture", help = "TPIU clock in Hz [default: 32 MHz nRF91 trace clock]")]
+    swo_clock: Option<u32>,
+
+    #[arg(long, value_name = "N", default_value_t = 0, requires = "rtt", help = "RTT up/down channel to use")]
+    rtt_channel: usize,
+
+    #[arg(long, requires = "rtt", help = "Forward stdin to the RTT down channel")]
+    rtt_interactive: bool,
+
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, requires = "rtt", help = "Stop the RTT capture after this long (e.g. 10s); with --rtt-expect, fail if the pattern was not seen by then")]
+    rtt_timeout: Option<Duration>,
+
+    #[arg(long, value_name = "REGEX", value_parser = rtt::parse_pattern, requires = "rtt_timeout", help = "Stop the RTT capture once the output matches this pattern, confirming the firmware booted")]
+    rtt_expect: Option<regex::Regex>,
+
+    #[command(flatten)]
+    probe: ProbeArgs,
+
+    #[command(flatten)]
+    ports: dap::DebugPorts,
+
+    #[command(flatten)]
+    station: station::Station,
+
+    #[command(flatten)]
+    clock: clock::ClockArgs,
 }
 
-fn try_unlock_device(mut probe: Probe, force: bool) -> Result<Probe, RecoveryError> {
-    // Attach to unspecified target for raw AP access.
-    probe.attach_to_unspecified()?;
+impl Args {
+    /// What to write to the protection words; `--lock` enables both.
+    fn protection(&self) -> approtect::Protection {
+        if self.lock {
+            return approtect::Protection::LOCKED;
+        }
+        approtect::Protection {
+            approtect: self.approtect,
+            secure_approtect: self.secure_approtect,
+        }
+    }
 
-    let mut iface = probe
-        .try_into_arm_interface()
-        .map_err(|(_p, e)| RecoveryError::DebugProbeError(e))?
-        .initialize_unspecified(DpAddress::Default)
-        .map_err(|(_p, e)| RecoveryError::ProbeError(e))?;
+    /// Uploader for the results file, if uploads are configured.
+    fn uploader(&self) -> Option<upload::Uploader> {
+        let results = self.results.as_ref()?;
+        Some(upload::Uploader {
+            command: self.upload.clone()?,
+            offline: self.offline,
+            pending: results.with_extension("pending.jsonl"),
+        })
+    }
+
+    /// Flow hooks for the event command, plus a lookup of every device in
+    /// the results file's history.
+    fn hooks(&self) -> Result<hooks::Hooks, RecoveryError> {
+        let hooks = match &self.event_command {
+            Some(command) => hooks::Hooks::command(command),
+            None => hooks::Hooks::default(),
+        };
+        Ok(match &self.results {
+            Some(path) => {
+                let history = history::History::load(path)?;
+                hooks.on_identified(move |device_id| history.print(device_id))
+            }
+            None => hooks,
+        })
+    }
 
-    // AP addresses (based on nRF91 docs, CTRL-AP typically at AP4).
-    const APP_MEM: FullyQualifiedApAddress = FullyQualifiedApAddress::v1_with_default_dp(0); // For CSW check.
-    const CTRL_AP: FullyQualifiedApAddress = FullyQualifiedApAddress::v1_with_default_dp(4); // CTRL-AP for nRF91.
+    /// Artifact sources for the configured fetch commands.
+    fn resolver(&self) -> Result<artifact::Resolver, RecoveryError> {
+        let cache = self
+            .artifact_cache
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("recovery-artifacts"));
+        self.fetch.iter().try_fold(artifact::Resolver::default(), |resolver, def| {
+            let limit = self.artifact_cache_size.map(|mb| mb * 1024 * 1024);
+            let source = artifact::CommandSource::parse(def, &cache, limit).map_err(RecoveryError::ConfigError)?;
+            Ok(resolver.with_source(source))
+        })
+    }
 
-    const ERASEALL: u64 = 0x004;
-    const ERASEALLSTATUS: u64 = 0x008;
-    const RESET: u64 = 0x000;
+    /// Symbols of `--elf`, if given.
+    fn symbols(&self) -> Result<Option<symbols::Symbols>, RecoveryError> {
+        self.elf.as_deref().map(symbols::Symbols::load).transpose()
+    }
 
-    // Check if locked
-    let csw = iface.read_raw_ap_register(&APP_MEM, CSW::ADDRESS)?;
-    let dbg_status = (csw >> 6) & 1;
-    log::info!("CSW: 0x{:x}, DbgStatus: {}", csw, dbg_status);
-    if dbg_status == 1 && !force {
-        println!("Device already unlocked!");
-        return Ok(iface.close());
+    /// Target chip: `--chip`, else the board preset's, else the default.
+    fn chip(&self) -> String {
+        match &self.chip {
+            Some(chip) => chip.clone(),
+            None => self.board.map_or(board::DEFAULT_CHIP, |b| b.preset().chip).to_string(),
+        }
     }
 
-    // Log IDR for debugging.
-    let idr = iface
-        .read_raw_ap_register(&CTRL_AP, IDR::ADDRESS)
-        .unwrap_or(0);
-    log::info!("CTRL-AP IDR: 0x{:x}", idr);
-    if idr == 0 {
-        return Err(RecoveryError::UnlockError(
-            "Invalid CTRL-AP IDR, check AP index".into(),
-        ));
+    /// Whether the run uses a single probe, which is pinned down before
+    /// anything is opened when the VID:PID is left open.
+    fn single_probe(&self) -> bool {
+        match &self.command {
+            Some(
+                Command::Job { .. }
+                | Command::Batch { .. }
+                | Command::Probes
+                | Command::Boards
+                | Command::ExportIdentities { .. }
+                | Command::Agent { stop: true, .. }
+                | Command::Agent { cancel: true, .. },
+            ) => false,
+            Some(_) => true,
+            None => !self.all && self.serials.is_empty(),
+        }
     }
 
-    // Step 1: Erase all through CTRL-AP.
-    iface.write_raw_ap_register(&CTRL_AP, ERASEALL, 1)?;
-    log::info!("Started ERASEALL");
+    /// Fill in the board preset's settings wherever the command line left
+    /// the default.
+    fn apply_board(&mut self, matches: &ArgMatches) {
+        let Some(board) = self.board else { return };
+        let preset = board.preset();
+        let defaulted = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
 
-    // Wait for ERASEALLSTATUS = 0 or 15 seconds.
-    let start = std::time::Instant::now();
-    loop {
-        let status = iface.read_raw_ap_register(&CTRL_AP, ERASEALLSTATUS)?;
-        if status == 0 {
-            log::info!("Erase completed");
-            break;
+        if let Some((vid, pid)) = preset.probe {
+            if defaulted("vendor_id") && defaulted("product_id") && self.probe.probes.is_empty() {
+                self.probe.vendor_id = Some(vid);
+                self.probe.product_id = Some(pid);
+            }
         }
-        if start.elapsed() >= Duration::from_secs(15) {
-            log::info!("Erase timeout after 15s");
-            break;
+        if let Some(console) = preset.console {
+            if defaulted("console") {
+                self.console = console;
+            }
         }
-        thread::sleep(Duration::from_millis(500));
     }
 
-    log::info!("Time used to erase: {:?}", start.elapsed());
+    /// Fill in the recipe's settings wherever the command line left the
+    /// default. Applied after the board preset, so the recipe wins over it.
+    fn apply_recipe(&mut self, recipe: recipe::Recipe, matches: &ArgMatches) {
+        let defaulted = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+
+        self.image = self.image.take().or(recipe.image);
+        self.chip = self.chip.take().or(recipe.chip);
+        if let Some(level) = recipe.verify_level.filter(|_| defaulted("verify_level")) {
+            self.verify_level = level;
+        }
+        self.force_erase |= recipe.force_erase;
+        if let Some(detach) = recipe.detach.filter(|_| defaulted("detach")) {
+            self.detach = detach;
+        }
 
-    // Step 2: Reset (nRF9160: pin reset, nRF91x1: soft reset).
-    // Soft reset for nRF91x1 via CTRL-AP.
-    thread::sleep(Duration::from_millis(10));
-    iface.write_raw_ap_register(&CTRL_AP, RESET, 1)?;
-    iface.write_raw_ap_register(&CTRL_AP, RESET, 0)?;
-    thread::sleep(Duration::from_millis(20));
-    log::info!("Issued soft reset for nRF91x1");
+        let probe = recipe.probe;
+        let typed = !self.probe.probes.is_empty();
+        if let Some(vid) = probe.vendor_id.filter(|_| defaulted("vendor_id") && !typed) {
+            self.probe.vendor_id = Some(vid);
+        }
+        if let Some(pid) = probe.product_id.filter(|_| defaulted("product_id") && !typed) {
+            self.probe.product_id = Some(pid);
+        }
+        if let Some(timeout) = probe.timeout.filter(|_| defaulted("timeout")) {
+            self.probe.timeout = timeout;
+        }
+        self.probe.serial = self.probe.serial.take().or(probe.serial);
+        self.probe.wait_for_target = self.probe.wait_for_target.or(probe.wait_for_target);
 
-    let start = std::time::Instant::now();
+        self.uicr_writes = recipe.uicr.iter().map(|word| (word.address, word.value)).collect();
 
-    loop {
-        // Step 3: Check CSW DbgStatus (bit 6) on AP0.
-        let csw = iface.read_raw_ap_register(&APP_MEM, CSW::ADDRESS)?;
-        let dbg_status = (csw >> 6) & 1;
-        log::info!("CSW: 0x{:x}, DbgStatus: {}", csw, dbg_status);
-        if dbg_status == 0 && start.elapsed() > Duration::from_secs(1) {
-            return Err(RecoveryError::UnlockError(
-                "Debug status = 0, access port not enabled".into(),
-            ));
-        } else if dbg_status == 1 {
-            break;
+        let checks = recipe.checks;
+        self.boot_check = self.boot_check.or(checks.boot);
+        self.shell_script = self.shell_script.take().or(checks.shell_script);
+        if self.expect_version.is_none() {
+            self.expect_version = checks.expect_version;
+            self.version_source = self.version_source.take().or(checks.version_source);
         }
+        self.read_imei |= checks.read_imei;
+        self.uicr_template = self.uicr_template.take().or(checks.uicr_template);
+        self.max_cycle_time = self.max_cycle_time.or(checks.max_cycle_time);
+    }
 
-        thread::sleep(Duration::from_millis(100));
+    fn cycle_budget(&self) -> Option<cycle::CycleBudget> {
+        self.max_cycle_time.map(|max| cycle::CycleBudget {
+            max,
+            strict: self.strict_cycle_time,
+        })
     }
+}
 
-    println!("Unlocked device!");
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Patch individual flash words without a full reflash")]
+    Poke {
+        #[arg(long, required = true, value_name = "ADDR=VALUE", value_parser = parse_assignment, help = "Flash word to patch; ADDR may be symbol:NAME[+OFFSET] (repeatable)")]
+        flash: Vec<(Location, u32)>,
+    },
+    #[command(about = "Unlock the device through CTRL-AP if it is locked (erases it)")]
+    Unlock {
+        #[arg(long, help = "Run the unlock even if the device appears unlocked")]
+        force: bool,
+    },
+    #[command(about = "Mass erase flash and UICR")]
+    Erase {
+        #[arg(long, help = "Erase only UICR on an unlocked nRF91, keeping the application flash, then write the protection words again")]
+        uicr_only: bool,
+    },
+    #[command(about = "Flash an image to an unlocked device and reset it, without unlocking or writing UICR")]
+    Flash {
+        #[arg(required = true, value_name = "IMAGE", help = "Paths or URLs of the images to flash, in order: Intel hex, or a raw .bin as PATH@ADDR")]
+        images: Vec<ImageSpec>,
+    },
+    #[command(about = "Compare flash with images and report the regions that differ, without unlocking or writing anything")]
+    Verify {
+        #[arg(required = true, value_name = "IMAGE", help = "Paths or URLs of the images to compare: Intel hex, ELF, or a raw .bin as PATH@ADDR")]
+        images: Vec<ImageSpec>,
+    },
+    #[command(about = "Show the device's factory information and lock state")]
+    Info,
+    #[command(about = "Only write the UICR protection words, verify them and reset, without flashing")]
+    FlashUicrOnly,
+    #[command(about = "Qualify the tool and the probe on a sacrificial board and print a pass/fail matrix")]
+    SelfCheck {
+        #[arg(long, help = "Also unlock, flash a built-in test image, verify it, write and check UICR, lock and unlock again; leaves the board erased")]
+        destructive: bool,
+    },
+    #[command(about = "List the credentials stored in the modem with AT%CMNG=1 over --console: sec tags, types and SHA-256 hashes, never the content")]
+    Credentials,
+    #[command(about = "Turn the identities in a results file into one file to import into nRF Cloud or another device registry")]
+    ExportIdentities {
+        #[arg(value_name = "RESULTS", help = "Results file of the production run, written with --results")]
+        results: PathBuf,
+
+        #[arg(long, value_enum, default_value_t = identity::ExportFormat::NrfCloud, help = "Registry to write for: nrf-cloud for nRF Cloud's onboarding CSV, json for every identity field")]
+        registry: identity::ExportFormat,
+
+        #[arg(long, value_name = "PATH", help = "File to write")]
+        out: PathBuf,
+    },
+    #[command(about = "List the connected debug probes with their VID:PID, serial number and type, and whether a target responds")]
+    Probes,
+    #[command(about = "Report the device's lock and power state without changing it")]
+    Diagnose {
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "1s", help = "How long to sample the target voltage")]
+        window: Duration,
+    },
+    #[command(about = "Dump target memory to a binary file")]
+    Dump {
+        #[arg(help = "File to write; may contain placeholders such as {deviceid}")]
+        output: PathBuf,
+
+        #[arg(long, value_parser = parse_number, default_value = "0x0", help = "First address to read")]
+        start: u64,
+
+        #[arg(long, value_parser = parse_number, default_value = "0x100000", help = "Number of bytes to read")]
+        length: u64,
+
+        #[arg(long, help = "If the device is locked, mass erase it to get access anyway (destroys the data to be dumped)")]
+        unlock_and_dump_anyway: bool,
+    },
+    #[command(about = "Read or write target memory")]
+    Mem {
+        #[command(subcommand)]
+        op: MemOp,
+
+        #[arg(long, global = true, help = "Halt the core before accessing memory and leave it halted")]
+        halt: bool,
+    },
+    #[command(about = "Read or write UICR registers")]
+    Uicr {
+        #[command(subcommand)]
+        op: UicrOp,
+    },
+    #[command(about = "Read or write core registers")]
+    Reg {
+        #[command(subcommand)]
+        op: RegOp,
+
+        #[arg(long, global = true, help = "Leave the core halted after accessing registers")]
+        halt: bool,
+    },
+    #[command(about = "Halt, resume, single step or reset the core")]
+    Core {
+        #[arg(value_enum, help = "Run control action")]
+        action: debug::CoreAction,
+    },
+    #[command(about = "Program one unit per CSV row using a job file")]
+    Batch {
+        #[arg(help = "CSV file with one row of per-unit data per device")]
+        units: PathBuf,
+
+        #[arg(help = "Path to the job file")]
+        job: PathBuf,
+
+        #[arg(long, value_name = "PATH", help = "Progress file used to resume an interrupted run [default: <UNITS>.state.json]")]
+        state: Option<PathBuf>,
+
+        #[arg(long, value_name = "TEMPLATE", help = "Write a JSON report per unit, e.g. reports/{date}/{unit}.json")]
+        report: Option<PathBuf>,
+
+        #[arg(
+            long,
+            value_name = "LINES",
+            help = "Handshake with the line PLC on host GPIOs instead of prompting: start=N,busy=N,pass=N,fail=N (!N for active low)"
+        )]
+        plc: Option<plc::PlcLines>,
+    },
+    #[command(about = "Run a job file programming one or more targets")]
+    Job {
+        #[command(subcommand)]
+        op: JobOp,
+    },
+    #[command(about = "List the board presets available to --board")]
+    Boards,
+    #[command(about = "Interactive prompt with status, erase, flash, uicr, mem and reset sharing one open session")]
+    Repl,
+    #[command(about = "Keep the probe open and the session attached, serving dev-flash requests")]
+    Agent {
+        #[arg(long, value_name = "ADDR", default_value = agent::DEFAULT_ADDRESS, help = "Address to listen on, or of the agent to stop")]
+        listen: SocketAddr,
+
+        #[arg(long, help = "Stop the agent listening on --listen instead of starting one")]
+        stop: bool,
 
-    Ok(iface.close())
+        #[arg(long, conflicts_with = "stop", help = "Cancel the request the agent on --listen is working on, at its next safe point")]
+        cancel: bool,
+    },
+    #[command(about = "Flash images and reset the device through a running agent")]
+    DevFlash {
+        #[arg(required = true, value_name = "IMAGE", help = "Paths or URLs of the images to flash, in order, as PATH[@ADDR]")]
+        images: Vec<ImageSpec>,
+
+        #[arg(long, value_name = "ADDR", default_value = agent::DEFAULT_ADDRESS, help = "Address of the agent")]
+        agent: SocketAddr,
+    },
 }
 
-pub fn write_uicr(
-    session: &mut Session,
-    addr: u64,
-    value: u32,
-) -> Result<(), Box<dyn std::error::Error>> {
-    const NVMC_CONFIG: u64 = 0x50039504; // NVMC.CONFIG
-    const NVMC_READY: u64 = 0x50039400; // NVMC.READY
+#[derive(Subcommand)]
+enum JobOp {
+    #[command(about = "Program every target in the job file")]
+    Run {
+        #[arg(help = "Path to the job file")]
+        file: PathBuf,
+
+        #[arg(long, value_name = "PATH", help = "Write a JSON report of all targets; may contain placeholders such as {deviceid}")]
+        report: Option<PathBuf>,
+    },
+    #[command(about = "Check job files and the files they refer to, without hardware")]
+    Lint {
+        #[arg(required = true, help = "Job files to check")]
+        files: Vec<PathBuf>,
+    },
+    #[command(about = "Fetch the images of job files into the artifact cache, all at once, ahead of the run")]
+    Prefetch {
+        #[arg(required = true, help = "Job files to fetch the images of")]
+        files: Vec<PathBuf>,
+    },
+    #[command(about = "Print the JSON Schema of job files")]
+    Schema,
+    #[command(about = "Print a job file template for a board with more than one chip")]
+    Template {
+        #[arg(value_enum, help = "Board to print the template for")]
+        board: board::Board,
+    },
+}
 
-    let mut core = session.core(0)?;
+#[derive(Subcommand)]
+enum UicrOp {
+    #[command(about = "Print every UICR word that is not erased")]
+    Read,
+    #[command(about = "Print UICR as a table of named registers, and optionally save it as Intel hex")]
+    Dump {
+        #[arg(long, value_name = "FILE", help = "Also write UICR to this Intel hex file, for a later restore")]
+        out: Option<PathBuf>,
+    },
+    #[command(about = "Write bytes to consecutive UICR words")]
+    Write {
+        #[arg(value_parser = parse_number, help = "Start address, word aligned")]
+        addr: u64,
 
-    // Step 1: Read current value and check if write is possible
-    let current_value = core.read_word_32(addr as u64)?;
-    if (current_value & value) != value && current_value != 0xFFFFFFFF {
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Unable to write",
-        )));
-    }
+        #[arg(value_parser = parse_bytes, help = "Data as hex bytes, e.g. 00112233445566778899aabbccddeeff")]
+        // Spelled out so clap takes it as one value rather than a list.
+        data: std::vec::Vec<u8>,
+    },
+}
 
-    // Step 2: Enable write (NVMC.CONFIG = 1)
-    core.write_word_32(NVMC_CONFIG, 1)?;
+#[derive(Subcommand)]
+enum MemOp {
+    #[command(about = "Read 32-bit words from memory")]
+    Read {
+        #[arg(value_parser = parse_location, help = "Start address, or symbol:NAME[+OFFSET]")]
+        addr: Location,
 
-    // Step 3: Wait for NVMC to be ready
-    loop {
-        let ready = core.read_word_32(NVMC_READY)?;
-        if ready & 0x1 == 1 {
-            break;
-        }
-        std::thread::sleep(Duration::from_millis(1));
-    }
+        #[arg(default_value_t = 1, help = "Number of words to read")]
+        count: usize,
+    },
+    #[command(about = "Write 32-bit words to memory")]
+    Write {
+        #[arg(value_parser = parse_location, help = "Start address, or symbol:NAME[+OFFSET]")]
+        addr: Location,
+
+        #[arg(required = true, value_parser = parse_word, help = "Words to write")]
+        values: Vec<u32>,
+    },
+    #[command(about = "Fail unless a 32-bit word holds the expected value")]
+    Expect {
+        #[arg(value_parser = parse_location, help = "Address, or symbol:NAME[+OFFSET]")]
+        addr: Location,
 
-    // Step 4: Write the value
-    core.write_word_32(addr, value)?;
+        #[arg(value_parser = parse_word, help = "Expected value")]
+        value: u32,
 
-    // Step 5: Wait for NVMC to be ready
-    loop {
-        let ready = core.read_word_32(NVMC_READY)?;
-        if ready & 0x1 == 1 {
-            break;
-        }
-        std::thread::sleep(Duration::from_millis(1));
-    }
+        #[arg(long, value_parser = parse_word, default_value = "0xffffffff", help = "Only compare the bits set in this mask")]
+        mask: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum RegOp {
+    #[command(about = "Read a core register, or all of them")]
+    Read {
+        #[arg(help = "Register name (e.g. R0, SP, PC, XPSR)")]
+        name: Option<String>,
+    },
+    #[command(about = "Write a core register")]
+    Write {
+        #[arg(help = "Register name (e.g. R0, SP, PC, XPSR)")]
+        name: String,
+
+        #[arg(value_parser = parse_word, help = "Value to write")]
+        value: u32,
+    },
+}
 
-    // Step 6: Disable write (NVMC.CONFIG = 0)
-    core.write_word_32(NVMC_CONFIG, 0)?;
+/// Parse hex bytes, with an optional 0x prefix.
+fn parse_bytes(s: &str) -> Result<Vec<u8>, String> {
+    let s = s.trim();
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    hex::decode(digits).map_err(|e| format!("Invalid hex data '{}': {}", s, e))
+}
+
+/// Parse an `ADDR=VALUE` pair.
+fn parse_assignment(s: &str) -> Result<(Location, u32), String> {
+    let (addr, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected ADDR=VALUE, got '{}'", s))?;
+    Ok((parse_location(addr)?, parse_word(value)?))
+}
 
-    // Step 7: Wait for NVMC to be ready
-    loop {
-        let ready = core.read_word_32(NVMC_READY)?;
-        if ready & 0x1 == 1 {
-            break;
+/// Open the probe and attach to an already unlocked device.
+fn open_session(args: &ProbeArgs, ports: &dap::DebugPorts, chip: &str) -> Session {
+    let probe = match open_probe(args, ports) {
+        Ok(p) => p,
+        Err(RecoveryError::TimeoutError) => {
+            eprintln!("{}", tr!("probe_timeout", ms = args.timeout));
+            notify::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            notify::exit(1);
         }
-        std::thread::sleep(Duration::from_millis(1));
+    };
+
+    match registry::attach(probe, chip, Permissions::new()) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Error attaching to device: {:?}", e);
+            notify::exit(1);
+        }
+    }
+}
+
+/// `image` with the `--format` and `--base-address` given for all images.
+fn with_defaults(image: &ImageSpec, format: Option<FileFormat>, base_address: Option<u64>) -> ImageSpec {
+    let format = format.or(image.format);
+    // Only raw images take a base address.
+    let raw = format.unwrap_or_else(|| FileFormat::detect(&image.reference)) == FileFormat::Bin;
+    ImageSpec {
+        format,
+        base: image.base.or(base_address.filter(|_| raw)),
+        ..image.clone()
     }
+}
 
+/// Warn about stale images in a job before anything is flashed.
+fn check_artifacts(job: &job::Job, max_age: Option<Duration>) -> Result<(), RecoveryError> {
+    let Some(max) = max_age else { return Ok(()) };
+    for image in job.targets.iter().flat_map(|t| &t.image_files) {
+        image::check_age(image, max)?;
+    }
     Ok(())
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
-    let args = Args::parse();
-
-    // Validate image file exists
-    if !args.image.exists() {
-        return Err(Box::new(RecoveryError::FileNotFound(
-            args.image.display().to_string(),
-        )));
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    args.apply_board(&matches);
+    if let Some(path) = &args.recipe {
+        let recipe = recipe::Recipe::load(path)?;
+        args.apply_recipe(recipe, &matches);
+    }
+    if let Some(path) = &args.uicr_file {
+        let words = uicr::load_words(path)?;
+        args.uicr_writes.extend(words);
+    }
+    args.uicr_writes.extend(args.uicr_words.clone());
+    i18n::init(args.lang);
+    notify::init(args.notify.clone());
+    strict::init(args.strict);
+    output::init(args.output_format);
+    if let Some(path) = &args.flash_algo {
+        registry::init(path)?;
+    }
+    if args.single_probe() {
+        recovery::probe::pin(&mut args.probe)?;
     }
 
-    let lister = Lister::new();
-    let start = Utc::now().timestamp_millis();
+    let manifest = args.manifest.is_some().then(|| manifest::RunManifest::new(&matches, &args.station));
+    let result = run(args, manifest);
+    notify::finished(result.is_ok());
+    result
+}
 
-    let mut probe;
+fn run(args: Args, mut manifest: Option<manifest::RunManifest>) -> Result<(), Box<dyn std::error::Error>> {
+    let budget = args.cycle_budget();
+    let chip = args.chip();
+    let chip = chip.as_str();
+    let mut uploader = args.uploader();
+    let resolver = args.resolver()?;
+    let hooks = args.hooks()?;
+    let symbols = args.symbols()?;
 
-    loop {
-        probe = match lister.open(DebugProbeSelector {
-            vendor_id: args.vendor_id,
-            product_id: args.product_id,
-            serial_number: args.serial.clone(),
-        }) {
-            Ok(p) => p,
-            Err(_e) => {
-                let now = Utc::now().timestamp_millis();
-                if now >= start + args.timeout as i64 {
-                    eprintln!("Timeout connecting to probe after {}ms", args.timeout);
-                    std::process::exit(1);
+    match args.command {
+        Some(Command::Poke { flash }) => {
+            let flash = flash
+                .iter()
+                .map(|(addr, value)| Ok((addr.resolve(symbols.as_ref())?, *value)))
+                .collect::<Result<Vec<_>, RecoveryError>>()?;
+            let mut session = open_session(&args.probe, &args.ports, chip);
+            if let Err(e) = poke::poke_flash(&mut session, &flash) {
+                eprintln!("Error poking flash: {:?}", e);
+                notify::exit(1);
+            }
+            println!("{}", tr!("done"));
+            return Ok(());
+        }
+        Some(Command::Mem { op, halt }) => {
+            let addr = match &op {
+                MemOp::Read { addr, .. } | MemOp::Write { addr, .. } | MemOp::Expect { addr, .. } => {
+                    addr.resolve(symbols.as_ref())?
+                }
+            };
+            let mut session = open_session(&args.probe, &args.ports, chip);
+            let result = match op {
+                MemOp::Read { count, .. } => {
+                    debug::mem_read(&mut session, addr, count, halt).map(|_| ())
+                }
+                MemOp::Write { values, .. } => debug::mem_write(&mut session, addr, &values, halt),
+                MemOp::Expect { value, mask, .. } => debug::mem_expect(&mut session, addr, value, mask, halt),
+            };
+            if let Err(e) = result {
+                eprintln!("Error accessing memory: {:?}", e);
+                notify::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Unlock { force }) => {
+            match open_probe(&args.probe, &args.ports).and_then(|probe| try_unlock_device(probe, force, UnlockReset::for_chip(chip), &args.ports, chip)) {
+                Ok(_) => println!("{}", tr!("done")),
+                Err(e) => {
+                    eprintln!("Error unlocking device: {:?}", e);
+                    notify::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Erase { uicr_only }) => {
+            let question = if uicr_only { tr!("erase_uicr_confirm") } else { tr!("erase_confirm") };
+            if !prompt::confirm(&question, args.yes) {
+                notify::exit(1);
+            }
+            if args.modem_guard && flow::is_nrf91(chip) {
+                if let Err(e) = traffic::guard(&args.probe, chip, &args.ports, &args.console) {
+                    eprintln!("Error: {}", e);
+                    notify::exit(1);
+                }
+            }
+            let result = open_probe(&args.probe, &args.ports).and_then(|probe| match &args.backup {
+                Some(dir) if flow::is_nrf91(chip) => flow::backup_before_erase(probe, dir, true, &args.ports),
+                _ => Ok(probe),
+            });
+            let result = result.and_then(|probe| {
+                if uicr_only {
+                    flow::erase_uicr(probe, chip, &args.protection(), &args.ports)
                 } else {
-                    thread::sleep(Duration::from_millis(100));
-                    continue;
+                    flow::erase(probe, chip, &args.ports)
                 }
+            });
+            if let Err(e) = result {
+                eprintln!("Error erasing device: {:?}", e);
+                notify::exit(1);
             }
-        };
+            return Ok(());
+        }
+        Some(Command::Flash { images }) => {
+            let images = images
+                .iter()
+                .map(|image| with_defaults(image, args.format, args.base_address).resolve(&resolver, Path::new(".")))
+                .collect::<Result<Vec<_>, _>>()?;
+            let options = RecoverySession::builder()
+                .chip(chip)
+                .images(images)
+                .force_reflash(args.force_reflash)
+                .verify(args.verify_level)
+                .stream(args.stream)
+                .progress(!args.no_progress)
+                .options();
+            let mut session = open_session(&args.probe, &args.ports, chip);
+            let result = flow::flash_images(&mut session, &options, &hooks).and_then(|_| {
+                session
+                    .core(0)
+                    .and_then(|mut core| core.reset())
+                    .map_err(|e| RecoveryError::from(e).at(flow::Stage::Reset))
+            });
+            if let Err(e) = result {
+                eprintln!("Error: {:?}", e);
+                notify::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Verify { images }) => {
+            let images = images
+                .iter()
+                .map(|image| with_defaults(image, args.format, args.base_address).resolve(&resolver, Path::new(".")))
+                .collect::<Result<Vec<_>, _>>()?;
+            let (probe, locked) = match open_probe(&args.probe, &args.ports).and_then(|probe| check_locked(probe, &args.ports)) {
+                Ok(p) => p,
+                Err(e) => {
+                    eprintln!("Error checking device: {:?}", e);
+                    notify::exit(1);
+                }
+            };
+            if locked {
+                eprintln!("{}", tr!("verify_locked"));
+                notify::exit(1);
+            }
+            let mut session = match registry::attach(probe, chip, Permissions::new()) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Error attaching to device: {:?}", e);
+                    notify::exit(1);
+                }
+            };
+            let mut matches = true;
+            for image in &images {
+                match verify::compare(&mut session, image) {
+                    Ok(regions) if regions.is_empty() => println!("{}", tr!("verify_match", image = image)),
+                    Ok(regions) => {
+                        matches = false;
+                        println!("{}", tr!("verify_mismatch", image = image, count = regions.len()));
+                        for region in regions {
+                            println!("  0x{:08x}..0x{:08x} ({} bytes)", region.start, region.end, region.end - region.start);
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Error reading {}: {:?}", image, e);
+                        notify::exit(1);
+                    }
+                }
+            }
+            if !matches {
+                notify::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::SelfCheck { destructive }) => {
+            if !flow::is_nrf91(chip) {
+                return Err(RecoveryError::ConfigError(format!("self-check needs an nRF91, not {}", chip)).into());
+            }
+            if destructive && !prompt::confirm(&tr!("self_check_confirm"), args.yes) {
+                notify::exit(1);
+            }
+            let report = selfcheck::run(&args.probe, chip, &args.ports, destructive);
+            if args.output_format == output::OutputFormat::Json {
+                println!("{}", serde_json::to_string(&report)?);
+            } else {
+                selfcheck::print(&report);
+            }
+            if !report.success {
+                notify::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Info) => {
+            let (probe, locked) = match open_probe(&args.probe, &args.ports).and_then(|probe| check_locked(probe, &args.ports)) {
+                Ok(p) => p,
+                Err(e) => {
+                    eprintln!("Error checking device: {:?}", e);
+                    notify::exit(1);
+                }
+            };
+            println!("Chip:           {}", chip);
+            println!("Locked:         {}", if locked { "yes" } else { "no" });
+            if !locked && flow::is_nrf91(chip) {
+                let result = registry::attach(probe, chip, Permissions::new())
+                    .map_err(RecoveryError::from)
+                    .and_then(|mut session| device::info(&mut session));
+                match result {
+                    Ok(info) => print!("{}", info),
+                    Err(e) => {
+                        eprintln!("Error reading device information: {:?}", e);
+                        notify::exit(1);
+                    }
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::ExportIdentities { results, registry, out }) => {
+            match identity::export(&results, registry, &out) {
+                Ok(count) => say(tr!("identities_exported", count = count, path = out.display())),
+                Err(e) => {
+                    eprintln!("Error exporting identities: {:?}", e);
+                    notify::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Probes) => {
+            let probes = recovery::probe::survey(&args.ports);
+            if probes.is_empty() {
+                println!("{}", tr!("probes_none"));
+                return Ok(());
+            }
+            println!("{:<9}  {:<24}  {:<12}  {:<28}  Target", "VID:PID", "Serial", "Driver", "Probe");
+            for probe in &probes {
+                println!(
+                    "{:04x}:{:04x}  {:<24}  {:<12}  {:<28}  {}",
+                    probe.info.vendor_id,
+                    probe.info.product_id,
+                    probe.info.serial_number.as_deref().unwrap_or("-"),
+                    probe.driver.as_deref().unwrap_or("-"),
+                    probe.info.identifier,
+                    match &probe.target {
+                        Ok(()) => "responds".to_string(),
+                        Err(e) => e.clone(),
+                    }
+                );
+            }
+            return Ok(());
+        }
+        Some(Command::Credentials) => {
+            if !flow::is_nrf91(chip) {
+                eprintln!("Error: {} has no modem", chip);
+                notify::exit(1);
+            }
+            let mut session = open_session(&args.probe, &args.ports, chip);
+            match credentials::list(&mut session, &args.console, credentials::DEFAULT_TIMEOUT) {
+                Ok(listed) => {
+                    println!("{:>10}  {:<29}  SHA-256", "Sec tag", "Type");
+                    for credential in &listed {
+                        println!("{}", credential);
+                    }
+                    println!("{}", tr!("credentials_listed", count = listed.len()));
+                }
+                Err(e) => {
+                    eprintln!("Error listing credentials: {:?}", e);
+                    notify::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Uicr { op: UicrOp::Read }) => {
+            let mut session = open_session(&args.probe, &args.ports, chip);
+            match uicr::read(&mut session) {
+                Ok(words) => {
+                    for (addr, value) in words {
+                        let name = uicr::name(addr).map(|name| format!(" {}", name)).unwrap_or_default();
+                        println!("0x{:08x}: 0x{:08x}{}", addr, value, name);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error reading UICR: {:?}", e);
+                    notify::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Uicr {
+            op: UicrOp::Dump { out },
+        }) => {
+            let mut session = open_session(&args.probe, &args.ports, chip);
+            if let Err(e) = uicr::dump(&mut session, out.as_deref()) {
+                eprintln!("Error reading UICR: {:?}", e);
+                notify::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Uicr {
+            op: UicrOp::Write { addr, data },
+        }) => {
+            let mut session = open_session(&args.probe, &args.ports, chip);
+            if let Err(e) = uicr::write_bytes(&mut session, addr, &data) {
+                eprintln!("Error writing UICR: {:?}", e);
+                notify::exit(1);
+            }
+            println!("Wrote {} bytes to UICR at 0x{:08x}", data.len(), addr);
+            return Ok(());
+        }
+        Some(Command::Reg { op, halt }) => {
+            let mut session = open_session(&args.probe, &args.ports, chip);
+            let result = match op {
+                RegOp::Read { name } => debug::reg_read(&mut session, name.as_deref(), halt),
+                RegOp::Write { name, value } => debug::reg_write(&mut session, &name, value, halt),
+            };
+            if let Err(e) = result {
+                eprintln!("Error accessing registers: {:?}", e);
+                notify::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::FlashUicrOnly) => {
+            let result = open_probe(&args.probe, &args.ports).and_then(|probe| flow::uicr_only(probe, chip, &args.protection(), args.detach, &args.ports));
+            match result {
+                Ok(_) => println!("{}", tr!("done")),
+                Err(RecoveryError::Stage { stage, source }) => {
+                    eprintln!("Error {}: {:?}", stage, source);
+                    notify::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Error: {:?}", e);
+                    notify::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Diagnose { window }) => {
+            let mut probe = match open_probe(&args.probe, &args.ports) {
+                Ok(p) => p,
+                Err(e) => {
+                    eprintln!("Error opening probe: {:?}", e);
+                    notify::exit(1);
+                }
+            };
+            let mut health = power::PowerHealth {
+                vtref: power::sample_vtref(&mut probe, window),
+                ..Default::default()
+            };
+            let (probe, locked) = match check_locked(probe, &args.ports) {
+                Ok(p) => p,
+                Err(e) => {
+                    eprintln!("Error checking device: {:?}", e);
+                    notify::exit(1);
+                }
+            };
+            println!("Locked:         {}", if locked { "yes" } else { "no" });
+            if !locked {
+                let result = registry::attach(probe, chip, Permissions::new())
+                    .map_err(RecoveryError::from)
+                    .and_then(|mut session| health.read_registers(&mut session));
+                if let Err(e) = result {
+                    eprintln!("Error reading power registers: {:?}", e);
+                }
+            }
+            print!("{}", health);
+            return Ok(());
+        }
+        Some(Command::Dump {
+            output,
+            start,
+            length,
+            unlock_and_dump_anyway,
+        }) => {
+            let probe = match open_probe(&args.probe, &args.ports).and_then(|probe| check_locked(probe, &args.ports)) {
+                Ok(p) => p,
+                Err(e) => {
+                    eprintln!("Error checking device: {:?}", e);
+                    notify::exit(1);
+                }
+            };
+            let probe = match probe {
+                (probe, false) => probe,
+                (probe, true) => {
+                    eprintln!("{}", tr!("dump_locked"));
+                    if !unlock_and_dump_anyway {
+                        notify::exit(1);
+                    }
+                    if prompt::ask(&tr!("dump_confirm")).as_deref() != Some("ERASE") {
+                        notify::exit(1);
+                    }
+                    match try_unlock_device(probe, false, UnlockReset::for_chip(chip), &args.ports, chip) {
+                        Ok((probe, _)) => probe,
+                        Err(e) => {
+                            eprintln!("Error unlocking device: {:?}", e);
+                            notify::exit(1);
+                        }
+                    }
+                }
+            };
+            let mut session = match registry::attach(probe, chip, Permissions::new()) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Error attaching to device: {:?}", e);
+                    notify::exit(1);
+                }
+            };
+            let mut vars = paths::PathVars::for_station(&args.station);
+            if flow::is_nrf91(chip) {
+                vars.device_id = device::device_id(&mut session).ok();
+            }
+            let output = paths::expand(&output, &vars)?;
+            if let Err(e) = dump::dump(&mut session, start, length, &output) {
+                eprintln!("Error dumping memory: {:?}", e);
+                notify::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Repl) => {
+            let options = RecoverySession::builder()
+                .chip(chip)
+                .debug_ports(args.ports)
+                .force_reflash(args.force_reflash)
+                .verify(args.verify_level)
+                .stream(args.stream)
+                .progress(!args.no_progress)
+                .options();
+            repl::Repl::new(&args.probe, options, &resolver, symbols.as_ref()).run();
+            return Ok(());
+        }
+        Some(Command::Agent { listen, stop: true, .. }) => {
+            if let Err(e) = agent::send(listen, &agent::Request::Stop) {
+                eprintln!("Error: {}", e);
+                notify::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Agent { listen, cancel: true, .. }) => {
+            if let Err(e) = agent::send(listen, &agent::Request::Cancel) {
+                eprintln!("Error: {}", e);
+                notify::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Agent { listen, .. }) => {
+            let options = RecoverySession::builder()
+                .chip(chip)
+                .debug_ports(args.ports)
+                .force_reflash(args.force_reflash)
+                .verify(args.verify_level)
+                .stream(args.stream)
+                .progress(!args.no_progress)
+                .options();
+            if let Err(e) = agent::Agent::new(&args.probe, options).serve(listen) {
+                eprintln!("Error: {}", e);
+                notify::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::DevFlash { images, agent: address }) => {
+            // The agent may run in another directory.
+            let images = images
+                .iter()
+                .map(|image| {
+                    let file = with_defaults(image, args.format, args.base_address).resolve(&resolver, Path::new("."))?;
+                    let path = std::fs::canonicalize(&file.path)
+                        .map_err(|e| RecoveryError::ImageError(format!("{}: {}", file.path.display(), e)))?;
+                    Ok(ImageFile { path, ..file })
+                })
+                .collect::<Result<Vec<_>, RecoveryError>>()?;
+            match agent::send(address, &agent::Request::Flash { images }) {
+                Ok(()) => println!("{}", tr!("done")),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    notify::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Core { action }) => {
+            let mut session = open_session(&args.probe, &args.ports, chip);
+            if let Err(e) = debug::core_control(&mut session, action) {
+                eprintln!("Error controlling core: {:?}", e);
+                notify::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Batch {
+            units,
+            job: job_file,
+            state,
+            report,
+            plc,
+        }) => {
+            let job = job::load(&job_file, &resolver)?;
+            check_artifacts(&job, args.max_artifact_age)?;
+            let state = state.unwrap_or_else(|| batch::BatchState::path_for(&units));
+            let clock = if args.offline {
+                clock::ClockStatus::default()
+            } else {
+                args.clock.status()
+            };
+            let results = args
+                .results
+                .as_deref()
+                .map(|path| results::ResultsWriter::open(path, &args.station, &clock, uploader.take()))
+                .transpose()?;
+            let plc = plc.as_ref().map(plc::Plc::open).transpose()?;
+            let result = batch::run(
+                &units,
+                &state,
+                &job_file,
+                &job,
+                job::Defaults {
+                    probe: &args.probe,
+                    ports: &args.ports,
+                },
+                budget.as_ref(),
+                batch::Reporting {
+                    results: results.as_ref(),
+                    hooks,
+                    on_duplicate: args.on_duplicate_device,
+                    report: report.as_deref(),
+                    station: &args.station,
+                    plc: plc.as_ref(),
+                },
+            );
+            if let Some(results) = results {
+                results.finish()?;
+            }
+            if let Err(e) = result {
+                eprintln!("Error running batch: {:?}", e);
+                notify::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Job {
+            op: JobOp::Run { file, report },
+        }) => {
+            let job = job::load(&file, &resolver)?;
+            check_artifacts(&job, args.max_artifact_age)?;
+            let clock = if args.offline {
+                clock::ClockStatus::default()
+            } else {
+                args.clock.status()
+            };
+            let results = args
+                .results
+                .as_deref()
+                .map(|path| results::ResultsWriter::open(path, &args.station, &clock, uploader.take()))
+                .transpose()?;
+            let record = |target: &job::TargetReport| {
+                if let Some(results) = &results {
+                    results.record(&file, None, target);
+                }
+            };
+            let mut result = job::run(&file, &job, None, &[], job::Defaults { probe: &args.probe, ports: &args.ports }, &hooks, &record);
+            result.station = args.station.clone();
+            result.clock.merge(&clock);
+            if let Some(results) = results {
+                results.finish()?;
+            }
+            if let Some(budget) = &budget {
+                job::apply_budget(&mut result, budget);
+            }
+            job::print_summary(&result);
+            if let Some(path) = &report {
+                let mut vars = paths::PathVars::for_station(&args.station);
+                vars.device_id = result.targets.iter().find_map(|t| t.device_id.clone());
+                vars.imei = result.targets.iter().find_map(|t| t.imei.clone());
+                job::write_report(&paths::expand(path, &vars)?, &result)?;
+            }
+            if !result.success {
+                notify::exit(1);
+            }
+            println!("{}", tr!("done"));
+            return Ok(());
+        }
+        Some(Command::Job {
+            op: JobOp::Lint { files },
+        }) => {
+            let mut failed = 0;
+            for file in &files {
+                let problems = lint::job(file, &resolver);
+                if problems.is_empty() {
+                    say(tr!("lint_ok", file = file.display()));
+                }
+                for problem in &problems {
+                    eprintln!("{}: {}", file.display(), problem);
+                }
+                failed += usize::from(!problems.is_empty());
+            }
+            if failed > 0 {
+                eprintln!("{}", tr!("lint_failed", failed = failed, total = files.len()));
+                notify::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Job {
+            op: JobOp::Prefetch { files },
+        }) => {
+            let mut references = Vec::new();
+            for file in &files {
+                let job: job::Job = config::load(file)?;
+                let base = file.parent().unwrap_or(Path::new(".")).to_path_buf();
+                for target in &job.targets {
+                    references.extend(target.images.iter().map(|image| (image.reference.clone(), base.clone())));
+                }
+            }
+            let mut failed = 0;
+            for (reference, result) in resolver.prefetch(&references) {
+                match result {
+                    Ok(path) => say(tr!("artifact_prefetched", reference = reference.display(), path = path.display())),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                notify::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Job { op: JobOp::Schema }) => {
+            print!("{}", lint::JOB_SCHEMA);
+            return Ok(());
+        }
+        Some(Command::Job {
+            op: JobOp::Template { board },
+        }) => {
+            match board.job_template() {
+                Some(template) => print!("{}", template),
+                None => {
+                    eprintln!("No job template for {:?}, it has a single chip", board);
+                    notify::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Boards) => {
+            for board in board::Board::value_variants() {
+                let name = board.to_possible_value().unwrap();
+                println!("{}: {}", name.get_name(), name.get_help().unwrap_or_default());
+                print!("{}", board.preset());
+            }
+            return Ok(());
+        }
+        None => {}
+    }
 
-        break;
+    // Required by clap unless a subcommand or a recipe is given.
+    if args.image.is_none() && args.images.is_empty() {
+        return Err(RecoveryError::ConfigError("no image given on the command line or in the recipe".into()).into());
     }
+    let images = args
+        .image
+        .iter()
+        .chain(&args.images)
+        .map(|image| with_defaults(image, args.format, args.base_address).resolve(&resolver, Path::new(".")))
+        .collect::<Result<Vec<_>, _>>()?;
 
-    println!("Got probe!");
+    if let Some(max) = args.max_artifact_age {
+        for image in &images {
+            image::check_age(image, max)?;
+        }
+    }
 
-    let _ = probe.set_speed(12000);
+    // Load the shell script up front so mistakes surface before flashing.
+    let shell_script: Option<shell::ShellScript> = match &args.shell_script {
+        Some(path) => Some(config::load(path)?),
+        None => None,
+    };
 
-    let probe = match try_unlock_device(probe, args.force) {
-        Ok(p) => p,
-        Err(e) => {
-            eprintln!("Error unlocking device: {:?}", e);
-            std::process::exit(1);
+    let uicr_template = args.uicr_template.as_deref().map(uicr::UicrTemplate::load).transpose()?;
+    if let Some(manifest) = &mut manifest {
+        manifest.add_images(&images)?;
+        manifest.add_artifact("recipe", args.recipe.as_deref())?;
+        manifest.add_artifact("uicr_template", args.uicr_template.as_deref())?;
+        manifest.add_artifact("shell_script", args.shell_script.as_deref())?;
+        manifest.add_artifact("modem", args.modem.as_deref())?;
+        manifest.add_artifact("elf", args.elf.as_deref())?;
+    }
+    let report = (args.output_format == output::OutputFormat::Json || manifest.is_some())
+        .then(|| Arc::new(Mutex::new(output::RunReport::new(&args.probe, chip))));
+    if uicr_template.is_some() && !flow::is_nrf91(chip) {
+        return Err(RecoveryError::ConfigError(format!("{} has no UICR to compare with a template", chip)).into());
+    }
+    if args.modem_guard && !flow::is_nrf91(chip) {
+        return Err(RecoveryError::ConfigError(format!("{} has no modem to guard", chip)).into());
+    }
+    if args.backup.is_some() && !flow::is_nrf91(chip) {
+        return Err(RecoveryError::ConfigError(format!("--backup only knows the memory layout of nRF91, not {}", chip)).into());
+    }
+    if !args.uicr_writes.is_empty() && !flow::is_nrf91(chip) {
+        return Err(RecoveryError::ConfigError(format!("{} has no UICR to write the words to", chip)).into());
+    }
+    let protection = args.protection();
+    if protection != approtect::Protection::default() && !flow::is_nrf91(chip) {
+        return Err(RecoveryError::ConfigError(format!("{} has no UICR protection words", chip)).into());
+    }
+    if protection.enables() {
+        if args.detach == detach::DetachMode::KeepUnlocked {
+            return Err(RecoveryError::ConfigError("enabling access port protection contradicts --detach keep-unlocked".into()).into());
+        }
+        let after = [
+            ("--boot-check", args.boot_check.is_some()),
+            ("--shell-script", args.shell_script.is_some()),
+            ("--expect-version", args.expect_version.is_some()),
+            ("--read-imei", args.read_imei),
+            ("--read-credentials", args.read_credentials),
+            ("--read-identity", args.read_identity),
+            ("--uicr-template", args.uicr_template.is_some()),
+            ("--blink", args.blink),
+            ("--keep-alive", args.keep_alive.is_some()),
+            ("--gdb", args.gdb.is_some()),
+            ("--rtt", args.rtt),
+            ("--swo-capture", args.swo_capture),
+        ];
+        if let Some((flag, _)) = after.iter().find(|(_, set)| *set) {
+            return Err(RecoveryError::ConfigError(format!(
+                "{} needs debug access after the flow, which enabling access port protection takes away",
+                flag
+            ))
+            .into());
         }
+    }
+
+    if args.all || !args.serials.is_empty() {
+        return run_all(&args, chip, images, uicr_template, &hooks, uploader, budget.as_ref());
+    }
+
+    let recovery = RecoverySession::builder()
+        .chip(chip)
+        .detect_chip(args.chip.is_none() && args.board.is_none())
+        .debug_ports(args.ports)
+        .images(images)
+        .force_unlock(args.force_unlock)
+        .force_erase(args.force_erase)
+        .force_reflash(args.force_reflash)
+        .verify(args.verify_level)
+        .rollback_counter(args.rollback_counter)
+        .slots(args.slots.map(|layout| slot::SlotOptions {
+            layout,
+            choice: args.slot,
+        }))
+        .uicr_words(args.uicr_writes.clone())
+        .protection(protection)
+        .backup(args.backup.clone())
+        .modem(args.modem.clone())
+        .detach(args.detach)
+        .stream(args.stream)
+        .confirm_erase(!args.yes)
+        .progress(!args.no_progress)
+        .hooks(match &report {
+            Some(report) => output::record(hooks, report),
+            None => hooks,
+        });
+
+    // Check the images against the chip's memory map before touching it.
+    let plan = recovery.plan()?;
+    if args.dry_run {
+        // Without a device the plan still shows what any device would get.
+        let plan = match open_probe(&args.probe, &args.ports) {
+            Ok(probe) => {
+                let options = recovery.options();
+                plan.for_device(&options, flow::inspect(probe, &options)?)
+            }
+            Err(e) => {
+                strict::warn(e)?;
+                say(tr!("dry_run_no_device"));
+                plan
+            }
+        };
+        print!("{}", plan);
+        return Ok(());
+    }
+
+    let outputs = RunOutputs {
+        report: report.as_deref(),
+        manifest: args.manifest.clone().zip(manifest),
+        started: Instant::now(),
     };
+    if args.modem_guard {
+        if let Err(e) = traffic::guard(&args.probe, chip, &args.ports, &args.console) {
+            eprintln!("Error: {}", e);
+            exit_failed(&outputs, &e);
+        }
+    }
+    let cycle_start = Instant::now();
 
-    let mut session = match probe.attach("nRF9151_xxAA", Permissions::new()) {
-        Ok(s) => s,
+    // Probe errors come untagged; flow errors carry their stage.
+    let mut session = match recovery.run_with_retries(&args.probe, args.retries) {
+        Ok(recovered) => recovered.session,
+        Err(e @ RecoveryError::TimeoutError) => {
+            eprintln!("{}", tr!("probe_timeout", ms = args.probe.timeout));
+            exit_failed(&outputs, &e);
+        }
         Err(e) => {
-            eprintln!("Error attaching to device: {:?}", e);
-            std::process::exit(1);
+            match &e {
+                RecoveryError::Stage { stage, source } => eprintln!("Error {}: {:?}", stage, source),
+                e => eprintln!("{}", e),
+            }
+            exit_failed(&outputs, &e);
         }
     };
 
-    println!("Created session!");
+    if let Some(template) = &uicr_template {
+        if let Err(e) = uicr::check(&mut session, template) {
+            eprintln!("Error comparing UICR: {:?}", e);
+            exit_failed(&outputs, &e);
+        }
+    }
+
+    if let Some(wait) = args.boot_check {
+        if let Err(e) = boot::check_boot(&mut session, Duration::from_millis(wait)) {
+            eprintln!("Error checking boot: {:?}", e);
+            if let Some(path) = &args.coredump {
+                if let Err(e) = coredump::capture(&mut session, path) {
+                    eprintln!("Error capturing coredump: {:?}", e);
+                }
+            }
+            exit_failed(&outputs, &e);
+        }
+        say(tr!("boot_check_passed"));
+    }
+
+    if let Some(script) = &shell_script {
+        if let Err(e) = shell::run_script(&mut session, script) {
+            eprintln!("Error running shell script: {:?}", e);
+            exit_failed(&outputs, &e);
+        }
+        say(tr!("shell_script_passed"));
+    }
+
+    if args.read_imei {
+        match imei::read_imei(&mut session, &args.console, imei::DEFAULT_TIMEOUT) {
+            Ok(imei) => {
+                say(tr!("imei", imei = imei));
+                if let Some(report) = outputs.report {
+                    report.lock().unwrap().imei = Some(imei);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error reading IMEI: {}", e);
+                exit_failed(&outputs, &e);
+            }
+        }
+    }
+
+    if args.read_identity {
+        match identity::read(&mut session, &args.console, imei::DEFAULT_TIMEOUT) {
+            Ok(read) => {
+                say(tr!("identity", uuid = read.uuid, imei = read.imei));
+                if let Some(report) = outputs.report {
+                    report.lock().unwrap().identity = Some(read);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error reading device identity: {}", e);
+                exit_failed(&outputs, &e);
+            }
+        }
+    }
+
+    if args.read_credentials {
+        match credentials::list(&mut session, &args.console, credentials::DEFAULT_TIMEOUT) {
+            Ok(listed) => {
+                for credential in &listed {
+                    say(credential);
+                }
+                say(tr!("credentials_listed", count = listed.len()));
+                if let Some(report) = outputs.report {
+                    report.lock().unwrap().credentials = Some(listed);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error listing credentials: {}", e);
+                exit_failed(&outputs, &e);
+            }
+        }
+    }
+
+    if let (Some(expected), Some(source)) = (&args.expect_version, &args.version_source) {
+        match version::check_version(&mut session, source, &args.console, expected) {
+            Ok(actual) => {
+                say(tr!("firmware_version", version = actual));
+                if let Some(report) = outputs.report {
+                    report.lock().unwrap().firmware_version = Some(actual.to_string());
+                }
+            }
+            Err(e) => {
+                eprintln!("Error checking firmware version: {:?}", e);
+                exit_failed(&outputs, &e);
+            }
+        }
+    }
+
+    if let Some(budget) = &budget {
+        match budget.check(cycle_start.elapsed()) {
+            Ok(true) => say(tr!(
+                "slow_pass",
+                elapsed = format!("{:?}", cycle_start.elapsed()),
+                budget = format!("{:?}", budget.max)
+            )),
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                exit_failed(&outputs, &e);
+            }
+        }
+    }
 
-    let mut options = flashing::DownloadOptions::new();
-    options.preverify = true;
+    if args.blink {
+        let led = args.board.and_then(|b| b.preset().led);
+        match led {
+            Some(led) => {
+                if let Err(e) = blink::blink(&mut session, &led) {
+                    eprintln!("Error blinking LED: {:?}", e);
+                }
+            }
+            None => log::warn!("Board has no LED to blink"),
+        }
+    }
 
-    // Flash file to device
-    if let Err(e) = flashing::download_file_with_options(&mut session, &args.image, Format::Hex, options)
-    {
-        eprintln!("Error flashing file: {:?}", e);
-        std::process::exit(1);
+    let gdb_addr = args.keep_alive.clone().or(args.gdb.map(|port| format!("127.0.0.1:{}", port)));
+    if let Some(addr) = &gdb_addr {
+        // The server runs until interrupted, so announce the flash now.
+        notify::finished(true);
+
+        if let Err(e) = gdb::serve(&mut session, addr) {
+            eprintln!("Error serving GDB: {:?}", e);
+            notify::exit(1);
+        }
     }
 
-    println!("Done flashing!");
+    if args.swo_capture {
+        // Capture runs until interrupted, so announce the flash now.
+        notify::finished(true);
 
-    if let Err(e) = write_uicr(&mut session, 0x00FF8000, 0x50FA50FA) {
-        eprintln!("Error writing UICR: {:?}", e);
-        std::process::exit(1);
+        let options = swo::SwoOptions {
+            baud: args.swo_baud,
+            port: args.swo_port,
+            tpiu_clock: args.swo_clock,
+        };
+        if let Err(e) = swo::capture(&mut session, flow::is_nrf91(chip), &options) {
+            eprintln!("Error capturing SWO: {:?}", e);
+            notify::exit(1);
+        }
     }
 
-    if let Err(e) = write_uicr(&mut session, 0x00FF802C, 0x50FA50FA) {
-        eprintln!("Error writing UICR: {:?}", e);
-        std::process::exit(1);
+    if args.rtt {
+        let options = rtt::RttOptions {
+            channel: args.rtt_channel,
+            interactive: args.rtt_interactive,
+            timeout: args.rtt_timeout,
+            expect: args.rtt_expect.clone(),
+        };
+        if options.timeout.is_none() {
+            // Capture runs until interrupted, so announce the flash now.
+            notify::finished(true);
+
+            if let Err(e) = rtt::capture(&mut session, &options) {
+                eprintln!("Error capturing RTT: {:?}", e);
+                notify::exit(1);
+            }
+        } else if let Err(e) = rtt::capture(&mut session, &options) {
+            eprintln!("Error capturing RTT: {:?}", e);
+            exit_failed(&outputs, &e);
+        }
     }
 
-    // Reset with probe_rs
-    session.core(0)?.reset()?;
+    if protection.enables() {
+        // The flow's reset put the protection words into effect; prove it.
+        let result = detach::verify_locked(session, &args.probe, &args.ports).and_then(|locked| {
+            if locked {
+                Ok(())
+            } else {
+                Err(RecoveryError::DetachError(
+                    "UICR enables access port protection, but the device still accepts debug access after a reset".into(),
+                ))
+            }
+        });
+        if let Err(e) = result {
+            eprintln!("Error locking device: {:?}", e);
+            exit_failed(&outputs, &e);
+        }
+    }
 
-    println!("Done!");
+    say(tr!("done"));
+    outputs.finish(None)?;
     Ok(())
 }
+
+/// Program every probe of `--all` or `--serials` at the same time, as a
+/// parallel job with one target per probe named after its serial number.
+fn run_all(
+    args: &Args,
+    chip: &str,
+    images: Vec<ImageFile>,
+    uicr_template: Option<uicr::UicrTemplate>,
+    hooks: &hooks::Hooks,
+    uploader: Option<upload::Uploader>,
+    budget: Option<&cycle::CycleBudget>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let serials = if args.all {
+        recovery::probe::serials(&args.probe)
+    } else {
+        args.serials.clone()
+    };
+    if serials.is_empty() {
+        return Err(RecoveryError::ConfigError(format!(
+            "no probes found{}",
+            match (args.probe.vendor_id, args.probe.product_id) {
+                (Some(vid), Some(pid)) => format!(" with VID:PID {:04x}:{:04x}", vid, pid),
+                (Some(vid), None) => format!(" with VID {:04x}", vid),
+                (None, Some(pid)) => format!(" with PID {:04x}", pid),
+                (None, None) if !args.probe.probes.is_empty() => format!(
+                    " of types {}",
+                    args.probe.probes.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(", ")
+                ),
+                (None, None) => String::new(),
+            }
+        ))
+        .into());
+    }
+    if (args.read_imei || args.read_credentials || args.read_identity) && !matches!(args.console, console::ConsoleSpec::Rtt(_)) {
+        return Err(RecoveryError::ConfigError("every device needs its own console; use --console rtt with --all".into()).into());
+    }
+    say(tr!("programming_probes", count = serials.len()));
+
+    let mut targets = Vec::new();
+    for serial in &serials {
+        let mut target = job::JobTarget::new(serial, chip, images.clone())?;
+        target.serial = Some(serial.clone());
+        target.force_unlock = args.force_unlock;
+        target.force_erase = args.force_erase;
+        target.force_reflash = args.force_reflash;
+        target.verify_level = args.verify_level;
+        target.rollback_counter = args.rollback_counter;
+        target.slots = args.slots;
+        target.slot = args.slot;
+        target.detach = args.detach;
+        target.stream = args.stream;
+        target.uicr = args
+            .uicr_writes
+            .iter()
+            .map(|(address, value)| recipe::UicrWord {
+                address: *address,
+                value: *value,
+            })
+            .collect();
+        target.approtect = args.approtect;
+        target.secure_approtect = args.secure_approtect;
+        target.lock = args.lock;
+        target.backup = args.backup.clone();
+        target.imei_console = args.read_imei.then(|| args.console.clone());
+        target.credentials_console = args.read_credentials.then(|| args.console.clone());
+        target.identity_console = args.read_identity.then(|| args.console.clone());
+        target.uicr_golden = uicr_template.clone();
+        targets.push(target);
+    }
+    let job = job::Job {
+        parallel: true,
+        targets,
+        fields: Vec::new(),
+        // Each probe retries on its own; a job ladder has no backoff, so
+        // reopening stands in for it.
+        escalation: escalate::Escalation {
+            steps: vec![escalate::Step::Reopen; args.retries as usize],
+            power_cycle: None,
+        },
+    };
+
+    let clock = if args.offline {
+        clock::ClockStatus::default()
+    } else {
+        args.clock.status()
+    };
+    let results = args
+        .results
+        .as_deref()
+        .map(|path| results::ResultsWriter::open(path, &args.station, &clock, uploader))
+        .transpose()?;
+    // Results name the (first) image where a job run names the job file.
+    let source = &images[0].path;
+    let record = |target: &job::TargetReport| {
+        if let Some(results) = &results {
+            results.record(source, None, target);
+        }
+    };
+    let mut result = job::run(source, &job, None, &[], job::Defaults { probe: &args.probe, ports: &args.ports }, hooks, &record);
+    result.station = args.station.clone();
+    result.clock.merge(&clock);
+    if let Some(results) = results {
+        results.finish()?;
+    }
+    if let Some(budget) = budget {
+        job::apply_budget(&mut result, budget);
+    }
+
+    if args.output_format == output::OutputFormat::Json {
+        println!("{}", serde_json::to_string(&result)?);
+    } else {
+        job::print_summary(&result);
+    }
+    if !result.success {
+        notify::exit(1);
+    }
+    say(tr!("done"));
+    Ok(())
+}
+
+/// Where the result of a recovery run goes besides the terminal: the JSON
+/// report on stdout and the run manifest.
+struct RunOutputs<'a> {
+    report: Option<&'a Mutex<output::RunReport>>,
+    /// Path template and the manifest started before the run.
+    manifest: Option<(PathBuf, manifest::RunManifest)>,
+    started: Instant,
+}
+
+impl RunOutputs<'_> {
+    /// Record the outcome, print the JSON report and write the manifest.
+    fn finish(&self, error: Option<&RecoveryError>) -> Result<(), RecoveryError> {
+        let Some(report) = self.report else { return Ok(()) };
+        let mut report = report.lock().unwrap();
+        match error {
+            Some(e) => report.fail(e),
+            None => report.success = true,
+        }
+        report.duration_ms = self.started.elapsed().as_millis();
+        if output::json() {
+            output::print(&report);
+        }
+        match &self.manifest {
+            Some((path, manifest)) => manifest.clone().write(path, report.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Report a failed run to the outputs that are kept, and exit.
+fn exit_failed(outputs: &RunOutputs, error: &RecoveryError) -> ! {
+    if let Err(e) = outputs.finish(Some(error)) {
+        eprintln!("Error: {}", e);
+    }
+    notify::exit(1)
+}
@@ -0,0 +1,133 @@
+use chrono::Utc;
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::image::{ImageFile, ImageInfo};
+use crate::output::RunReport;
+use crate::paths::{self, PathVars};
+use crate::station::Station;
+use crate::RecoveryError;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Tool {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
+/// An option as the run saw it, and where its value came from.
+#[derive(Clone, Debug, Serialize)]
+pub struct OptionValue {
+    pub values: Vec<String>,
+    /// `command-line`, `env` or `default`.
+    pub source: &'static str,
+}
+
+/// A file that went into the run besides the images.
+#[derive(Clone, Debug, Serialize)]
+pub struct Artifact {
+    /// What the file was used as, e.g. `recipe` or `uicr_template`.
+    pub role: &'static str,
+    pub path: String,
+    pub sha256: String,
+}
+
+/// Everything needed to repeat a programming run: the tool version, every
+/// option with its source, hashes of all input files, and what the run did
+/// to which device. Written by `--manifest` for device history records.
+#[derive(Clone, Debug, Serialize)]
+pub struct RunManifest {
+    pub tool: Tool,
+    pub started: String,
+    pub finished: String,
+    #[serde(flatten)]
+    pub station: Station,
+    pub command_line: Vec<String>,
+    pub options: BTreeMap<String, OptionValue>,
+    pub images: Vec<ImageInfo>,
+    pub artifacts: Vec<Artifact>,
+    pub run: RunReport,
+}
+
+impl RunManifest {
+    /// Start a manifest for a run with the options in `matches`, before
+    /// anything is flashed.
+    pub fn new(matches: &ArgMatches, station: &Station) -> Self {
+        RunManifest {
+            tool: Tool {
+                name: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+            },
+            started: Utc::now().to_rfc3339(),
+            finished: String::new(),
+            station: station.clone(),
+            command_line: std::env::args().collect(),
+            options: options(matches),
+            images: Vec::new(),
+            artifacts: Vec::new(),
+            run: RunReport::default(),
+        }
+    }
+
+    pub fn add_images(&mut self, images: &[ImageFile]) -> Result<(), RecoveryError> {
+        for image in images {
+            self.images.push(ImageInfo::load(image)?);
+        }
+        Ok(())
+    }
+
+    /// Record `path` with its hash. Files that are not given are skipped.
+    pub fn add_artifact(&mut self, role: &'static str, path: Option<&Path>) -> Result<(), RecoveryError> {
+        let Some(path) = path else { return Ok(()) };
+        let bytes = fs::read(path).map_err(|e| RecoveryError::ReportError(format!("{}: {}", path.display(), e)))?;
+        self.artifacts.push(Artifact {
+            role,
+            path: path.display().to_string(),
+            sha256: hex::encode(Sha256::digest(&bytes)),
+        });
+        Ok(())
+    }
+
+    /// Complete the manifest with the run's result and write it to
+    /// `template`, which may contain path placeholders.
+    pub fn write(mut self, template: &Path, run: RunReport) -> Result<(), RecoveryError> {
+        let vars = PathVars {
+            device_id: run.device_id.clone(),
+            imei: run.imei.clone(),
+            ..PathVars::for_station(&self.station)
+        };
+        self.finished = Utc::now().to_rfc3339();
+        self.run = run;
+
+        let path = paths::expand(template, &vars)?;
+        let json = serde_json::to_string_pretty(&self).map_err(|e| RecoveryError::ReportError(e.to_string()))?;
+        fs::write(&path, json).map_err(|e| RecoveryError::ReportError(format!("{}: {}", path.display(), e)))
+    }
+}
+
+/// Every top-level option that has a value, with its source.
+fn options(matches: &ArgMatches) -> BTreeMap<String, OptionValue> {
+    let mut options = BTreeMap::new();
+    for id in matches.ids() {
+        // Argument groups have no values of their own.
+        let Ok(Some(raw)) = matches.try_get_raw(id.as_str()) else { continue };
+        let source = match matches.value_source(id.as_str()) {
+            Some(ValueSource::CommandLine) => "command-line",
+            Some(ValueSource::EnvVariable) => "env",
+            _ => "default",
+        };
+        options.insert(
+            id.to_string(),
+            OptionValue {
+                values: raw.map(|value| value.to_string_lossy().into_owned()).collect(),
+                source,
+            },
+        );
+    }
+    options
+}
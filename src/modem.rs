@@ -0,0 +1,283 @@
+use flate2::read::DeflateDecoder;
+use probe_rs::{Core, MemoryInterface, Session};
+use sha2::{Digest, Sha256};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::i18n::tr;
+use crate::output::say;
+use crate::image::Image;
+use crate::RecoveryError;
+
+const IPC: u64 = 0x5002A000;
+const IPC_TASKS_SEND0: u64 = IPC;
+const IPC_EVENTS_RECEIVE1: u64 = IPC + 0x104;
+const IPC_SEND_CNF0: u64 = IPC + 0x510;
+const IPC_RECEIVE_CNF1: u64 = IPC + 0x594;
+const IPC_GPMEM0: u64 = IPC + 0x610;
+const IPC_GPMEM1: u64 = IPC + 0x614;
+
+const SPU_RAMREGION_PERM: u64 = 0x50003700;
+const RAM_REGIONS: u64 = 32;
+/// Non-secure, readable, writable and executable, so the modem can use it.
+const RAM_NONSECURE_RWX: u32 = 0x7;
+
+const LTEMODEM_STARTN: u64 = 0x50005610;
+const LTEMODEM_FORCEOFF: u64 = 0x50005614;
+
+/// Shared memory the modem is pointed at through GPMEM: a descriptor of the
+/// transfer buffer, followed by the status and command words and the buffer.
+const SHMEM: u64 = 0x20000000;
+const STATUS: u64 = 0x2000000C;
+const COMMAND: u64 = 0x20000010;
+const ARG_ADDRESS: u64 = 0x20000014;
+const ARG_LENGTH: u64 = 0x20000018;
+const BUFFER: u64 = 0x2000001C;
+const BUFFER_SIZE: u32 = 0x0003FC00;
+
+const SHMEM_FLAGS: u32 = 0x80010000;
+const SHMEM_STATUS_PTR: u32 = 0x2100000C;
+
+const COMMAND_WRITE: u32 = 0x3;
+const COMMAND_DIGEST: u32 = 0x7;
+
+/// Posted by the modem's ROM once it waits for the DFU bootloader.
+const STATUS_READY: u32 = 0xA5000001;
+/// Posted by the bootloader when a command has completed.
+const STATUS_ACK: u32 = 0x5A000001;
+
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Covers erasing and writing a full buffer of modem flash.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Contents of a Nordic modem firmware package, `mfw_nrf91*_<version>.zip`.
+pub struct ModemFirmware {
+    pub name: String,
+    /// The DFU bootloader, run by the modem from RAM.
+    bootloader: Image,
+    /// `firmware.update.image.segments.N.hex`, in order.
+    segments: Vec<Image>,
+    /// SHA-256 of all segment data in ascending address order.
+    digest: Option<[u8; 32]>,
+}
+
+/// Read the files of a zip archive, stored or deflated.
+fn read_zip(path: &Path) -> Result<BTreeMap<String, Vec<u8>>, RecoveryError> {
+    let err = |e: &str| RecoveryError::ImageError(format!("{}: {}", path.display(), e));
+    let data = fs::read(path).map_err(|e| err(&e.to_string()))?;
+    let u16_at = |at: usize| data.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize);
+    let u32_at = |at: usize| data.get(at..at + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize);
+
+    let end = (0..data.len().saturating_sub(21))
+        .rev()
+        .find(|&i| u32_at(i) == Some(0x06054b50))
+        .ok_or_else(|| err("not a zip file"))?;
+    let count = u16_at(end + 10).unwrap_or(0);
+    let mut entry = u32_at(end + 16).unwrap_or(0);
+
+    let mut files = BTreeMap::new();
+    for _ in 0..count {
+        let header = |at: usize| -> Option<(usize, usize, usize, usize, usize)> {
+            (u32_at(at)? == 0x02014b50).then_some(())?;
+            Some((
+                u16_at(at + 10)?,
+                u32_at(at + 20)?,
+                u16_at(at + 28)?,
+                u16_at(at + 28)? + u16_at(at + 30)? + u16_at(at + 32)?,
+                u32_at(at + 42)?,
+            ))
+        };
+        let (method, size, name_len, var_len, local) = header(entry).ok_or_else(|| err("corrupt central directory"))?;
+        let name = String::from_utf8_lossy(data.get(entry + 46..entry + 46 + name_len).unwrap_or_default()).into_owned();
+        entry += 46 + var_len;
+
+        let start = match (u32_at(local), u16_at(local + 26), u16_at(local + 28)) {
+            (Some(0x04034b50), Some(n), Some(e)) => local + 30 + n + e,
+            _ => return Err(err(&format!("corrupt local header of {}", name))),
+        };
+        let raw = data.get(start..start + size).ok_or_else(|| err(&format!("{} is truncated", name)))?;
+        let contents = match method {
+            0 => raw.to_vec(),
+            8 => {
+                let mut out = Vec::new();
+                DeflateDecoder::new(raw)
+                    .read_to_end(&mut out)
+                    .map_err(|e| err(&format!("{}: {}", name, e)))?;
+                out
+            }
+            _ => return Err(err(&format!("{} uses unsupported compression method {}", name, method))),
+        };
+        files.insert(name, contents);
+    }
+    Ok(files)
+}
+
+impl ModemFirmware {
+    pub fn load(path: &Path) -> Result<Self, RecoveryError> {
+        let err = |e: String| RecoveryError::ImageError(format!("{}: {}", path.display(), e));
+        let files = read_zip(path)?;
+        let text = |name: &str, data: &[u8]| {
+            String::from_utf8(data.to_vec()).map_err(|_| err(format!("{} is not text", name)))
+        };
+
+        let (name, data) = files
+            .iter()
+            .find(|(name, _)| name.contains("ipc_dfu") && name.ends_with(".ihex"))
+            .ok_or_else(|| err("no ipc_dfu bootloader in the package".into()))?;
+        let bootloader = Image::parse(&text(name, data)?, name)?;
+
+        let mut segments = Vec::new();
+        for index in 0.. {
+            let name = format!("firmware.update.image.segments.{}.hex", index);
+            let Some(data) = files.get(&name) else { break };
+            segments.push(Image::parse(&text(&name, data)?, &name)?);
+        }
+        if segments.is_empty() {
+            return Err(err("no firmware.update.image.segments in the package".into()));
+        }
+
+        // The digest is the last 64 digit hex string in the file.
+        let digest = match files.get("firmware.update.image.digest.txt") {
+            Some(data) => text("firmware.update.image.digest.txt", data)?
+                .split_whitespace()
+                .rev()
+                .find_map(|word| hex::decode(word).ok()?.try_into().ok()),
+            None => None,
+        };
+
+        Ok(ModemFirmware {
+            name: path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+            bootloader,
+            segments,
+            digest,
+        })
+    }
+}
+
+/// Wait for the modem to signal on IPC channel 1 and return its status.
+fn wait(core: &mut Core, timeout: Duration) -> Result<u32, RecoveryError> {
+    let start = Instant::now();
+    while core.read_word_32(IPC_EVENTS_RECEIVE1)? == 0 {
+        if start.elapsed() > timeout {
+            return Err(RecoveryError::ModemError(format!("no response from the modem within {:?}", timeout)));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    core.write_word_32(IPC_EVENTS_RECEIVE1, 0)?;
+    Ok(core.read_word_32(STATUS)?)
+}
+
+fn expect(status: u32, expected: u32, what: &str) -> Result<(), RecoveryError> {
+    if status == expected {
+        Ok(())
+    } else {
+        Err(RecoveryError::ModemError(format!("{}: modem status 0x{:08x}", what, status)))
+    }
+}
+
+/// Run one bootloader command and wait for its acknowledgement.
+fn command(core: &mut Core, command: u32, address: u32, length: u32, what: &str) -> Result<(), RecoveryError> {
+    core.write_word_32(COMMAND, command)?;
+    core.write_word_32(ARG_ADDRESS, address)?;
+    core.write_word_32(ARG_LENGTH, length)?;
+    core.write_word_32(IPC_TASKS_SEND0, 1)?;
+    let status = wait(core, COMMAND_TIMEOUT)?;
+    expect(status, STATUS_ACK, what)
+}
+
+/// Update the modem firmware over SWD with the modem's IPC DFU protocol.
+///
+/// The application core is halted and hands the modem non-secure RAM for a
+/// transfer buffer. The modem is restarted into its ROM, which loads the DFU
+/// bootloader from the package into that RAM; the bootloader then takes the
+/// firmware segments one buffer at a time and writes them to modem flash.
+/// Finally the modem hashes what it wrote, which must match the package's
+/// digest.
+pub fn update(session: &mut Session, firmware: &ModemFirmware) -> Result<(), RecoveryError> {
+    say(tr!("modem_updating", package = firmware.name));
+    let mut core = session.core(0)?;
+    core.reset_and_halt(Duration::from_millis(100))?;
+
+    for region in 0..RAM_REGIONS {
+        core.write_word_32(SPU_RAMREGION_PERM + 4 * region, RAM_NONSECURE_RWX)?;
+    }
+    core.write_word_32(IPC_SEND_CNF0, 1 << 0)?;
+    core.write_word_32(IPC_RECEIVE_CNF1, 1 << 1)?;
+    core.write_word_32(IPC_GPMEM0, SHMEM_FLAGS)?;
+    core.write_word_32(IPC_GPMEM1, STATUS as u32)?;
+    core.write_32(SHMEM, &[SHMEM_FLAGS, SHMEM_STATUS_PTR, BUFFER_SIZE])?;
+    core.write_word_32(IPC_EVENTS_RECEIVE1, 0)?;
+
+    // Restart the modem into its ROM.
+    core.write_word_32(LTEMODEM_STARTN, 0)?;
+    core.write_word_32(LTEMODEM_FORCEOFF, 1)?;
+    core.write_word_32(LTEMODEM_STARTN, 1)?;
+    core.write_word_32(LTEMODEM_FORCEOFF, 0)?;
+    core.write_word_32(LTEMODEM_STARTN, 0)?;
+    expect(wait(&mut core, READY_TIMEOUT)?, STATUS_READY, "modem did not start its DFU ROM")?;
+
+    // The bootloader is linked to its place in RAM.
+    for segment in &firmware.bootloader.segments {
+        core.write(segment.address, &segment.data)?;
+    }
+    core.write_word_32(IPC_TASKS_SEND0, 1)?;
+    expect(wait(&mut core, READY_TIMEOUT)?, STATUS_ACK, "DFU bootloader did not start")?;
+
+    let mut ranges = Vec::new();
+    for image in &firmware.segments {
+        for segment in &image.segments {
+            let mut address = segment.address;
+            for chunk in segment.data.chunks(BUFFER_SIZE as usize) {
+                core.write(BUFFER, chunk)?;
+                command(
+                    &mut core,
+                    COMMAND_WRITE,
+                    address as u32,
+                    chunk.len() as u32,
+                    &format!("writing 0x{:08x}", address),
+                )?;
+                address += chunk.len() as u64;
+            }
+            ranges.push((segment.address as u32, segment.data.len() as u32));
+            log::info!("Modem segment 0x{:08x}, {} bytes written", segment.address, segment.data.len());
+        }
+    }
+
+    // Hash the written ranges in ascending address order.
+    ranges.sort();
+    let words: Vec<u32> = ranges.iter().flat_map(|&(start, len)| [start, len]).collect();
+    core.write_32(BUFFER, &words)?;
+    command(&mut core, COMMAND_DIGEST, ranges.len() as u32, 0, "hashing modem flash")?;
+    let mut digest = [0u8; 32];
+    core.read(BUFFER, &mut digest)?;
+
+    let expected = match firmware.digest {
+        Some(expected) => expected,
+        // Older packages have no digest file; hash the package data instead.
+        None => {
+            let mut segments: Vec<_> = firmware.segments.iter().flat_map(|i| &i.segments).collect();
+            segments.sort_by_key(|s| s.address);
+            let mut hasher = Sha256::new();
+            for segment in segments {
+                hasher.update(&segment.data);
+            }
+            hasher.finalize().into()
+        }
+    };
+    if digest != expected {
+        return Err(RecoveryError::ModemError(format!(
+            "modem flash digest {} does not match the package's {}",
+            hex::encode(digest),
+            hex::encode(expected)
+        )));
+    }
+
+    say(tr!("modem_updated"));
+    Ok(())
+}
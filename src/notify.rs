@@ -0,0 +1,111 @@
+use std::io::{self, Write};
+use std::process::{self, Command};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use crate::i18n::tr;
+
+/// How to announce that a run has finished.
+#[derive(Clone, Debug)]
+pub enum Notify {
+    /// Ring the terminal bell.
+    Bell,
+    /// Show a desktop notification.
+    Desktop,
+    /// Run a shell command, with `RECOVERY_RESULT` set to `pass` or `fail`.
+    Command(String),
+}
+
+impl FromStr for Notify {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("command", cmd)) if !cmd.trim().is_empty() => Ok(Notify::Command(cmd.to_string())),
+            Some(("command", _)) => Err("command notification requires a command".into()),
+            _ => match s {
+                "bell" => Ok(Notify::Bell),
+                "desktop" => Ok(Notify::Desktop),
+                _ => Err(format!(
+                    "Unknown notification '{}', expected bell, desktop or command:<cmd>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+static NOTIFY: OnceLock<Notify> = OnceLock::new();
+static SENT: AtomicBool = AtomicBool::new(false);
+
+/// Set the notification to send when the run finishes.
+pub fn init(notify: Option<Notify>) {
+    if let Some(notify) = notify {
+        let _ = NOTIFY.set(notify);
+    }
+}
+
+/// Run `cmd` through the platform shell.
+pub fn shell(cmd: &str) -> Command {
+    if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(cmd);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd);
+        command
+    }
+}
+
+fn desktop(message: &str) -> io::Result<()> {
+    let status = if cfg!(target_os = "macos") {
+        Command::new("osascript")
+            .arg("-e")
+            .arg(format!("display notification \"{}\" with title \"recovery\"", message))
+            .status()?
+    } else {
+        Command::new("notify-send").arg("recovery").arg(message).status()?
+    };
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("exited with {}", status)))
+    }
+}
+
+/// Announce that the run finished. Only the first call has any effect, so it
+/// is safe to call both when the flash is done and again on exit.
+pub fn finished(success: bool) {
+    let Some(notify) = NOTIFY.get() else { return };
+    if SENT.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let message = if success {
+        tr!("notify_finished")
+    } else {
+        tr!("notify_failed")
+    };
+    let result = match notify {
+        Notify::Bell => {
+            let mut stderr = io::stderr();
+            stderr.write_all(b"\x07").and_then(|_| stderr.flush())
+        }
+        Notify::Desktop => desktop(&message),
+        Notify::Command(cmd) => shell(cmd)
+            .env("RECOVERY_RESULT", if success { "pass" } else { "fail" })
+            .status()
+            .map(|_| ()),
+    };
+    if let Err(e) = result {
+        log::warn!("Unable to send notification: {}", e);
+    }
+}
+
+/// Notify and exit the process.
+pub fn exit(code: i32) -> ! {
+    finished(code == 0);
+    process::exit(code)
+}
@@ -0,0 +1,57 @@
+use probe_rs::{Core, MemoryInterface};
+
+use std::time::Duration;
+
+pub const NVMC_READY: u64 = 0x50039400; // NVMC.READY
+pub const NVMC_CONFIG: u64 = 0x50039504; // NVMC.CONFIG
+
+// NVMC.CONFIG values.
+pub const CONFIG_REN: u32 = 0;
+pub const CONFIG_WEN: u32 = 1;
+pub const CONFIG_EEN: u32 = 2;
+
+pub const FLASH_START: u64 = 0x00000000;
+pub const FLASH_END: u64 = 0x00100000;
+pub const FLASH_PAGE_SIZE: u64 = 0x1000;
+
+/// Poll NVMC.READY until the controller is idle.
+pub fn wait_ready(core: &mut Core) -> Result<(), probe_rs::Error> {
+    loop {
+        let ready = core.read_word_32(NVMC_READY)?;
+        if ready & 0x1 == 1 {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Set NVMC.CONFIG and wait for the controller to settle.
+pub fn set_config(core: &mut Core, config: u32) -> Result<(), probe_rs::Error> {
+    core.write_word_32(NVMC_CONFIG, config)?;
+    wait_ready(core)
+}
+
+/// Erase the flash page starting at `page`.
+///
+/// On nRF91 a page is erased by writing 0xFFFFFFFF to its first word while
+/// NVMC.CONFIG is set to Een.
+pub fn erase_page(core: &mut Core, page: u64) -> Result<(), probe_rs::Error> {
+    set_config(core, CONFIG_EEN)?;
+    core.write_word_32(page, 0xFFFFFFFF)?;
+    wait_ready(core)?;
+    set_config(core, CONFIG_REN)
+}
+
+/// Write consecutive words starting at `addr`. Words equal to the erased
+/// value are skipped.
+pub fn write_words(core: &mut Core, addr: u64, words: &[u32]) -> Result<(), probe_rs::Error> {
+    set_config(core, CONFIG_WEN)?;
+    for (i, word) in words.iter().enumerate() {
+        if *word == 0xFFFFFFFF {
+            continue;
+        }
+        core.write_word_32(addr + i as u64 * 4, *word)?;
+        wait_ready(core)?;
+    }
+    set_config(core, CONFIG_REN)
+}
@@ -0,0 +1,187 @@
+use clap::ValueEnum;
+use serde::Serialize;
+
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::approtect::{UICR_APPROTECT, UICR_SECUREAPPROTECT};
+use crate::credentials::Credential;
+use crate::identity::Identity;
+use crate::hooks::Hooks;
+use crate::probe;
+use crate::{ProbeArgs, RecoveryError};
+
+/// What the recovery run prints on stdout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Progress messages for the operator.
+    #[default]
+    Text,
+    /// A single JSON object with the result; progress goes to stderr.
+    Json,
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Select the output format for the rest of the process.
+pub fn init(format: OutputFormat) {
+    JSON.store(format == OutputFormat::Json, Ordering::Relaxed);
+}
+
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Print a progress message, on stderr when stdout carries JSON.
+pub fn say(message: impl Display) {
+    if json() {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+/// A UICR word the run wrote, as read back.
+#[derive(Clone, Debug, Serialize)]
+pub struct UicrWrite {
+    pub register: &'static str,
+    pub address: String,
+    pub value: String,
+}
+
+/// Why a run failed.
+#[derive(Clone, Debug, Serialize)]
+pub struct RunError {
+    /// Recovery stage, if the error happened inside the flow.
+    pub stage: Option<String>,
+    pub message: String,
+}
+
+impl RunError {
+    fn new(error: &RecoveryError) -> Self {
+        match error {
+            RecoveryError::Stage { stage, source } => RunError {
+                stage: Some(stage.to_string()),
+                message: source.to_string(),
+            },
+            e => RunError {
+                stage: None,
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
+/// Result of a recovery run, printed by `--output json`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RunReport {
+    pub probe_serial: Option<String>,
+    pub chip: String,
+    /// FICR device ID of nRF91 devices.
+    pub device_id: Option<String>,
+    /// Whether the device had to be unlocked, which mass-erases it.
+    pub unlocked: bool,
+    pub images: Vec<String>,
+    /// Time from attaching until the last image was written, including a
+    /// `--force-erase`.
+    pub flash_ms: Option<u128>,
+    pub uicr_writes: Vec<UicrWrite>,
+    pub imei: Option<String>,
+    /// Credentials stored in the modem, with `--read-credentials`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<Vec<Credential>>,
+    /// IMEI, device UUID and attestation token, with `--read-identity`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<Identity>,
+    pub firmware_version: Option<String>,
+    /// Failures of the attempts `--retries` ran again, oldest first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub retried: Vec<RunError>,
+    pub success: bool,
+    /// Stopped at a safe point on request, rather than failed.
+    pub cancelled: bool,
+    pub error: Option<RunError>,
+    pub duration_ms: u128,
+}
+
+impl RunReport {
+    pub fn new(probe: &ProbeArgs, chip: &str) -> Self {
+        RunReport {
+            probe_serial: probe_serial(probe),
+            chip: chip.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Record a failure. `stage` is taken from a flow error.
+    pub fn fail(&mut self, error: &RecoveryError) {
+        self.success = false;
+        self.cancelled = error.is_cancelled();
+        self.error = Some(RunError::new(error));
+    }
+
+    /// Record a failed attempt that is run again, forgetting what it wrote.
+    fn retry(&mut self, error: &RecoveryError) {
+        self.retried.push(RunError::new(error));
+        self.images.clear();
+        self.flash_ms = None;
+        self.uicr_writes.clear();
+    }
+}
+
+/// Serial number of the probe the run uses: `--serial`, or the first probe
+/// the probe settings select.
+fn probe_serial(args: &ProbeArgs) -> Option<String> {
+    if args.serial.is_some() {
+        return args.serial.clone();
+    }
+    probe::serials(args).into_iter().next()
+}
+
+/// Add hooks that fill in `report` as the flow progresses.
+pub fn record(hooks: Hooks, report: &Arc<Mutex<RunReport>>) -> Hooks {
+    let attached = Arc::new(Mutex::new(None::<Instant>));
+    let (unlocked, identified, flashed, uicr_written, retrying) =
+        (report.clone(), report.clone(), report.clone(), report.clone(), report.clone());
+    let flash_start = attached.clone();
+
+    hooks
+        .on_unlocked(move |erased| {
+            *attached.lock().unwrap() = Some(Instant::now());
+            // An earlier attempt may have erased the device already.
+            unlocked.lock().unwrap().unlocked |= erased;
+        })
+        .on_identified(move |device_id| identified.lock().unwrap().device_id = Some(device_id.to_string()))
+        .on_flashed(move |image: &Path| {
+            let mut report = flashed.lock().unwrap();
+            report.images.push(image.display().to_string());
+            report.flash_ms = flash_start.lock().unwrap().map(|start| start.elapsed().as_millis());
+        })
+        .on_uicr_written(move |words| {
+            let hex = |value: u64| format!("0x{:08x}", value);
+            uicr_written.lock().unwrap().uicr_writes = vec![
+                UicrWrite {
+                    register: "APPROTECT",
+                    address: hex(UICR_APPROTECT),
+                    value: hex(words.approtect as u64),
+                },
+                UicrWrite {
+                    register: "SECUREAPPROTECT",
+                    address: hex(UICR_SECUREAPPROTECT),
+                    value: hex(words.secureapprotect as u64),
+                },
+            ];
+        })
+        .on_retrying(move |_, e| retrying.lock().unwrap().retry(e))
+}
+
+/// Print `report` as one line of JSON on stdout.
+pub fn print(report: &RunReport) {
+    match serde_json::to_string(report) {
+        Ok(line) => println!("{}", line),
+        Err(e) => log::error!("Unable to serialize the run report: {}", e),
+    }
+}
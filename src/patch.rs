@@ -0,0 +1,185 @@
+use serde::Deserialize;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::image::{Image, ImageFile};
+use crate::job::Unit;
+use crate::RecoveryError;
+
+/// Writes per-unit identity into an image format the job fields cannot
+/// reach, such as a block whose layout is only known by walking it.
+///
+/// Patchers are compiled in, each behind a cargo feature, and picked by
+/// name in a job target's `patch` table. The images are patched before
+/// they are handed to the flow, so the flash planner sees plain images.
+pub trait ImagePatcher: Send + Sync {
+    /// Name used in job files.
+    fn name(&self) -> &'static str;
+
+    /// Check the keys of a job's `values` table when the job is loaded.
+    fn check_key(&self, key: &str) -> Result<(), String>;
+
+    /// Write `values`, by key, into `image`. Returns whether the image holds
+    /// what the patcher is looking for; images that do not are flashed as
+    /// they are.
+    fn patch(&self, image: &mut Image, values: &BTreeMap<String, String>) -> Result<bool, RecoveryError>;
+}
+
+/// The patchers built into this binary.
+const PATCHERS: &[&dyn ImagePatcher] = &[
+    #[cfg(feature = "legacy-tlv")]
+    &LegacyTlv,
+];
+
+/// The built-in patcher called `name`.
+pub fn find(name: &str) -> Result<&'static dyn ImagePatcher, String> {
+    PATCHERS.iter().copied().find(|p| p.name() == name).ok_or_else(|| {
+        let built: Vec<&str> = PATCHERS.iter().map(|p| p.name()).collect();
+        format!(
+            "no image patcher '{}' in this build (built in: {}); patchers are enabled with cargo features",
+            name,
+            if built.is_empty() { "none".to_string() } else { built.join(", ") }
+        )
+    })
+}
+
+/// A job target's `patch` table: the patcher, and which unit column goes
+/// to which of its keys.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatchSpec {
+    pub format: String,
+    /// Patcher key to unit column.
+    pub values: BTreeMap<String, String>,
+}
+
+impl PatchSpec {
+    /// Check that the patcher is built in and knows the keys.
+    pub fn check(&self) -> Result<(), String> {
+        let patcher = find(&self.format)?;
+        for key in self.values.keys() {
+            patcher.check_key(key).map_err(|e| format!("{}: {}", self.format, e))?;
+        }
+        Ok(())
+    }
+
+    /// Patch `images` with the values of `unit`, saving the patched ones as
+    /// Intel hex files named after `name` in `dir`. Images the patcher finds
+    /// nothing in are returned as they are; finding nothing in any of them
+    /// is an error, since the unit would go out without its identity.
+    pub fn apply(&self, images: &[ImageFile], unit: &Unit, dir: &Path, name: &str) -> Result<Vec<ImageFile>, RecoveryError> {
+        let patcher = find(&self.format).map_err(RecoveryError::ImageError)?;
+        let values = self
+            .values
+            .iter()
+            .map(|(key, column)| {
+                let value = unit.get(column).ok_or_else(|| {
+                    RecoveryError::FieldError(format!("column '{}' missing for unit", column))
+                })?;
+                Ok((key.clone(), value.clone()))
+            })
+            .collect::<Result<BTreeMap<_, _>, RecoveryError>>()?;
+
+        fs::create_dir_all(dir).map_err(|e| RecoveryError::ImageError(format!("{}: {}", dir.display(), e)))?;
+        let mut patched = Vec::new();
+        let mut found = false;
+        for (i, file) in images.iter().enumerate() {
+            let mut image = file.load()?;
+            if !patcher.patch(&mut image, &values)? {
+                patched.push(file.clone());
+                continue;
+            }
+            found = true;
+            let path = dir.join(format!("{}-{}.hex", name, i));
+            image.save_hex(&path)?;
+            log::info!("Patched {} into {}", file, path.display());
+            patched.push(ImageFile::hex(path));
+        }
+        if !found {
+            return Err(RecoveryError::ImageError(format!(
+                "none of the images holds what the {} patcher writes",
+                self.format
+            )));
+        }
+        Ok(patched)
+    }
+}
+
+/// Identity block of legacy products: the magic `IDV1`, a little-endian
+/// u16 with the size of the entries, then entries of a tag byte, a length
+/// byte and that many bytes. Builds reserve the identity entries filled with
+/// 0xFF; a value is written as text, padded with 0xFF to the entry's length.
+/// Keys are the tags, e.g. `0x01`.
+#[cfg(feature = "legacy-tlv")]
+pub struct LegacyTlv;
+
+#[cfg(feature = "legacy-tlv")]
+impl LegacyTlv {
+    const MAGIC: &'static [u8] = b"IDV1";
+
+    fn tag(key: &str) -> Result<u8, String> {
+        let word = crate::parse_word(key)?;
+        u8::try_from(word).map_err(|_| format!("tag {} does not fit in a byte", key))
+    }
+}
+
+#[cfg(feature = "legacy-tlv")]
+impl ImagePatcher for LegacyTlv {
+    fn name(&self) -> &'static str {
+        "legacy-tlv"
+    }
+
+    fn check_key(&self, key: &str) -> Result<(), String> {
+        Self::tag(key).map(|_| ())
+    }
+
+    fn patch(&self, image: &mut Image, values: &BTreeMap<String, String>) -> Result<bool, RecoveryError> {
+        let err = |e: String| RecoveryError::ImageError(format!("identity block: {}", e));
+        let tags = values
+            .iter()
+            .map(|(key, value)| Ok((Self::tag(key).map_err(err)?, value.as_bytes())))
+            .collect::<Result<BTreeMap<u8, &[u8]>, RecoveryError>>()?;
+
+        for segment in &mut image.segments {
+            let data = &mut segment.data;
+            let Some(start) = data.windows(Self::MAGIC.len()).position(|w| w == Self::MAGIC) else {
+                continue;
+            };
+            let header = start + Self::MAGIC.len();
+            let size = data
+                .get(header..header + 2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+                .ok_or_else(|| err("truncated header".into()))?;
+            let end = header + 2 + size;
+            if end > data.len() {
+                return Err(err(format!("{} bytes of entries run past the image data", size)));
+            }
+
+            let mut written = Vec::new();
+            let mut offset = header + 2;
+            while offset + 2 <= end {
+                let (tag, len) = (data[offset], data[offset + 1] as usize);
+                let entry = offset + 2..offset + 2 + len;
+                if entry.end > end {
+                    return Err(err(format!("entry 0x{:02x} runs past the block", tag)));
+                }
+                if let Some(value) = tags.get(&tag) {
+                    if value.len() > len {
+                        return Err(err(format!("{} bytes do not fit in entry 0x{:02x} of {} bytes", value.len(), tag, len)));
+                    }
+                    data[entry.clone()].fill(0xFF);
+                    data[entry.start..entry.start + value.len()].copy_from_slice(value);
+                    written.push(tag);
+                }
+                offset = entry.end;
+            }
+            if let Some(missing) = tags.keys().find(|tag| !written.contains(tag)) {
+                return Err(err(format!("no entry with tag 0x{:02x}", missing)));
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
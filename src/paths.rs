@@ -0,0 +1,121 @@
+use chrono::Local;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::station::Station;
+use crate::RecoveryError;
+
+/// Values for the placeholders of an output path.
+#[derive(Clone, Debug, Default)]
+pub struct PathVars {
+    pub device_id: Option<String>,
+    pub imei: Option<String>,
+    pub station: Option<String>,
+    pub operator: Option<String>,
+    /// Unit label in batch runs.
+    pub unit: Option<String>,
+}
+
+impl PathVars {
+    pub fn for_station(station: &Station) -> Self {
+        PathVars {
+            station: station.station_id.clone(),
+            operator: station.operator.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Keep a value from adding directories or odd characters to the path.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.+".contains(c) { c } else { '_' })
+        .collect()
+}
+
+/// Expand `{date}`, `{time}`, `{deviceid}`, `{imei}`, `{station}`,
+/// `{operator}` and `{unit}` in `template`, and create the directory the
+/// path ends up in.
+///
+/// Values that are not known, such as the IMEI of a device that was never
+/// booted, expand to `unknown`.
+pub fn expand(template: &Path, vars: &PathVars) -> Result<PathBuf, RecoveryError> {
+    let text = template.to_string_lossy();
+    let now = Local::now();
+    let mut out = String::new();
+    let mut rest = text.as_ref();
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find('}').map(|e| start + e).ok_or_else(|| {
+            RecoveryError::ConfigError(format!("unclosed placeholder in {}", text))
+        })?;
+        let value = match &rest[start + 1..end] {
+            "date" => Some(now.format("%Y-%m-%d").to_string()),
+            "time" => Some(now.format("%H%M%S").to_string()),
+            "deviceid" => vars.device_id.clone(),
+            "imei" => vars.imei.clone(),
+            "station" => vars.station.clone(),
+            "operator" => vars.operator.clone(),
+            "unit" => vars.unit.clone(),
+            name => {
+                return Err(RecoveryError::ConfigError(format!(
+                    "unknown placeholder {{{}}} in {}",
+                    name, text
+                )))
+            }
+        };
+        out.push_str(&sanitize(value.as_deref().unwrap_or("unknown")));
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+
+    let path = PathBuf::from(out);
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)
+            .map_err(|e| RecoveryError::ReportError(format!("{}: {}", parent.display(), e)))?;
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("recovery-paths-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn bad_placeholders_are_refused() {
+        let vars = PathVars::default();
+        assert!(expand(Path::new("{serial}.json"), &vars).is_err());
+        assert!(expand(Path::new("{deviceid.json"), &vars).is_err());
+    }
+
+    #[test]
+    fn missing_values_expand_to_unknown() {
+        let dir = dir("unknown");
+        let vars = PathVars {
+            unit: Some("A-17".into()),
+            ..Default::default()
+        };
+        let path = expand(&dir.join("{unit}/{deviceid}_{imei}.json"), &vars).unwrap();
+        assert_eq!(path, dir.join("A-17/unknown_unknown.json"));
+        assert!(dir.join("A-17").is_dir());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn values_cannot_add_directories() {
+        let dir = dir("sanitize");
+        let vars = PathVars {
+            operator: Some("../a/b c".into()),
+            ..Default::default()
+        };
+        let path = expand(&dir.join("{operator}.json"), &vars).unwrap();
+        assert_eq!(path, dir.join(".._a_b_c.json"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
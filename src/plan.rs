@@ -0,0 +1,291 @@
+use probe_rs::config::MemoryRegion;
+
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use crate::approtect::UicrProtect;
+use crate::device::Part;
+use crate::flow::{is_nrf91, FlowOptions};
+use crate::image::Segment;
+use crate::provision::{PROVISION, PROVISION_ADDRESS, PROVISION_END};
+use crate::registry;
+use crate::uicr;
+use crate::verify::VerifyLevel;
+use crate::RecoveryError;
+
+/// Window used to read images while planning, see [`image::stream`].
+const PLAN_WINDOW: u64 = 64 * 1024;
+
+/// A separately erasable block of non-volatile memory: a flash bank, UICR,
+/// an RRAM region.
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub name: String,
+    pub range: Range<u64>,
+}
+
+/// When a mass erase happens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EraseWhen {
+    /// Only if the device turns out to be locked.
+    IfLocked,
+    Always,
+}
+
+/// One step of a plan.
+#[derive(Clone, Debug)]
+pub enum Action {
+    /// Erase every region.
+    EraseAll(EraseWhen),
+    /// Write image data to part of a region. Sectors touched are erased first.
+    Write {
+        image: PathBuf,
+        region: String,
+        range: Range<u64>,
+        bytes: u64,
+        /// The device already holds the image, so pre-verify skips it.
+        unchanged: bool,
+    },
+    /// Write NSIB provisioning data to UICR.OTP word by word, leaving the
+    /// rest of UICR alone.
+    Provision { image: PathBuf, range: Range<u64>, bytes: u64 },
+    /// Write a single word, e.g. a UICR register.
+    Word { region: String, address: u64, value: u32 },
+    Reset,
+}
+
+/// What a connected device looked like, for a plan made against it.
+#[derive(Clone, Debug)]
+pub struct DeviceState {
+    /// Part read from FICR, when the device is unlocked.
+    pub part: Option<Part>,
+    pub locked: bool,
+    /// UICR protection words, when the device is unlocked.
+    pub protection: Option<UicrProtect>,
+    /// Images whose data the device already holds byte for byte.
+    pub unchanged: Vec<PathBuf>,
+}
+
+/// What the flow will do to the device, region by region.
+#[derive(Clone, Debug)]
+pub struct Plan {
+    pub chip: String,
+    pub regions: Vec<Region>,
+    pub actions: Vec<Action>,
+    /// The device the plan was made against, if one was connected.
+    pub device: Option<DeviceState>,
+}
+
+/// Non-volatile regions of `chip` from the probe-rs target description.
+pub fn regions(chip: &str) -> Result<Vec<Region>, RecoveryError> {
+    let target = registry::registry()
+        .get_target_by_name(registry::resolve(chip))
+        .map_err(|e| RecoveryError::ConfigError(format!("{}: {}", chip, e)))?;
+    Ok(target
+        .memory_map
+        .iter()
+        .filter_map(|region| match region {
+            MemoryRegion::Nvm(nvm) if !nvm.is_alias => Some(Region {
+                name: nvm
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("NVM@0x{:08x}", nvm.range.start)),
+                range: nvm.range.clone(),
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+fn region_at(regions: &[Region], address: u64) -> Option<&Region> {
+    regions.iter().find(|r| r.range.contains(&address))
+}
+
+impl Plan {
+    /// Work out the plan for `options` without touching a device. Fails if
+    /// an image has data outside every non-volatile region of the chip.
+    pub fn build(options: &FlowOptions) -> Result<Plan, RecoveryError> {
+        let regions = regions(&options.chip)?;
+        let nrf91 = is_nrf91(&options.chip);
+        let mut actions = Vec::new();
+
+        if options.force_unlock || options.force_erase {
+            actions.push(Action::EraseAll(EraseWhen::Always));
+        } else if nrf91 {
+            actions.push(Action::EraseAll(EraseWhen::IfLocked));
+        }
+
+        for file in &options.images {
+            let path = &file.path;
+            let mut writes: Vec<Action> = Vec::new();
+            let mut add = |segments: &[Segment]| {
+                for segment in segments {
+                    let mut address = segment.address;
+                    let end = segment.address + segment.data.len() as u64;
+                    while address < end {
+                        if nrf91 && PROVISION.contains(&address) {
+                            let stop = end.min(PROVISION_END);
+                            match writes.last_mut() {
+                                Some(Action::Provision { range, bytes, .. }) => {
+                                    range.end = range.end.max(stop);
+                                    *bytes += stop - address;
+                                }
+                                _ => writes.push(Action::Provision {
+                                    image: path.clone(),
+                                    range: address..stop,
+                                    bytes: stop - address,
+                                }),
+                            }
+                            address = stop;
+                            continue;
+                        }
+                        let region = region_at(&regions, address).ok_or_else(|| {
+                            RecoveryError::ImageError(format!(
+                                "{}: data at 0x{:08x} is outside every non-volatile region of {}",
+                                path.display(),
+                                address,
+                                options.chip
+                            ))
+                        })?;
+                        let mut stop = end.min(region.range.end);
+                        if nrf91 && address < PROVISION_ADDRESS {
+                            stop = stop.min(PROVISION_ADDRESS);
+                        }
+                        // One write per image and region, spanning all its data.
+                        match writes.last_mut() {
+                            Some(Action::Write { region: name, range, bytes, .. }) if *name == region.name => {
+                                range.end = range.end.max(stop);
+                                *bytes += stop - address;
+                            }
+                            _ => writes.push(Action::Write {
+                                image: path.clone(),
+                                region: region.name.clone(),
+                                range: address..stop,
+                                bytes: stop - address,
+                                unchanged: false,
+                            }),
+                        }
+                        address = stop;
+                    }
+                }
+                Ok(())
+            };
+            // Streamed images are read in windows here too, so planning
+            // does not undo the memory savings. Otherwise the hex file may
+            // be in any order.
+            if options.stream {
+                file.stream(PLAN_WINDOW, &mut add)?;
+            } else {
+                add(&file.load()?.segments)?;
+            }
+            actions.extend(writes);
+        }
+
+        if nrf91 {
+            for (address, value) in uicr::flow_words(&options.uicr, &options.protection, &options.chip) {
+                actions.push(Action::Word {
+                    region: region_at(&regions, address).map(|r| r.name.clone()).unwrap_or_default(),
+                    address,
+                    value,
+                });
+            }
+        }
+        actions.push(Action::Reset);
+
+        Ok(Plan {
+            chip: options.chip.clone(),
+            regions,
+            actions,
+            device: None,
+        })
+    }
+
+    /// Settle the plan for `device`: whether the mass erase happens, and
+    /// which writes pre-verify will skip.
+    pub fn for_device(mut self, options: &FlowOptions, device: DeviceState) -> Self {
+        let erased = device.locked || options.force_unlock || options.force_erase;
+        let skips = !erased && options.verify >= VerifyLevel::Preverify && !options.force_reflash;
+        self.actions.retain(|action| match action {
+            Action::EraseAll(EraseWhen::IfLocked) => device.locked,
+            _ => true,
+        });
+        for action in &mut self.actions {
+            match action {
+                Action::EraseAll(when) => *when = EraseWhen::Always,
+                Action::Write { image, unchanged, .. } => *unchanged = skips && device.unchanged.contains(image),
+                _ => {}
+            }
+        }
+        self.device = Some(device);
+        self
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Plan for {}", self.chip)?;
+        if let Some(device) = &self.device {
+            writeln!(f, "Device:")?;
+            if let Some(part) = &device.part {
+                writeln!(f, "  part         {}", part)?;
+            }
+            writeln!(f, "  debug access {}", if device.locked { "locked" } else { "unlocked" })?;
+            if let Some(words) = &device.protection {
+                writeln!(
+                    f,
+                    "  UICR         APPROTECT 0x{:08x}, SECUREAPPROTECT 0x{:08x}",
+                    words.approtect, words.secureapprotect
+                )?;
+            }
+        }
+        writeln!(f, "Regions:")?;
+        for region in &self.regions {
+            writeln!(
+                f,
+                "  {:<12} 0x{:08x}..0x{:08x} ({} KiB)",
+                region.name,
+                region.range.start,
+                region.range.end,
+                (region.range.end - region.range.start) / 1024
+            )?;
+        }
+        writeln!(f, "Steps:")?;
+        for (i, action) in self.actions.iter().enumerate() {
+            write!(f, "  {}. ", i + 1)?;
+            match action {
+                Action::EraseAll(EraseWhen::IfLocked) => writeln!(f, "erase all regions if the device is locked")?,
+                Action::EraseAll(EraseWhen::Always) => writeln!(f, "erase all regions")?,
+                Action::Write {
+                    image,
+                    region,
+                    range,
+                    bytes,
+                    unchanged,
+                } => writeln!(
+                    f,
+                    "write {} bytes of {} to {} 0x{:08x}..0x{:08x}{}",
+                    bytes,
+                    image.display(),
+                    region,
+                    range.start,
+                    range.end,
+                    if *unchanged { " (already on the device, skipped)" } else { "" }
+                )?,
+                Action::Provision { image, range, bytes } => writeln!(
+                    f,
+                    "write {} bytes of provisioning data from {} to UICR.OTP 0x{:08x}..0x{:08x}, word by word without erasing",
+                    bytes,
+                    image.display(),
+                    range.start,
+                    range.end
+                )?,
+                Action::Word { region, address, value } => {
+                    writeln!(f, "write 0x{:08x} to {} 0x{:08x}", value, region, address)?
+                }
+                Action::Reset => writeln!(f, "reset")?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use crate::RecoveryError;
+
+const SYSFS_GPIO: &str = "/sys/class/gpio";
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// udev needs a moment to hand a freshly exported line to the gpio group.
+const EXPORT_SETTLE: Duration = Duration::from_millis(100);
+
+/// A host GPIO line by its sysfs number, `!` in front for active low.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub gpio: u32,
+    pub active_low: bool,
+}
+
+impl FromStr for Line {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (active_low, number) = match s.trim().strip_prefix('!') {
+            Some(number) => (true, number),
+            None => (false, s.trim()),
+        };
+        let gpio = number.parse().map_err(|_| format!("Invalid GPIO number '{}'", number))?;
+        Ok(Line { gpio, active_low })
+    }
+}
+
+/// The lines of the three-wire handshake with the line PLC: START from the
+/// PLC, BUSY from the station, and the result on PASS or FAIL.
+///
+/// Given as `start=17,busy=27,pass=22,fail=23`.
+#[derive(Clone, Copy, Debug)]
+pub struct PlcLines {
+    pub start: Line,
+    pub busy: Line,
+    pub pass: Line,
+    pub fail: Line,
+}
+
+impl FromStr for PlcLines {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut start, mut busy, mut pass, mut fail) = (None, None, None, None);
+        for part in s.split(',') {
+            let (name, line) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Expected NAME=GPIO, got '{}'", part))?;
+            let slot = match name.trim() {
+                "start" => &mut start,
+                "busy" => &mut busy,
+                "pass" => &mut pass,
+                "fail" => &mut fail,
+                other => return Err(format!("Unknown PLC line '{}', expected start, busy, pass or fail", other)),
+            };
+            *slot = Some(line.parse()?);
+        }
+        let missing = |name: &str| format!("PLC line '{}' is missing", name);
+        Ok(PlcLines {
+            start: start.ok_or_else(|| missing("start"))?,
+            busy: busy.ok_or_else(|| missing("busy"))?,
+            pass: pass.ok_or_else(|| missing("pass"))?,
+            fail: fail.ok_or_else(|| missing("fail"))?,
+        })
+    }
+}
+
+fn gpio_error(line: Line, e: io::Error) -> RecoveryError {
+    RecoveryError::PlcError(format!("GPIO {}: {}", line.gpio, e))
+}
+
+/// A line exported through sysfs and set up for its direction.
+struct Gpio {
+    line: Line,
+    value: PathBuf,
+}
+
+impl Gpio {
+    fn open(line: Line, output: bool) -> Result<Self, RecoveryError> {
+        let dir = PathBuf::from(SYSFS_GPIO).join(format!("gpio{}", line.gpio));
+        if !dir.exists() {
+            fs::write(PathBuf::from(SYSFS_GPIO).join("export"), line.gpio.to_string())
+                .map_err(|e| gpio_error(line, e))?;
+            thread::sleep(EXPORT_SETTLE);
+        }
+        fs::write(dir.join("active_low"), if line.active_low { "1" } else { "0" })
+            .map_err(|e| gpio_error(line, e))?;
+        // Outputs start out inactive without a glitch. The level given to
+        // `direction` is physical, so an active low line starts high.
+        let direction = match (output, line.active_low) {
+            (false, _) => "in",
+            (true, false) => "low",
+            (true, true) => "high",
+        };
+        fs::write(dir.join("direction"), direction).map_err(|e| gpio_error(line, e))?;
+        Ok(Gpio { line, value: dir.join("value") })
+    }
+
+    fn get(&self) -> Result<bool, RecoveryError> {
+        let value = fs::read_to_string(&self.value).map_err(|e| gpio_error(self.line, e))?;
+        Ok(value.trim() == "1")
+    }
+
+    fn set(&self, active: bool) -> Result<(), RecoveryError> {
+        fs::write(&self.value, if active { "1" } else { "0" }).map_err(|e| gpio_error(self.line, e))
+    }
+}
+
+/// The station's side of the PLC handshake.
+///
+/// 1. All outputs are inactive while the station waits for START.
+/// 2. On START the station raises BUSY and programs the unit.
+/// 3. BUSY drops and PASS or FAIL comes up with the result.
+/// 4. The PLC acknowledges by dropping START, which clears PASS and FAIL.
+pub struct Plc {
+    start: Gpio,
+    busy: Gpio,
+    pass: Gpio,
+    fail: Gpio,
+}
+
+impl Plc {
+    pub fn open(lines: &PlcLines) -> Result<Self, RecoveryError> {
+        Ok(Plc {
+            start: Gpio::open(lines.start, false)?,
+            busy: Gpio::open(lines.busy, true)?,
+            pass: Gpio::open(lines.pass, true)?,
+            fail: Gpio::open(lines.fail, true)?,
+        })
+    }
+
+    fn wait_for(&self, start: bool) -> Result<(), RecoveryError> {
+        while self.start.get()? != start {
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    /// Wait for START, then signal BUSY.
+    pub fn begin(&self) -> Result<(), RecoveryError> {
+        self.wait_for(true)?;
+        self.pass.set(false)?;
+        self.fail.set(false)?;
+        self.busy.set(true)
+    }
+
+    /// Signal the result and wait for the PLC to take it by dropping START.
+    pub fn finish(&self, pass: bool) -> Result<(), RecoveryError> {
+        self.busy.set(false)?;
+        if pass {
+            self.pass.set(true)?;
+        } else {
+            self.fail.set(true)?;
+        }
+        self.wait_for(false)?;
+        self.pass.set(false)?;
+        self.fail.set(false)
+    }
+}
+
+impl Drop for Plc {
+    /// Never leave BUSY or a stale result up when the station stops.
+    fn drop(&mut self) {
+        for gpio in [&self.busy, &self.pass, &self.fail] {
+            let _ = gpio.set(false);
+        }
+    }
+}
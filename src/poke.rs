@@ -0,0 +1,79 @@
+use probe_rs::{MemoryInterface, Session};
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::nvmc::{self, FLASH_END, FLASH_PAGE_SIZE, FLASH_START};
+use crate::RecoveryError;
+
+/// Patch individual flash words in place.
+///
+/// For every page touched by `writes` the page is read back, patched, erased
+/// and rewritten through the NVMC. Pages whose contents would not change are
+/// left alone.
+pub fn poke_flash(session: &mut Session, writes: &[(u64, u32)]) -> Result<(), RecoveryError> {
+    // Group writes by containing page so each page is only erased once.
+    let mut pages: BTreeMap<u64, Vec<(u64, u32)>> = BTreeMap::new();
+    for &(addr, value) in writes {
+        if addr % 4 != 0 {
+            return Err(RecoveryError::PokeError(format!(
+                "Address 0x{:08x} is not word aligned",
+                addr
+            )));
+        }
+        if !(FLASH_START..FLASH_END).contains(&addr) {
+            return Err(RecoveryError::PokeError(format!(
+                "Address 0x{:08x} is outside of flash",
+                addr
+            )));
+        }
+        pages
+            .entry(addr & !(FLASH_PAGE_SIZE - 1))
+            .or_default()
+            .push((addr, value));
+    }
+
+    let mut core = session.core(0)?;
+    core.halt(Duration::from_millis(100))?;
+
+    for (page, writes) in pages {
+        let mut words = vec![0u32; (FLASH_PAGE_SIZE / 4) as usize];
+        core.read_32(page, &mut words)?;
+
+        let original = words.clone();
+        for (addr, value) in writes {
+            let index = ((addr - page) / 4) as usize;
+            log::info!(
+                "0x{:08x}: 0x{:08x} -> 0x{:08x}",
+                addr,
+                words[index],
+                value
+            );
+            words[index] = value;
+        }
+
+        if words == original {
+            println!("Page 0x{:08x} already up to date", page);
+            continue;
+        }
+
+        nvmc::erase_page(&mut core, page)?;
+        nvmc::write_words(&mut core, page, &words)?;
+
+        // Verify the rewritten page.
+        let mut readback = vec![0u32; words.len()];
+        core.read_32(page, &mut readback)?;
+        if readback != words {
+            return Err(RecoveryError::PokeError(format!(
+                "Verify failed for page 0x{:08x}",
+                page
+            )));
+        }
+
+        println!("Patched page 0x{:08x}", page);
+    }
+
+    core.reset()?;
+
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use probe_rs::probe::Probe;
+use probe_rs::{MemoryInterface, Session};
+
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::RecoveryError;
+
+const POWER_RESETREAS: u64 = 0x50005400; // Secure POWER
+const REGULATORS_DCDCEN: u64 = 0x50004578; // Secure REGULATORS
+
+/// RESETREAS bits, from bit 0 up.
+const RESET_SOURCES: [&str; 7] = ["reset pin", "watchdog", "wake from System OFF", "debug interface", "soft reset", "CPU lockup", "CTRL-AP"];
+
+const VTREF_INTERVAL: Duration = Duration::from_millis(50);
+/// Lowest VTref the nRF91's GPIO supply is specified for.
+const VTREF_MIN: f32 = 1.7;
+/// Ripple above which the supply is reported as unstable.
+const VTREF_RIPPLE: f32 = 0.1;
+
+/// Target voltage samples taken through the probe.
+#[derive(Clone, Copy, Debug)]
+pub struct Vtref {
+    pub min: f32,
+    pub max: f32,
+    pub samples: usize,
+}
+
+/// Power-related state of the device, to tell a firmware brick from a
+/// marginal supply.
+#[derive(Debug, Default)]
+pub struct PowerHealth {
+    /// `None` if the probe cannot measure the target voltage.
+    pub vtref: Option<Vtref>,
+    /// POWER.RESETREAS, if the device is unlocked.
+    pub reset_reason: Option<u32>,
+    /// Whether the DC/DC regulator is enabled, if the device is unlocked.
+    pub dcdc: Option<bool>,
+}
+
+/// Sample the probe's VTref for `window`.
+pub fn sample_vtref(probe: &mut Probe, window: Duration) -> Option<Vtref> {
+    let start = Instant::now();
+    let mut vtref: Option<Vtref> = None;
+    loop {
+        match probe.get_target_voltage() {
+            Ok(Some(volts)) => {
+                let v = vtref.get_or_insert(Vtref {
+                    min: volts,
+                    max: volts,
+                    samples: 0,
+                });
+                v.min = v.min.min(volts);
+                v.max = v.max.max(volts);
+                v.samples += 1;
+            }
+            Ok(None) => return None,
+            Err(e) => {
+                log::info!("Unable to read target voltage: {}", e);
+                return None;
+            }
+        }
+        if start.elapsed() >= window {
+            return vtref;
+        }
+        thread::sleep(VTREF_INTERVAL);
+    }
+}
+
+impl PowerHealth {
+    /// Read the reset reason and regulator state. The reset reason is read,
+    /// not cleared, so the firmware still sees it.
+    pub fn read_registers(&mut self, session: &mut Session) -> Result<(), RecoveryError> {
+        let mut core = session.core(0)?;
+        self.reset_reason = Some(core.read_word_32(POWER_RESETREAS)?);
+        self.dcdc = Some(core.read_word_32(REGULATORS_DCDCEN)? & 1 == 1);
+        Ok(())
+    }
+}
+
+impl fmt::Display for PowerHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Power health:")?;
+        match &self.vtref {
+            Some(v) => {
+                write!(f, "  VTref:        {:.2}..{:.2} V over {} samples", v.min, v.max, v.samples)?;
+                if v.min < VTREF_MIN {
+                    write!(f, " (LOW)")?;
+                } else if v.max - v.min > VTREF_RIPPLE {
+                    write!(f, " (UNSTABLE)")?;
+                }
+                writeln!(f)?;
+            }
+            None => writeln!(f, "  VTref:        not measured by this probe")?,
+        }
+        match self.reset_reason {
+            Some(0) => writeln!(f, "  Reset reason: power-on or brown-out")?,
+            Some(bits) => {
+                let sources: Vec<&str> = RESET_SOURCES
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| bits & (1 << i) != 0)
+                    .map(|(_, name)| *name)
+                    .collect();
+                writeln!(f, "  Reset reason: {} (0x{:08x})", sources.join(", "), bits)?;
+            }
+            None => writeln!(f, "  Reset reason: unreadable (device locked)")?,
+        }
+        match self.dcdc {
+            Some(enabled) => writeln!(f, "  DC/DC:        {}", if enabled { "enabled" } else { "disabled" })?,
+            None => writeln!(f, "  DC/DC:        unreadable (device locked)")?,
+        }
+        Ok(())
+    }
+}
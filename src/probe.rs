@@ -0,0 +1,383 @@
+use clap::ValueEnum;
+use probe_rs::architecture::arm::ap::{ApRegister, CSW};
+use probe_rs::architecture::arm::{ArmError, DapError};
+use probe_rs::probe::{list::Lister, DebugProbeError, DebugProbeInfo, Probe, ProbeCreationError};
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::board::{CIRCUIT_DOJO_PID, CIRCUIT_DOJO_VID};
+use crate::dap::DebugPorts;
+use crate::i18n::tr;
+use crate::prompt::ask;
+use crate::output::{self, say};
+use crate::{strict, ProbeArgs, RecoveryError};
+
+/// SWD clocks in kHz to fall back to, fastest first, while the target
+/// answers WAIT or FAULT, as it does over long or flat cables.
+pub const SPEED_STEPS: [u32; 3] = [12000, 4000, 1000];
+
+/// Probe settings for the USB host the station runs on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum UsbProfile {
+    /// Desktop and laptop hosts.
+    #[default]
+    Default,
+    /// Hosts with a slow or shared USB controller, such as the Raspberry Pi
+    /// 3, where full-speed SWD transfers occasionally time out.
+    SlowHost,
+}
+
+impl UsbProfile {
+    /// SWD clock in kHz.
+    pub fn swd_khz(self) -> u32 {
+        match self {
+            UsbProfile::Default => 12000,
+            // Trades throughput for margin on hosts where full-speed
+            // transfers see errors.
+            UsbProfile::SlowHost => 4000,
+        }
+    }
+
+    /// Lower bound for the probe open timeout. Probes take longer to
+    /// enumerate behind a slow hub.
+    pub fn min_timeout(self) -> Duration {
+        match self {
+            UsbProfile::Default => Duration::ZERO,
+            UsbProfile::SlowHost => Duration::from_secs(5),
+        }
+    }
+
+    /// Lower bound for the interval between probe open attempts, so polling
+    /// does not compete with the enumeration it is waiting for.
+    pub fn min_poll_interval(self) -> Duration {
+        match self {
+            UsbProfile::Default => Duration::ZERO,
+            UsbProfile::SlowHost => Duration::from_millis(250),
+        }
+    }
+}
+
+/// A probe type by its USB IDs, given as `VID:PID` in hex, e.g. `2e8a:000c`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UsbId {
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+impl FromStr for UsbId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (vid, pid) = s
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| format!("expected VID:PID, got '{}'", s))?;
+        let hex = |part: &str| {
+            let part = part.trim();
+            let digits = part.strip_prefix("0x").or_else(|| part.strip_prefix("0X")).unwrap_or(part);
+            u16::from_str_radix(digits, 16).map_err(|e| format!("invalid USB ID '{}': {}", part, e))
+        };
+        Ok(UsbId {
+            vendor_id: hex(vid)?,
+            product_id: hex(pid)?,
+        })
+    }
+}
+
+impl fmt::Display for UsbId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor_id, self.product_id)
+    }
+}
+
+/// What to do about an error returned while opening the debug probe.
+pub enum OpenFailure {
+    /// The probe is missing or busy, e.g. still enumerating after being
+    /// plugged in. Worth retrying until the timeout.
+    Transient,
+    /// Retrying will not help. Carries a hint for the operator.
+    Permanent(&'static str),
+}
+
+const PERMISSION_HINT: &str = if cfg!(target_os = "linux") {
+    "permission denied opening the probe; install the probe-rs udev rules (https://probe.rs/docs/getting-started/probe-setup/) and replug it"
+} else {
+    "permission denied opening the probe; check that no other tool is using it"
+};
+
+const DRIVER_HINT: &str = if cfg!(windows) {
+    "the probe driver is not supported; install the WinUSB driver for it (e.g. with Zadig)"
+} else {
+    "the probe driver is not supported on this system"
+};
+
+fn classify_io(e: &io::Error) -> OpenFailure {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => OpenFailure::Permanent(PERMISSION_HINT),
+        io::ErrorKind::Unsupported => OpenFailure::Permanent(DRIVER_HINT),
+        _ => OpenFailure::Transient,
+    }
+}
+
+/// Sort a probe open error into one that is worth retrying or not.
+pub fn classify(e: &DebugProbeError) -> OpenFailure {
+    match e {
+        DebugProbeError::Usb(e) => classify_io(e),
+        DebugProbeError::ProbeCouldNotBeCreated(e) => match e {
+            ProbeCreationError::NotFound | ProbeCreationError::CouldNotOpen => OpenFailure::Transient,
+            ProbeCreationError::Usb(e) => classify_io(e),
+            // hidapi only reports text, so look for the usual permission failure.
+            ProbeCreationError::HidApi(e) if e.to_string().contains("Permission denied") => {
+                OpenFailure::Permanent(PERMISSION_HINT)
+            }
+            ProbeCreationError::HidApi(_) => OpenFailure::Transient,
+            _ => OpenFailure::Permanent("the probe reported an error while opening; check its firmware"),
+        },
+        _ => OpenFailure::Permanent("the probe reported an error while opening; check its firmware"),
+    }
+}
+
+/// Try the handshake with the debug port of `ports` once, handing the probe
+/// back either way.
+fn handshake(mut probe: Probe, ports: &DebugPorts) -> (Probe, Result<(), String>) {
+    if let Err(e) = probe.attach_to_unspecified() {
+        return (probe, Err(e.to_string()));
+    }
+    let iface = match probe.try_into_arm_interface() {
+        Ok(iface) => iface,
+        Err((probe, e)) => return (probe, Err(e.to_string())),
+    };
+    let (mut probe, result) = match iface.initialize_unspecified(ports.dp()) {
+        Ok(iface) => (iface.close(), Ok(())),
+        Err((iface, e)) => (iface.close(), Err(e.to_string())),
+    };
+    // Leave the probe as it was opened so the normal flow can attach again.
+    let _ = probe.detach();
+    (probe, result)
+}
+
+/// Whether `e`, or an error behind it, is a WAIT or FAULT answer from the
+/// target.
+fn wait_or_fault(e: &(dyn Error + 'static)) -> bool {
+    let mut next = Some(e);
+    while let Some(e) = next {
+        if matches!(e.downcast_ref::<DapError>(), Some(DapError::WaitResponse | DapError::FaultResponse))
+            || matches!(
+                e.downcast_ref::<ArmError>(),
+                Some(ArmError::Dap(DapError::WaitResponse | DapError::FaultResponse))
+            )
+        {
+            return true;
+        }
+        next = e.source();
+    }
+    false
+}
+
+/// Power up the debug port and read CSW of the memory access port of
+/// `ports`, handing the probe back either way. Returns the error if the
+/// target answered WAIT or FAULT; any other failure, such as no target at
+/// all, says nothing about the clock.
+fn marginal_link(mut probe: Probe, ports: &DebugPorts) -> (Probe, Option<String>) {
+    if probe.attach_to_unspecified().is_err() {
+        return (probe, None);
+    }
+    let iface = match probe.try_into_arm_interface() {
+        Ok(iface) => iface,
+        Err((probe, _)) => return (probe, None),
+    };
+    let (mut probe, problem) = match iface.initialize_unspecified(ports.dp()) {
+        Ok(mut iface) => {
+            let problem = match iface.read_raw_ap_register(&ports.mem_ap(), CSW::ADDRESS) {
+                Err(e) if wait_or_fault(&e) => Some(e.to_string()),
+                _ => None,
+            };
+            (iface.close(), problem)
+        }
+        Err((iface, e)) => (iface.close(), wait_or_fault(&e).then(|| e.to_string())),
+    };
+    let _ = probe.detach();
+    (probe, problem)
+}
+
+fn set_speed(probe: &mut Probe, khz: u32) -> Result<(), RecoveryError> {
+    match probe.set_speed(khz) {
+        Ok(actual) if actual < khz => strict::warn(RecoveryError::ProbeSpeedError(format!(
+            "SWD runs at {} kHz instead of {} kHz",
+            actual, khz
+        ))),
+        Ok(_) => Ok(()),
+        Err(e) => strict::warn(RecoveryError::ProbeSpeedError(format!(
+            "unable to set SWD speed to {} kHz: {}",
+            khz, e
+        ))),
+    }
+}
+
+/// Set the SWD clock to `khz`, then step down through [`SPEED_STEPS`] while
+/// the target answers WAIT or FAULT to an access port read. Without a
+/// target there is nothing to test and `khz` is kept. Each step down is a
+/// [`strict::warn`]ing, as the run goes on at a degraded speed.
+pub fn negotiate_speed(mut probe: Probe, khz: u32, ports: &DebugPorts) -> Result<Probe, RecoveryError> {
+    let mut steps = std::iter::once(khz).chain(SPEED_STEPS.into_iter().filter(|step| *step < khz)).peekable();
+    while let Some(khz) = steps.next() {
+        set_speed(&mut probe, khz)?;
+        let Some(&slower) = steps.peek() else { break };
+        let problem;
+        (probe, problem) = marginal_link(probe, ports);
+        match problem {
+            Some(e) => strict::warn(RecoveryError::ProbeSpeedError(tr!("speed_lowered", from = khz, to = slower, error = e)))?,
+            None => break,
+        }
+    }
+    Ok(probe)
+}
+
+/// Retry the DP handshake until a target answers or `timeout` runs out.
+///
+/// Used with probes that stay connected to a fixture while boards are
+/// swapped in and out.
+pub fn wait_for_target(mut probe: Probe, ports: &DebugPorts, timeout: Duration, interval: Duration) -> Result<Probe, RecoveryError> {
+    let start = Instant::now();
+    let mut waiting = false;
+    loop {
+        let result;
+        (probe, result) = handshake(probe, ports);
+        match result {
+            Ok(()) => {
+                if waiting {
+                    say(tr!("target_detected"));
+                }
+                return Ok(probe);
+            }
+            Err(e) => {
+                log::debug!("No target yet: {}", e);
+                if start.elapsed() >= timeout {
+                    return Err(RecoveryError::TargetTimeout(timeout));
+                }
+                if !waiting {
+                    say(tr!("waiting_for_target"));
+                    waiting = true;
+                }
+                thread::sleep(interval);
+            }
+        }
+    }
+}
+
+/// The connected probes `args` selects: any supported probe, narrowed down
+/// by whichever of VID, PID, `--probe` types and serial number are given.
+/// The order is the same on every call, so the first one is a
+/// deterministic pick: the `--probe` types in the order given, else Circuit
+/// Dojo programmers first, then by VID:PID and serial number.
+pub fn candidates(lister: &Lister, args: &ProbeArgs) -> Vec<DebugProbeInfo> {
+    let preference = |p: &DebugProbeInfo| {
+        args.probes
+            .iter()
+            .position(|id| (id.vendor_id, id.product_id) == (p.vendor_id, p.product_id))
+    };
+    let mut probes: Vec<DebugProbeInfo> = lister
+        .list_all()
+        .into_iter()
+        .filter(|p| args.vendor_id.is_none_or(|vid| p.vendor_id == vid))
+        .filter(|p| args.product_id.is_none_or(|pid| p.product_id == pid))
+        .filter(|p| args.probes.is_empty() || preference(p).is_some())
+        .filter(|p| args.serial.is_none() || p.serial_number == args.serial)
+        .collect();
+    probes.sort_by_key(|p| {
+        (
+            preference(p),
+            (p.vendor_id, p.product_id) != (CIRCUIT_DOJO_VID, CIRCUIT_DOJO_PID),
+            p.vendor_id,
+            p.product_id,
+            p.serial_number.clone(),
+        )
+    });
+    probes
+}
+
+/// Settle on one probe when `args` leaves the VID:PID open, so every time
+/// the run opens the probe it gets the same one. With several candidates
+/// and an operator at the terminal, the operator picks; otherwise the first
+/// candidate is taken. Nothing is pinned if no probe is connected yet; the
+/// probe is then looked for until the timeout, as usual.
+pub fn pin(args: &mut ProbeArgs) -> Result<(), RecoveryError> {
+    if args.vendor_id.is_some() && args.product_id.is_some() {
+        return Ok(());
+    }
+    let probes = candidates(&Lister::new(), args);
+    let info = match probes.as_slice() {
+        [] => return Ok(()),
+        [only] => only,
+        _ if io::stdin().is_terminal() && !output::json() => {
+            println!("{}", tr!("probe_choose"));
+            for (i, probe) in probes.iter().enumerate() {
+                println!("  {}) {}", i + 1, probe);
+            }
+            let answer = ask(&tr!("probe_choice", count = probes.len())).ok_or(RecoveryError::Cancelled)?;
+            match answer.parse::<usize>() {
+                Ok(n) if (1..=probes.len()).contains(&n) => &probes[n - 1],
+                _ => return Err(RecoveryError::Cancelled),
+            }
+        }
+        [first, ..] => {
+            log::warn!("{} probes connected, using {}; select one with --serial", probes.len(), first);
+            first
+        }
+    };
+    say(tr!("probe_selected", probe = info));
+    args.vendor_id = Some(info.vendor_id);
+    args.product_id = Some(info.product_id);
+    args.serial = args.serial.take().or_else(|| info.serial_number.clone());
+    Ok(())
+}
+
+/// Serial numbers of the connected probes `args` selects, in the order of
+/// [`candidates`]. Probes without a serial number are left out, since they
+/// cannot be told apart.
+pub fn serials(args: &ProbeArgs) -> Vec<String> {
+    candidates(&Lister::new(), args)
+        .into_iter()
+        .filter_map(|p| p.serial_number)
+        .collect()
+}
+
+/// A connected probe as `recovery probes` reports it.
+pub struct ProbeStatus {
+    pub info: DebugProbeInfo,
+    /// probe-rs driver name, if the probe could be opened.
+    pub driver: Option<String>,
+    /// Whether a target answered the DP handshake, or why not.
+    pub target: Result<(), String>,
+}
+
+/// Every connected probe, each opened in turn to see whether a target
+/// answers on the debug port of `ports`. Probes in use by another tool fail
+/// to open and say so.
+pub fn survey(ports: &DebugPorts) -> Vec<ProbeStatus> {
+    Lister::new()
+        .list_all()
+        .into_iter()
+        .map(|info| match info.open() {
+            Ok(probe) => {
+                let driver = probe.get_name();
+                let (_probe, target) = handshake(probe, ports);
+                ProbeStatus {
+                    info,
+                    driver: Some(driver),
+                    target: target.map_err(|e| format!("no response: {}", e)),
+                }
+            }
+            Err(e) => ProbeStatus {
+                info,
+                driver: None,
+                target: Err(format!("cannot open the probe: {}", e)),
+            },
+        })
+        .collect()
+}
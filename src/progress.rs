@@ -0,0 +1,129 @@
+use probe_rs::flashing::{FlashProgress, ProgressEvent, ProgressOperation};
+
+use std::cell::RefCell;
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use crate::i18n::tr;
+
+const WIDTH: usize = 30;
+/// Redraw at most this often, so slow terminals do not hold up flashing.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// One phase of a flash operation: erase, program, verify or fill.
+struct Phase {
+    operation: ProgressOperation,
+    total: Option<u64>,
+    done: u64,
+    started: Instant,
+    drawn: Option<Instant>,
+}
+
+impl Phase {
+    fn label(&self) -> String {
+        match self.operation {
+            ProgressOperation::Erase => tr!("progress_erase"),
+            ProgressOperation::Program => tr!("progress_program"),
+            ProgressOperation::Verify => tr!("progress_verify"),
+            ProgressOperation::Fill => tr!("progress_fill"),
+        }
+    }
+
+    fn draw(&mut self, last: bool) {
+        let now = Instant::now();
+        if !last && self.drawn.is_some_and(|drawn| now - drawn < REDRAW_INTERVAL) {
+            return;
+        }
+        self.drawn = Some(now);
+
+        let line = match self.total.filter(|total| *total > 0) {
+            Some(total) => {
+                let done = self.done.min(total);
+                let filled = (done * WIDTH as u64 / total) as usize;
+                let elapsed = self.started.elapsed();
+                let eta = match done {
+                    0 => String::new(),
+                    _ if last => format!("{:.1}s", elapsed.as_secs_f64()),
+                    _ => tr!(
+                        "progress_eta",
+                        eta = format!("{}s", (elapsed.as_secs_f64() * (total - done) as f64 / done as f64).ceil())
+                    ),
+                };
+                format!(
+                    "{:<14} [{}{}] {:>3}% {:>5}/{} KiB {}",
+                    self.label(),
+                    "#".repeat(filled),
+                    ".".repeat(WIDTH - filled),
+                    done * 100 / total,
+                    done / 1024,
+                    total / 1024,
+                    eta
+                )
+            }
+            None => format!("{:<14} {} KiB", self.label(), self.done / 1024),
+        };
+
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[2K{}", line);
+        if last {
+            let _ = writeln!(stderr);
+        }
+        let _ = stderr.flush();
+    }
+}
+
+/// probe-rs announces the phases of every flash algorithm up front, then
+/// runs them in the same order.
+#[derive(Default)]
+struct Bars {
+    announced: Vec<(ProgressOperation, Option<u64>)>,
+    current: Option<Phase>,
+}
+
+impl Bars {
+    /// Size of the next announced phase of `operation`.
+    fn take_total(&mut self, operation: ProgressOperation) -> Option<u64> {
+        let same = |op: &ProgressOperation| std::mem::discriminant(op) == std::mem::discriminant(&operation);
+        let index = self.announced.iter().position(|(op, _)| same(op))?;
+        self.announced.remove(index).1
+    }
+
+    fn handle(&mut self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::AddProgressBar { operation, total } => self.announced.push((operation, total)),
+            ProgressEvent::Started(operation) => {
+                self.current = Some(Phase {
+                    operation,
+                    total: self.take_total(operation),
+                    done: 0,
+                    started: Instant::now(),
+                    drawn: None,
+                });
+            }
+            ProgressEvent::Progress { size, .. } => {
+                if let Some(phase) = &mut self.current {
+                    phase.done += size;
+                    phase.draw(false);
+                }
+            }
+            ProgressEvent::Finished(_) | ProgressEvent::Failed(_) => {
+                if let Some(mut phase) = self.current.take() {
+                    phase.draw(true);
+                }
+            }
+            ProgressEvent::DiagnosticMessage { message } => log::info!("{}", message),
+            _ => {}
+        }
+    }
+}
+
+/// Progress bars for the erase, program and verify phases of a flash
+/// operation, drawn on stderr. `None` if stderr is not a terminal, so logs
+/// and pipes stay free of control characters.
+pub fn flash_progress() -> Option<FlashProgress<'static>> {
+    if !io::stderr().is_terminal() {
+        return None;
+    }
+    let bars = RefCell::new(Bars::default());
+    Some(FlashProgress::new(move |event| bars.borrow_mut().handle(event)))
+}
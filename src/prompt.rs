@@ -0,0 +1,20 @@
+use std::io::{self, BufRead, Write};
+
+/// Ask the operator a question on stdin. Returns the trimmed answer, or
+/// `None` if stdin was closed.
+pub fn ask(question: &str) -> Option<String> {
+    print!("{}", question);
+    let _ = io::stdout().flush();
+
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim().to_string()),
+    }
+}
+
+/// Ask the operator a yes/no question. `assume_yes` answers it without
+/// asking; otherwise anything but y or yes, or a closed stdin, is no.
+pub fn confirm(question: &str, assume_yes: bool) -> bool {
+    assume_yes || matches!(ask(question).map(|a| a.to_ascii_lowercase()).as_deref(), Some("y" | "yes"))
+}
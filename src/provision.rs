@@ -0,0 +1,92 @@
+use probe_rs::Session;
+
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::image::{ImageFile, Segment};
+use crate::uicr;
+use crate::RecoveryError;
+
+/// Start of UICR.OTP on nRF91, where NSIB keeps its provisioned data.
+pub const PROVISION_ADDRESS: u64 = 0x00FF8108;
+/// End of UICR.OTP.
+pub const PROVISION_END: u64 = 0x00FF8400;
+
+/// Where NSIB's `provision.hex` puts the key hashes, slot addresses and
+/// monotonic counters.
+pub const PROVISION: Range<u64> = PROVISION_ADDRESS..PROVISION_END;
+
+/// Split `segments` into the data outside the provisioned area and the data
+/// inside it.
+pub fn split(segments: &[Segment]) -> (Vec<Segment>, Vec<Segment>) {
+    let mut outside = Vec::new();
+    let mut inside = Vec::new();
+    for segment in segments {
+        let end = segment.address + segment.data.len() as u64;
+        let mut address = segment.address;
+        while address < end {
+            let (stop, part) = if PROVISION.contains(&address) {
+                (end.min(PROVISION_END), &mut inside)
+            } else if address < PROVISION_ADDRESS {
+                (end.min(PROVISION_ADDRESS), &mut outside)
+            } else {
+                (end, &mut outside)
+            };
+            let offset = (address - segment.address) as usize;
+            part.push(Segment {
+                address,
+                data: segment.data[offset..offset + (stop - address) as usize].to_vec(),
+            });
+            address = stop;
+        }
+    }
+    (outside, inside)
+}
+
+/// The provisioning data in `file`, read a window at a time.
+pub fn find(file: &ImageFile) -> Result<Vec<Segment>, RecoveryError> {
+    let mut found = Vec::new();
+    file.stream(0x10000, |segments| {
+        found.extend(split(segments).1);
+        Ok(())
+    })?;
+    Ok(found)
+}
+
+/// Write provisioning data word by word, without erasing UICR.
+///
+/// UICR.OTP words can only have bits cleared, and probe-rs would erase all
+/// of UICR to program it like flash, taking the protection words and any
+/// raised counters with it. Words the data leaves erased, such as unused
+/// counter slots, are not touched, so counters raised since the device was
+/// provisioned survive. A word that would need a bit set again fails: the
+/// device holds other provisioning and has to be erased first. Returns the
+/// number of words written.
+pub fn write(session: &mut Session, segments: &[Segment]) -> Result<usize, RecoveryError> {
+    let mut words: Vec<(u64, u32)> = Vec::new();
+    for segment in segments {
+        for (i, byte) in segment.data.iter().enumerate() {
+            let address = segment.address + i as u64;
+            let word = address & !3;
+            let shift = (address - word) * 8;
+            match words.last_mut() {
+                Some((last, value)) if *last == word => *value &= !(0xFF << shift) | ((*byte as u32) << shift),
+                _ => words.push((word, !(0xFF << shift) | ((*byte as u32) << shift))),
+            }
+        }
+    }
+
+    session.core(0)?.halt(Duration::from_millis(100))?;
+    let mut written = 0;
+    for (address, value) in words.into_iter().filter(|(_, value)| *value != 0xFFFF_FFFF) {
+        uicr::write_bytes(session, address, &value.to_le_bytes()).map_err(|e| match e {
+            RecoveryError::UicrError(e) => RecoveryError::UicrError(format!(
+                "provisioning data: {}; the device holds other provisioning, use --force-unlock to erase it",
+                e
+            )),
+            e => e,
+        })?;
+        written += 1;
+    }
+    Ok(written)
+}
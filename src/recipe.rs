@@ -0,0 +1,145 @@
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::detach::DetachMode;
+use crate::image::ImageSpec;
+use crate::verify::VerifyLevel;
+use crate::version::{ImageVersion, VersionSource};
+use crate::{config, parse_duration, RecoveryError};
+
+/// Everything a station needs to program one product, loaded with
+/// `--recipe` instead of spelling it out on the command line. Settings given
+/// on the command line win.
+///
+/// ```toml
+/// chip = "nRF9151_xxAA"
+/// image = "sku123/merged.hex"
+/// verify_level = "crc"
+///
+/// [probe]
+/// vendor_id = 0x2e8a
+/// product_id = 0x000c
+/// timeout = 5000
+/// wait_for_target = "30s"
+///
+/// [[uicr]]
+/// address = 0x00FF8080
+/// value = 0x00000123
+///
+/// [checks]
+/// boot = 500
+/// shell_script = "sku123/selftest.toml"
+/// expect_version = "2.4.1"
+/// version_source = "mcuboot:0x10000"
+/// read_imei = true
+/// uicr_template = "sku123/uicr.json"
+/// max_cycle_time = "45s"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Recipe {
+    pub chip: Option<String>,
+    /// Path or URL of the image, relative to the recipe, as `PATH[@ADDR]`.
+    #[serde(default, deserialize_with = "parsed")]
+    pub image: Option<ImageSpec>,
+    pub verify_level: Option<VerifyLevel>,
+    #[serde(default)]
+    pub force_erase: bool,
+    pub detach: Option<DetachMode>,
+    #[serde(default)]
+    pub probe: RecipeProbe,
+    /// UICR words written after flashing.
+    #[serde(default)]
+    pub uicr: Vec<UicrWord>,
+    #[serde(default)]
+    pub checks: RecipeChecks,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecipeProbe {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub serial: Option<String>,
+    /// Timeout in milliseconds for the probe connection.
+    pub timeout: Option<u64>,
+    #[serde(default, deserialize_with = "duration")]
+    pub wait_for_target: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UicrWord {
+    pub address: u64,
+    pub value: u32,
+}
+
+/// What to check once the device runs the new firmware.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecipeChecks {
+    /// Milliseconds the firmware must run without faulting.
+    pub boot: Option<u64>,
+    pub shell_script: Option<PathBuf>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub expect_version: Option<ImageVersion>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub version_source: Option<VersionSource>,
+    #[serde(default)]
+    pub read_imei: bool,
+    pub uicr_template: Option<PathBuf>,
+    #[serde(default, deserialize_with = "duration")]
+    pub max_cycle_time: Option<Duration>,
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| parse_duration(&s))
+        .transpose()
+        .map_err(de::Error::custom)
+}
+
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse())
+        .transpose()
+        .map_err(de::Error::custom)
+}
+
+impl Recipe {
+    /// Load a recipe, making its local paths relative to the recipe file.
+    pub fn load(path: &Path) -> Result<Self, RecoveryError> {
+        let mut recipe: Recipe = config::load(path)?;
+        let base = path.parent().unwrap_or(Path::new("."));
+
+        // URLs are left to the artifact sources.
+        if let Some(image) = &mut recipe.image {
+            if !image.reference.to_string_lossy().contains("://") {
+                image.reference = base.join(&image.reference);
+            }
+        }
+        for file in [&mut recipe.checks.shell_script, &mut recipe.checks.uicr_template]
+            .into_iter()
+            .flatten()
+        {
+            *file = base.join(&*file);
+        }
+        if recipe.checks.expect_version.is_some() != recipe.checks.version_source.is_some() {
+            return Err(RecoveryError::ConfigError(format!(
+                "{}: expect_version and version_source go together",
+                path.display()
+            )));
+        }
+        Ok(recipe)
+    }
+}
@@ -0,0 +1,71 @@
+use probe_rs::config::{ChipFamily, Registry};
+use probe_rs::probe::Probe;
+use probe_rs::{Permissions, Session};
+
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::RecoveryError;
+
+/// Target description from `--flash-algo`, for chips probe-rs does not know.
+static FALLBACK: OnceLock<ChipFamily> = OnceLock::new();
+
+/// Load a probe-rs target description, as written by `target-gen` from a
+/// CMSIS pack, to fall back on for the rest of the process. Its flash
+/// algorithm and memory map are used for chips missing from probe-rs.
+pub fn init(path: &Path) -> Result<(), RecoveryError> {
+    let err = |e: String| RecoveryError::ConfigError(format!("--flash-algo {}: {}", path.display(), e));
+    let yaml = fs::read_to_string(path).map_err(|e| err(e.to_string()))?;
+    let mut registry = Registry::new();
+    let name = registry.add_target_family_from_yaml(&yaml).map_err(|e| err(e.to_string()))?;
+    let family = registry
+        .families()
+        .iter()
+        .find(|family| family.name == name)
+        .cloned()
+        .ok_or_else(|| err("no target family".into()))?;
+    if family.variants.is_empty() {
+        return Err(err("the description has no chips".into()));
+    }
+    let _ = FALLBACK.set(family);
+    Ok(())
+}
+
+/// probe-rs' built-in targets, plus the `--flash-algo` description.
+pub fn registry() -> Registry {
+    let mut registry = Registry::from_builtin_families();
+    if let Some(family) = FALLBACK.get() {
+        // Validated when it was loaded.
+        let _ = registry.add_target_family(family.clone());
+    }
+    registry
+}
+
+/// The target to use for `chip`: the built-in one if probe-rs knows the
+/// chip, otherwise the `--flash-algo` chip of that name or, failing that,
+/// its first chip.
+pub fn resolve(chip: &str) -> String {
+    let Some(family) = FALLBACK.get() else {
+        return chip.to_string();
+    };
+    if Registry::from_builtin_families().get_target_by_name(chip).is_ok() {
+        return chip.to_string();
+    }
+    let variant = family
+        .variants
+        .iter()
+        .find(|variant| variant.name.eq_ignore_ascii_case(chip))
+        .unwrap_or(&family.variants[0]);
+    log::info!("{} is not a probe-rs target, using {} from --flash-algo", chip, variant.name);
+    variant.name.clone()
+}
+
+/// Attach to `chip`, falling back on the `--flash-algo` description when
+/// probe-rs does not know it.
+pub fn attach(probe: Probe, chip: &str, permissions: Permissions) -> Result<Session, probe_rs::Error> {
+    if FALLBACK.get().is_none() {
+        return probe.attach(chip, permissions);
+    }
+    probe.attach_with_registry(resolve(chip).as_str(), permissions, &registry())
+}
@@ -0,0 +1,241 @@
+use clap::{Parser, Subcommand};
+use probe_rs::{Permissions, Session};
+
+use std::path::Path;
+use std::time::Duration;
+
+use crate::artifact::Resolver;
+use crate::debug;
+use crate::device;
+use crate::flow::{self, FlowOptions, Stage};
+use crate::hooks::Hooks;
+use crate::i18n::tr;
+use crate::image::ImageSpec;
+use crate::prompt::{ask, confirm};
+use crate::registry;
+use crate::symbols::{parse_location, Location, Symbols};
+use crate::uicr;
+use crate::{check_locked, open_probe, parse_number, parse_word, ProbeArgs, RecoveryError};
+
+const HALT_TIMEOUT: Duration = Duration::from_millis(100);
+
+// One line typed at the prompt.
+#[derive(Parser)]
+#[command(no_binary_name = true, disable_version_flag = true)]
+struct Line {
+    #[command(subcommand)]
+    command: ReplCommand,
+}
+
+#[derive(Subcommand)]
+enum ReplCommand {
+    #[command(about = "Show the lock state, the device's factory information and the core state")]
+    Status,
+    #[command(about = "Mass erase flash and UICR")]
+    Erase,
+    #[command(about = "Flash images and reset the device, without unlocking or writing UICR")]
+    Flash {
+        #[arg(required = true, value_name = "IMAGE", help = "Paths or URLs of the images to flash, in order, as PATH[@ADDR]")]
+        images: Vec<ImageSpec>,
+    },
+    #[command(about = "Read or write UICR registers")]
+    Uicr {
+        #[command(subcommand)]
+        op: UicrCommand,
+    },
+    #[command(about = "Read or write target memory")]
+    Mem {
+        #[command(subcommand)]
+        op: MemCommand,
+
+        #[arg(long, global = true, help = "Halt the core before accessing memory and leave it halted")]
+        halt: bool,
+    },
+    #[command(about = "Reset the core")]
+    Reset {
+        #[arg(long, help = "Halt the core at the reset vector")]
+        halt: bool,
+    },
+    #[command(about = "Close the session and leave", alias = "exit")]
+    Quit,
+}
+
+#[derive(Subcommand)]
+enum UicrCommand {
+    #[command(about = "Print every UICR word that is not erased")]
+    Read,
+    #[command(about = "Write a word to UICR")]
+    Write {
+        #[arg(value_parser = parse_number, help = "Address, word aligned")]
+        addr: u64,
+
+        #[arg(value_parser = parse_word, help = "Value to write")]
+        value: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum MemCommand {
+    #[command(about = "Read 32-bit words from memory")]
+    Read {
+        #[arg(value_parser = parse_location, help = "Start address, or symbol:NAME[+OFFSET]")]
+        addr: Location,
+
+        #[arg(default_value_t = 1, help = "Number of words to read")]
+        count: usize,
+    },
+    #[command(about = "Write 32-bit words to memory")]
+    Write {
+        #[arg(value_parser = parse_location, help = "Start address, or symbol:NAME[+OFFSET]")]
+        addr: Location,
+
+        #[arg(required = true, value_parser = parse_word, help = "Words to write")]
+        values: Vec<u32>,
+    },
+}
+
+/// An interactive bring-up session: commands typed at a prompt share one
+/// attached session instead of opening the probe for each.
+pub struct Repl<'a> {
+    probe: &'a ProbeArgs,
+    /// Chip, ports and flash settings; the images come from `flash`.
+    options: FlowOptions,
+    resolver: &'a Resolver,
+    symbols: Option<&'a Symbols>,
+    session: Option<Session>,
+}
+
+impl<'a> Repl<'a> {
+    pub fn new(probe: &'a ProbeArgs, options: FlowOptions, resolver: &'a Resolver, symbols: Option<&'a Symbols>) -> Self {
+        Repl {
+            probe,
+            options,
+            resolver,
+            symbols,
+            session: None,
+        }
+    }
+
+    /// Read commands from stdin until `quit` or end of input. A failed
+    /// command prints its error and leaves the session as it is.
+    pub fn run(&mut self) {
+        println!("{}", tr!("repl_welcome", chip = self.options.chip));
+        while let Some(line) = ask("recovery> ") {
+            let Some(words) = shlex::split(&line) else {
+                eprintln!("{}", tr!("repl_unbalanced_quotes"));
+                continue;
+            };
+            if words.is_empty() {
+                continue;
+            }
+            let command = match Line::try_parse_from(words) {
+                Ok(line) => line.command,
+                Err(e) => {
+                    let _ = e.print();
+                    continue;
+                }
+            };
+            if matches!(command, ReplCommand::Quit) {
+                break;
+            }
+            if let Err(e) = self.execute(command) {
+                eprintln!("Error: {}", e);
+            }
+        }
+    }
+
+    /// The attached session, attaching on first use. A locked device cannot
+    /// be attached to; `erase` unlocks it.
+    fn session(&mut self) -> Result<&mut Session, RecoveryError> {
+        if self.session.is_none() {
+            let probe = open_probe(self.probe, &self.options.ports)?;
+            self.session = Some(registry::attach(probe, self.options.chip.as_str(), Permissions::new())?);
+        }
+        Ok(self.session.as_mut().unwrap())
+    }
+
+    fn execute(&mut self, command: ReplCommand) -> Result<(), RecoveryError> {
+        match command {
+            ReplCommand::Status => self.status(),
+            ReplCommand::Erase => {
+                if !confirm(&tr!("erase_confirm"), false) {
+                    return Err(RecoveryError::Cancelled);
+                }
+                // The erase needs the bare probe; the next command attaches
+                // again.
+                self.session = None;
+                flow::erase(open_probe(self.probe, &self.options.ports)?, &self.options.chip, &self.options.ports)
+            }
+            ReplCommand::Flash { images } => {
+                let options = FlowOptions {
+                    images: images
+                        .iter()
+                        .map(|image| image.resolve(self.resolver, Path::new(".")))
+                        .collect::<Result<_, _>>()?,
+                    ..self.options.clone()
+                };
+                let session = self.session()?;
+                flow::flash_images(session, &options, &Hooks::default())?;
+                session
+                    .core(0)
+                    .and_then(|mut core| core.reset())
+                    .map_err(|e| RecoveryError::from(e).at(Stage::Reset))
+            }
+            ReplCommand::Uicr { op: UicrCommand::Read } => {
+                for (addr, value) in uicr::read(self.session()?)? {
+                    let name = uicr::name(addr).map(|name| format!(" {}", name)).unwrap_or_default();
+                    println!("0x{:08x}: 0x{:08x}{}", addr, value, name);
+                }
+                Ok(())
+            }
+            ReplCommand::Uicr {
+                op: UicrCommand::Write { addr, value },
+            } => uicr::write_bytes(self.session()?, addr, &value.to_le_bytes()),
+            ReplCommand::Mem { op, halt } => match op {
+                MemCommand::Read { addr, count } => {
+                    let addr = addr.resolve(self.symbols)?;
+                    debug::mem_read(self.session()?, addr, count, halt).map(|_| ())
+                }
+                MemCommand::Write { addr, values } => {
+                    let addr = addr.resolve(self.symbols)?;
+                    debug::mem_write(self.session()?, addr, &values, halt)
+                }
+            },
+            ReplCommand::Reset { halt } => {
+                let mut core = self.session()?.core(0)?;
+                if halt {
+                    core.reset_and_halt(HALT_TIMEOUT)?;
+                } else {
+                    core.reset()?;
+                }
+                Ok(())
+            }
+            ReplCommand::Quit => Ok(()),
+        }
+    }
+
+    /// Print the lock state, and on an unlocked device its factory
+    /// information and the core state. A locked device is checked through
+    /// the bare probe, since it cannot be attached to.
+    fn status(&mut self) -> Result<(), RecoveryError> {
+        if self.session.is_none() {
+            let (probe, locked) = check_locked(open_probe(self.probe, &self.options.ports)?, &self.options.ports)?;
+            println!("Locked:         {}", if locked { "yes" } else { "no" });
+            if locked {
+                return Ok(());
+            }
+            self.session = Some(registry::attach(probe, self.options.chip.as_str(), Permissions::new())?);
+        } else {
+            println!("Locked:         no");
+        }
+
+        let nrf91 = flow::is_nrf91(&self.options.chip);
+        let session = self.session()?;
+        if nrf91 {
+            print!("{}", device::info(session)?);
+        }
+        let mut core = session.core(0)?;
+        println!("Core:           {:?}", core.status()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use chrono::Utc;
+use serde::Serialize;
+
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use crate::clock::ClockStatus;
+use crate::job::TargetReport;
+use crate::station::Station;
+use crate::upload::Uploader;
+use crate::RecoveryError;
+
+/// Results queued before workers block on the writer.
+const QUEUE_DEPTH: usize = 16;
+
+/// One line of the results file.
+#[derive(Serialize)]
+struct Entry<'a> {
+    time: String,
+    #[serde(flatten)]
+    station: &'a Station,
+    clock: &'a ClockStatus,
+    job: &'a Path,
+    /// Unit label in batch runs.
+    unit: Option<&'a str>,
+    #[serde(flatten)]
+    target: &'a TargetReport,
+}
+
+/// Appends each finished target to a JSON lines file.
+///
+/// All writes go through one writer thread, so results recorded by parallel
+/// workers never interleave. The queue is bounded: a worker recording a result
+/// waits while the writer is behind. With an uploader, every line is also
+/// uploaded from the writer thread, after it is safely on disk.
+pub struct ResultsWriter {
+    path: PathBuf,
+    station: Station,
+    clock: ClockStatus,
+    sender: Option<SyncSender<String>>,
+    handle: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl ResultsWriter {
+    /// Open `path` for appending, stamping every line with `station` and the
+    /// clock check made before the run.
+    pub fn open(
+        path: &Path,
+        station: &Station,
+        clock: &ClockStatus,
+        uploader: Option<Uploader>,
+    ) -> Result<Self, RecoveryError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| RecoveryError::ReportError(format!("{}: {}", path.display(), e)))?;
+
+        let (sender, receiver) = mpsc::sync_channel::<String>(QUEUE_DEPTH);
+        let handle = thread::spawn(move || {
+            let mut out = BufWriter::new(file);
+            // Deliver what an earlier run left queued.
+            if let Some(uploader) = &uploader {
+                uploader.push(None);
+            }
+            for line in receiver {
+                writeln!(out, "{}", line)?;
+                // Keep the file complete up to the last result in case the
+                // station loses power.
+                out.flush()?;
+                if let Some(uploader) = &uploader {
+                    uploader.push(Some(&line));
+                }
+            }
+            out.into_inner().map_err(|e| e.into_error())?.sync_all()
+        });
+
+        Ok(ResultsWriter {
+            path: path.to_path_buf(),
+            station: station.clone(),
+            clock: clock.clone(),
+            sender: Some(sender),
+            handle: Some(handle),
+        })
+    }
+
+    /// Queue a finished target, waiting if the writer is behind.
+    pub fn record(&self, job: &Path, unit: Option<&str>, target: &TargetReport) {
+        let entry = Entry {
+            time: Utc::now().to_rfc3339(),
+            station: &self.station,
+            clock: &self.clock,
+            job,
+            unit,
+            target,
+        };
+        let line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(e) => return log::warn!("Unable to encode result: {}", e),
+        };
+        if let Some(sender) = &self.sender {
+            if sender.send(line).is_err() {
+                log::warn!("Results writer for {} stopped", self.path.display());
+            }
+        }
+    }
+
+    /// Write out everything queued and close the file.
+    pub fn finish(mut self) -> Result<(), RecoveryError> {
+        self.close()
+    }
+
+    fn close(&mut self) -> Result<(), RecoveryError> {
+        drop(self.sender.take());
+        match self.handle.take().map(|h| h.join()) {
+            Some(Ok(Err(e))) => Err(RecoveryError::ReportError(format!("{}: {}", self.path.display(), e))),
+            Some(Err(_)) => Err(RecoveryError::ReportError(format!("{}: writer panicked", self.path.display()))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for ResultsWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            log::warn!("{}", e);
+        }
+    }
+}
+
@@ -0,0 +1,180 @@
+use clap::ValueEnum;
+use probe_rs::{MemoryInterface, Session};
+use serde::Deserialize;
+
+use crate::image::{Image, ImageFile};
+use crate::provision::{PROVISION_ADDRESS, PROVISION_END};
+use crate::uicr;
+use crate::RecoveryError;
+
+/// `BL_COLLECTION_TYPE_MONOTONIC_COUNTERS` in NSIB's `bl_storage.h`.
+const COLLECTION_MONOTONIC_COUNTERS: u16 = 1;
+
+/// Magic words starting an NSIB `fw_info` structure. A third word holds
+/// hardware and version compatibility IDs and varies between builds.
+pub(crate) const FW_INFO_MAGIC: [u32; 2] = [0x281ee6de, 0x8fcebb4c];
+/// Offset of `version` in `fw_info`.
+const FW_INFO_VERSION: u64 = 20;
+
+/// MCUboot TLV holding the image's security counter.
+const IMAGE_TLV_SEC_CNT: u16 = 0x50;
+
+/// Which downgrade protection counter to provision.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CounterKind {
+    /// NSIB's counter for the S0/S1 slots, checked against `fw_info.version`.
+    Nsib,
+    /// MCUboot's hardware security counter for image 0, checked against the
+    /// image's security counter TLV.
+    Mcuboot,
+}
+
+impl CounterKind {
+    /// Counter description in the provisioned data.
+    fn id(self) -> u16 {
+        match self {
+            CounterKind::Nsib => 1,
+            CounterKind::Mcuboot => 2,
+        }
+    }
+
+    /// The counter value the images claim, from the first image that has
+    /// one.
+    pub fn image_version(self, images: &[ImageFile]) -> Result<u16, RecoveryError> {
+        for file in images {
+            let image = file.load()?;
+            let version = match self {
+                CounterKind::Nsib => fw_info_version(&image),
+                CounterKind::Mcuboot => image
+                    .mcuboot_header()
+                    .and_then(|header| image.mcuboot_tlv(header, IMAGE_TLV_SEC_CNT))
+                    .and_then(|value| Some(u32::from_le_bytes(value.try_into().ok()?))),
+            };
+            if let Some(version) = version {
+                return u16::try_from(version)
+                    .ok()
+                    .filter(|v| *v < u16::MAX)
+                    .ok_or_else(|| RecoveryError::RollbackError(format!("version {} does not fit the counter", version)));
+            }
+        }
+        Err(RecoveryError::RollbackError(match self {
+            CounterKind::Nsib => "no image has an NSIB fw_info structure".into(),
+            CounterKind::Mcuboot => "no image has an MCUboot security counter TLV".into(),
+        }))
+    }
+}
+
+/// `fw_info.version` of the first `fw_info` structure in the image.
+fn fw_info_version(image: &Image) -> Option<u32> {
+    let magic: Vec<u8> = FW_INFO_MAGIC.iter().flat_map(|w| w.to_le_bytes()).collect();
+    let address = image.segments.iter().find_map(|s| {
+        (0..s.data.len().saturating_sub(magic.len() - 1))
+            .step_by(4)
+            .find(|i| s.data[*i..*i + magic.len()] == magic[..])
+            .map(|i| s.address + i as u64)
+    })?;
+    let version = image.read(address + FW_INFO_VERSION, 4)?;
+    Some(u32::from_le_bytes(version.try_into().unwrap()))
+}
+
+/// A monotonic counter in the provisioned data.
+struct Counter {
+    /// Address of the first slot.
+    slots: u64,
+    /// Raw slot contents. Values are stored inverted, so an unwritten slot
+    /// reads 0xFFFF.
+    raw: Vec<u16>,
+}
+
+impl Counter {
+    fn value(&self) -> u16 {
+        self.raw.iter().map(|r| !r).max().unwrap_or(0)
+    }
+
+    fn free_slot(&self) -> Option<u64> {
+        self.raw.iter().position(|r| *r == 0xFFFF).map(|i| self.slots + 2 * i as u64)
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// Find counter `id` in the provisioned data, a `bl_storage_data` structure
+/// read from `PROVISION_ADDRESS`. NCS releases with lifecycle state put 8
+/// more bytes after the slot addresses; both layouts are tried.
+fn find_counter(data: &[u8], id: u16) -> Option<Counter> {
+    [8usize, 16].into_iter().find_map(|header| {
+        let keys = u32_at(data, header)? as usize;
+        // Each key entry is a 32-bit valid flag and a 16-byte hash.
+        let mut offset = header + 4 + keys.checked_mul(20)?;
+        if u16_at(data, offset)? != COLLECTION_MONOTONIC_COUNTERS {
+            return None;
+        }
+        let count = u16_at(data, offset + 2)?;
+        offset += 4;
+        for _ in 0..count {
+            let kind = u16_at(data, offset)?;
+            let slots = u16_at(data, offset + 2)? as usize;
+            let raw = (0..slots)
+                .map(|i| u16_at(data, offset + 4 + 2 * i))
+                .collect::<Option<Vec<_>>>()?;
+            if kind == id {
+                return Some(Counter {
+                    slots: PROVISION_ADDRESS + offset as u64 + 4,
+                    raw,
+                });
+            }
+            offset += 4 + 2 * slots;
+        }
+        None
+    })
+}
+
+/// Raise the `kind` counter of the provisioned data to `version`, after
+/// the provisioning data itself has been flashed.
+///
+/// Fails if the device's counter is already above `version`, which the
+/// bootloader would treat as a downgrade and refuse to boot. Returns the
+/// previous value.
+pub fn provision(session: &mut Session, kind: CounterKind, version: u16) -> Result<u16, RecoveryError> {
+    let mut data = vec![0u8; (PROVISION_END - PROVISION_ADDRESS) as usize];
+    session.core(0)?.read(PROVISION_ADDRESS, &mut data)?;
+
+    let counter = find_counter(&data, kind.id()).ok_or_else(|| {
+        RecoveryError::RollbackError(format!(
+            "no {:?} counter in the provisioned data at 0x{:08x}; flash provision.hex (or a merged image containing it) first",
+            kind, PROVISION_ADDRESS
+        ))
+    })?;
+
+    let current = counter.value();
+    if version < current {
+        return Err(RecoveryError::RollbackError(format!(
+            "image version {} is below the device's counter {}; the bootloader would reject it",
+            version, current
+        )));
+    }
+    if version == current {
+        return Ok(current);
+    }
+
+    let slot = counter
+        .free_slot()
+        .ok_or_else(|| RecoveryError::RollbackError("all counter slots are used".into()))?;
+    // Slots are half words; NVMC writes whole words, so leave the other half
+    // as it is.
+    let word = slot & !3;
+    let shift = (slot - word) * 8;
+    let have = session.core(0)?.read_word_32(word)?;
+    let want = have & !((version as u32) << shift);
+    uicr::write_bytes(session, word, &want.to_le_bytes())?;
+
+    log::info!("{:?} counter raised from {} to {}", kind, current, version);
+    Ok(current)
+}
@@ -0,0 +1,160 @@
+use probe_rs::rtt::Rtt;
+use probe_rs::{Core, Session};
+use regex::Regex;
+
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::RecoveryError;
+
+/// How long to wait for the firmware to set up its RTT control block.
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Output kept to match `expect` against, so a line split over two reads
+/// still matches.
+const EXPECT_WINDOW: usize = 4096;
+
+/// Options for an RTT capture session.
+pub struct RttOptions {
+    /// Up/down channel pair to use.
+    pub channel: usize,
+    /// Forward stdin lines to the down channel.
+    pub interactive: bool,
+    /// Stop once this much time has passed since the capture started.
+    pub timeout: Option<Duration>,
+    /// Stop once the output matches; failing to see it within `timeout`
+    /// is an error.
+    pub expect: Option<Regex>,
+}
+
+pub fn parse_pattern(s: &str) -> Result<Regex, String> {
+    Regex::new(s).map_err(|e| format!("Invalid pattern '{}': {}", s, e))
+}
+
+/// Attach to the RTT control block, retrying while the firmware boots.
+pub fn attach(core: &mut Core) -> Result<Rtt, RecoveryError> {
+    let start = Instant::now();
+    loop {
+        match Rtt::attach(core) {
+            Ok(rtt) => return Ok(rtt),
+            Err(e) => {
+                if start.elapsed() >= ATTACH_TIMEOUT {
+                    return Err(RecoveryError::RttError(e));
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+}
+
+/// Forward stdin line by line through a channel so the capture loop never blocks on it.
+fn spawn_stdin_reader() -> Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Stream the selected RTT up channel to stdout, optionally forwarding stdin
+/// to the matching down channel.
+///
+/// Runs until interrupted, until stdin is closed in interactive mode, until
+/// the output matches `expect` or until `timeout`, whichever comes first.
+pub fn capture(session: &mut Session, options: &RttOptions) -> Result<(), RecoveryError> {
+    let start = Instant::now();
+    let mut core = session.core(0)?;
+    let mut rtt = attach(&mut core)?;
+
+    let up_channels = rtt.up_channels().len();
+    if options.channel >= up_channels {
+        return Err(RecoveryError::RttChannelError(format!(
+            "Up channel {} not found, target has {}",
+            options.channel, up_channels
+        )));
+    }
+
+    if let Some(name) = rtt.up_channel(options.channel).and_then(|c| c.name()) {
+        log::info!("RTT up channel {}: {}", options.channel, name);
+    }
+
+    let stdin = if options.interactive {
+        if rtt.down_channel(options.channel).is_none() {
+            return Err(RecoveryError::RttChannelError(format!(
+                "Down channel {} not found",
+                options.channel
+            )));
+        }
+        Some(spawn_stdin_reader())
+    } else {
+        None
+    };
+
+    println!("Attached to RTT channel {}", options.channel);
+
+    let mut buf = [0u8; 1024];
+    let mut stdout = io::stdout();
+    let mut seen = String::new();
+    loop {
+        let up = rtt.up_channel(options.channel).unwrap();
+        let count = up.read(&mut core, &mut buf)?;
+        if count > 0 {
+            let _ = stdout.write_all(&buf[..count]);
+            let _ = stdout.flush();
+        }
+
+        if let Some(expect) = &options.expect {
+            seen.push_str(&String::from_utf8_lossy(&buf[..count]));
+            if expect.is_match(&seen) {
+                log::info!("RTT output matched '{}' after {:?}", expect, start.elapsed());
+                return Ok(());
+            }
+            if seen.len() > EXPECT_WINDOW {
+                let mut cut = seen.len() - EXPECT_WINDOW;
+                while !seen.is_char_boundary(cut) {
+                    cut += 1;
+                }
+                seen.drain(..cut);
+            }
+        }
+        if let Some(timeout) = options.timeout.filter(|timeout| start.elapsed() >= *timeout) {
+            return match &options.expect {
+                Some(expect) => Err(RecoveryError::BootCheckFailed(format!(
+                    "RTT output did not match '{}' within {:?}",
+                    expect, timeout
+                ))),
+                None => Ok(()),
+            };
+        }
+
+        if let Some(stdin) = &stdin {
+            match stdin.try_recv() {
+                Ok(mut line) => {
+                    line.push('\n');
+                    let down = rtt.down_channel(options.channel).unwrap();
+                    let mut pending = line.as_bytes();
+                    while !pending.is_empty() {
+                        let written = down.write(&mut core, pending)?;
+                        pending = &pending[written..];
+                        if written == 0 {
+                            thread::sleep(POLL_INTERVAL);
+                        }
+                    }
+                }
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+
+        if count == 0 {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use probe_rs::{MemoryInterface, Permissions};
+use serde::Serialize;
+
+use std::time::Instant;
+
+use crate::approtect::{ApprotectReport, Protection};
+use crate::dap::DebugPorts;
+use crate::detach;
+use crate::flow;
+use crate::hooks::Hooks;
+use crate::i18n::tr;
+use crate::image::{Image, ImageFile, Segment};
+use crate::registry;
+use crate::uicr;
+use crate::verify::{self, VerifyLevel};
+use crate::{check_locked, open_probe, try_unlock_device, ProbeArgs, RecoveryError, RecoverySession, UnlockReset};
+
+/// UICR.OTP[0] and the pattern written to it.
+const TEST_WORD: (u64, u32) = (0x00FF8108, 0x5E1F_C4EC);
+
+/// Size of the built-in test image, enough to span several flash pages.
+const IMAGE_SIZE: usize = 16 * 1024;
+
+/// Checks that leave the board as it is.
+const READ_ONLY: [&str; 2] = ["probe", "lock state"];
+
+/// Checks of `--destructive`, in the order they run.
+const DESTRUCTIVE: [&str; 8] = [
+    "unlock",
+    "flash",
+    "verify",
+    "uicr write",
+    "uicr verify",
+    "lock",
+    "unlock locked",
+    "erased",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// Not run, since an earlier check failed.
+    Skipped,
+}
+
+/// One row of the pass/fail matrix.
+#[derive(Clone, Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+    pub duration_ms: u128,
+}
+
+/// Result of `recovery self-check`, printed as JSON by `--output json`.
+#[derive(Clone, Debug, Serialize)]
+pub struct SelfCheckReport {
+    pub tool_version: &'static str,
+    pub chip: String,
+    pub probe_serial: Option<String>,
+    pub checks: Vec<Check>,
+    pub success: bool,
+}
+
+/// A check failed; the ones after it are skipped.
+struct Stopped;
+
+#[derive(Default)]
+struct Matrix {
+    checks: Vec<Check>,
+}
+
+impl Matrix {
+    fn step<T>(&mut self, name: &'static str, f: impl FnOnce() -> Result<T, RecoveryError>) -> Result<T, Stopped> {
+        log::info!("Self-check: {}", name);
+        let start = Instant::now();
+        let result = f();
+        self.checks.push(Check {
+            name,
+            outcome: if result.is_ok() { Outcome::Pass } else { Outcome::Fail },
+            error: result.as_ref().err().map(|e| e.to_string()),
+            duration_ms: start.elapsed().as_millis(),
+        });
+        result.map_err(|_| Stopped)
+    }
+}
+
+/// A vector table whose reset handler spins, followed by a pattern that
+/// differs from word to word, so a misplaced page shows in the readback.
+fn test_image() -> Image {
+    let mut data: Vec<u8> = (0..IMAGE_SIZE as u32).map(|i| (i.wrapping_mul(0x9E37_79B1) >> 24) as u8).collect();
+    // Initial stack pointer, then a reset handler at 0x8: `b .`.
+    data[..4].copy_from_slice(&0x2001_0000u32.to_le_bytes());
+    data[4..8].copy_from_slice(&0x0000_0009u32.to_le_bytes());
+    data[8..10].copy_from_slice(&0xE7FEu16.to_le_bytes());
+    Image {
+        segments: vec![Segment { address: 0, data }],
+    }
+}
+
+/// Check the board through the probe of `args`, stopping at the first
+/// failure. The read-only checks open the probe and read the lock state;
+/// `destructive` adds a soak of everything the tool does to a unit on the
+/// line, from unlock through locking and unlocking again, which leaves the
+/// board erased.
+pub fn run(args: &ProbeArgs, chip: &str, ports: &DebugPorts, destructive: bool) -> SelfCheckReport {
+    let mut matrix = Matrix::default();
+    let _ = checks(&mut matrix, args, chip, ports, destructive);
+
+    let planned = READ_ONLY.iter().chain(if destructive { &DESTRUCTIVE[..] } else { &[] });
+    for name in planned.skip(matrix.checks.len()) {
+        matrix.checks.push(Check {
+            name,
+            outcome: Outcome::Skipped,
+            error: None,
+            duration_ms: 0,
+        });
+    }
+    SelfCheckReport {
+        tool_version: env!("CARGO_PKG_VERSION"),
+        chip: chip.to_string(),
+        probe_serial: args.serial.clone(),
+        success: matrix.checks.iter().all(|check| check.outcome == Outcome::Pass),
+        checks: matrix.checks,
+    }
+}
+
+fn checks(m: &mut Matrix, args: &ProbeArgs, chip: &str, ports: &DebugPorts, destructive: bool) -> Result<(), Stopped> {
+    let probe = m.step("probe", || open_probe(args, ports))?;
+    m.step("lock state", || check_locked(probe, ports))?;
+    if !destructive {
+        return Ok(());
+    }
+
+    m.step("unlock", || {
+        flow::erase(open_probe(args, ports)?, chip, ports)?;
+        match check_locked(open_probe(args, ports)?, ports)? {
+            (_, true) => Err(RecoveryError::UnlockError("the device is still locked after ERASEALL".into())),
+            (_, false) => Ok(()),
+        }
+    })?;
+
+    let path = std::env::temp_dir().join("recovery-self-check.hex");
+    let file = ImageFile::hex(&path);
+    let options = RecoverySession::builder()
+        .chip(chip)
+        .debug_ports(*ports)
+        .image(file.clone())
+        .verify(VerifyLevel::None)
+        .options();
+    let mut session = m.step("flash", || {
+        test_image().save_hex(&path)?;
+        flow::run(open_probe(args, ports)?, &options, &mut ApprotectReport::default(), &mut false, &Hooks::default())
+    })?;
+    m.step("verify", || match verify::compare(&mut session, &file)?.len() {
+        0 => Ok(()),
+        regions => Err(RecoveryError::VerifyError(format!("{} regions differ from the test image", regions))),
+    })?;
+
+    m.step("uicr write", || uicr::write_words(&mut session, &[TEST_WORD]))?;
+    m.step("uicr verify", || match uicr::read(&mut session)?.into_iter().find(|(address, _)| *address == TEST_WORD.0) {
+        Some((_, value)) if value == TEST_WORD.1 => Ok(()),
+        found => Err(RecoveryError::UicrError(format!(
+            "0x{:08x} reads 0x{:08x} instead of 0x{:08x}",
+            TEST_WORD.0,
+            found.map_or(0xFFFF_FFFF, |(_, value)| value),
+            TEST_WORD.1
+        ))),
+    })?;
+
+    m.step("lock", || {
+        uicr::write_words(&mut session, &Protection::LOCKED.words(chip))?;
+        match detach::verify_locked(session, args, ports)? {
+            true => Ok(()),
+            false => Err(RecoveryError::DetachError(
+                "UICR enables access port protection, but the device still accepts debug access after a reset".into(),
+            )),
+        }
+    })?;
+
+    let probe = m.step("unlock locked", || {
+        match try_unlock_device(open_probe(args, ports)?, false, UnlockReset::for_chip(chip), ports, chip)? {
+            (probe, true) => Ok(probe),
+            (_, false) => Err(RecoveryError::UnlockError("the device did not need unlocking after it was locked".into())),
+        }
+    })?;
+
+    m.step("erased", || {
+        let mut session = registry::attach(probe, chip, Permissions::new())?;
+        let mut core = session.core(0)?;
+        for address in [0, TEST_WORD.0] {
+            let word = core.read_word_32(address)?;
+            if word != 0xFFFF_FFFF {
+                return Err(RecoveryError::VerifyError(format!(
+                    "0x{:08x} reads 0x{:08x} after the unlock instead of being erased",
+                    address, word
+                )));
+            }
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// Print the matrix, one check per line.
+pub fn print(report: &SelfCheckReport) {
+    println!("{}", tr!("self_check_header", version = report.tool_version, chip = report.chip));
+    for check in &report.checks {
+        let outcome = match check.outcome {
+            Outcome::Pass => tr!("target_pass"),
+            Outcome::Fail => tr!("target_fail"),
+            Outcome::Skipped => tr!("self_check_skipped"),
+        };
+        match &check.error {
+            None => println!("{:<16} {} ({} ms)", check.name, outcome, check.duration_ms),
+            Some(e) => println!("{:<16} {} ({} ms): {}", check.name, outcome, check.duration_ms, e),
+        }
+    }
+}
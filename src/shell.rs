@@ -0,0 +1,100 @@
+use probe_rs::Session;
+use regex::Regex;
+use serde::Deserialize;
+
+use std::time::Duration;
+
+use crate::console::{self, ConsoleSpec};
+use crate::i18n::tr;
+use crate::output::say;
+use crate::RecoveryError;
+
+/// How to reach the firmware's shell.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Rtt,
+    Uart,
+}
+
+/// A list of shell commands to run after boot, loaded from a TOML file.
+///
+/// ```toml
+/// transport = "uart"
+/// port = "/dev/ttyACM0"
+///
+/// [[step]]
+/// send = "sensor get temp"
+/// expect = "temp: \\d+"
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct ShellScript {
+    #[serde(default)]
+    pub transport: Transport,
+    /// RTT channel, for the RTT transport.
+    #[serde(default)]
+    pub channel: usize,
+    /// Serial port, for the UART transport.
+    pub port: Option<String>,
+    #[serde(default = "default_baud")]
+    pub baud: u32,
+    /// Default time to wait for each response.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default, rename = "step")]
+    pub steps: Vec<ShellStep>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShellStep {
+    /// Command line to send.
+    pub send: String,
+    /// Regex the response must match. Defaults to any response at all.
+    pub expect: Option<String>,
+    pub timeout_ms: Option<u64>,
+}
+
+fn default_baud() -> u32 {
+    115200
+}
+
+fn default_timeout_ms() -> u64 {
+    2000
+}
+
+impl ShellScript {
+    /// Console described by the script's transport settings.
+    pub fn console(&self) -> Result<ConsoleSpec, RecoveryError> {
+        match self.transport {
+            Transport::Rtt => Ok(ConsoleSpec::Rtt(self.channel)),
+            Transport::Uart => {
+                let port = self.port.clone().ok_or_else(|| {
+                    RecoveryError::ConfigError("UART transport requires a port".into())
+                })?;
+                Ok(ConsoleSpec::Uart {
+                    port,
+                    baud: self.baud,
+                })
+            }
+        }
+    }
+}
+
+/// Run every step of `script`, failing on the first response that does not match.
+pub fn run_script(session: &mut Session, script: &ShellScript) -> Result<(), RecoveryError> {
+    let mut console = console::open(session, &script.console()?)?;
+
+    for step in &script.steps {
+        let expect = Regex::new(step.expect.as_deref().unwrap_or(r"\S"))
+            .map_err(|e| RecoveryError::ConfigError(format!("Invalid regex: {}", e)))?;
+        let timeout = Duration::from_millis(step.timeout_ms.unwrap_or(script.timeout_ms));
+
+        log::info!("Shell: {}", step.send);
+        let output = console::exchange(console.as_mut(), &step.send, &expect, timeout)?;
+        log::debug!("Shell output: {:?}", output);
+        say(tr!("shell_step_passed", command = step.send));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,291 @@
+use clap::ValueEnum;
+use probe_rs::{MemoryInterface, Session};
+use serde::{Deserialize, Deserializer};
+
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::i18n::tr;
+use crate::image::{ImageFile, ImageFormat};
+use crate::output::say;
+use crate::rollback::FW_INFO_MAGIC;
+use crate::version::MCUBOOT_MAGIC;
+use crate::{parse_number, RecoveryError};
+
+/// Bytes read from the start of each slot to find its image header.
+/// `fw_info` sits at `CONFIG_FW_INFO_OFFSET`, 0x200 in NCS by default.
+const HEADER_AREA: usize = 0x1000;
+
+/// Offset of `valid` in `fw_info`, and the value NSIB boots.
+const FW_INFO_VALID: usize = 32;
+const FW_INFO_VALID_VAL: u32 = 0x9102FFFF;
+
+/// Which slot to flash, from `--slot`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SlotChoice {
+    /// The slot the bootloader considers active, or A if neither holds a
+    /// valid image.
+    #[default]
+    Auto,
+    A,
+    B,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Slot::A => "A",
+            Slot::B => "B",
+        })
+    }
+}
+
+/// Base addresses of two equally sized application slots, as `A,B`, e.g.
+/// NSIB's S0 and S1 or MCUboot's primary and secondary slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlotLayout {
+    pub a: u64,
+    pub b: u64,
+}
+
+impl FromStr for SlotLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (a, b) = s
+            .split_once(',')
+            .ok_or_else(|| format!("expected A_ADDR,B_ADDR, got '{}'", s))?;
+        let (a, b) = (parse_number(a)?, parse_number(b)?);
+        if a >= b {
+            return Err(format!("slot A (0x{:08x}) must come before slot B (0x{:08x})", a, b));
+        }
+        Ok(SlotLayout { a, b })
+    }
+}
+
+impl<'de> Deserialize<'de> for SlotLayout {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl SlotLayout {
+    pub fn range(&self, slot: Slot) -> Range<u64> {
+        let size = self.b - self.a;
+        match slot {
+            Slot::A => self.a..self.b,
+            Slot::B => self.b..self.b + size,
+        }
+    }
+
+    /// The slot holding all of `range`, if any.
+    fn slot_of(&self, range: &Range<u64>) -> Option<Slot> {
+        [Slot::A, Slot::B].into_iter().find(|slot| {
+            let slot = self.range(*slot);
+            range.start >= slot.start && range.end <= slot.end
+        })
+    }
+}
+
+/// A/B slot handling for a run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlotOptions {
+    pub layout: SlotLayout,
+    pub choice: SlotChoice,
+}
+
+/// A bootable image found in a slot.
+struct SlotImage {
+    /// Orders images the way the bootloader does: the higher one boots.
+    rank: u64,
+    version: String,
+}
+
+/// Read the image header at the start of `slot`: an MCUboot header or an
+/// NSIB `fw_info` marked valid.
+fn read_slot(session: &mut Session, layout: &SlotLayout, slot: Slot) -> Result<Option<SlotImage>, RecoveryError> {
+    let mut data = vec![0u8; HEADER_AREA];
+    session.core(0)?.read(layout.range(slot).start, &mut data)?;
+    let word = |offset: usize| data.get(offset..offset + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+
+    if word(0) == Some(MCUBOOT_MAGIC) {
+        let (major, minor) = (data[20] as u64, data[21] as u64);
+        let revision = u16::from_le_bytes([data[22], data[23]]) as u64;
+        let build = word(24).unwrap_or(0) as u64;
+        return Ok(Some(SlotImage {
+            rank: major << 56 | minor << 48 | revision << 32 | build,
+            version: format!("{}.{}.{}+{}", major, minor, revision, build),
+        }));
+    }
+
+    let fw_info = (0..HEADER_AREA - 8)
+        .step_by(4)
+        .find(|i| word(*i) == Some(FW_INFO_MAGIC[0]) && word(i + 4) == Some(FW_INFO_MAGIC[1]));
+    Ok(fw_info.and_then(|i| {
+        if word(i + FW_INFO_VALID)? != FW_INFO_VALID_VAL {
+            return None;
+        }
+        let version = word(i + 20)?;
+        Some(SlotImage {
+            rank: version as u64,
+            version: format!("fw_info version {}", version),
+        })
+    }))
+}
+
+/// Decide which slot to flash.
+///
+/// With `auto`, the slot holding the higher version is the one the
+/// bootloader boots, and the one replaced; on a tie both NSIB and MCUboot
+/// take slot A. A device `erased` by the unlock has nothing to read, so
+/// slot A is used.
+pub fn choose(session: &mut Session, options: &SlotOptions, erased: bool) -> Result<Slot, RecoveryError> {
+    let slot = match options.choice {
+        SlotChoice::A => Slot::A,
+        SlotChoice::B => Slot::B,
+        SlotChoice::Auto if erased => {
+            say(tr!("slot_default", slot = Slot::A));
+            return Ok(Slot::A);
+        }
+        SlotChoice::Auto => {
+            let a = read_slot(session, &options.layout, Slot::A)?;
+            let b = read_slot(session, &options.layout, Slot::B)?;
+            let (slot, image) = match (a, b) {
+                (Some(a), Some(b)) if b.rank > a.rank => (Slot::B, b),
+                (Some(a), _) => (Slot::A, a),
+                (None, Some(b)) => (Slot::B, b),
+                (None, None) => {
+                    say(tr!("slot_default", slot = Slot::A));
+                    return Ok(Slot::A);
+                }
+            };
+            say(tr!("slot_active", slot = slot, version = image.version));
+            return Ok(slot);
+        }
+    };
+    say(tr!("slot_chosen", slot = slot));
+    Ok(slot)
+}
+
+/// Fit `images` to `slot`. Hex images built for the other slot are left out,
+/// since their code is linked to its addresses; raw images in the other slot
+/// are moved to the same offset in `slot`. Images outside both slots, such as
+/// a bootloader, are kept as they are.
+pub fn place(images: &[ImageFile], layout: &SlotLayout, slot: Slot) -> Result<Vec<ImageFile>, RecoveryError> {
+    let mut placed = Vec::new();
+    let mut found = false;
+    for image in images {
+        let mut range: Option<Range<u64>> = None;
+        image.stream(0x10000, |segments| {
+            for segment in segments {
+                let end = segment.address + segment.data.len() as u64;
+                range = Some(match range.take() {
+                    Some(r) => r.start.min(segment.address)..r.end.max(end),
+                    None => segment.address..end,
+                });
+            }
+            Ok(())
+        })?;
+
+        match range.and_then(|r| layout.slot_of(&r)) {
+            None => placed.push(image.clone()),
+            Some(s) if s == slot => {
+                found = true;
+                placed.push(image.clone());
+            }
+            Some(other) => match image.format {
+                ImageFormat::Bin { base } => {
+                    let base = base - layout.range(other).start + layout.range(slot).start;
+                    log::info!("moving {} to slot {} at 0x{:08x}", image, slot, base);
+                    found = true;
+                    placed.push(ImageFile {
+                        format: ImageFormat::Bin { base },
+                        ..image.clone()
+                    });
+                }
+                _ => log::info!("skipping {}, which is built for slot {}", image, other),
+            },
+        }
+    }
+    if !found {
+        return Err(RecoveryError::SlotError(format!("none of the images fits in slot {}", slot)));
+    }
+    Ok(placed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{FileFormat, Image, Segment};
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    const LAYOUT: SlotLayout = SlotLayout { a: 0x10000, b: 0x50000 };
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("recovery-slot-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn parses_layouts() {
+        assert_eq!("0x10000,0x50000".parse(), Ok(LAYOUT));
+        assert!("0x50000,0x10000".parse::<SlotLayout>().is_err());
+        assert!("0x10000".parse::<SlotLayout>().is_err());
+        assert_eq!(LAYOUT.range(Slot::B), 0x50000..0x90000);
+    }
+
+    #[test]
+    fn finds_the_slot_holding_a_range() {
+        assert_eq!(LAYOUT.slot_of(&(0x10200..0x20000)), Some(Slot::A));
+        assert_eq!(LAYOUT.slot_of(&(0x50000..0x90000)), Some(Slot::B));
+        // A bootloader, and an image too big for one slot.
+        assert_eq!(LAYOUT.slot_of(&(0x0..0x8000)), None);
+        assert_eq!(LAYOUT.slot_of(&(0x40000..0x60000)), None);
+    }
+
+    #[test]
+    fn raw_images_move_to_the_other_slot() {
+        let raw = temp("app.bin");
+        fs::write(&raw, [0xAA; 0x100]).unwrap();
+        let hex = temp("app.hex");
+        Image {
+            segments: vec![Segment {
+                address: 0x50000,
+                data: vec![0x55; 0x100],
+            }],
+        }
+        .save_hex(&hex)
+        .unwrap();
+        let boot = temp("boot.bin");
+        fs::write(&boot, [0x11; 0x100]).unwrap();
+
+        let images = [
+            ImageFile::new(boot.clone(), Some(0x0), Some(FileFormat::Bin)).unwrap(),
+            ImageFile::new(raw.clone(), Some(0x50200), Some(FileFormat::Bin)).unwrap(),
+            ImageFile::hex(&hex),
+        ];
+        let placed = place(&images, &LAYOUT, Slot::A).unwrap();
+        // The hex image is linked for slot B and left out.
+        assert_eq!(placed.len(), 2);
+        assert_eq!(placed[0].format, ImageFormat::Bin { base: 0x0 });
+        assert_eq!(placed[1].format, ImageFormat::Bin { base: 0x10200 });
+        assert_eq!(placed[1].path, raw);
+
+        let placed = place(&images, &LAYOUT, Slot::B).unwrap();
+        assert_eq!(placed.len(), 3);
+        assert!(place(&images[2..], &LAYOUT, Slot::A).is_err());
+
+        for path in [raw, hex, boot] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
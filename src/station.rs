@@ -0,0 +1,14 @@
+use clap::Args as ClapArgs;
+use serde::Serialize;
+
+/// Who produced a result, stamped into reports and the results file.
+#[derive(ClapArgs, Clone, Debug, Default, Serialize)]
+pub struct Station {
+    #[arg(long, global = true, env = "RECOVERY_STATION_ID", help = "Identifier of this programming station, recorded in reports")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub station_id: Option<String>,
+
+    #[arg(long, global = true, env = "RECOVERY_OPERATOR", help = "Name or badge number of the operator, recorded in reports")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operator: Option<String>,
+}
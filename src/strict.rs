@@ -0,0 +1,26 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::i18n::tr;
+use crate::RecoveryError;
+
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// Turn warnings into failures for the rest of the process.
+pub fn init(strict: bool) {
+    STRICT.store(strict, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    STRICT.load(Ordering::Relaxed)
+}
+
+/// Report something that leaves the result in doubt, such as an erase that
+/// never reported completion. Strict mode fails with `error`; otherwise it
+/// is printed as a warning and the run goes on.
+pub fn warn(error: RecoveryError) -> Result<(), RecoveryError> {
+    if enabled() {
+        return Err(error);
+    }
+    eprintln!("{}", tr!("warning", message = error));
+    Ok(())
+}
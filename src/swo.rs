@@ -0,0 +1,128 @@
+use probe_rs::architecture::arm::component::TraceSink;
+use probe_rs::architecture::arm::SwoConfig;
+use probe_rs::{MemoryInterface, Session};
+
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+
+use crate::RecoveryError;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// nRF91 trace and debug control, which routes the trace port to pins.
+const TAD: u64 = 0xE0080000;
+const TAD_ENABLE: u64 = TAD + 0x500;
+const TAD_PSEL_TRACEDATA0: u64 = TAD + 0x508;
+const TAD_TRACEPORTSPEED: u64 = TAD + 0x518;
+/// TRACEDATA[0], which doubles as SWO.
+const SWO_PIN: u32 = 22;
+/// TRACEPORTSPEED value for a 32 MHz trace clock.
+const TRACEPORTSPEED_32MHZ: u32 = 0;
+const TRACE_CLOCK_HZ: u32 = 32_000_000;
+
+/// Options for an SWO capture session.
+pub struct SwoOptions {
+    /// SWO baud rate the target sends and the probe receives at.
+    pub baud: u32,
+    /// Only print this ITM stimulus port.
+    pub port: Option<u8>,
+    /// TPIU clock in Hz, if not the nRF91 trace clock.
+    pub tpiu_clock: Option<u32>,
+}
+
+/// Decodes ITM packets from the SWO byte stream, keeping software source
+/// (stimulus port) payloads and dropping everything else.
+#[derive(Default)]
+struct ItmDecoder {
+    /// Payload bytes still to come for the current packet.
+    pending: usize,
+    /// Stimulus port of the current packet, or `None` when its payload is
+    /// skipped.
+    port: Option<u8>,
+    /// Inside a packet whose length is given by continuation bits.
+    continued: bool,
+}
+
+impl ItmDecoder {
+    /// Feed `data`, calling `out` with each stimulus byte and its port.
+    fn feed(&mut self, data: &[u8], mut out: impl FnMut(u8, u8)) {
+        for &byte in data {
+            if self.pending > 0 {
+                self.pending -= 1;
+                if let Some(port) = self.port {
+                    out(port, byte);
+                }
+                continue;
+            }
+            if self.continued {
+                self.continued = byte & 0x80 != 0;
+                continue;
+            }
+
+            self.port = None;
+            match byte {
+                // Synchronization and overflow.
+                0x00 | 0x80 | 0x70 => {}
+                // Local timestamp, extension and global timestamp packets
+                // carry a continuation bit.
+                _ if byte & 0x0F == 0x00 || byte & 0x0B == 0x08 || byte & 0xDF == 0x94 => {
+                    self.continued = byte & 0x80 != 0;
+                }
+                _ => {
+                    let size = match byte & 0x03 {
+                        1 => 1,
+                        2 => 2,
+                        3 => 4,
+                        _ => 0,
+                    };
+                    self.pending = size;
+                    // Bit 2 clear: software source, i.e. a stimulus port.
+                    if byte & 0x04 == 0 {
+                        self.port = Some(byte >> 3);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Route SWO to its pin on nRF91, where probe-rs does not set up the trace
+/// port itself.
+fn enable_nrf91_trace_port(session: &mut Session) -> Result<(), RecoveryError> {
+    let mut core = session.core(0)?;
+    core.write_word_32(TAD_TRACEPORTSPEED, TRACEPORTSPEED_32MHZ)?;
+    core.write_word_32(TAD_PSEL_TRACEDATA0, SWO_PIN)?;
+    core.write_word_32(TAD_ENABLE, 1)?;
+    Ok(())
+}
+
+/// Set up SWO tracing and print ITM stimulus output to stdout.
+///
+/// Runs until interrupted.
+pub fn capture(session: &mut Session, nrf91: bool, options: &SwoOptions) -> Result<(), RecoveryError> {
+    if nrf91 {
+        enable_nrf91_trace_port(session)?;
+    }
+    let clock = options.tpiu_clock.unwrap_or(TRACE_CLOCK_HZ);
+    let config = SwoConfig::new(clock).set_baud(options.baud).set_continuous_formatting(false);
+    session.setup_tracing(0, TraceSink::Swo(config))?;
+
+    println!("Capturing SWO at {} Bd", options.baud);
+
+    let mut decoder = ItmDecoder::default();
+    let mut stdout = io::stdout();
+    loop {
+        let data = session.read_trace_data()?;
+        if data.is_empty() {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+        decoder.feed(&data, |port, byte| {
+            if options.port.is_none_or(|p| p == port) {
+                let _ = stdout.write_all(&[byte]);
+            }
+        });
+        let _ = stdout.flush();
+    }
+}
@@ -0,0 +1,112 @@
+use object::{Object, ObjectSymbol, SymbolKind};
+use serde::{Deserialize, Deserializer};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::{parse_number, RecoveryError};
+
+const PREFIX: &str = "symbol:";
+
+/// An address given as a number or as `symbol:name[+offset]`, resolved from
+/// the symbol table of the ELF that was flashed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    Address(u64),
+    Symbol { name: String, offset: u64 },
+}
+
+impl Location {
+    /// The address, looking symbols up in `symbols`.
+    pub fn resolve(&self, symbols: Option<&Symbols>) -> Result<u64, RecoveryError> {
+        match self {
+            Location::Address(address) => Ok(*address),
+            Location::Symbol { name, offset } => {
+                let symbols = symbols.ok_or_else(|| {
+                    RecoveryError::SymbolError(format!("{} needs an ELF file to look it up in (--elf, or elf in a job target)", self))
+                })?;
+                Ok(symbols.get(name)? + offset)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Address(address) => write!(f, "0x{:08x}", address),
+            Location::Symbol { name, offset: 0 } => write!(f, "{}{}", PREFIX, name),
+            Location::Symbol { name, offset } => write!(f, "{}{}+0x{:x}", PREFIX, name, offset),
+        }
+    }
+}
+
+/// Parse an address or `symbol:name[+offset]`.
+pub fn parse_location(s: &str) -> Result<Location, String> {
+    let Some(symbol) = s.strip_prefix(PREFIX) else {
+        return parse_number(s).map(Location::Address);
+    };
+    let (name, offset) = match symbol.split_once('+') {
+        Some((name, offset)) => (name, parse_number(offset)?),
+        None => (symbol, 0),
+    };
+    if name.is_empty() {
+        return Err(format!("No symbol name in '{}'", s));
+    }
+    Ok(Location::Symbol { name: name.to_string(), offset })
+}
+
+impl<'de> Deserialize<'de> for Location {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Number(u64),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Number(address) => Ok(Location::Address(address)),
+            Raw::Text(text) => parse_location(&text).map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Addresses of the symbols defined in an ELF file.
+#[derive(Debug)]
+pub struct Symbols {
+    path: String,
+    addresses: HashMap<String, u64>,
+}
+
+impl Symbols {
+    pub fn load(path: &Path) -> Result<Self, RecoveryError> {
+        let err = |e: String| RecoveryError::SymbolError(format!("{}: {}", path.display(), e));
+        let data = fs::read(path).map_err(|e| err(e.to_string()))?;
+        let file = object::File::parse(&*data).map_err(|e| err(e.to_string()))?;
+
+        let mut addresses = HashMap::new();
+        for symbol in file.symbols().filter(|s| s.is_definition()) {
+            let Ok(name) = symbol.name() else { continue };
+            // Thumb function addresses have bit 0 set.
+            let address = match symbol.kind() {
+                SymbolKind::Text => symbol.address() & !1,
+                _ => symbol.address(),
+            };
+            addresses.insert(name.to_string(), address);
+        }
+        if addresses.is_empty() {
+            return Err(err("no symbol table; was the ELF stripped?".into()));
+        }
+
+        Ok(Symbols { path: path.display().to_string(), addresses })
+    }
+
+    pub fn get(&self, name: &str) -> Result<u64, RecoveryError> {
+        self.addresses
+            .get(name)
+            .copied()
+            .ok_or_else(|| RecoveryError::SymbolError(format!("{}: no symbol '{}'", self.path, name)))
+    }
+}
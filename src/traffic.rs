@@ -0,0 +1,116 @@
+use probe_rs::Permissions;
+use regex::Regex;
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::console::{self, ConsoleSpec};
+use crate::dap::DebugPorts;
+use crate::i18n::tr;
+use crate::prompt::ask;
+use crate::output::say;
+use crate::registry;
+use crate::strict;
+use crate::{check_locked, open_probe, ProbeArgs, RecoveryError};
+
+/// Time one AT query may take to answer.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// What the operator has to type to erase a device with an active modem.
+const CONFIRMATION: &str = "erase";
+
+/// What the modem reports about its network use.
+#[derive(Debug, Default)]
+pub struct ModemActivity {
+    /// `<stat>` of `+CEREG`: 1 is registered on the home network, 5 roaming.
+    pub registration: Option<u8>,
+    /// PDP contexts that are active, by context ID.
+    pub active_contexts: Vec<u8>,
+}
+
+impl ModemActivity {
+    pub fn registered(&self) -> bool {
+        matches!(self.registration, Some(1 | 5))
+    }
+
+    /// Whether the modem is on the network or has a data session up.
+    pub fn active(&self) -> bool {
+        self.registered() || !self.active_contexts.is_empty()
+    }
+}
+
+impl fmt::Display for ModemActivity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        match self.registration {
+            Some(1) => parts.push("LTE registered (home)".to_string()),
+            Some(5) => parts.push("LTE registered (roaming)".to_string()),
+            _ => {}
+        }
+        if !self.active_contexts.is_empty() {
+            let cids: Vec<String> = self.active_contexts.iter().map(|c| c.to_string()).collect();
+            parts.push(format!("data session on PDP context {}", cids.join(", ")));
+        }
+        if parts.is_empty() {
+            parts.push("idle".into());
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Ask the modem about its registration and PDP contexts with `AT+CEREG?`
+/// and `AT+CGACT?` over the firmware's console.
+pub fn query(console: &mut dyn console::Console) -> Result<ModemActivity, RecoveryError> {
+    let done = Regex::new(r"\b(OK|ERROR)\b").unwrap();
+    let cereg = Regex::new(r"\+CEREG:\s*\d+\s*,\s*(\d+)").unwrap();
+    let cgact = Regex::new(r"\+CGACT:\s*(\d+)\s*,\s*(\d+)").unwrap();
+
+    let response = console::exchange(console, "AT+CEREG?", &done, QUERY_TIMEOUT)?;
+    let registration = cereg.captures(&response).and_then(|c| c[1].parse().ok());
+
+    let response = console::exchange(console, "AT+CGACT?", &done, QUERY_TIMEOUT)?;
+    let active_contexts = cgact
+        .captures_iter(&response)
+        .filter(|c| &c[2] == "1")
+        .filter_map(|c| c[1].parse().ok())
+        .collect();
+
+    Ok(ModemActivity { registration, active_contexts })
+}
+
+/// Before a readable device is erased or reprogrammed, check whether its
+/// modem is on the network and, if so, make the operator confirm by typing
+/// `erase`.
+///
+/// The device is attached as `chip` for the query and released again, so the
+/// recovery run opens the probe afresh. A locked device cannot be asked and
+/// is let through. A modem that does not answer is a warning, since the
+/// firmware may not forward AT commands.
+pub fn guard(probe_args: &ProbeArgs, chip: &str, ports: &DebugPorts, spec: &ConsoleSpec) -> Result<(), RecoveryError> {
+    let (probe, locked) = check_locked(open_probe(probe_args, ports)?, ports)?;
+    if locked {
+        log::info!("Device is locked; not checking for modem activity");
+        return Ok(());
+    }
+
+    let mut session = registry::attach(probe, chip, Permissions::new())?;
+    let activity = match console::open(&mut session, spec).and_then(|mut console| query(console.as_mut())) {
+        Ok(activity) => activity,
+        Err(e) => {
+            return strict::warn(RecoveryError::ConsoleError(format!(
+                "unable to tell whether the modem is active: {}",
+                e
+            )))
+        }
+    };
+    drop(session);
+    log::info!("Modem activity: {:?}", activity);
+
+    if !activity.active() {
+        return Ok(());
+    }
+    say(tr!("modem_active", state = activity));
+    match ask(&tr!("modem_active_confirm", word = CONFIRMATION)) {
+        Some(answer) if answer == CONFIRMATION => Ok(()),
+        _ => Err(RecoveryError::Cancelled),
+    }
+}
@@ -0,0 +1,432 @@
+use probe_rs::{MemoryInterface, Session};
+use serde::Deserialize;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::approtect::{Protection, PROTECTED};
+use crate::config;
+use crate::i18n::tr;
+use crate::image::{Image, Segment};
+use crate::output::say;
+use crate::nvmc;
+use crate::provision::{PROVISION, PROVISION_ADDRESS};
+use crate::recipe::UicrWord;
+use crate::{parse_number, parse_word, RecoveryError};
+
+pub const UICR_START: u64 = 0x00FF8000;
+pub const UICR_END: u64 = 0x00FF9000;
+
+/// nRF91 UICR registers with a fixed meaning, listed by `dump` even when
+/// erased.
+const REGISTERS: [(u64, &str); 6] = [
+    (0x00FF8000, "APPROTECT"),
+    (0x00FF8014, "XOSC32M"),
+    (0x00FF801C, "HFXOSRC"),
+    (0x00FF8020, "HFXOCNT"),
+    (0x00FF802C, "SECUREAPPROTECT"),
+    (0x00FF8030, "ERASEPROTECT"),
+];
+const KEYSLOT_CONFIG: u64 = 0x00FF8400;
+const KEYSLOT_KEY: u64 = 0x00FF8800;
+
+/// The nRF91 name of the UICR word at `address`, e.g. `HFXOSRC`, `OTP[3]`
+/// or `KEYSLOT.KEY[2].VALUE[1]`. UICR.OTP is the area NSIB provisioning
+/// data and the rollback counters go to, see [`PROVISION`].
+pub fn name(address: u64) -> Option<String> {
+    if let Some((_, name)) = REGISTERS.iter().find(|(a, _)| *a == address) {
+        return Some(name.to_string());
+    }
+    match address {
+        a if PROVISION.contains(&a) => Some(format!("OTP[{}]", (a - PROVISION_ADDRESS) / 4)),
+        a if (KEYSLOT_CONFIG..KEYSLOT_KEY).contains(&a) => {
+            let field = if (a - KEYSLOT_CONFIG).is_multiple_of(8) { "DEST" } else { "PERM" };
+            Some(format!("KEYSLOT.CONFIG[{}].{}", (a - KEYSLOT_CONFIG) / 8, field))
+        }
+        a if (KEYSLOT_KEY..UICR_END).contains(&a) => Some(format!(
+            "KEYSLOT.KEY[{}].VALUE[{}]",
+            (a - KEYSLOT_KEY) / 16,
+            (a - KEYSLOT_KEY) % 16 / 4
+        )),
+        _ => None,
+    }
+}
+
+/// The address of a UICR register named like [`name`] gives it, e.g.
+/// `HFXOSRC` or `OTP[3]`.
+fn address_of(register: &str) -> Option<u64> {
+    let register = register.to_ascii_uppercase();
+    if let Some((address, _)) = REGISTERS.iter().find(|(_, name)| *name == register) {
+        return Some(*address);
+    }
+    let index: u64 = register.strip_prefix("OTP[")?.strip_suffix(']')?.parse().ok()?;
+    Some(PROVISION_ADDRESS + index * 4).filter(|address| PROVISION.contains(address))
+}
+
+/// Parse a UICR write given as `ADDR=VALUE`, where ADDR is a word address
+/// or a register name such as `HFXOSRC` or `OTP[3]`.
+pub fn parse_write(s: &str) -> Result<(u64, u32), String> {
+    let (register, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected ADDR=VALUE, got '{}'", s))?;
+    let address = match address_of(register.trim()) {
+        Some(address) => address,
+        None => parse_number(register)
+            .map_err(|_| format!("'{}' is neither a UICR register nor an address", register))?,
+    };
+    check_word(address)?;
+    Ok((address, parse_word(value)?))
+}
+
+pub(crate) fn check_word(address: u64) -> Result<(), String> {
+    if !(UICR_START..UICR_END).contains(&address) || !address.is_multiple_of(4) {
+        return Err(format!("0x{:08x} is not a UICR word", address));
+    }
+    Ok(())
+}
+
+/// A `--uicr-file`, with the same `[[uicr]]` tables as a recipe.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UicrFile {
+    #[serde(default)]
+    uicr: Vec<UicrWord>,
+}
+
+/// Load the UICR words of a `--uicr-file`:
+///
+/// ```toml
+/// [[uicr]]
+/// address = 0x00FF801C # HFXOSRC
+/// value = 0x00000000
+/// ```
+pub fn load_words(path: &Path) -> Result<Vec<(u64, u32)>, RecoveryError> {
+    let file: UicrFile = config::load(path)?;
+    file.uicr
+        .iter()
+        .map(|word| {
+            check_word(word.address)
+                .map(|_| (word.address, word.value))
+                .map_err(|e| RecoveryError::ConfigError(format!("{}: {}", path.display(), e)))
+        })
+        .collect()
+}
+
+/// The UICR words the recovery flow writes: the protection words of
+/// `protection`, then `extra`. A later word for the same address replaces an
+/// earlier one, except that a protection word to be enabled stays
+/// [`PROTECTED`] whatever `extra` holds.
+pub fn flow_words(extra: &[(u64, u32)], protection: &Protection, chip: &str) -> Vec<(u64, u32)> {
+    let mut words = protection.words(chip);
+    for (address, value) in extra {
+        match words.iter_mut().find(|(a, _)| a == address) {
+            Some(word) => word.1 = *value,
+            None => words.push((*address, *value)),
+        }
+    }
+    for (address, value) in protection.words(chip) {
+        if value == PROTECTED {
+            if let Some(word) = words.iter_mut().find(|(a, _)| *a == address) {
+                word.1 = PROTECTED;
+            }
+        }
+    }
+    words
+}
+
+/// Whether `want` can be written over `have` without erasing UICR, which
+/// only lets bits be cleared.
+fn writable(address: u64, have: u32, want: u32) -> Result<(), RecoveryError> {
+    if have & want != want {
+        return Err(RecoveryError::UicrError(format!(
+            "0x{:08x} holds 0x{:08x}, cannot write 0x{:08x} without erasing UICR",
+            address, have, want
+        )));
+    }
+    Ok(())
+}
+
+/// Check that each of `words` can be written over what UICR holds now, so a
+/// run fails before flashing rather than after.
+pub fn check_writable(session: &mut Session, words: &[(u64, u32)]) -> Result<(), RecoveryError> {
+    let mut core = session.core(0)?;
+    for (address, want) in words {
+        writable(*address, core.read_word_32(*address)?, *want)?;
+    }
+    Ok(())
+}
+
+/// Read all of UICR, returning the words that are not erased.
+pub fn read(session: &mut Session) -> Result<Vec<(u64, u32)>, RecoveryError> {
+    let mut words = vec![0u32; ((UICR_END - UICR_START) / 4) as usize];
+    session.core(0)?.read_32(UICR_START, &mut words)?;
+    Ok(words
+        .into_iter()
+        .enumerate()
+        .filter(|(_, w)| *w != 0xFFFF_FFFF)
+        .map(|(i, w)| (UICR_START + i as u64 * 4, w))
+        .collect())
+}
+
+/// Read all of UICR, print the named registers and every word that is not
+/// erased as a table, marking the words in UICR.OTP as provisioning data,
+/// and optionally save UICR to an Intel hex file that can be flashed back
+/// later.
+pub fn dump(session: &mut Session, out: Option<&Path>) -> Result<(), RecoveryError> {
+    let mut words = vec![0u32; ((UICR_END - UICR_START) / 4) as usize];
+    session.core(0)?.read_32(UICR_START, &mut words)?;
+
+    println!("{:<10}  {:<24}  Value", "Address", "Register");
+    for (i, value) in words.iter().enumerate() {
+        let address = UICR_START + i as u64 * 4;
+        let named = REGISTERS.iter().any(|(a, _)| *a == address);
+        if *value == 0xFFFF_FFFF && !named {
+            continue;
+        }
+        let state = match *value {
+            0xFFFF_FFFF => "  (erased)",
+            _ if PROVISION.contains(&address) => "  (provisioning data)",
+            _ => "",
+        };
+        println!(
+            "0x{:08x}  {:<24}  0x{:08x}{}",
+            address,
+            name(address).unwrap_or_default(),
+            value,
+            state
+        );
+    }
+
+    if let Some(path) = out {
+        let segments = vec![Segment {
+            address: UICR_START,
+            data: words.iter().flat_map(|word| word.to_le_bytes()).collect(),
+        }];
+        Image { segments }.save_hex(path)?;
+        say(tr!("uicr_saved", path = path.display()));
+    }
+    Ok(())
+}
+
+/// Write `data` to UICR as consecutive little-endian words starting at
+/// `addr`, in a single NVMC write cycle, and read it back.
+///
+/// A trailing partial word is padded with 0xFF. Each destination word must
+/// still be erased or only need bits cleared, since UICR can only be erased
+/// as a whole.
+pub fn write_bytes(session: &mut Session, addr: u64, data: &[u8]) -> Result<(), RecoveryError> {
+    let mut bytes = data.to_vec();
+    while !bytes.len().is_multiple_of(4) {
+        bytes.push(0xFF);
+    }
+    let words: Vec<u32> = bytes
+        .chunks(4)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+        .collect();
+
+    let end = addr + bytes.len() as u64;
+    if !addr.is_multiple_of(4) || addr < UICR_START || end > UICR_END {
+        return Err(RecoveryError::UicrError(format!(
+            "0x{:08x}..0x{:08x} is not a word-aligned range inside UICR",
+            addr, end
+        )));
+    }
+
+    let mut core = session.core(0)?;
+
+    let mut current = vec![0u32; words.len()];
+    core.read_32(addr, &mut current)?;
+    if current == words {
+        return Ok(());
+    }
+    for (i, (have, want)) in current.iter().zip(&words).enumerate() {
+        writable(addr + i as u64 * 4, *have, *want)?;
+    }
+
+    nvmc::write_words(&mut core, addr, &words)?;
+
+    core.read_32(addr, &mut current)?;
+    if current != words {
+        return Err(RecoveryError::UicrError(format!(
+            "verify failed at 0x{:08x}: wrote {}, read {}",
+            addr,
+            hex::encode(&bytes),
+            hex::encode(current.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<u8>>())
+        )));
+    }
+    Ok(())
+}
+
+/// Erase UICR alone, leaving flash as it is, with the core halted.
+///
+/// NVMC erases a page when 0xFFFFFFFF is written to its first word with
+/// CONFIG set to Een, and UICR is such a page to it, unless the part
+/// reserves UICR for ERASEALL. Whether the erase took is checked by reading
+/// all of UICR back.
+pub fn erase(session: &mut Session) -> Result<(), RecoveryError> {
+    let mut core = session.core(0)?;
+    core.halt(Duration::from_millis(100))?;
+    nvmc::erase_page(&mut core, UICR_START)?;
+
+    let mut words = vec![0u32; ((UICR_END - UICR_START) / 4) as usize];
+    core.read_32(UICR_START, &mut words)?;
+    if let Some(i) = words.iter().position(|word| *word != 0xFFFF_FFFF) {
+        return Err(RecoveryError::UicrError(format!(
+            "0x{:08x} still reads 0x{:08x}; this part cannot erase UICR on its own, erase the whole device instead",
+            UICR_START + i as u64 * 4,
+            words[i]
+        )));
+    }
+    Ok(())
+}
+
+/// Write single UICR words with the core halted, then reset so they take
+/// effect.
+pub fn write_words(session: &mut Session, words: &[(u64, u32)]) -> Result<(), RecoveryError> {
+    session.core(0)?.halt(Duration::from_millis(100))?;
+    for (addr, value) in words {
+        log::info!("Writing UICR 0x{:08x} = 0x{:08x}", addr, value);
+        write_bytes(session, *addr, &value.to_le_bytes())?;
+    }
+    session.core(0)?.reset()?;
+    Ok(())
+}
+
+/// What a UICR word of a golden template must hold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expected {
+    Value(u32),
+    /// Any programmed value, for per-unit data such as serial numbers.
+    Written,
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expected::Value(value) => write!(f, "0x{:08x}", value),
+            Expected::Written => f.write_str("any written value"),
+        }
+    }
+}
+
+/// Expected UICR contents after programming. Words not listed must be
+/// erased.
+#[derive(Clone, Debug)]
+pub struct UicrTemplate {
+    words: BTreeMap<u64, Expected>,
+}
+
+/// A UICR word that differs from the template.
+#[derive(Clone, Debug)]
+pub struct Deviation {
+    pub address: u64,
+    pub expected: Expected,
+    pub actual: u32,
+}
+
+impl UicrTemplate {
+    /// Load a JSON object mapping word addresses to values, e.g.
+    /// `{"0x00FF8000": "0x50FA50FA", "0x00FF8080": "*"}`, where `*` stands
+    /// for any written value.
+    pub fn load(path: &Path) -> Result<Self, RecoveryError> {
+        let err = |e: String| RecoveryError::ConfigError(format!("{}: {}", path.display(), e));
+        let text = fs::read_to_string(path).map_err(|e| err(e.to_string()))?;
+        let entries: BTreeMap<String, String> = serde_json::from_str(&text).map_err(|e| err(e.to_string()))?;
+
+        let mut words = BTreeMap::new();
+        for (address, value) in entries {
+            let address = parse_number(&address).map_err(err)?;
+            if !(UICR_START..UICR_END).contains(&address) || address % 4 != 0 {
+                return Err(err(format!("0x{:08x} is not a UICR word", address)));
+            }
+            let expected = match value.trim() {
+                "*" => Expected::Written,
+                value => Expected::Value(
+                    parse_number(value)
+                        .ok()
+                        .and_then(|v| u32::try_from(v).ok())
+                        .ok_or_else(|| err(format!("invalid value '{}' for 0x{:08x}", value, address)))?,
+                ),
+            };
+            words.insert(address, expected);
+        }
+        Ok(UicrTemplate { words })
+    }
+
+    /// Read all of UICR and list the words that differ from the template.
+    pub fn compare(&self, session: &mut Session) -> Result<Vec<Deviation>, RecoveryError> {
+        let mut words = vec![0u32; ((UICR_END - UICR_START) / 4) as usize];
+        session.core(0)?.read_32(UICR_START, &mut words)?;
+
+        Ok(words
+            .into_iter()
+            .enumerate()
+            .map(|(i, actual)| (UICR_START + i as u64 * 4, actual))
+            .filter_map(|(address, actual)| {
+                let expected = self.words.get(&address).copied().unwrap_or(Expected::Value(0xFFFF_FFFF));
+                let matches = match expected {
+                    Expected::Value(value) => actual == value,
+                    Expected::Written => actual != 0xFFFF_FFFF,
+                };
+                (!matches).then_some(Deviation { address, expected, actual })
+            })
+            .collect())
+    }
+}
+
+/// Compare UICR with the golden template, printing every deviation.
+pub fn check(session: &mut Session, template: &UicrTemplate) -> Result<(), RecoveryError> {
+    let deviations = template.compare(session)?;
+    if deviations.is_empty() {
+        say(tr!("uicr_template_matches"));
+        return Ok(());
+    }
+    for d in &deviations {
+        eprintln!("  0x{:08x}: expected {}, read 0x{:08x}", d.address, d.expected, d.actual);
+    }
+    Err(RecoveryError::UicrError(format!(
+        "{} UICR word(s) differ from the template",
+        deviations.len()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::approtect::{HW_UNPROTECTED, UICR_APPROTECT, UICR_SECUREAPPROTECT};
+
+    #[test]
+    fn register_names_map_to_addresses() {
+        assert_eq!(parse_write("HFXOSRC=0"), Ok((0x00FF801C, 0)));
+        assert_eq!(parse_write("otp[3]=0x123"), Ok((0x00FF8114, 0x123)));
+        assert_eq!(parse_write("0x00FF8020=1"), Ok((0x00FF8020, 1)));
+        assert_eq!(address_of("OTP[0]"), Some(PROVISION_ADDRESS));
+        // UICR.OTP ends where KEYSLOT.CONFIG begins.
+        assert_eq!(address_of("OTP[189]"), Some(0x00FF83FC));
+        assert_eq!(address_of("OTP[190]"), None);
+        assert!(parse_write("0x00FF8022=1").is_err());
+        assert!(parse_write("0x00FF9000=1").is_err());
+
+        assert_eq!(name(0x00FF801C).as_deref(), Some("HFXOSRC"));
+        assert_eq!(name(0x00FF8114).as_deref(), Some("OTP[3]"));
+        assert_eq!(name(0x00FF8404).as_deref(), Some("KEYSLOT.CONFIG[0].PERM"));
+        assert_eq!(name(0x00FF8824).as_deref(), Some("KEYSLOT.KEY[2].VALUE[1]"));
+    }
+
+    #[test]
+    fn extra_words_cannot_unlock_a_locked_unit() {
+        let extra = [(UICR_APPROTECT, HW_UNPROTECTED), (0x00FF801C, 0)];
+        let words = flow_words(&extra, &Protection::LOCKED, "nRF9151_xxAA");
+        assert_eq!(
+            words,
+            [(UICR_APPROTECT, PROTECTED), (UICR_SECUREAPPROTECT, PROTECTED), (0x00FF801C, 0)]
+        );
+
+        // Without protection to enforce, the extra word wins.
+        let words = flow_words(&extra, &Protection::default(), "nRF9151_xxAA");
+        assert_eq!(words[0], (UICR_APPROTECT, HW_UNPROTECTED));
+    }
+}
@@ -0,0 +1,82 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use crate::notify::shell;
+
+/// Hands result lines to an upload command, keeping the ones that could not
+/// be delivered in a local queue until the network is back.
+pub struct Uploader {
+    /// Shell command receiving one result line on stdin.
+    pub command: String,
+    /// Queue everything without trying to upload.
+    pub offline: bool,
+    /// Lines waiting to be uploaded, one per line.
+    pub pending: PathBuf,
+}
+
+impl Uploader {
+    fn send(&self, line: &str) -> io::Result<()> {
+        let mut child = shell(&self.command).stdin(Stdio::piped()).spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            writeln!(stdin, "{}", line)?;
+        }
+        let status = child.wait()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("exited with {}", status)))
+        }
+    }
+
+    fn load(&self) -> Vec<String> {
+        match fs::read_to_string(&self.pending) {
+            Ok(text) => text.lines().filter(|l| !l.is_empty()).map(str::to_string).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn save(&self, lines: &[String]) -> io::Result<()> {
+        if lines.is_empty() {
+            return match fs::remove_file(&self.pending) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let tmp = self.pending.with_extension("tmp");
+        let mut text = lines.join("\n");
+        text.push('\n');
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, &self.pending)
+    }
+
+    /// Upload the queued lines, oldest first, followed by `line`. Whatever is
+    /// left after the first failure stays queued.
+    pub fn push(&self, line: Option<&str>) {
+        let mut queue = self.load();
+        let queued = queue.len();
+        queue.extend(line.map(str::to_string));
+        if queue.is_empty() {
+            return;
+        }
+
+        let mut sent = 0;
+        if !self.offline {
+            for line in &queue {
+                if let Err(e) = self.send(line) {
+                    log::warn!("Upload failed, keeping results for later: {}", e);
+                    break;
+                }
+                sent += 1;
+            }
+        }
+        if sent > 0 && queued > 0 {
+            log::info!("Uploaded {} queued results", sent.min(queued));
+        }
+
+        if let Err(e) = self.save(&queue[sent..]) {
+            log::warn!("Unable to update {}: {}", self.pending.display(), e);
+        }
+    }
+}
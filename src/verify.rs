@@ -0,0 +1,214 @@
+use clap::ValueEnum;
+use probe_rs::config::MemoryRegion;
+use probe_rs::{MemoryInterface, Session};
+use serde::Deserialize;
+
+use std::ops::Range;
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::debug::find_register;
+use crate::image::{ImageFile, Segment};
+use crate::RecoveryError;
+
+/// How thoroughly flashed data is checked. Each level includes the ones
+/// before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyLevel {
+    /// Program every page, check nothing.
+    None,
+    /// Read flash before programming and skip pages that already match.
+    Preverify,
+    /// Also compare a CRC32 computed on the target against the image.
+    Crc,
+    /// Also read back every programmed byte.
+    Full,
+}
+
+/// Bitwise CRC-32 (IEEE) over r1 bytes at r0, starting from r2. Returns the
+/// final CRC in r0 and stops on a breakpoint.
+///
+/// ```text
+///     movw  r3, #0x8320
+///     movt  r3, #0xEDB8
+/// next:
+///     cbz   r1, done
+///     ldrb  r4, [r0], #1
+///     eors  r2, r4
+///     movs  r5, #8
+/// bit:
+///     lsrs  r2, r2, #1
+///     it    cs
+///     eorcs r2, r3
+///     subs  r5, r5, #1
+///     bne   bit
+///     subs  r1, r1, #1
+///     b     next
+/// done:
+///     mvns  r0, r2
+///     bkpt  #0
+/// ```
+const CRC_STUB: [u8; 36] = [
+    0x48, 0xf2, 0x20, 0x33, 0xce, 0xf6, 0xb8, 0x53, 0x51, 0xb1, 0x10, 0xf8, 0x01, 0x4b, 0x62, 0x40,
+    0x08, 0x25, 0x52, 0x08, 0x28, 0xbf, 0x5a, 0x40, 0x6d, 0x1e, 0xfa, 0xd1, 0x49, 0x1e, 0xf3, 0xe7,
+    0xd0, 0x43, 0x00, 0xbe,
+];
+
+/// The stub checks about a megabyte per second at 64 MHz.
+const CRC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Compare a CRC32 of each segment, computed by a stub running on the
+/// target, with the CRC of the image data. Only the 4-byte result crosses
+/// SWD, instead of the whole image.
+///
+/// The stub is loaded into RAM, so RAM contents are lost; call this before
+/// the device is reset into its firmware.
+pub fn crc_check(session: &mut Session, segments: &[Segment]) -> Result<(), RecoveryError> {
+    let ram = session
+        .target()
+        .memory_map
+        .iter()
+        .find_map(|region| match region {
+            MemoryRegion::Ram(ram) => Some(ram.range.clone()),
+            _ => None,
+        })
+        .ok_or_else(|| RecoveryError::VerifyError("target has no RAM for the CRC stub".into()))?;
+
+    let mut core = session.core(0)?;
+    core.halt(Duration::from_millis(100))?;
+    core.write_8(ram.start, &CRC_STUB)?;
+
+    let r0 = find_register(&core, "R0")?.id();
+    let r1 = find_register(&core, "R1")?.id();
+    let r2 = find_register(&core, "R2")?.id();
+    let sp = find_register(&core, "SP")?.id();
+    let pc = find_register(&core, "PC")?.id();
+    let xpsr = find_register(&core, "XPSR")?.id();
+
+    for segment in segments {
+        core.write_core_reg(r0, segment.address as u32)?;
+        core.write_core_reg(r1, segment.data.len() as u32)?;
+        core.write_core_reg(r2, 0xFFFF_FFFFu32)?;
+        core.write_core_reg(sp, ram.end as u32)?;
+        core.write_core_reg(pc, ram.start as u32)?;
+        // Thumb state.
+        core.write_core_reg(xpsr, 0x0100_0000u32)?;
+
+        core.run()?;
+        core.wait_for_core_halted(CRC_TIMEOUT)?;
+
+        let device: u32 = core.read_core_reg(r0)?;
+        let expected = crc32fast::hash(&segment.data);
+        log::debug!(
+            "CRC 0x{:08x}+{}: device 0x{:08x}, image 0x{:08x}",
+            segment.address,
+            segment.data.len(),
+            device,
+            expected
+        );
+        if device != expected {
+            return Err(RecoveryError::VerifyError(format!(
+                "CRC mismatch at 0x{:08x}..0x{:08x}: device 0x{:08x}, image 0x{:08x}",
+                segment.address,
+                segment.address + segment.data.len() as u64,
+                device,
+                expected
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Window the images are compared in, to keep memory use flat.
+const COMPARE_WINDOW: u64 = 64 * 1024;
+
+/// Read back everything `file` would write and return the address ranges
+/// that differ, in order. Differing bytes next to each other make one range.
+/// Nothing is written, so this works on units programmed by anything.
+pub fn compare(session: &mut Session, file: &ImageFile) -> Result<Vec<Range<u64>>, RecoveryError> {
+    let mut mismatches: Vec<Range<u64>> = Vec::new();
+    file.stream(COMPARE_WINDOW, |segments| {
+        let mut core = session.core(0)?;
+        for segment in segments {
+            let mut data = vec![0u8; segment.data.len()];
+            core.read(segment.address, &mut data)?;
+            for (i, _) in data.iter().zip(&segment.data).enumerate().filter(|(_, (a, b))| a != b) {
+                let address = segment.address + i as u64;
+                match mismatches.last_mut() {
+                    Some(last) if last.end == address => last.end += 1,
+                    _ => mismatches.push(address..address + 1),
+                }
+            }
+        }
+        Ok(())
+    })?;
+    Ok(mismatches)
+}
+
+/// Data programmed at `address`, and what was read back from there.
+pub struct Readback {
+    pub address: u64,
+    pub expected: Vec<u8>,
+    pub actual: Vec<u8>,
+}
+
+/// Full verification overlapped with programming: each window is read back
+/// right after it is programmed and compared on a worker thread while the
+/// next window is programmed. The channel holds one window, so at most two
+/// are in memory, one being compared and one waiting.
+pub struct Pipeline {
+    sender: Option<SyncSender<Vec<Readback>>>,
+    worker: Option<JoinHandle<Result<(), String>>>,
+}
+
+impl Pipeline {
+    pub fn start() -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<Readback>>(1);
+        let worker = thread::spawn(move || {
+            for window in receiver {
+                for readback in window {
+                    if let Some(i) = readback.expected.iter().zip(&readback.actual).position(|(a, b)| a != b) {
+                        return Err(format!(
+                            "0x{:08x} reads 0x{:02x}, expected 0x{:02x}",
+                            readback.address + i as u64,
+                            readback.actual[i],
+                            readback.expected[i]
+                        ));
+                    }
+                }
+            }
+            Ok(())
+        });
+        Pipeline {
+            sender: Some(sender),
+            worker: Some(worker),
+        }
+    }
+
+    /// Queue a window for comparison. Blocks while the window before it is
+    /// still waiting, and fails as soon as an earlier window mismatched.
+    pub fn check(&mut self, window: Vec<Readback>) -> Result<(), RecoveryError> {
+        let sent = self.sender.as_ref().map(|sender| sender.send(window).is_ok());
+        if sent == Some(true) {
+            return Ok(());
+        }
+        // The worker stopped at a mismatch.
+        self.join()
+    }
+
+    /// Wait for the remaining windows to be compared.
+    pub fn finish(mut self) -> Result<(), RecoveryError> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<(), RecoveryError> {
+        self.sender = None;
+        match self.worker.take().map(|worker| worker.join()) {
+            Some(Ok(Err(e))) => Err(RecoveryError::VerifyError(e)),
+            Some(Err(_)) => Err(RecoveryError::VerifyError("verify thread panicked".into())),
+            _ => Ok(()),
+        }
+    }
+}
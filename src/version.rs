@@ -0,0 +1,194 @@
+use probe_rs::{MemoryInterface, Session};
+use regex::Regex;
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::console::{self, ConsoleSpec};
+use crate::RecoveryError;
+
+pub const MCUBOOT_MAGIC: u32 = 0x96f3b83d;
+
+/// Firmware version in MCUboot's `major.minor.revision+build` form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub revision: u16,
+    pub build: Option<u32>,
+}
+
+impl ImageVersion {
+    /// Whether `actual` satisfies this expected version. The build number is
+    /// only compared when the expected version specifies one.
+    pub fn matches(&self, actual: &ImageVersion) -> bool {
+        self.major == actual.major
+            && self.minor == actual.minor
+            && self.revision == actual.revision
+            && (self.build.is_none() || self.build == actual.build)
+    }
+}
+
+impl fmt::Display for ImageVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.revision)?;
+        if let Some(build) = self.build {
+            write!(f, "+{}", build)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ImageVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid version '{}', expected MAJOR.MINOR.REVISION[+BUILD]", s);
+        let (version, build) = match s.split_once('+') {
+            Some((version, build)) => (version, Some(build.parse().map_err(|_| err())?)),
+            None => (s, None),
+        };
+        let mut parts = version.split('.');
+        let major = parts.next().and_then(|p| p.parse().ok()).ok_or_else(err)?;
+        let minor = parts.next().and_then(|p| p.parse().ok()).unwrap_or(0);
+        let revision = parts.next().and_then(|p| p.parse().ok()).unwrap_or(0);
+        if parts.next().is_some() {
+            return Err(err());
+        }
+        Ok(ImageVersion {
+            major,
+            minor,
+            revision,
+            build,
+        })
+    }
+}
+
+/// Where to read the running firmware's version from:
+/// `mcuboot:ADDR` for an MCUboot image header in flash, or
+/// `shell:COMMAND` for a console command whose response contains the version.
+#[derive(Clone, Debug)]
+pub enum VersionSource {
+    Mcuboot(u64),
+    Shell(String),
+}
+
+impl FromStr for VersionSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("mcuboot", addr)) => Ok(VersionSource::Mcuboot(crate::parse_number(addr)?)),
+            Some(("shell", command)) if !command.is_empty() => {
+                Ok(VersionSource::Shell(command.to_string()))
+            }
+            _ => Err(format!(
+                "Invalid version source '{}', expected mcuboot:ADDR or shell:COMMAND",
+                s
+            )),
+        }
+    }
+}
+
+/// Read the version from the MCUboot image header at `addr`.
+fn read_mcuboot(session: &mut Session, addr: u64) -> Result<ImageVersion, RecoveryError> {
+    let mut core = session.core(0)?;
+    let mut header = [0u8; 32];
+    core.read(addr, &mut header)?;
+
+    let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    if magic != MCUBOOT_MAGIC {
+        return Err(RecoveryError::VersionError(format!(
+            "No MCUboot header at 0x{:08x} (magic 0x{:08x})",
+            addr, magic
+        )));
+    }
+
+    Ok(ImageVersion {
+        major: header[20],
+        minor: header[21],
+        revision: u16::from_le_bytes(header[22..24].try_into().unwrap()),
+        build: Some(u32::from_le_bytes(header[24..28].try_into().unwrap())),
+    })
+}
+
+/// Query the version over the console and take the first version-like string.
+fn read_shell(
+    session: &mut Session,
+    console: &ConsoleSpec,
+    command: &str,
+) -> Result<ImageVersion, RecoveryError> {
+    let pattern = Regex::new(r"(\d+)\.(\d+)\.(\d+)(\+\d+)?").unwrap();
+    let mut console = console::open(session, console)?;
+    let response = console::exchange(console.as_mut(), command, &pattern, Duration::from_secs(2))?;
+
+    let found = pattern.find(&response).unwrap().as_str();
+    found
+        .parse()
+        .map_err(|e: String| RecoveryError::VersionError(e))
+}
+
+/// Read the running firmware's version from `source`.
+pub fn read_version(
+    session: &mut Session,
+    source: &VersionSource,
+    console: &ConsoleSpec,
+) -> Result<ImageVersion, RecoveryError> {
+    match source {
+        VersionSource::Mcuboot(addr) => read_mcuboot(session, *addr),
+        VersionSource::Shell(command) => read_shell(session, console, command),
+    }
+}
+
+/// Fail unless the firmware reports a version matching `expected`.
+pub fn check_version(
+    session: &mut Session,
+    source: &VersionSource,
+    console: &ConsoleSpec,
+    expected: &ImageVersion,
+) -> Result<ImageVersion, RecoveryError> {
+    let actual = read_version(session, source, console)?;
+    if !expected.matches(&actual) {
+        return Err(RecoveryError::VersionMismatch {
+            expected: *expected,
+            actual,
+        });
+    }
+    Ok(actual)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(major: u8, minor: u8, revision: u16, build: Option<u32>) -> ImageVersion {
+        ImageVersion {
+            major,
+            minor,
+            revision,
+            build,
+        }
+    }
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!("1.2.3+4".parse(), Ok(version(1, 2, 3, Some(4))));
+        assert_eq!("1.2".parse(), Ok(version(1, 2, 0, None)));
+        assert_eq!("1".parse(), Ok(version(1, 0, 0, None)));
+        assert_eq!("1.2.3+4".parse::<ImageVersion>().unwrap().to_string(), "1.2.3+4");
+        assert!("1.2.3.4".parse::<ImageVersion>().is_err());
+        assert!("1.2.3+".parse::<ImageVersion>().is_err());
+        assert!("v1.2.3".parse::<ImageVersion>().is_err());
+    }
+
+    #[test]
+    fn build_number_only_counts_when_expected() {
+        let actual = version(1, 2, 3, Some(4));
+        assert!(version(1, 2, 3, None).matches(&actual));
+        assert!(version(1, 2, 3, Some(4)).matches(&actual));
+        assert!(!version(1, 2, 3, Some(5)).matches(&actual));
+        assert!(!version(1, 2, 4, None).matches(&actual));
+        assert!(!version(1, 2, 3, Some(4)).matches(&version(1, 2, 3, None)));
+    }
+}
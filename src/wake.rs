@@ -0,0 +1,73 @@
+use probe_rs::architecture::arm::ap::{ApRegister, CSW};
+use probe_rs::probe::Probe;
+
+use std::thread;
+use std::time::Duration;
+
+use crate::dap::{self, DebugPorts};
+use crate::i18n::tr;
+use crate::output::say;
+use crate::RecoveryError;
+
+const CTRL_AP_RESET: u64 = 0x000;
+
+/// Debug power-up requests made before giving up on the debug port.
+const POWER_UP_ATTEMPTS: usize = 5;
+const POWER_UP_INTERVAL: Duration = Duration::from_millis(200);
+/// Time the device gets to start after the CTRL-AP reset.
+const RESET_TIME: Duration = Duration::from_millis(100);
+
+/// Make sure an nRF91 answers on its access ports, waking it from System
+/// OFF if needed.
+///
+/// A device in System OFF leaves the debug port silent until a debug
+/// power-up request wakes it into emulated System OFF, and its memory access
+/// port can stay unresponsive after that until the device is reset. Both look
+/// like a dead board, so power-up is retried and, if the memory access port
+/// still does not answer, the device is reset through CTRL-AP, which is
+/// always powered. Returns whether the device had to be woken.
+pub fn wake(mut probe: Probe, ports: &DebugPorts) -> Result<(Probe, bool), RecoveryError> {
+    let mut attempt = 1;
+    let mut iface = loop {
+        probe.attach_to_unspecified()?;
+        let uninit = probe
+            .try_into_arm_interface()
+            .map_err(|(_p, e)| RecoveryError::DebugProbeError(e))?;
+        match uninit.initialize_unspecified(ports.dp()) {
+            Ok(iface) => break iface,
+            Err((uninit, e)) if attempt < POWER_UP_ATTEMPTS => {
+                log::info!("Debug power-up attempt {} failed: {}", attempt, e);
+                probe = uninit.close();
+                let _ = probe.detach();
+                attempt += 1;
+                thread::sleep(POWER_UP_INTERVAL);
+            }
+            Err((_uninit, e)) => {
+                return Err(RecoveryError::UnlockError(format!(
+                    "no response from the debug port after {} power-up requests ({}); check power and SWD wiring",
+                    POWER_UP_ATTEMPTS, e
+                )))
+            }
+        }
+    };
+
+    let mut woke = attempt > 1;
+    if let Err(e) = iface.read_raw_ap_register(&ports.mem_ap(), CSW::ADDRESS) {
+        log::info!("Memory access port not responding ({}), resetting through CTRL-AP", e);
+        iface.write_raw_ap_register(&ports.ctrl_ap(), CTRL_AP_RESET, 1)?;
+        iface.write_raw_ap_register(&ports.ctrl_ap(), CTRL_AP_RESET, 0)?;
+        thread::sleep(RESET_TIME);
+        iface.read_raw_ap_register(&ports.mem_ap(), CSW::ADDRESS).map_err(|e| {
+            RecoveryError::UnlockError(format!(
+                "memory access port still not responding after a CTRL-AP reset: {}",
+                e
+            ))
+        })?;
+        woke = true;
+    }
+    if woke {
+        say(tr!("woke_from_sleep"));
+    }
+
+    Ok((dap::disconnect(iface), woke))
+}